@@ -0,0 +1,113 @@
+//! Benchmarks of the per-packet work: LTS alignment, channel estimation and the full parse. Run
+//! with `cargo bench`. The packets are generated from a fixed seed, so results are comparable
+//! across runs
+
+use channel_est::config::{build_packet, ChannelEstConfig};
+use channel_est::equalization::estimate_subcarrier_equalization;
+use channel_est::lts_align::{cross_correlation_direct, lts_align};
+use channel_est::parse_80211::parse_80211_pkt;
+use channel_est::sim::{add_awgn, random_bpsk_symbols};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use num::{Complex, Zero};
+use rand::SeedableRng;
+
+/// Number of data symbols in the benchmark packet. With the preamble and the quiet samples around
+/// it, the buffer is about 1400 samples long, like a short 802.11 frame
+const NUM_SYMBOLS: usize = 13;
+
+/// Number of data symbols in the long packet benchmark, where the per-symbol work dominates
+const NUM_SYMBOLS_LONG: usize = 200;
+
+/// A packet of `num_symbols` random BPSK symbols with 802.11 pilots, preceded by
+/// `config.pkt_spacing` quiet samples as `PktTrigger` would leave them, through a two-path channel
+/// with a little noise
+fn synthetic_packet(config: &ChannelEstConfig, num_symbols: usize, seed: u64) -> Vec<Complex<f32>> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let symbols = random_bpsk_symbols(config, num_symbols, &mut rng);
+
+    let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize];
+    pkt.extend(build_packet(config, &symbols));
+    pkt.extend(std::iter::repeat(Complex::zero()).take(2 * config.pkt_spacing as usize));
+    // An echo 4 samples late, well within the cyclic prefix
+    let echo = Complex::new(0.3, -0.2);
+    for i in (4..pkt.len()).rev() {
+        pkt[i] = pkt[i] + echo * pkt[i - 4];
+    }
+    add_awgn(&mut pkt, 1e-3, &mut rng);
+    pkt
+}
+
+fn bench_lts_align(c: &mut Criterion) {
+    let config = ChannelEstConfig::default();
+    let pkt = synthetic_packet(&config, NUM_SYMBOLS, 0);
+    let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+    c.bench_function("lts_align", |b| {
+        b.iter(|| lts_align(black_box(&pkt), lts, corr_plans))
+    });
+}
+
+/// `lts_align` on a 4096-sample search window, where it correlates by FFT with the config's plans,
+/// against the direct correlation alone. The latter is what `lts_align` cost at this length
+/// before it used the FFT, less the peak search
+fn bench_lts_align_4096(c: &mut Criterion) {
+    let config = ChannelEstConfig::default();
+    let mut pkt = synthetic_packet(&config, NUM_SYMBOLS, 0);
+    pkt.resize(4096, Complex::zero());
+    add_awgn(&mut pkt, 1e-3, &mut rand::rngs::StdRng::seed_from_u64(1));
+    let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+    let mut group = c.benchmark_group("lts_align_4096");
+    group.bench_function("direct_correlation", |b| {
+        b.iter(|| cross_correlation_direct(black_box(&pkt), lts))
+    });
+    group.bench_function("lts_align", |b| {
+        b.iter(|| lts_align(black_box(&pkt), lts, corr_plans))
+    });
+    group.finish();
+}
+
+fn bench_estimate_equalization(c: &mut Criterion) {
+    let config = ChannelEstConfig::default();
+    let pkt = synthetic_packet(&config, NUM_SYMBOLS, 0);
+    let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+    let lts_start = lts_align(&pkt, lts, corr_plans);
+    let lts_len = lts.len();
+    // The long preamble including its guard interval (no CFO to correct)
+    let long = &pkt[lts_start..lts_start + 5 * lts_len / 2];
+    c.bench_function("estimate_subcarrier_equalization", |b| {
+        b.iter(|| estimate_subcarrier_equalization(black_box(long), &config))
+    });
+}
+
+fn bench_parse_80211_pkt(c: &mut Criterion) {
+    let config = ChannelEstConfig::default();
+    let pkt = synthetic_packet(&config, NUM_SYMBOLS, 0);
+    assert_eq!(
+        parse_80211_pkt(&pkt, &config).unwrap().len(),
+        NUM_SYMBOLS * 52
+    );
+    c.bench_function("parse_80211_pkt", |b| {
+        b.iter(|| parse_80211_pkt(black_box(&pkt), &config))
+    });
+}
+
+fn bench_parse_80211_pkt_long(c: &mut Criterion) {
+    let config = ChannelEstConfig::default();
+    let pkt = synthetic_packet(&config, NUM_SYMBOLS_LONG, 0);
+    assert_eq!(
+        parse_80211_pkt(&pkt, &config).unwrap().len(),
+        NUM_SYMBOLS_LONG * 52
+    );
+    c.bench_function("parse_80211_pkt_200_symbols", |b| {
+        b.iter(|| parse_80211_pkt(black_box(&pkt), &config))
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_lts_align,
+    bench_lts_align_4096,
+    bench_estimate_equalization,
+    bench_parse_80211_pkt,
+    bench_parse_80211_pkt_long
+);
+criterion_main!(benches);
@@ -0,0 +1,124 @@
+//! Automatic gain control for raw samples. `PktTrigger` compares against a fixed threshold, so
+//! when the input level varies widely (e.g. transmitters at very different distances), it helps to
+//! first normalize the level
+
+use num::Complex;
+
+/// Gain applied to each sample by `agc`: `target_rms` divided by the RMS of the `window` most
+/// recent samples (including the current one). While fewer than `window` samples have been seen,
+/// the RMS is over the samples so far. Where the window is all zeros, the gain is 1
+pub fn agc_gains(samps: &[Complex<f32>], target_rms: f32, window: usize) -> Vec<f32> {
+    assert!(window > 0);
+    // Running sum of the power in the window. Accumulate in f64 so it doesn't drift
+    let mut power = 0f64;
+    samps
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            power += f64::from(x.norm_sqr());
+            if i >= window {
+                power -= f64::from(samps[i - window].norm_sqr());
+            }
+            let rms = (power.max(0.) / (i + 1).min(window) as f64).sqrt() as f32;
+            if rms > 0. {
+                target_rms / rms
+            } else {
+                1.
+            }
+        })
+        .collect()
+}
+
+/// Scale `samps` by a sliding-window gain so their RMS stays close to `target_rms` (see
+/// `agc_gains`). Note that this also raises the noise between packets to `target_rms`, so
+/// `power_trig` should be set relative to the target rather than the noise floor. The window
+/// should be long compared to a preamble so it doesn't flatten the packet's rising edge
+pub fn agc(samps: &[Complex<f32>], target_rms: f32, window: usize) -> Vec<Complex<f32>> {
+    samps
+        .iter()
+        .zip(agc_gains(samps, target_rms, window))
+        .map(|(x, g)| x * g)
+        .collect()
+}
+
+/// Scale all of `samps` by one gain so their RMS is `target_rms`, and return the gain. Unlike
+/// `agc`, this keeps the relative levels within the buffer, so it suits whole recordings captured
+/// at different gains: a fixed `power_trig` then works across them. A buffer of zeros is left
+/// alone, with a gain of 1
+pub fn normalize_rms(samps: &mut [Complex<f32>], target_rms: f32) -> f32 {
+    if samps.is_empty() {
+        return 1.;
+    }
+    let power = samps.iter().map(|x| f64::from(x.norm_sqr())).sum::<f64>() / samps.len() as f64;
+    if power <= 0. {
+        return 1.;
+    }
+    let gain = target_rms / power.sqrt() as f32;
+    for x in samps.iter_mut() {
+        *x *= gain;
+    }
+    gain
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::add_awgn;
+    use num::Zero;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_agc() {
+        // A signal whose level drops by 20 dB halfway through
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut samps = vec![Complex::zero(); 10_000];
+        add_awgn(&mut samps, 1., &mut rng);
+        for x in &mut samps[5_000..] {
+            *x *= 0.1;
+        }
+
+        let (target_rms, window) = (0.5, 200);
+        let gains = agc_gains(&samps, target_rms, window);
+        let out = agc(&samps, target_rms, window);
+        assert_eq!(out.len(), samps.len());
+        assert!((gains[4_999] - 0.5).abs() < 0.1);
+        assert!((gains[9_999] - 5.).abs() < 1.);
+
+        // Away from the step, the output level stays near the target
+        let rms = |x: &[Complex<f32>]| {
+            (x.iter().map(|x| x.norm_sqr()).sum::<f32>() / x.len() as f32).sqrt()
+        };
+        for start in (window..10_000).step_by(window) {
+            if (5_000..5_000 + window).contains(&start) {
+                continue;
+            }
+            assert!((rms(&out[start..start + window]) - target_rms).abs() < 0.2 * target_rms);
+        }
+
+        // Silence is left alone
+        assert_eq!(
+            agc_gains(&[Complex::zero(); 10], target_rms, window),
+            vec![1.; 10]
+        );
+    }
+
+    #[test]
+    fn test_normalize_rms() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut samps = vec![Complex::zero(); 10_000];
+        add_awgn(&mut samps, 1e-4, &mut rng);
+        let orig = samps.clone();
+
+        let gain = normalize_rms(&mut samps, 0.5);
+        let rms = (samps.iter().map(|x| x.norm_sqr()).sum::<f32>() / samps.len() as f32).sqrt();
+        assert!((rms - 0.5).abs() < 1e-3 * 0.5);
+        assert!((gain - 50.).abs() < 1.);
+        for (x, y) in samps.iter().zip(&orig) {
+            assert!((x - y * gain).norm() < 1e-6);
+        }
+
+        let mut silence = [Complex::zero(); 10];
+        assert_eq!(normalize_rms(&mut silence, 0.5), 1.);
+        assert_eq!(normalize_rms(&mut [], 0.5), 1.);
+    }
+}
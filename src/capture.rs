@@ -0,0 +1,577 @@
+//! Metadata describing how a capture was recorded. Captures usually carry their sample rate and
+//! center frequency either in a sidecar file or in a small header. Reading it from there and
+//! applying it to `ChannelEstConfig` avoids having to copy these numbers by hand.
+//!
+//! Two layouts are supported:
+//!  - A raw `.cfile` with a `.json` sidecar of the same name (e.g. `cap.cfile` and `cap.json`).
+//!    The sidecar is an object with `sample_rate` and `center_freq` keys (`samp_rate` and
+//!    `frequency` are accepted as aliases)
+//!  - A raw file that starts with a 20 byte header: the magic `IQHD` followed by the sample rate
+//!    and center frequency as little-endian `f64`s. Samples follow the header
+//!
+//! SigMF recordings (a `.sigmf-meta` JSON file describing a `.sigmf-data` file) are read by
+//! `read_sigmf`.
+//!
+//! Stereo WAV files with I in the left channel and Q in the right (as recorded by SDR# and GQRX)
+//! are read by `read_iq_wav`.
+//!
+//! Raw captures without a header (`fc32`, as written by GNU Radio and UHD) are read by
+//! `read_iq_fc32`.
+//!
+//! Any capture (including the ASCII format read by `config::filename_to_cplx_vec`) may be gzip
+//! compressed. Compressed files are recognized by their magic bytes and decompressed on the fly
+
+use crate::config::ChannelEstConfig;
+use crate::error::{Error, Result};
+use flate2::read::MultiGzDecoder;
+use num::Complex;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of a raw capture with a header
+pub const RAW_HEADER_MAGIC: &[u8; 4] = b"IQHD";
+/// Length of the header (in bytes) in a raw capture with a header
+pub const RAW_HEADER_LEN: usize = 20;
+
+/// Magic bytes at the start of a gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Open a capture for reading, transparently decompressing it if it is gzip compressed
+pub fn open_capture<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(path)?);
+    if file.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Read a raw `fc32` capture: interleaved real and imaginary parts as little-endian `f32`s. The
+/// file may be gzip compressed. A file whose length isn't a whole number of samples is an
+/// `InvalidData` error
+pub fn read_iq_fc32(path: &str) -> io::Result<Vec<Complex<f32>>> {
+    let mut bytes = Vec::new();
+    open_capture(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() % 8 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "'{}' is {} bytes long, which is not a whole number of fc32 samples",
+                path,
+                bytes.len()
+            ),
+        ));
+    }
+
+    let read_f32 = |b: &[u8]| {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(b);
+        f32::from_le_bytes(buf)
+    };
+    Ok(bytes
+        .chunks(8)
+        .map(|b| Complex::new(read_f32(&b[..4]), read_f32(&b[4..])))
+        .collect())
+}
+
+/// Write `samps` as a raw `fc32` capture, readable by `read_iq_fc32`
+pub fn write_iq_fc32(path: &str, samps: &[Complex<f32>]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for x in samps {
+        out.write_all(&x.re.to_le_bytes())?;
+        out.write_all(&x.im.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+/// Metadata of a SigMF recording
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigmfMeta {
+    /// `core:datatype`, e.g. `cf32_le`
+    pub datatype: String,
+    /// `core:sample_rate` in samples per second
+    pub samp_rate: Option<f64>,
+    /// `core:frequency` of the first capture segment, in Hz
+    pub center_freq: Option<f64>,
+}
+
+impl SigmfMeta {
+    /// The fields that `CaptureMeta::apply` can copy into a config
+    pub fn capture_meta(&self) -> CaptureMeta {
+        CaptureMeta {
+            samp_rate: self.samp_rate,
+            center_freq: self.center_freq,
+        }
+    }
+}
+
+/// Parse the contents of a `.sigmf-meta` file
+pub fn parse_sigmf_meta(json: &str) -> Result<SigmfMeta> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| Error::Parse(format!("bad SigMF metadata: {}", e)))?;
+    let global = value
+        .get("global")
+        .ok_or_else(|| Error::Parse("SigMF metadata has no 'global' object".to_string()))?;
+    let number = |v: Option<&serde_json::Value>, key: &str| -> Result<Option<f64>> {
+        match v {
+            Some(v) => v
+                .as_f64()
+                .map(Some)
+                .ok_or_else(|| Error::Parse(format!("'{}' is not a number", key))),
+            None => Ok(None),
+        }
+    };
+
+    let datatype = global
+        .get("core:datatype")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| Error::Parse("SigMF metadata has no 'core:datatype'".to_string()))?;
+    let capture = value.get("captures").and_then(|v| v.get(0));
+    Ok(SigmfMeta {
+        datatype: datatype.to_string(),
+        samp_rate: number(global.get("core:sample_rate"), "core:sample_rate")?,
+        center_freq: number(
+            capture.and_then(|c| c.get("core:frequency")),
+            "core:frequency",
+        )?,
+    })
+}
+
+/// Read a SigMF recording given the path to its `.sigmf-meta` file. The samples are read from the
+/// `.sigmf-data` file of the same name. Supports the `cf32_le` and `ci16_le` datatypes; `ci16`
+/// samples are scaled to [-1, 1)
+pub fn read_sigmf(meta_path: &str) -> Result<(Vec<Complex<f32>>, SigmfMeta)> {
+    let meta = parse_sigmf_meta(&std::fs::read_to_string(meta_path)?)?;
+    let data_path = Path::new(meta_path).with_extension("sigmf-data");
+    let data_path = data_path
+        .to_str()
+        .ok_or_else(|| Error::Parse(format!("bad data path for '{}'", meta_path)))?;
+
+    let samps = match meta.datatype.as_str() {
+        "cf32_le" => read_iq_fc32(data_path)?,
+        "ci16_le" => {
+            let bytes = std::fs::read(data_path)?;
+            if bytes.len() % 4 != 0 {
+                return Err(Error::Parse(format!(
+                    "'{}' is {} bytes long, which is not a whole number of ci16 samples",
+                    data_path,
+                    bytes.len()
+                )));
+            }
+            let read_i16 = |b: &[u8]| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.;
+            bytes
+                .chunks(4)
+                .map(|b| Complex::new(read_i16(&b[..2]), read_i16(&b[2..])))
+                .collect()
+        }
+        datatype => {
+            return Err(Error::Parse(format!(
+                "unsupported SigMF datatype '{}'",
+                datatype
+            )))
+        }
+    };
+    Ok((samps, meta))
+}
+
+/// Read a stereo WAV file whose left channel is I and right channel is Q. Returns the samples and
+/// the sample rate. Supports 16-bit PCM (normalized to [-1, 1)) and 32-bit float samples
+pub fn read_iq_wav(path: &str) -> Result<(Vec<Complex<f32>>, u32)> {
+    let bytes = std::fs::read(path)?;
+    let bad = |msg: &str| Error::Parse(format!("'{}' is not a supported WAV file: {}", path, msg));
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(bad("missing RIFF/WAVE header"));
+    }
+    let read_u16 = |b: &[u8]| u16::from_le_bytes([b[0], b[1]]);
+    let read_u32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+
+    // Walk the chunks, remembering the format and finding the data
+    let mut fmt = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = read_u32(&bytes[pos + 4..]) as usize;
+        let body = &bytes[pos + 8..bytes.len().min(pos + 8 + len)];
+        if id == b"fmt " {
+            if body.len() < 16 {
+                return Err(bad("'fmt ' chunk is too short"));
+            }
+            let mut tag = read_u16(body);
+            // WAVE_FORMAT_EXTENSIBLE keeps the actual format in the sub-format GUID
+            if tag == 0xfffe && body.len() >= 26 {
+                tag = read_u16(&body[24..]);
+            }
+            let (channels, samp_rate, bits) = (
+                read_u16(&body[2..]),
+                read_u32(&body[4..]),
+                read_u16(&body[14..]),
+            );
+            if channels != 2 {
+                return Err(bad(&format!("has {} channels, expected 2", channels)));
+            }
+            fmt = Some((tag, bits, samp_rate));
+        } else if id == b"data" {
+            let (tag, bits, samp_rate) = fmt.ok_or_else(|| bad("'data' before 'fmt '"))?;
+            let samps = match (tag, bits) {
+                (1, 16) => body
+                    .chunks_exact(4)
+                    .map(|b| {
+                        let i = i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.;
+                        let q = i16::from_le_bytes([b[2], b[3]]) as f32 / 32768.;
+                        Complex::new(i, q)
+                    })
+                    .collect(),
+                (3, 32) => body
+                    .chunks_exact(8)
+                    .map(|b| {
+                        let i = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                        let q = f32::from_le_bytes([b[4], b[5], b[6], b[7]]);
+                        Complex::new(i, q)
+                    })
+                    .collect(),
+                _ => return Err(bad(&format!("format {} with {} bits", tag, bits))),
+            };
+            return Ok((samps, samp_rate));
+        }
+        // Chunks are padded to an even length
+        pos += 8 + len + len % 2;
+    }
+    Err(bad("no 'data' chunk"))
+}
+
+/// What we know about how a capture was recorded. Fields that the metadata didn't specify are
+/// `None`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CaptureMeta {
+    /// Sample rate in samples per second
+    pub samp_rate: Option<f64>,
+    /// Center frequency in Hz
+    pub center_freq: Option<f64>,
+}
+
+impl CaptureMeta {
+    /// Copy the known fields into `config`. Fields that are `None` leave `config` untouched
+    pub fn apply(&self, config: &mut ChannelEstConfig) {
+        if self.samp_rate.is_some() {
+            config.samp_rate = self.samp_rate;
+        }
+        if self.center_freq.is_some() {
+            config.center_freq = self.center_freq;
+        }
+    }
+}
+
+/// Parse the contents of a JSON sidecar
+pub fn parse_json_meta(json: &str) -> Result<CaptureMeta> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| Error::Parse(format!("bad JSON metadata: {}", e)))?;
+    let obj = value
+        .as_object()
+        .ok_or_else(|| Error::Parse("JSON metadata is not an object".to_string()))?;
+
+    // Look up the first of `keys` present, insisting it is a number
+    let field = |keys: &[&str]| -> Result<Option<f64>> {
+        for key in keys {
+            if let Some(v) = obj.get(*key) {
+                return v
+                    .as_f64()
+                    .map(Some)
+                    .ok_or_else(|| Error::Parse(format!("'{}' is not a number", key)));
+            }
+        }
+        Ok(None)
+    };
+
+    Ok(CaptureMeta {
+        samp_rate: field(&["sample_rate", "samp_rate"])?,
+        center_freq: field(&["center_freq", "frequency"])?,
+    })
+}
+
+/// Read the `.json` sidecar belonging to the capture at `data_path` (e.g. `cap.json` for
+/// `cap.cfile`)
+pub fn read_json_sidecar<P: AsRef<Path>>(data_path: P) -> Result<CaptureMeta> {
+    let meta_path = data_path.as_ref().with_extension("json");
+    parse_json_meta(&std::fs::read_to_string(meta_path)?)
+}
+
+/// Parse the header at the start of a raw capture. `bytes` must contain at-least
+/// `RAW_HEADER_LEN` bytes
+pub fn parse_raw_header(bytes: &[u8]) -> Result<CaptureMeta> {
+    if bytes.len() < RAW_HEADER_LEN {
+        return Err(Error::Parse(format!(
+            "header is {} bytes long, expected {}",
+            bytes.len(),
+            RAW_HEADER_LEN
+        )));
+    }
+    if &bytes[..4] != RAW_HEADER_MAGIC {
+        return Err(Error::Parse(
+            "capture header has the wrong magic".to_string(),
+        ));
+    }
+
+    let read_f64 = |offset: usize| {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&bytes[offset..offset + 8]);
+        f64::from_le_bytes(buf)
+    };
+    Ok(CaptureMeta {
+        samp_rate: Some(read_f64(4)),
+        center_freq: Some(read_f64(12)),
+    })
+}
+
+/// Read the header of the raw capture at `path`. The samples start `RAW_HEADER_LEN` bytes into
+/// the file
+pub fn read_raw_header<P: AsRef<Path>>(path: P) -> Result<CaptureMeta> {
+    let mut header = [0u8; RAW_HEADER_LEN];
+    open_capture(path)?.read_exact(&mut header)?;
+    parse_raw_header(&header)
+}
+
+/// Write the header for a raw capture. Both fields of `meta` must be known
+pub fn write_raw_header<W: Write>(writer: &mut W, meta: &CaptureMeta) -> Result<()> {
+    let missing = |field: &str| Error::Config(format!("{} is required in the header", field));
+    let samp_rate = meta.samp_rate.ok_or_else(|| missing("sample rate"))?;
+    let center_freq = meta
+        .center_freq
+        .ok_or_else(|| missing("center frequency"))?;
+    writer.write_all(RAW_HEADER_MAGIC)?;
+    writer.write_all(&samp_rate.to_le_bytes())?;
+    writer.write_all(&center_freq.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_sidecar() {
+        let dir = std::env::temp_dir().join("channel_est_test_json_sidecar");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("cap.json"),
+            r#"{"sample_rate": 20e6, "center_freq": 5.5e9, "gain": 30}"#,
+        )
+        .unwrap();
+
+        let meta = read_json_sidecar(dir.join("cap.cfile")).unwrap();
+        assert_eq!(meta.samp_rate, Some(20e6));
+        assert_eq!(meta.center_freq, Some(5.5e9));
+
+        let mut config = ChannelEstConfig::default();
+        assert!(config.samp_rate.is_none());
+        meta.apply(&mut config);
+        assert_eq!(config.samp_rate, Some(20e6));
+        assert_eq!(config.center_freq, Some(5.5e9));
+
+        // Aliases and missing fields
+        let meta = parse_json_meta(r#"{"samp_rate": 1e6}"#).unwrap();
+        assert_eq!(meta.samp_rate, Some(1e6));
+        assert_eq!(meta.center_freq, None);
+        assert!(parse_json_meta(r#"{"frequency": "2.4 GHz"}"#).is_err());
+        assert!(parse_json_meta("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_raw_header() {
+        let meta = CaptureMeta {
+            samp_rate: Some(25e6),
+            center_freq: Some(2.412e9),
+        };
+        let path = std::env::temp_dir().join("channel_est_test_raw_header.iq");
+        let mut file = Vec::new();
+        write_raw_header(&mut file, &meta).unwrap();
+        assert_eq!(file.len(), RAW_HEADER_LEN);
+        // Some samples after the header
+        file.extend(&[0u8; 16]);
+        std::fs::write(&path, &file).unwrap();
+
+        assert_eq!(read_raw_header(&path).unwrap(), meta);
+        assert!(parse_raw_header(&file[..10]).is_err());
+        assert!(parse_raw_header(&[0u8; RAW_HEADER_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_iq_fc32() {
+        let samps: Vec<_> = (0..50)
+            .map(|i| Complex::new(0.1 * i as f32, -1e-3 * i as f32))
+            .collect();
+        let path = std::env::temp_dir().join("channel_est_test_fc32.cfile");
+        let path = path.to_str().unwrap();
+        write_iq_fc32(path, &samps).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 8 * 50);
+        assert_eq!(read_iq_fc32(path).unwrap(), samps);
+
+        // Truncated in the middle of a sample
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() - 4]).unwrap();
+        let err = read_iq_fc32(path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = read_iq_fc32("/nonexistent/channel_est/cap.cfile").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_sigmf() {
+        let dir = std::env::temp_dir().join("channel_est_test_sigmf");
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta_json = |datatype: &str| {
+            format!(
+                r#"{{
+                    "global": {{"core:datatype": "{}", "core:sample_rate": 2e6,
+                                "core:version": "1.0.0"}},
+                    "captures": [{{"core:sample_start": 0, "core:frequency": 2.45e9}}],
+                    "annotations": []
+                }}"#,
+                datatype
+            )
+        };
+
+        // cf32
+        let samps = vec![Complex::new(0.5, -0.25), Complex::new(-1., 0.125)];
+        let meta_path = dir.join("cf32.sigmf-meta");
+        std::fs::write(&meta_path, meta_json("cf32_le")).unwrap();
+        write_iq_fc32(dir.join("cf32.sigmf-data").to_str().unwrap(), &samps).unwrap();
+        let (read, meta) = read_sigmf(meta_path.to_str().unwrap()).unwrap();
+        assert_eq!(read, samps);
+        assert_eq!(meta.datatype, "cf32_le");
+        assert_eq!(meta.samp_rate, Some(2e6));
+        assert_eq!(meta.center_freq, Some(2.45e9));
+        let mut config = ChannelEstConfig::default();
+        meta.capture_meta().apply(&mut config);
+        assert_eq!(config.samp_rate, Some(2e6));
+
+        // ci16
+        let meta_path = dir.join("ci16.sigmf-meta");
+        std::fs::write(&meta_path, meta_json("ci16_le")).unwrap();
+        let data: Vec<u8> = [16384i16, -32768, 0, 8192]
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+        std::fs::write(dir.join("ci16.sigmf-data"), &data).unwrap();
+        let (read, _) = read_sigmf(meta_path.to_str().unwrap()).unwrap();
+        assert_eq!(read, vec![Complex::new(0.5, -1.), Complex::new(0., 0.25)]);
+
+        // Unsupported datatype and malformed metadata
+        let meta_path = dir.join("ri8.sigmf-meta");
+        std::fs::write(&meta_path, meta_json("ri8")).unwrap();
+        assert!(read_sigmf(meta_path.to_str().unwrap()).is_err());
+        assert!(parse_sigmf_meta(r#"{"captures": []}"#).is_err());
+        assert!(
+            parse_sigmf_meta(r#"{"global": {"core:datatype": "cf32_le"}}"#)
+                .unwrap()
+                .samp_rate
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_iq_wav() {
+        // A minimal stereo WAV file
+        let wav = |tag: u16, bits: u16, data: &[u8]| {
+            let mut file = b"RIFF".to_vec();
+            file.extend(&(36 + data.len() as u32).to_le_bytes());
+            file.extend(b"WAVEfmt ");
+            file.extend(&16u32.to_le_bytes());
+            file.extend(&tag.to_le_bytes());
+            file.extend(&2u16.to_le_bytes());
+            file.extend(&48000u32.to_le_bytes());
+            file.extend(&(48000 * 2 * bits as u32 / 8).to_le_bytes());
+            file.extend(&(2 * bits / 8).to_le_bytes());
+            file.extend(&bits.to_le_bytes());
+            file.extend(b"data");
+            file.extend(&(data.len() as u32).to_le_bytes());
+            file.extend(data);
+            file
+        };
+        let dir = std::env::temp_dir().join("channel_est_test_wav");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // 16-bit PCM
+        let data: Vec<u8> = [16384i16, -32768, -8192, 0]
+            .iter()
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+        let path = dir.join("pcm.wav");
+        std::fs::write(&path, wav(1, 16, &data)).unwrap();
+        let (samps, samp_rate) = read_iq_wav(path.to_str().unwrap()).unwrap();
+        assert_eq!(samp_rate, 48000);
+        assert_eq!(samps, vec![Complex::new(0.5, -1.), Complex::new(-0.25, 0.)]);
+
+        // 32-bit float
+        let sent: [Complex<f32>; 2] = [Complex::new(0.1, -0.7), Complex::new(1., 0.3)];
+        let data: Vec<u8> = sent
+            .iter()
+            .flat_map(|x| [x.re, x.im].to_vec())
+            .flat_map(|x| x.to_le_bytes().to_vec())
+            .collect();
+        let path = dir.join("float.wav");
+        std::fs::write(&path, wav(3, 32, &data)).unwrap();
+        let (samps, _) = read_iq_wav(path.to_str().unwrap()).unwrap();
+        assert_eq!(samps, sent);
+
+        // Unsupported
+        let path = dir.join("pcm8.wav");
+        std::fs::write(&path, wav(1, 8, &data)).unwrap();
+        assert!(read_iq_wav(path.to_str().unwrap()).is_err());
+        std::fs::write(&path, b"RIFF").unwrap();
+        assert!(read_iq_wav(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_gzip_capture() {
+        use crate::config::{cplx_vec_to_file, filename_to_cplx_vec};
+        use flate2::{write::GzEncoder, Compression};
+
+        let dir = std::env::temp_dir().join("channel_est_test_gzip");
+        std::fs::create_dir_all(&dir).unwrap();
+        let gzip = |path: &Path| {
+            let gz_path = path.with_extension("gz");
+            let file = File::create(&gz_path).unwrap();
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder.write_all(&std::fs::read(path).unwrap()).unwrap();
+            encoder.finish().unwrap();
+            gz_path
+        };
+
+        // ASCII samples
+        let samps: Vec<_> = (0..100)
+            .map(|i| Complex::new(0.01 * i as f32, -0.3 * i as f32))
+            .collect();
+        let path = dir.join("samps.txt");
+        cplx_vec_to_file(&path, &samps).unwrap();
+        let gz_path = gzip(&path);
+        assert_ne!(
+            std::fs::read(&path).unwrap(),
+            std::fs::read(&gz_path).unwrap()
+        );
+        let read = filename_to_cplx_vec(gz_path.to_str().unwrap().to_string()).unwrap();
+        let plain = filename_to_cplx_vec(path.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(read, plain);
+        assert_eq!(read, samps);
+
+        // Binary capture with a header
+        let meta = CaptureMeta {
+            samp_rate: Some(1e6),
+            center_freq: Some(915e6),
+        };
+        let path = dir.join("cap.iq");
+        let mut file = Vec::new();
+        write_raw_header(&mut file, &meta).unwrap();
+        file.extend(&[7u8; 64]);
+        std::fs::write(&path, &file).unwrap();
+        let gz_path = gzip(&path);
+        assert_eq!(read_raw_header(&gz_path).unwrap(), meta);
+        let mut decompressed = Vec::new();
+        open_capture(&gz_path)
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, file);
+    }
+}
@@ -1,53 +1,425 @@
 use crate::config::ChannelEstConfig;
-use num::{Complex, One};
+use crate::pilots::{correct_sampling_offset, signed_bin};
+use crate::sample::Sample;
+use num::{Complex, One, Zero};
+use std::f32::consts::PI;
+
+/// Number of samples between renormalizations of the rotation `correct_cfo_in_place` accumulates
+const PHASOR_RENORM: usize = 1024;
+
+/// Convert a CFO in radians per sample (as returned by `estimate_cfo`) to Hz
+pub fn cfo_rad_per_samp_to_hz(cfo: f32, samp_rate: f32) -> f32 {
+    cfo * samp_rate / (2. * PI)
+}
+
+/// Convert a CFO in Hz to radians per sample, the inverse of `cfo_rad_per_samp_to_hz`
+pub fn cfo_hz_to_rad_per_samp(cfo_hz: f32, samp_rate: f32) -> f32 {
+    cfo_hz * 2. * PI / samp_rate
+}
+
+/// A CFO estimate, with conversions to the units oscillator errors are usually specified in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CfoEstimate {
+    pub rad_per_samp: f32,
+}
+
+impl CfoEstimate {
+    pub fn new(rad_per_samp: f32) -> Self {
+        Self { rad_per_samp }
+    }
+
+    /// CFO in Hz given the sample rate
+    pub fn as_hz(&self, samp_rate: f32) -> f32 {
+        cfo_rad_per_samp_to_hz(self.rad_per_samp, samp_rate)
+    }
+
+    /// CFO in parts per million of the center frequency, i.e. the combined oscillator error of
+    /// the transmitter and receiver
+    pub fn as_ppm(&self, samp_rate: f32, center_freq: f32) -> f32 {
+        1e6 * self.as_hz(samp_rate) / center_freq
+    }
+}
 
 /// CFO correct using the short and long preambles. Returns the per-sample phase shift due to CFO
-/// (hence correction should be in the opposite direction)
-pub fn estimate_cfo(
-    short: &[Complex<f32>],
-    long: &[Complex<f32>],
+/// (hence correction should be in the opposite direction). Both stages take the phase of a sum of
+/// products of samples one period apart, so each product is already weighted by the magnitude of
+/// its samples and weak (noisy) samples count for less. The fine stage leaves out the first
+/// `config.cfo_fine_skip` samples of each LTS copy
+pub fn estimate_cfo<T: Sample>(
+    short: &[Complex<T>],
+    long: &[Complex<T>],
     config: &ChannelEstConfig,
-) -> f32 {
+) -> T {
     // Coarse estimation using the short preamble
     let sts_len = config.sts.as_ref().unwrap().len();
-    assert_eq!(short.len(), 10 * sts_len as usize);
-    let coarse = (0..9 * sts_len as usize)
-        .map(|i| short[i].conj() * short[i + sts_len as usize])
+    assert_eq!(short.len(), config.short_len());
+    let coarse = (0..(config.sts_repeats as usize - 1) * sts_len)
+        .map(|i| short[i].conj() * short[i + sts_len])
         .sum::<Complex<_>>()
         .arg()
-        / sts_len as f32;
+        / T::from_config_f32(sts_len as f32);
 
+    coarse + fine_cfo(long, coarse, config)
+}
+
+/// Like `estimate_cfo`, but handles CFOs beyond `pi / sts_len` radians per sample (up to `pi`).
+/// The repetition of the short preamble only determines the CFO modulo `2 pi / sts_len`, so the
+/// wrap is resolved by picking the candidate at which the short preamble best matches the known
+/// STS. Correlations at lags of `2 sts_len` and `4 sts_len` (those within the short preamble)
+/// then refine the estimate, so that the residual left for the long preamble is well within its
+/// smaller unambiguous range
+pub fn estimate_cfo_wide<T: Sample>(
+    short: &[Complex<T>],
+    long: &[Complex<T>],
+    config: &ChannelEstConfig,
+) -> T {
+    let sts = config.sts.as_ref().unwrap();
+    let sts_len = sts.len();
+    assert_eq!(short.len(), config.short_len());
+    let lag_corr = |lag: usize| {
+        (0..short.len() - lag)
+            .map(|i| short[i].conj() * short[i + lag])
+            .sum::<Complex<T>>()
+    };
+    let sts_len_t = T::from_config_f32(sts_len as f32);
+    let coarse = lag_corr(sts_len).arg() / sts_len_t;
+
+    // Correlation with the known STS after correcting for `cfo`
+    let sts_match = |cfo: T| {
+        let rot = Complex::new(T::zero(), -cfo).exp();
+        let mut corr = Complex::one();
+        short
+            .iter()
+            .zip(sts.iter().cycle())
+            .map(|(x, s)| {
+                let res = x * T::from_cplx(*s).conj() * corr;
+                corr = corr * rot;
+                res
+            })
+            .sum::<Complex<T>>()
+            .norm_sqr()
+    };
+    let wrap = T::from_config_f32(2. * PI) / sts_len_t;
+    let (mut cfo, _) = (0..=sts_len)
+        .map(|k| coarse + wrap * T::from_config_f32(k as f32 - (sts_len / 2) as f32))
+        .map(|cfo| (cfo, sts_match(cfo)))
+        .fold((coarse, T::zero()), |best, cand| {
+            if cand.1 > best.1 {
+                cand
+            } else {
+                best
+            }
+        });
+
+    // Each longer lag resolves the residual more finely, if the short preamble is long enough
+    for mult in [2, 4]
+        .iter()
+        .filter(|mult| (**mult as u64) < config.sts_repeats)
+    {
+        let lag = T::from_config_f32((mult * sts_len) as f32);
+        let residual = lag_corr(mult * sts_len) * Complex::new(T::zero(), -cfo * lag).exp();
+        cfo = cfo + residual.arg() / lag;
+    }
+
+    cfo + fine_cfo(long, cfo, config)
+}
+
+/// Estimate the CFO remaining after correcting the long preamble by `coarse`
+fn fine_cfo<T: Sample>(long: &[Complex<T>], coarse: T, config: &ChannelEstConfig) -> T {
     // Correct the long preamble using the coarse estimate and estimate the residual CFO
     let lts_len = config.lts.as_ref().unwrap().0.len();
     assert_eq!(lts_len % 2, 0);
     assert_eq!(long.len(), 5 * lts_len / 2);
+    assert!(config.cfo_fine_skip < lts_len);
     // CFO correction for config.lts.len() samples
-    let coarse_lts_corr = Complex::new(1., -coarse * lts_len as f32).exp();
-    let fine = (lts_len / 2..3 * lts_len / 2)
+    let lts_len_t = T::from_config_f32(lts_len as f32);
+    let coarse_lts_corr = Complex::new(T::one(), -coarse * lts_len_t).exp();
+    (lts_len / 2 + config.cfo_fine_skip..3 * lts_len / 2)
         .map(|i| long[i].conj() * long[i + lts_len] * coarse_lts_corr)
         .sum::<Complex<_>>()
         .arg()
-        / lts_len as f32;
+        / lts_len_t
+}
 
-    coarse + fine
+/// Estimate CFO (in radians per sample) from the rotation of the pilots between two OFDM symbols
+/// `symbol_spacing` samples apart (including the cyclic prefix), without needing a preamble. The
+/// pilots must have their known values removed (e.g. by `pilots::extract_pilots`), since the
+/// pilot polarity changes from symbol to symbol. Only CFOs smaller than
+/// `pi / symbol_spacing` are unambiguous, so this is meant for tracking residual CFO
+pub fn estimate_cfo_from_pilots(
+    prev_symbol_pilots: &[Complex<f32>],
+    cur_symbol_pilots: &[Complex<f32>],
+    symbol_spacing: usize,
+) -> f32 {
+    assert_eq!(prev_symbol_pilots.len(), cur_symbol_pilots.len());
+    prev_symbol_pilots
+        .iter()
+        .zip(cur_symbol_pilots)
+        .map(|(p, c)| p.conj() * c)
+        .sum::<Complex<_>>()
+        .arg()
+        / symbol_spacing as f32
 }
 
 /// Take a buffer and CFO estimate (in radians per sample) and correct the samples for the CFO
-pub fn correct_cfo(samps: &[Complex<f32>], cfo: f32) -> Vec<Complex<f32>> {
-    let cfo = Complex::new(0., -cfo).exp();
-    let mut corr = Complex::one();
-    let mut res = Vec::with_capacity(samps.len());
-    for s in samps {
-        res.push(s * corr);
-        corr = corr * cfo;
-    }
+pub fn correct_cfo<T: Sample>(samps: &[Complex<T>], cfo: T) -> Vec<Complex<T>> {
+    let mut res = samps.to_vec();
+    correct_cfo_in_place(&mut res, cfo);
     res
 }
 
+/// Like `correct_cfo`, but rotates `samps` in place instead of allocating
+pub fn correct_cfo_in_place<T: Sample>(samps: &mut [Complex<T>], cfo: T) {
+    // The rotation is accumulated in f64 and renormalized every `PHASOR_RENORM` samples, so that
+    // neither its magnitude nor its phase drifts over long buffers, even for `f32` samples
+    let step = Complex::new(0., -cfo.to_f64().unwrap()).exp();
+    let mut corr = Complex::<f64>::one();
+    for (i, s) in samps.iter_mut().enumerate() {
+        *s = *s * Complex::new(T::from_calc_f64(corr.re), T::from_calc_f64(corr.im));
+        corr = corr * step;
+        if (i + 1) % PHASOR_RENORM == 0 {
+            corr = corr / corr.norm();
+        }
+    }
+}
+
+/// Estimate the sample frequency offset (SFO), i.e. how many samples the receiver's sampling
+/// drifts by per sample because its clock differs from the transmitter's, from the (CFO
+/// corrected) long preamble `long` including its guard interval. The drift between the two LTS
+/// copies delays the second one, which shows up as a phase that grows linearly with frequency.
+/// Only drifts smaller than about 1.5 samples per LTS are unambiguous
+pub fn estimate_sfo(long: &[Complex<f32>], config: &ChannelEstConfig) -> f32 {
+    let (lts, lts_fft, plans, _) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+    assert_eq!(long.len(), 5 * lts_len / 2);
+
+    let fft = |copy: &[Complex<f32>]| {
+        let mut res = vec![Complex::zero(); lts_len];
+        plans.inverse.process(&mut copy.to_vec(), &mut res);
+        res
+    };
+    let first = fft(&long[lts_len / 2..3 * lts_len / 2]);
+    let second = fft(&long[3 * lts_len / 2..]);
+
+    // Least squares fit of phase = slope * bin (as in `pilots::estimate_sampling_offset`)
+    let (mut num, mut den) = (0., 0.);
+    for (bin, l) in lts_fft.iter().enumerate() {
+        if l.is_some() {
+            let b = signed_bin(bin, lts_len);
+            num += b * (second[bin] * first[bin].conj()).arg();
+            den += b * b;
+        }
+    }
+    if den == 0. {
+        return 0.;
+    }
+    // Delay (in samples) of the second copy relative to the first, one LTS later
+    let delay = num / den * lts_len as f32 / (2. * PI);
+    delay / lts_len as f32
+}
+
+/// Undo the rotation that a sample frequency offset of `sfo` (from `estimate_sfo`) causes in the
+/// equalized bins of a symbol (as returned by `equalize_symbol_bins`) whose FFT window starts
+/// `elapsed` samples after the window the equalization was estimated from
+pub fn correct_sfo(bins: &mut [Option<Complex<f32>>], sfo: f32, elapsed: f32) {
+    correct_sampling_offset(bins, sfo * elapsed);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::build_preamble;
+    use crate::equalization::equalize_symbol_bins;
+    use crate::parse_80211::parse_80211_pkt;
+    use crate::pilots::{extract_pilots, insert_pilots};
+    use crate::sim::{add_awgn, modulate_symbol, random_bpsk_symbols};
     use num::Zero;
+    use rand::{Rng, SeedableRng};
+
+    /// Estimate and correct the CFO in samples of type `T`, returning the results as `f64`
+    fn estimate_and_correct<T: Sample>(
+        short: &[Complex<f32>],
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> (f64, Vec<Complex<f64>>) {
+        let convert = |v: &[Complex<f32>]| v.iter().map(|x| T::from_cplx(*x)).collect::<Vec<_>>();
+        let cfo = estimate_cfo(&convert(short), &convert(long), config);
+        let corrected = correct_cfo(&convert(long), cfo)
+            .iter()
+            .map(|x| Complex::new(x.re.to_f64().unwrap(), x.im.to_f64().unwrap()))
+            .collect();
+        (cfo.to_f64().unwrap(), corrected)
+    }
+
+    #[test]
+    fn test_cfo_f32_f64() {
+        let cfo = 0.05;
+        let config = ChannelEstConfig::default();
+        let pkt: Vec<_> = build_preamble(&config)
+            .iter()
+            .enumerate()
+            .map(|(i, x)| f64::from_cplx(*x) * Complex::new(0., cfo * i as f64).exp())
+            .map(|x| Complex::new(x.re as f32, x.im as f32))
+            .collect();
+        let (short, long) = pkt.split_at(config.short_len());
+
+        let (cfo_f32, corrected_f32) = estimate_and_correct::<f32>(short, long, &config);
+        let (cfo_f64, corrected_f64) = estimate_and_correct::<f64>(short, long, &config);
+        assert!((cfo_f64 - cfo).abs() < 1e-6, "{}", cfo_f64);
+        assert!((cfo_f32 - cfo_f64).abs() < 1e-5);
+        for (x, y) in corrected_f32.iter().zip(&corrected_f64) {
+            assert!((x - y).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_cfo_hz() {
+        let samp_rate = 20e6;
+        let cfo = CfoEstimate::new(0.1);
+        // 0.1 / 2pi cycles per sample at 20 MHz
+        assert!((cfo.as_hz(samp_rate) - 318_309.9).abs() < 1.);
+        assert!((cfo.as_ppm(samp_rate, 2.4e9) - 132.629).abs() < 1e-2);
+        let back = cfo_hz_to_rad_per_samp(cfo.as_hz(samp_rate), samp_rate);
+        assert!((back - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cfo_fine_skip() {
+        let mut config = ChannelEstConfig::default();
+        let sts_len = config.sts.as_ref().unwrap().len();
+        let lts_len = config.lts.as_ref().unwrap().0.len();
+        let mut preamble = build_preamble(&config);
+        preamble.extend(vec![Complex::zero(); 8]);
+        let channel = [
+            Complex::new(1., 0.),
+            Complex::new(0.3, 0.),
+            Complex::zero(),
+            Complex::new(0., 0.5),
+            Complex::new(0.3, -0.2),
+        ];
+        let received: Vec<_> = (0..preamble.len())
+            .map(|i| {
+                channel
+                    .iter()
+                    .enumerate()
+                    .filter(|(d, _)| *d <= i)
+                    .map(|(d, h)| h * preamble[i - d])
+                    .sum::<Complex<f32>>()
+            })
+            .collect();
+
+        // RMS error over several CFOs with and without skipping the samples the channel smears
+        // the guard interval into
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+        let num_trials = 40;
+        let mut sq_err = [0.; 2];
+        for _ in 0..num_trials {
+            let cfo = rng.gen_range(-0.05, 0.05);
+            let mut samps: Vec<_> = received
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x * Complex::new(0., cfo * i as f32).exp())
+                .collect();
+            add_awgn(&mut samps, 1e-4, &mut rng);
+            let short = &samps[..10 * sts_len];
+            let long = &samps[10 * sts_len..10 * sts_len + 5 * lts_len / 2];
+            for (skip, err) in [0, channel.len() - 1].iter().zip(&mut sq_err) {
+                config.cfo_fine_skip = *skip;
+                *err += (estimate_cfo(short, long, &config) - cfo).powi(2);
+            }
+        }
+        assert!(sq_err[1] < 0.5 * sq_err[0], "{:?}", sq_err);
+    }
+
+    #[test]
+    fn test_wide_cfo() {
+        let config = ChannelEstConfig::default();
+        let sts_len = config.sts.as_ref().unwrap().len();
+        let preamble = build_preamble(&config);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+
+        // 0.3 is beyond pi / 16 and aliases to 0.3 - 2 pi / 16 for the single-lag estimate
+        for &cfo in &[0.3f32, -0.3, 0.1, 1.2, -2.5] {
+            let mut samps: Vec<_> = preamble
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x * Complex::new(0., cfo * i as f32).exp())
+                .collect();
+            add_awgn(&mut samps, 1e-3, &mut rng);
+            let (short, long) = samps.split_at(10 * sts_len);
+
+            let est = estimate_cfo_wide(short, long, &config);
+            assert!((est - cfo).abs() < 1e-3, "{} {}", est, cfo);
+            let single_lag = estimate_cfo(short, long, &config);
+            assert_eq!(
+                (single_lag - cfo).abs() < 1e-3,
+                cfo.abs() < PI / sts_len as f32
+            );
+        }
+    }
+
+    #[test]
+    fn test_sts_repeats() {
+        let mut config = ChannelEstConfig::default();
+        config.sts_repeats = 7;
+        let sts_len = config.sts.as_ref().unwrap().len();
+        let preamble = build_preamble(&config);
+        assert_eq!(
+            preamble.len(),
+            7 * sts_len + 5 * config.lts.as_ref().unwrap().0.len() / 2
+        );
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+
+        for &cfo in &[0.05f32, -0.15, 0.7] {
+            let mut samps: Vec<_> = preamble
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x * Complex::new(0., cfo * i as f32).exp())
+                .collect();
+            add_awgn(&mut samps, 1e-3, &mut rng);
+            let (short, long) = samps.split_at(config.short_len());
+
+            if cfo.abs() < PI / sts_len as f32 {
+                let est = estimate_cfo(short, long, &config);
+                assert!((est - cfo).abs() < 1e-3, "{} {}", est, cfo);
+            }
+            let est = estimate_cfo_wide(short, long, &config);
+            assert!((est - cfo).abs() < 1e-3, "{} {}", est, cfo);
+        }
+    }
+
+    #[test]
+    fn test_correct_cfo_long_buffer() {
+        // A tone at exactly the CFO, which correction should bring down to DC
+        let cfo = 0.0123f32;
+        let samps: Vec<_> = (0..100_000)
+            .map(|i| {
+                let x = Complex::new(0., f64::from(cfo) * i as f64).exp();
+                Complex::new(x.re as f32, x.im as f32)
+            })
+            .collect();
+        let corrected = correct_cfo(&samps, cfo);
+        for (x, y) in corrected.iter().zip(&samps) {
+            assert!((x.norm() - y.norm()).abs() < 1e-5, "{} {}", x, y);
+            assert!((x - Complex::new(1., 0.)).norm() < 1e-4, "{}", x);
+        }
+    }
+
+    #[test]
+    fn test_correct_cfo_in_place() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let samps: Vec<_> = (0..200)
+            .map(|_| Complex::new(rng.gen_range(-1f32, 1.), rng.gen_range(-1f32, 1.)))
+            .collect();
+        let cfo = 0.013;
+        let mut in_place = samps.clone();
+        correct_cfo_in_place(&mut in_place, cfo);
+        // Compare bit patterns so the test doesn't depend on float equality
+        let bits = |v: &[Complex<f32>]| -> Vec<(u32, u32)> {
+            v.iter().map(|x| (x.re.to_bits(), x.im.to_bits())).collect()
+        };
+        assert_eq!(bits(&in_place), bits(&correct_cfo(&samps, cfo)));
+    }
 
     /// Test if CFO estimation is going ok
     #[test]
@@ -103,4 +475,102 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_cfo_from_pilots() {
+        let cfo = 0.01;
+        let fft_len = 64;
+        let cp_len = fft_len / 4;
+        let no_equalization = vec![Some(Complex::one()); fft_len];
+
+        // Two consecutive symbols carrying only pilots (symbol indices 3 and 4 have opposite
+        // polarities), with CFO
+        let mut samps = Vec::new();
+        for symbol_idx in 3..5 {
+            let mut bins = vec![Complex::zero(); fft_len];
+            insert_pilots(&mut bins, symbol_idx);
+            samps.extend(modulate_symbol(&bins, cp_len));
+        }
+        let samps: Vec<_> = samps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * Complex::new(0., cfo * i as f32).exp())
+            .collect();
+
+        let spacing = cp_len + fft_len;
+        let pilots: Vec<_> = (0..2)
+            .map(|i| {
+                let symbol = &samps[i * spacing + cp_len..(i + 1) * spacing];
+                extract_pilots(&equalize_symbol_bins(symbol, &no_equalization), 3 + i)
+            })
+            .collect();
+        assert_eq!(pilots[0].len(), 4);
+        let cfo_est = estimate_cfo_from_pilots(&pilots[0], &pilots[1], spacing);
+        assert!((cfo_est - cfo).abs() < 1e-4);
+    }
+
+    /// The receiver's clock runs 200 ppm fast, so the FFT window of a symbol starting at sample
+    /// `w` is late by `sfo * w` samples. By the end of the packet this is over half a sample
+    #[test]
+    fn test_sfo_estimation_and_correction() {
+        let sfo = 2e-4;
+        let mut config = ChannelEstConfig::default();
+        let (lts, lts_fft, _, _) = config.lts.clone().unwrap();
+        let sts = config.sts.clone().unwrap();
+        let fft_len = lts.len();
+
+        // Frequency-domain `bins` delayed by `delay` samples
+        let delayed = |bins: &[Complex<f32>], delay: f32| -> Vec<Complex<f32>> {
+            bins.iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let phase = 2. * PI * signed_bin(i, fft_len) * delay / fft_len as f32;
+                    x * Complex::new(0., phase).exp()
+                })
+                .collect()
+        };
+
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(sts.iter().cycle().take(10 * sts.len()));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() / 2));
+        let long_start = pkt.len() - lts.len() / 2;
+        let lts_bins: Vec<_> = lts_fft.iter().map(|x| x.unwrap_or_default()).collect();
+        for _ in 0..2 {
+            let copy = modulate_symbol(&delayed(&lts_bins, sfo * pkt.len() as f32), 0);
+            pkt.extend(copy.iter().map(|x| x / fft_len as f32));
+        }
+
+        let num_symbols = 40;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut symbols_data = Vec::new();
+        for bins in random_bpsk_symbols(&config, num_symbols, &mut rng) {
+            symbols_data.extend(lts_fft.iter().zip(&bins).filter_map(|(l, x)| l.map(|_| *x)));
+            let window = pkt.len() + config.cp_len;
+            pkt.extend(modulate_symbol(
+                &delayed(&bins, sfo * window as f32),
+                config.cp_len,
+            ));
+        }
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * lts.len()));
+
+        let sfo_est = estimate_sfo(&pkt[long_start..long_start + 5 * lts.len() / 2], &config);
+        assert!((sfo_est - sfo).abs() < 1e-5, "{}", sfo_est);
+
+        let count_errors = |parsed: &[Complex<f32>]| {
+            assert_eq!(parsed.len(), symbols_data.len());
+            parsed
+                .iter()
+                .zip(&symbols_data)
+                .filter(|(x, y)| (x.re > 0.) != (y.re > 0.))
+                .count()
+        };
+        assert!(count_errors(&parse_80211_pkt(&pkt, &config).unwrap()) > 0);
+
+        config.correct_sfo = true;
+        let parsed = parse_80211_pkt(&pkt, &config).unwrap();
+        assert_eq!(count_errors(&parsed), 0);
+        for (x, y) in parsed.iter().zip(&symbols_data) {
+            assert!((x - y).norm() < 0.1);
+        }
+    }
 }
@@ -4,18 +4,30 @@
 //!
 //! [<short preamble> <long preamble>] x repeat n times
 
-use channel_est::cfo::{correct_cfo, estimate_cfo};
-use channel_est::config::{ChannelEstConfig, ChannelEstConfigDes};
-use channel_est::equalization::estimate_subcarrier_equalization;
-use channel_est::lts_align::lts_align;
-use channel_est::pkt_trigger::PktTrigger;
-use failure::Error;
+use channel_est::capture::{read_iq_fc32, write_iq_fc32};
+use channel_est::cfo::{correct_cfo, estimate_cfo, CfoEstimate};
+use channel_est::config::{
+    build_preamble, filename_to_cplx_vec, ChannelEstConfig, ChannelEstConfigDes,
+};
+use channel_est::dc_offset::{DcBlocker, DC_WINDOW};
+use channel_est::equalization::{estimate_snr, Equalizer};
+use channel_est::error::{ConfigError, Error};
+use channel_est::lts_align::{correlation_pslr, lts_align, lts_align_with_confidence};
+use channel_est::pkt_trigger::{DetectedPacket, PktTrigger};
+use channel_est::resample::fir_filter;
+use log::{debug, error, info, warn};
 use num::{Complex, Zero};
 use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
+    mpsc::{self, Receiver, RecvTimeoutError, TrySendError},
     Arc,
 };
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use usrp::{create_simulator, RadioRx, RadioSimulatorConfig, RadioTx};
 
 #[derive(Clone, Debug)]
@@ -26,6 +38,195 @@ pub struct MonitorConfig {
     num_repeats: u64,
     /// Duty cycle, so we can give time for others to transmit
     duty_cycle: f32,
+    /// If set, `run_rx` logs a JSON object per detected packet
+    packet_log: Option<PacketLogConfig>,
+    /// FIR filter applied to the transmitted signal, e.g. to pre-distort it against a known PA or
+    /// cable response. Taps can be loaded with `filename_to_cplx_vec`
+    tx_filter: Option<Vec<Complex<f32>>>,
+    /// If set, `run_rx` writes the raw samples of each detected packet (before any processing
+    /// other than `remove_dc`) to a numbered `fc32` file in this directory, `pkt<index>.cfile`
+    record_dir: Option<PathBuf>,
+    /// Remove the receiver's DC offset (see `DcBlocker`) before looking for packets, so it doesn't
+    /// add to the power `PktTrigger` compares against `power_trig`
+    remove_dc: bool,
+}
+
+/// `MonitorConfig` as it is written in a TOML file (see `MonitorConfig::from_toml_str`). The OFDM
+/// parameters go in an `[ofdm]` table with the fields of `ChannelEstConfigDes`. Fields that are
+/// absent take their default values
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct MonitorConfigDes {
+    pub num_repeats: u64,
+    pub duty_cycle: f32,
+    pub packet_log: Option<PacketLogConfig>,
+    /// File with the taps of the transmit filter, in the format `filename_to_cplx_vec` reads
+    pub tx_filter: Option<String>,
+    pub record_dir: Option<PathBuf>,
+    pub remove_dc: bool,
+    pub ofdm: ChannelEstConfigDes,
+}
+
+impl Default for MonitorConfigDes {
+    fn default() -> Self {
+        Self {
+            num_repeats: 100,
+            duty_cycle: 0.5,
+            packet_log: None,
+            tx_filter: None,
+            record_dir: None,
+            remove_dc: false,
+            ofdm: ChannelEstConfigDes::default(),
+        }
+    }
+}
+
+impl MonitorConfigDes {
+    /// Read the files the config names and check it (see `MonitorConfig::validate`)
+    pub fn try_into_config(self) -> Result<MonitorConfig, ConfigError> {
+        let mut builder = MonitorConfig::builder(self.ofdm.try_into_config()?)
+            .num_repeats(self.num_repeats)
+            .duty_cycle(self.duty_cycle)
+            .remove_dc(self.remove_dc);
+        if let Some(log_config) = self.packet_log {
+            builder = builder.packet_log(log_config);
+        }
+        if let Some(path) = self.tx_filter {
+            builder = builder.tx_filter(filename_to_cplx_vec(path)?);
+        }
+        if let Some(dir) = self.record_dir {
+            builder = builder.record_dir(dir);
+        }
+        builder.build()
+    }
+}
+
+impl MonitorConfig {
+    /// Start building a config in code. The other fields start out with the defaults of
+    /// `MonitorConfigDes`
+    pub fn builder(ofdm: ChannelEstConfig) -> MonitorConfigBuilder {
+        let des = MonitorConfigDes::default();
+        MonitorConfigBuilder {
+            config: MonitorConfig {
+                ofdm,
+                num_repeats: des.num_repeats,
+                duty_cycle: des.duty_cycle,
+                packet_log: None,
+                tx_filter: None,
+                record_dir: None,
+                remove_dc: des.remove_dc,
+            },
+        }
+    }
+
+    /// Load the config from a TOML file. See `from_toml_str`
+    pub fn from_toml_path(path: &str) -> Result<Self, ConfigError> {
+        let toml = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        Self::from_toml_str(&toml)
+    }
+
+    /// Parse the fields of `MonitorConfigDes` from TOML, read the files they name and check the
+    /// result (see `validate`)
+    pub fn from_toml_str(toml: &str) -> Result<Self, ConfigError> {
+        let des: MonitorConfigDes = toml::from_str(toml)?;
+        des.try_into_config()
+    }
+
+    /// Check the OFDM config (see `ChannelEstConfig::validate`), that there is at least one
+    /// repeat and that the duty cycle is in (0, 1]. A duty cycle of 0 would never transmit
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.ofdm.validate()?;
+        if self.num_repeats == 0 {
+            return Err(ConfigError::Invalid {
+                field: "num_repeats",
+                reason: "must be at least 1".to_string(),
+            });
+        }
+        let duty_cycle = self.duty_cycle;
+        if duty_cycle.is_nan() || duty_cycle <= 0. || duty_cycle > 1. {
+            return Err(ConfigError::Invalid {
+                field: "duty_cycle",
+                reason: format!("{} is not in (0, 1]", duty_cycle),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Builds a `MonitorConfig` in code (see `MonitorConfig::builder`)
+pub struct MonitorConfigBuilder {
+    config: MonitorConfig,
+}
+
+impl MonitorConfigBuilder {
+    pub fn num_repeats(mut self, num_repeats: u64) -> Self {
+        self.config.num_repeats = num_repeats;
+        self
+    }
+
+    pub fn duty_cycle(mut self, duty_cycle: f32) -> Self {
+        self.config.duty_cycle = duty_cycle;
+        self
+    }
+
+    pub fn packet_log(mut self, packet_log: PacketLogConfig) -> Self {
+        self.config.packet_log = Some(packet_log);
+        self
+    }
+
+    pub fn tx_filter(mut self, taps: Vec<Complex<f32>>) -> Self {
+        self.config.tx_filter = Some(taps);
+        self
+    }
+
+    pub fn record_dir(mut self, dir: PathBuf) -> Self {
+        self.config.record_dir = Some(dir);
+        self
+    }
+
+    pub fn remove_dc(mut self, remove_dc: bool) -> Self {
+        self.config.remove_dc = remove_dc;
+        self
+    }
+
+    /// The config, if it is valid (see `MonitorConfig::validate`)
+    pub fn build(self) -> Result<MonitorConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// A measurement that can be included in the per-packet log. The JSON key, which is also its name
+/// in a config file, is given in brackets
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketLogField {
+    /// Time of detection in seconds since the UNIX epoch (`timestamp`)
+    Timestamp,
+    /// CFO in Hz, or null if the sample rate isn't known (`cfo_hz`)
+    CfoHz,
+    /// CFO in parts per million of the center frequency, or null if the sample rate or center
+    /// frequency isn't known (`cfo_ppm`)
+    CfoPpm,
+    /// Average power of the long preamble in dB (`rssi_db`)
+    #[serde(rename = "rssi_db")]
+    Rssi,
+    /// RMS difference between the two copies of the LTS relative to their RMS (`evm`)
+    Evm,
+    /// Channel on each used subcarrier as `[re, im]` pairs, in FFT bin order (`channel`)
+    Channel,
+}
+
+/// Where and what to log for each detected packet
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PacketLogConfig {
+    /// File the JSON lines are appended to
+    path: PathBuf,
+    /// Fields included in each JSON object
+    fields: Vec<PacketLogField>,
 }
 
 /// Loops forever as a transmitter until signalled to close by `close`
@@ -34,15 +235,10 @@ pub fn run_tx<T: RadioTx>(
     config: &MonitorConfig,
     close: Arc<AtomicBool>,
 ) -> Result<(), Error> {
-    let sts = config.ofdm.sts.as_ref().unwrap();
     let lts = config.ofdm.lts.as_ref().unwrap();
-    assert_eq!(lts.0.len() % 2, 0);
 
     // Construct the preamble that will be repeatedly transmitted
-    let mut preamble = Vec::new();
-    preamble.extend(sts.iter().cycle().take(10 * sts.len()));
-    preamble.extend(std::iter::repeat(Complex::zero()).take(lts.0.len() / 2));
-    preamble.extend(lts.0.iter().cycle().take(2 * lts.0.len()));
+    let preamble = build_preamble(&config.ofdm);
 
     assert!(0. <= config.duty_cycle && config.duty_cycle <= 1.);
     // Silence period to allow any other radios in the vicinity time to transmit
@@ -50,14 +246,24 @@ pub fn run_tx<T: RadioTx>(
     let silence = vec![Complex::zero(); silence_len];
     assert!(silence_len > lts.0.len() / 2);
 
-    // Construct the packet we will transmit repeatedly
-    'outer: while !close.load(Ordering::Relaxed) {
-        for _ in 0..config.num_repeats {
-            let res = tx.send(&preamble);
-            if res.is_err() {
-                println!("{:?}", res);
-                break 'outer;
-            }
+    // Construct the packet we will transmit repeatedly. Filter the whole burst at once so the
+    // filter's response to one preamble carries over into the next
+    let burst: Vec<_> = preamble
+        .iter()
+        .cycle()
+        .take(config.num_repeats as usize * preamble.len())
+        .copied()
+        .collect();
+    let burst = match &config.tx_filter {
+        Some(taps) => fir_filter(&burst, taps),
+        None => burst,
+    };
+
+    while !close.load(Ordering::Relaxed) {
+        let res = tx.send(&burst);
+        if let Err(e) = res {
+            error!("Tx failed: {:?}", e);
+            break;
         }
 
         let res = tx.send(&silence);
@@ -66,93 +272,524 @@ pub fn run_tx<T: RadioTx>(
         }
     }
 
-    println!("Tx closed");
+    info!("Tx closed");
     Ok(())
 }
 
-/// Loops forever as a receiver until signalled to close by `close`
-pub fn run_rx<R: RadioRx, F: FnMut(&[Option<Complex<f32>>])>(
-    rx: &mut R,
+/// Minimum peak-to-sidelobe ratio (see `correlation_pslr`) with which the next expected LTS of a
+/// burst must align. Below this we assume the burst has ended. A clean preamble gives about 8 dB
+/// and noise about 1 dB
+const MIN_LTS_PSLR_DB: f32 = 4.;
+
+/// Minimum confidence (see `lts_align_with_confidence`) with which the next expected LTS of a
+/// burst must align. A clean preamble in the window we search gives well over 100 and noise
+/// rarely more than 30
+const MIN_LTS_CONFIDENCE: f32 = 50.;
+
+/// Find the first LTS in a packet detected by `PktTrigger`. Returns `None` if the packet is too
+/// short to contain a preamble there
+fn first_lts_start(pkt: &DetectedPacket, config: &MonitorConfig) -> Option<usize> {
+    let (origin, pkt) = (pkt.trigger_offset, &pkt.samples);
+    let lts = config.ofdm.lts.as_ref().unwrap();
+
+    // The preamble (short + long) is this many samples long.
+    assert_eq!(lts.0.len() % 2, 0);
+    let preamble_len = config.ofdm.short_len() + 5 * lts.0.len() / 2;
+    // First align the first LTS. The long preamble will be within a margin of the trigger. We only
+    // pass that to `lts_align` so it doesn't get confused by what comes before or after. We use an
+    // additional lts.0.len() / 2 samples, so we have some margin for error
+    let first_lts_margin = origin + preamble_len + lts.0.len() / 2;
+    if pkt.len() < first_lts_margin {
+        return None;
+    }
+    let lts_start = origin + lts_align(&pkt[origin..first_lts_margin], &lts.0, &lts.3);
+    if lts_start < config.ofdm.short_len() || lts_start + 5 * lts.0.len() / 2 > pkt.len() {
+        return None;
+    }
+    Some(lts_start)
+}
+
+/// Measure the first preamble of a packet detected by `PktTrigger` and write the requested fields
+/// to `sink` as a single line of JSON. Writes nothing if the packet doesn't contain a preamble
+pub fn log_packet<W: Write>(
+    sink: &mut W,
+    pkt: &DetectedPacket,
+    config: &MonitorConfig,
+    fields: &[PacketLogField],
+) -> Result<(), Error> {
+    let lts_start = match first_lts_start(pkt, config) {
+        Some(lts_start) => lts_start,
+        None => return Ok(()),
+    };
+    let pkt = &pkt.samples;
+    let lts_len = config.ofdm.lts.as_ref().unwrap().0.len();
+    let short = &pkt[lts_start - config.ofdm.short_len()..lts_start];
+    let long = &pkt[lts_start..lts_start + 5 * lts_len / 2];
+    let cfo = CfoEstimate::new(estimate_cfo(short, long, &config.ofdm));
+    let long = correct_cfo(long, cfo.rad_per_samp);
+    let (first, second) = long[lts_len / 2..].split_at(lts_len);
+
+    let mut record = serde_json::Map::new();
+    for field in fields {
+        let (key, value) = match field {
+            PacketLogField::Timestamp => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                ("timestamp", serde_json::json!(now.as_secs_f64()))
+            }
+            PacketLogField::CfoHz => {
+                let cfo_hz = config.ofdm.samp_rate.map(|r| cfo.as_hz(r as f32));
+                ("cfo_hz", serde_json::json!(cfo_hz))
+            }
+            PacketLogField::CfoPpm => {
+                let cfo_ppm = match (config.ofdm.samp_rate, config.ofdm.center_freq) {
+                    (Some(r), Some(f)) => Some(cfo.as_ppm(r as f32, f as f32)),
+                    _ => None,
+                };
+                ("cfo_ppm", serde_json::json!(cfo_ppm))
+            }
+            PacketLogField::Rssi => {
+                let power = long.iter().map(|x| x.norm_sqr()).sum::<f32>() / long.len() as f32;
+                ("rssi_db", serde_json::json!(10. * power.log10()))
+            }
+            PacketLogField::Evm => {
+                let err = first
+                    .iter()
+                    .zip(second)
+                    .map(|(a, b)| (a - b).norm_sqr())
+                    .sum::<f32>();
+                let signal = first
+                    .iter()
+                    .zip(second)
+                    .map(|(a, b)| ((a + b) / 2.).norm_sqr())
+                    .sum::<f32>();
+                // Each copy has half the noise power of their difference
+                ("evm", serde_json::json!((err / (2. * signal)).sqrt()))
+            }
+            PacketLogField::Channel => {
+                let channel: Vec<_> = config
+                    .ofdm
+                    .equalizer
+                    .estimate(&long, &config.ofdm)
+                    .iter()
+                    .filter_map(|e| e.map(|e| 1. / e))
+                    .map(|h| [h.re, h.im])
+                    .collect();
+                ("channel", serde_json::json!(channel))
+            }
+        };
+        record.insert(key.to_string(), value);
+    }
+    writeln!(sink, "{}", serde_json::Value::Object(record))?;
+    Ok(())
+}
+
+/// What `process_burst` measures from each preamble
+#[derive(Clone, Copy, Debug)]
+pub struct Estimate<'a> {
+    /// Equalization of each subcarrier, `None` where unused
+    pub equalization: &'a [Option<Complex<f32>>],
+    /// CFO in radians per sample
+    pub cfo: f32,
+    /// CFO in Hz, if the sample rate is known. Its drift over time is that of the oscillators
+    pub cfo_hz: Option<f32>,
+    /// SNR (in dB) of the long preamble, as by `estimate_snr`
+    pub snr_db: f32,
+}
+
+/// Adapt a callback that only wants the equalization for `process_burst` and `run_rx`
+pub fn equalization_only<F: FnMut(&[Option<Complex<f32>>])>(
+    mut callback: F,
+) -> impl FnMut(&Estimate) {
+    move |est: &Estimate| callback(est.equalization)
+}
+
+/// Process the repeated preambles in a packet detected by `PktTrigger`, calling `callback` with
+/// the estimates computed from each. Stops early if the burst ends (e.g. because of a collision
+/// or because the transmitter stopped) before `config.num_repeats` preambles, which is detected
+/// when the next expected LTS doesn't fit in the packet or doesn't align confidently. Returns the
+/// number of preambles processed
+pub fn process_burst<F: FnMut(&Estimate)>(
+    pkt: &DetectedPacket,
+    config: &MonitorConfig,
+    callback: &mut F,
+) -> u64 {
+    // Known preamble; the lts
+    let lts = config.ofdm.lts.as_ref().unwrap();
+    let preamble_len = config.ofdm.short_len() + 5 * lts.0.len() / 2;
+
+    let mut cur_lts_start = match first_lts_start(pkt, config) {
+        Some(lts_start) => lts_start,
+        None => return 0,
+    };
+    let pkt = &pkt.samples;
+
+    // Now process each repetition one-by-one
+    for i in 0..config.num_repeats {
+        // Figure out where the preambles are
+        let cur_sts_start = cur_lts_start - config.ofdm.short_len();
+        let short = &pkt[cur_sts_start..cur_lts_start];
+        let long = &pkt[cur_lts_start..cur_lts_start + 5 * lts.0.len() / 2];
+
+        // Calculate the CFO and correct it in the long preamble
+        let cfo = estimate_cfo(short, long, &config.ofdm);
+        let long = correct_cfo(long, cfo);
+        debug!(
+            "Preamble {} at sample {}: cfo {} rad/samp",
+            i, cur_lts_start, cfo
+        );
+
+        // Calculate the equalization
+        let equalization = config.ofdm.equalizer.estimate(&long, &config.ofdm);
+        callback(&Estimate {
+            equalization: &equalization,
+            cfo,
+            cfo_hz: config
+                .ofdm
+                .samp_rate
+                .map(|r| CfoEstimate::new(cfo).as_hz(r as f32)),
+            snr_db: estimate_snr(&long, &config.ofdm),
+        });
+
+        // Estimate the start of the next long preamble. Sample frequency offset aside, it should
+        // be pretty close to `cur_lts_start + preamble_len`. No need to do this if this was the
+        // last repeat
+        if i < config.num_repeats - 1 {
+            // Leave this much margin for samples to have drifted
+            let margin = 5;
+            // If margin is so large it includes the previous LTS, it can cause trouble
+            assert!(margin < lts.0.len() / 2);
+            let expected_sts_start = cur_sts_start + preamble_len;
+            let expected_lts_start = cur_lts_start + preamble_len;
+
+            // The burst ends if the next preamble isn't there
+            let window_end = expected_sts_start + preamble_len + lts.0.len() / 2;
+            if window_end > pkt.len() {
+                return i + 1;
+            }
+            let window = &pkt[expected_sts_start - margin..window_end];
+            if correlation_pslr(window, &lts.0, &lts.3) < MIN_LTS_PSLR_DB {
+                return i + 1;
+            }
+
+            let (offset, confidence) = lts_align_with_confidence(window, &lts.0, &lts.3);
+            if confidence < MIN_LTS_CONFIDENCE {
+                return i + 1;
+            }
+            cur_lts_start = expected_sts_start - margin + offset;
+            if (cur_lts_start as i64 - expected_lts_start as i64).abs() > margin as i64 {
+                warn!(
+                    "LTS drifted more than {} samples after preamble {}: expected at {}, found at \
+                     {} (packet has {} samples). Skipping the rest of the packet",
+                    margin,
+                    i,
+                    expected_lts_start,
+                    cur_lts_start,
+                    pkt.len()
+                );
+                return i + 1;
+            }
+        }
+    }
+    config.num_repeats
+}
+
+/// Number of samples `run_rx` asks the radio for at a time
+const RX_CHUNK: usize = 512;
+
+/// Number of detected packets that can be waiting to be processed. If processing falls behind
+/// and the queue fills up, `run_rx` drops newly detected packets (with a warning) rather than
+/// stop reading the radio. That way capture never stalls, the radio's buffers don't overflow and
+/// the sample count stays accurate
+const PKT_QUEUE_LEN: usize = 16;
+
+/// Longest `run_rx` waits for a packet before checking whether it should close
+const CLOSE_POLL: Duration = Duration::from_millis(100);
+
+/// The packet detection `run_rx` does on each received sample, on the thread that reads the
+/// radio. Shared with `replay_rx` so recordings take the same path as live samples
+struct RxDetector {
+    pkt_trigger: PktTrigger,
+    dc_blocker: Option<DcBlocker>,
+    /// Number of samples received so far
+    num_samps: u64,
+}
+
+impl RxDetector {
+    fn new(config: &MonitorConfig) -> Self {
+        Self {
+            pkt_trigger: PktTrigger::new(&config.ofdm),
+            dc_blocker: if config.remove_dc {
+                Some(DcBlocker::new(DC_WINDOW))
+            } else {
+                None
+            },
+            num_samps: 0,
+        }
+    }
+
+    /// Take the next sample and return the packet that ends with it, if any
+    fn push_samp(&mut self, samp: Complex<f32>) -> Option<DetectedPacket> {
+        let samp = match &mut self.dc_blocker {
+            Some(dc_blocker) => dc_blocker.push_samp(samp, self.pkt_trigger.in_packet()),
+            None => samp,
+        };
+        let pkt = self.pkt_trigger.push_samp(samp);
+        self.num_samps += 1;
+
+        let pkt = pkt?;
+        // A packet has been detected. The current sample is its last one
+        let trigger_idx = self.num_samps - (pkt.samples.len() - pkt.trigger_offset) as u64;
+        debug!(
+            "Packet detected at sample {} ({} samples)",
+            trigger_idx,
+            pkt.samples.len()
+        );
+        Some(pkt)
+    }
+}
+
+/// The processing `run_rx` does on each packet `RxDetector` returns, shared with `replay_rx`.
+/// Anything that writes files happens here, so its errors reach the caller
+struct RxProcessor<'a> {
+    config: &'a MonitorConfig,
+    packet_log: Option<std::fs::File>,
+    /// Index of the next packet written to `config.record_dir`
+    num_recorded: u64,
+}
+
+/// One more than the largest `N` of the `pktN.cfile` recordings in `dir` (0 if there are none), so
+/// a new run adds to the recordings of earlier ones rather than overwriting them
+fn next_recording_index(dir: &std::path::Path) -> std::io::Result<u64> {
+    let mut next = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let index = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("pkt"))
+            .and_then(|name| name.strip_suffix(".cfile"))
+            .and_then(|index| index.parse::<u64>().ok());
+        if let Some(index) = index {
+            next = next.max(index + 1);
+        }
+    }
+    Ok(next)
+}
+
+impl<'a> RxProcessor<'a> {
+    fn new(config: &'a MonitorConfig) -> Result<Self, Error> {
+        let num_recorded = match &config.record_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                next_recording_index(dir)?
+            }
+            None => 0,
+        };
+        let packet_log = match &config.packet_log {
+            Some(log_config) => Some(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&log_config.path)?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            config,
+            packet_log,
+            num_recorded,
+        })
+    }
+
+    fn process<F: FnMut(&Estimate)>(
+        &mut self,
+        pkt: &DetectedPacket,
+        callback: &mut F,
+    ) -> Result<(), Error> {
+        let config = self.config;
+        if let Some(dir) = &config.record_dir {
+            let path = dir.join(format!("pkt{}.cfile", self.num_recorded));
+            write_iq_fc32(&path.to_string_lossy(), &pkt.samples)?;
+            self.num_recorded += 1;
+        }
+        if let (Some(log), Some(log_config)) = (&mut self.packet_log, &config.packet_log) {
+            log_packet(log, pkt, config, &log_config.fields)?;
+        }
+
+        let num_processed = process_burst(pkt, config, callback);
+        if num_processed < config.num_repeats {
+            warn!(
+                "Burst ended early, after {} of {} repeats",
+                num_processed, config.num_repeats
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Loops forever as a receiver until signalled to close by `close`. The radio is read and packets
+/// are detected on a separate thread, which queues them for this one to process (see
+/// `PKT_QUEUE_LEN`). Returns within `CLOSE_POLL` of `close` being set, even if the radio is blocked
+/// in `recv`: the reader thread is not waited for, and drops the radio once its current `recv`
+/// returns
+pub fn run_rx<R: RadioRx + Send + 'static, F: FnMut(&Estimate)>(
+    mut rx: R,
     config: &MonitorConfig,
     mut callback: F,
     close: Arc<AtomicBool>,
 ) -> Result<(), Error> {
-    let mut pkt_trigger = PktTrigger::new(&config.ofdm);
-    // Known preambles; the lts and sts
-    let sts = config.ofdm.sts.as_ref().unwrap();
-    let lts = config.ofdm.lts.as_ref().unwrap();
+    let mut processor = RxProcessor::new(config)?;
+    // Stops the reader however processing ends, without closing anything else listening to
+    // `close`
+    let stop = Arc::new(AtomicBool::new(false));
+    let (pkts, _reader) = spawn_detector(
+        move || match rx.recv(RX_CHUNK) {
+            Ok(buf) => Ok(Some(buf.0.to_vec())),
+            Err(e) => Err(Error::Radio(format!("{:?}", e))),
+        },
+        RxDetector::new(config),
+        stop.clone(),
+    );
+    let res = process_packets(&mut processor, &pkts, &mut callback, &close);
+    // The reader may be blocked in `recv`, so detach it rather than joining
+    stop.store(true, Ordering::Relaxed);
+    info!("Rx closed");
 
+    res
+}
+
+/// On a new thread, call `recv` repeatedly for chunks of samples and pass them through `detector`,
+/// queueing the packets it detects. Stops when `stop` is set, `recv` returns `None` or fails, or
+/// the returned receiver is dropped; `stop` is only checked between calls to `recv`. A failure is
+/// queued after the packets before it
+fn spawn_detector<S>(
+    mut recv: S,
+    mut detector: RxDetector,
+    stop: Arc<AtomicBool>,
+) -> (Receiver<Result<DetectedPacket, Error>>, JoinHandle<()>)
+where
+    S: FnMut() -> Result<Option<Vec<Complex<f32>>>, Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(PKT_QUEUE_LEN);
+    let handle = std::thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            let chunk = match recv() {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    // Unlike packets, the error must not be dropped, so wait for room
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+            for samp in chunk {
+                let pkt = match detector.push_samp(samp) {
+                    Some(pkt) => pkt,
+                    None => continue,
+                };
+                match sender.try_send(Ok(pkt)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Processing fell behind, dropping a packet")
+                    }
+                    Err(TrySendError::Disconnected(_)) => return,
+                }
+            }
+        }
+    });
+    (receiver, handle)
+}
+
+/// Process the packets from `pkts` (see `spawn_detector`) until `close` is set or the detector
+/// stops. Returns the detector's error if it failed. `close` is checked at least every
+/// `CLOSE_POLL`, even while no packets arrive
+fn process_packets<F: FnMut(&Estimate)>(
+    processor: &mut RxProcessor,
+    pkts: &Receiver<Result<DetectedPacket, Error>>,
+    callback: &mut F,
+    close: &AtomicBool,
+) -> Result<(), Error> {
     while !close.load(Ordering::Relaxed) {
-        let buf = if let Ok(buf) = rx.recv(512) {
-            buf
-        } else {
-            break;
-        };
-        for samp in buf.0 {
-            let pkt = pkt_trigger.push_samp(*samp);
-
-            if pkt.is_none() {
-                continue;
-            }
-            // A packet has been detected, let's process it.
-            let pkt = pkt.unwrap();
-            println!("Packet detected");
-
-            // The preamble (short + long) is this many samples long.
-            assert_eq!(lts.0.len() % 2, 0);
-            let preamble_len = 10 * sts.len() + 5 * lts.0.len() / 2;
-            // First align the first LTS. The long preamble will be within a margin of the
-            // beginning of the packet. We only pass that to `lts_align` so it doesn't get confused
-            // by what comes after. We use an additional lts.0.len() / 2 samples, so we have some
-            // margin for error
-            let first_lts_margin = config.ofdm.pkt_spacing as usize + preamble_len + lts.0.len()/2;
-            let mut cur_lts_start = lts_align(&pkt[..first_lts_margin], &lts.0);
-
-            // Now process each repetition one-by-one
-            for i in 0..config.num_repeats {
-                // Figure out where the preambles are
-                let cur_sts_start = cur_lts_start - 10 * sts.len();
-                let short = &pkt[cur_sts_start..cur_lts_start];
-                let long = &pkt[cur_lts_start..cur_lts_start + 5 * lts.0.len() / 2];
-
-                // Calculate the CFO and correct it in the long preamble
-                let cfo = estimate_cfo(short, long, &config.ofdm);
-                let long = correct_cfo(long, cfo);
-
-                // Calculate the equalization
-                let equalization = estimate_subcarrier_equalization(&long, &config.ofdm);
-                callback(&equalization);
-
-                // Estimate the start of the next long preamble. Sample frequency offset aside, it
-                // should be pretty close to `cur_lts_start + preamble_len`. No need to do this if
-                // this was the last repeat
-                if i < config.num_repeats - 1 {
-                    // Leave this much margin for samples to have drifted
-                    let margin = 5;
-                    // If margin is so large it includes the previous LTS, it can cause trouble
-                    assert!(margin < lts.0.len() / 2);
-                    let expected_sts_start = cur_sts_start + preamble_len;
-                    let expected_lts_start = cur_lts_start + preamble_len;
-                    cur_lts_start = expected_sts_start - margin
-                        + lts_align(
-                            &pkt[expected_sts_start - margin..expected_sts_start + preamble_len + lts.0.len() / 2],
-                            &lts.0,
-                        );
-                    if (cur_lts_start as i64 - expected_lts_start as i64).abs() > margin as i64 {
-                        eprintln!("It seems that the LTS drifted more than the expected margin. Skipping the rest of the packet: {} {} {} {}",
-                            i, cur_lts_start, expected_lts_start, pkt.len());
-                        break;
+        match pkts.recv_timeout(CLOSE_POLL) {
+            Ok(pkt) => processor.process(&pkt?, callback)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// A recording played back in chunks, like a radio's receive stream, for testing the receiver
+/// without hardware (see `replay_rx`)
+pub struct FileReplayRx {
+    samps: Vec<Complex<f32>>,
+    /// Index of the next sample to return
+    pos: usize,
+}
+
+impl FileReplayRx {
+    pub fn new(samps: Vec<Complex<f32>>) -> Self {
+        Self { samps, pos: 0 }
+    }
+
+    /// Replay a raw `fc32` capture, as read by `capture::read_iq_fc32`
+    pub fn from_fc32(path: &str) -> std::io::Result<Self> {
+        Ok(Self::new(read_iq_fc32(path)?))
+    }
+}
+
+impl RadioRx for FileReplayRx {
+    type Error = std::io::Error;
+
+    /// The next (up to) `num_samps` samples and the index of the first of them in the recording.
+    /// Once the recording is exhausted, returns an `UnexpectedEof` error
+    fn recv(&mut self, num_samps: usize) -> std::io::Result<(&[Complex<f32>], u64)> {
+        if self.pos >= self.samps.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "end of the recording",
+            ));
+        }
+        let (start, end) = (self.pos, self.samps.len().min(self.pos + num_samps));
+        self.pos = end;
+        Ok((&self.samps[start..end], start as u64))
+    }
+}
+
+/// Like `run_rx`, but receives from a `FileReplayRx` until the recording runs out. The end of the
+/// recording counts as quiet, so a burst that runs up to it is still processed
+pub fn replay_rx<F: FnMut(&Estimate)>(
+    rx: &mut FileReplayRx,
+    config: &MonitorConfig,
+    mut callback: F,
+) -> Result<(), Error> {
+    let mut detector = RxDetector::new(config);
+    let mut processor = RxProcessor::new(config)?;
+    loop {
+        match rx.recv(RX_CHUNK) {
+            Ok((chunk, _)) => {
+                for samp in chunk {
+                    if let Some(pkt) = detector.push_samp(*samp) {
+                        processor.process(&pkt, &mut callback)?;
                     }
                 }
             }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
         }
     }
-    println!("Rx closed");
+    for _ in 0..=config.ofdm.pkt_spacing {
+        if let Some(pkt) = detector.push_samp(Complex::zero()) {
+            processor.process(&pkt, &mut callback)?;
+        }
+    }
+    info!("Replay finished after {} samples", detector.num_samps);
 
     Ok(())
 }
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     // Register signal handler to close USRP on Ctrl-C
     let close = Arc::new(AtomicBool::new(false));
     let close_handler = close.clone();
@@ -174,41 +811,566 @@ fn main() {
     };
 
     // Create Tx and Rx
-    let (mut tx, mut rx) = create_simulator(&radio_config, rand::rngs::StdRng::seed_from_u64(0));
-
-    // Start the transmitter and receiver
-    let mut monitor_config = MonitorConfig {
-        ofdm: ChannelEstConfigDes {
-            stabilize_samps: 0,
-            power_trig: 0.1,
-            pkt_spacing: 0, // will set later
-            sts: Some("data/short-802.11.txt".to_string()),
-            lts: Some("data/lts-802.11.txt".to_string()),
-        }
-        .into(),
-        num_repeats: 100,
-        duty_cycle: 0.5,
+    let (mut tx, rx) = create_simulator(&radio_config, rand::rngs::StdRng::seed_from_u64(0));
+
+    // Start the transmitter and receiver, configured from the TOML file given as the first
+    // argument, if any
+    let monitor_config = match std::env::args().nth(1) {
+        Some(path) => MonitorConfig::from_toml_path(&path).expect("Could not load the config"),
+        None => {
+            let ofdm = ChannelEstConfigDes {
+                power_trig: 0.1,
+                samp_rate: Some(radio_config.samp_rate as f64),
+                center_freq: Some(radio_config.start_freq.into()),
+                ..Default::default()
+            }
+            .try_into_config()
+            .expect("Invalid OFDM config");
+            MonitorConfig::builder(ofdm)
+                .build()
+                .expect("Invalid config")
+        }
     };
-    // The minimum gap between packets has to be at least this large, so we don't mistake the LTS
-    // guard interval for the end of the packet
-    monitor_config.ofdm.pkt_spacing = monitor_config.ofdm.lts.as_ref().unwrap().0.len() as u64;
 
     let close_rx = close.clone();
     let monitor_config_rx = monitor_config.clone();
-    let callback = |est: &[Option<Complex<f32>>]| {
-        for x in est {
+    let callback = |est: &Estimate| {
+        match est.cfo_hz {
+            Some(cfo_hz) => print!("cfo {:.1} Hz ", cfo_hz),
+            None => print!("cfo {:.5} rad/samp ", est.cfo),
+        }
+        print!("snr {:.1} dB: ", est.snr_db);
+        for x in est.equalization {
             match x {
                 Some(x) => print!("{:.3}+i{:.3} ", x.re, x.im),
-                None => print!("_ ")
+                None => print!("_ "),
             }
         }
         println!("");
     };
-    let rx_handle =
-        std::thread::spawn(move || run_rx(&mut rx, &monitor_config_rx, callback, close_rx));
+    let rx_handle = std::thread::spawn(move || run_rx(rx, &monitor_config_rx, callback, close_rx));
 
     let tx_handle = std::thread::spawn(move || run_tx(&mut tx, &monitor_config, close));
 
     rx_handle.join().unwrap().unwrap();
     tx_handle.join().unwrap().unwrap();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use channel_est::equalization::EqualizerKind;
+    use std::sync::{Mutex, Once};
+    use std::time::Instant;
+
+    /// Logger that keeps every record, so tests can check what was logged
+    struct TestLogger(Mutex<Vec<(log::Level, String)>>);
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let msg = record.args().to_string();
+            self.0.lock().unwrap().push((record.level(), msg));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+
+    /// Install `LOGGER`, once for all the tests
+    fn install_logger() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    fn monitor_config(num_repeats: u64) -> MonitorConfig {
+        MonitorConfig {
+            ofdm: ChannelEstConfig::default(),
+            num_repeats,
+            duty_cycle: 0.5,
+            packet_log: None,
+            tx_filter: None,
+            record_dir: None,
+            remove_dc: false,
+        }
+    }
+
+    fn preamble(config: &MonitorConfig) -> Vec<Complex<f32>> {
+        build_preamble(&config.ofdm)
+    }
+
+    /// Run a burst through `PktTrigger` like `run_rx` does and process the detected packet
+    fn run_burst(burst: &[Complex<f32>], config: &MonitorConfig) -> (u64, usize) {
+        let mut trigger = PktTrigger::new(&config.ofdm);
+        let silence = vec![Complex::zero(); 2 * config.ofdm.pkt_spacing as usize];
+        let mut pkts = silence
+            .iter()
+            .chain(burst)
+            .chain(&silence)
+            .filter_map(|x| trigger.push_samp(*x));
+        let pkt = pkts.next().unwrap();
+        assert!(pkts.next().is_none());
+
+        let mut num_callbacks = 0;
+        let num_processed =
+            process_burst(&pkt, config, &mut equalization_only(|_| num_callbacks += 1));
+        (num_processed, num_callbacks)
+    }
+
+    #[test]
+    fn test_full_burst() {
+        let config = monitor_config(5);
+        let preamble = preamble(&config);
+        let burst: Vec<_> = preamble
+            .iter()
+            .cycle()
+            .take(5 * preamble.len())
+            .copied()
+            .collect();
+        assert_eq!(run_burst(&burst, &config), (5, 5));
+    }
+
+    #[test]
+    fn test_drift_warning() {
+        install_logger();
+        let config = monitor_config(3);
+        let preamble = preamble(&config);
+
+        // The second preamble arrives 8 samples late, beyond the 5 sample margin
+        let mut burst = preamble.clone();
+        burst.extend(std::iter::repeat(Complex::zero()).take(8));
+        burst.extend(preamble.iter().cycle().take(2 * preamble.len()));
+        assert_eq!(run_burst(&burst, &config), (1, 1));
+
+        let records = LOGGER.0.lock().unwrap();
+        assert!(
+            records
+                .iter()
+                .any(|(level, msg)| *level == log::Level::Warn && msg.contains("drifted")),
+            "{:?}",
+            *records
+        );
+    }
+
+    #[test]
+    fn test_burst_estimates() {
+        let mut config = monitor_config(3);
+        config.ofdm.samp_rate = Some(20e6);
+        let preamble = preamble(&config);
+        let cfo = 0.01;
+        let mut pkt = vec![Complex::zero(); 200];
+        pkt.extend(preamble.iter().cycle().take(3 * preamble.len()));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(200));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        channel_est::sim::add_awgn(&mut pkt, 1e-3, &mut rng);
+        let pkt: Vec<_> = pkt
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x * Complex::new(0., cfo * i as f32).exp())
+            .collect();
+        let pkt = DetectedPacket {
+            samples: pkt,
+            trigger_offset: 200,
+        };
+
+        let mut estimates = Vec::new();
+        let num_processed = process_burst(&pkt, &config, &mut |est: &Estimate| {
+            estimates.push((est.cfo, est.cfo_hz, est.snr_db, est.equalization.len()))
+        });
+        assert_eq!(num_processed, 3);
+        assert_eq!(estimates.len(), 3);
+        for (est_cfo, cfo_hz, snr_db, len) in estimates {
+            // Each estimate only sees one preamble at an SNR of about 30 dB
+            assert!((est_cfo - cfo).abs() < 3e-4, "{}", est_cfo);
+            // est_cfo / 2pi cycles per sample at 20 MHz
+            let expected_hz = est_cfo / (2. * std::f32::consts::PI) * 20e6;
+            assert!((cfo_hz.unwrap() - expected_hz).abs() < 1., "{:?}", cfo_hz);
+            // Unit power preamble with noise of power 1e-3
+            assert!(snr_db > 20. && snr_db < 40., "{}", snr_db);
+            assert_eq!(len, 64);
+        }
+    }
+
+    #[test]
+    fn test_replay_rx() {
+        let config = monitor_config(3);
+        let preamble = preamble(&config);
+        // Two bursts, the second running up to the end of the recording. Each spans several of
+        // the chunks `replay_rx` reads
+        let mut recording = vec![Complex::zero(); 300];
+        for _ in 0..2 {
+            recording.extend(preamble.iter().cycle().take(3 * preamble.len()));
+            recording.extend(std::iter::repeat(Complex::zero()).take(100));
+        }
+        recording.truncate(recording.len() - 100);
+
+        let path = std::env::temp_dir().join("channel_est_test_replay.cfile");
+        let path = path.to_str().unwrap();
+        channel_est::capture::write_iq_fc32(path, &recording).unwrap();
+        let mut rx = FileReplayRx::from_fc32(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut num_callbacks = 0;
+        replay_rx(&mut rx, &config, equalization_only(|_| num_callbacks += 1)).unwrap();
+        assert_eq!(num_callbacks, 6);
+        let err = rx.recv(RX_CHUNK).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_run_rx_replay() {
+        let config = monitor_config(3);
+        let preamble = preamble(&config);
+        let mut recording = vec![Complex::zero(); 300];
+        for _ in 0..2 {
+            recording.extend(preamble.iter().cycle().take(3 * preamble.len()));
+            recording.extend(std::iter::repeat(Complex::zero()).take(300));
+        }
+
+        // `run_rx` reads the recording like a radio, processing both bursts before the end of the
+        // recording stops it with an error
+        let mut num_callbacks = 0;
+        let res = run_rx(
+            FileReplayRx::new(recording),
+            &config,
+            equalization_only(|_| num_callbacks += 1),
+            Arc::new(AtomicBool::new(false)),
+        );
+        match res {
+            Err(Error::Radio(msg)) => assert!(msg.contains("UnexpectedEof"), "{}", msg),
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(num_callbacks, 6);
+    }
+
+    /// A radio that delivers one burst and then blocks forever in `recv`
+    struct BlockingRx {
+        burst: Vec<Complex<f32>>,
+        sent: bool,
+    }
+
+    impl RadioRx for BlockingRx {
+        type Error = ();
+
+        fn recv(&mut self, _num_samps: usize) -> Result<(&[Complex<f32>], u64), ()> {
+            if self.sent {
+                loop {
+                    std::thread::park();
+                }
+            }
+            self.sent = true;
+            Ok((&self.burst, 0))
+        }
+    }
+
+    #[test]
+    fn test_close_while_blocked() {
+        let config = monitor_config(3);
+        let preamble = preamble(&config);
+        let mut burst = vec![Complex::zero(); 100];
+        burst.extend(preamble.iter().cycle().take(3 * preamble.len()));
+        burst.extend(std::iter::repeat(Complex::zero()).take(100));
+
+        let close = Arc::new(AtomicBool::new(false));
+        let close_later = close.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            close_later.store(true, Ordering::Relaxed);
+        });
+
+        // `run_rx` returns promptly after `close` is set, although the radio never returns again
+        let start = Instant::now();
+        let mut num_callbacks = 0;
+        run_rx(
+            BlockingRx { burst, sent: false },
+            &config,
+            equalization_only(|_| num_callbacks += 1),
+            close.clone(),
+        )
+        .unwrap();
+        assert!(close.load(Ordering::Relaxed));
+        assert!(start.elapsed() < Duration::from_millis(200) + 5 * CLOSE_POLL);
+        assert_eq!(num_callbacks, 3);
+    }
+
+    #[test]
+    fn test_packet_queue() {
+        let config = monitor_config(3);
+        let preamble = preamble(&config);
+        let mut recording = Vec::new();
+        for _ in 0..4 {
+            recording.extend(std::iter::repeat(Complex::zero()).take(100));
+            recording.extend(preamble.iter().cycle().take(3 * preamble.len()));
+        }
+        recording.extend(std::iter::repeat(Complex::zero()).take(100));
+
+        // Packets detected on the reader's thread come through the queue, and processing stops
+        // once the recording runs out
+        let mut chunks: Vec<_> = recording.chunks(RX_CHUNK).map(|c| c.to_vec()).collect();
+        chunks.reverse();
+        let close = Arc::new(AtomicBool::new(false));
+        let (pkts, reader) = spawn_detector(
+            move || Ok(chunks.pop()),
+            RxDetector::new(&config),
+            close.clone(),
+        );
+        let mut processor = RxProcessor::new(&config).unwrap();
+        let mut num_callbacks = 0;
+        {
+            let mut callback = equalization_only(|_| num_callbacks += 1);
+            process_packets(&mut processor, &pkts, &mut callback, &close).unwrap();
+        }
+        assert!(!close.load(Ordering::Relaxed));
+        assert_eq!(num_callbacks, 12);
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn test_rx_error() {
+        let config = monitor_config(3);
+        let preamble = preamble(&config);
+        let mut burst = vec![Complex::zero(); 100];
+        burst.extend(preamble.iter().cycle().take(3 * preamble.len()));
+        burst.extend(std::iter::repeat(Complex::zero()).take(100));
+
+        // The packet received before the radio failed is still processed, then the error is
+        // returned
+        let close = Arc::new(AtomicBool::new(false));
+        let mut chunks = vec![burst];
+        let (pkts, _) = spawn_detector(
+            move || match chunks.pop() {
+                Some(chunk) => Ok(Some(chunk)),
+                None => Err(Error::Radio("overflow".to_string())),
+            },
+            RxDetector::new(&config),
+            close.clone(),
+        );
+        let mut processor = RxProcessor::new(&config).unwrap();
+        let mut num_callbacks = 0;
+        let res = {
+            let mut callback = equalization_only(|_| num_callbacks += 1);
+            process_packets(&mut processor, &pkts, &mut callback, &close)
+        };
+        assert!(matches!(res, Err(Error::Radio(msg)) if msg == "overflow"));
+        assert_eq!(num_callbacks, 3);
+    }
+
+    #[test]
+    fn test_monitor_config_toml() {
+        let toml = r#"
+            num_repeats = 7
+            duty_cycle = 0.25
+            remove_dc = true
+            record_dir = "/tmp/channel_est_recordings"
+
+            [packet_log]
+            path = "/tmp/channel_est_packets.jsonl"
+            fields = ["timestamp", "cfo_hz", "rssi_db"]
+
+            [ofdm]
+            pkt_spacing = 96
+            power_trig = 0.05
+            power_trig_mode = { kind = "Adaptive", factor = 4.0 }
+            equalizer = { kind = "Mmse", snr_db = 20.0 }
+        "#;
+        let des: MonitorConfigDes = toml::from_str(toml).unwrap();
+        assert_eq!(des.num_repeats, 7);
+        assert_eq!(des.ofdm.pkt_spacing, 96);
+        // Fields that weren't given keep their defaults
+        assert_eq!(des.ofdm.cp_len, 16);
+        assert_eq!(des.tx_filter, None);
+
+        // Round trip. Going through `toml::Value` writes the tables (e.g. `power_trig_mode`) after
+        // the plain values, which TOML requires but the fields' order doesn't give
+        let written = toml::to_string(&toml::Value::try_from(&des).unwrap()).unwrap();
+        assert_eq!(toml::from_str::<MonitorConfigDes>(&written).unwrap(), des);
+
+        let config = MonitorConfig::from_toml_str(toml).unwrap();
+        assert_eq!(config.num_repeats, 7);
+        assert!((config.duty_cycle - 0.25).abs() < 1e-9);
+        assert!(config.remove_dc);
+        assert_eq!(
+            config.record_dir,
+            Some(PathBuf::from("/tmp/channel_est_recordings"))
+        );
+        let log_config = config.packet_log.unwrap();
+        assert_eq!(
+            log_config.fields,
+            vec![
+                PacketLogField::Timestamp,
+                PacketLogField::CfoHz,
+                PacketLogField::Rssi
+            ]
+        );
+        assert_eq!(config.ofdm.pkt_spacing, 96);
+        assert_eq!(config.ofdm.equalizer, EqualizerKind::Mmse { snr_db: 20. });
+        assert_eq!(config.ofdm.lts.unwrap().0.len(), 64);
+
+        // Invalid values are rejected at load
+        let invalid_field = |toml: &str| match MonitorConfig::from_toml_str(toml) {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            x => panic!("Expected the config to be invalid, got {:?}", x),
+        };
+        assert_eq!(invalid_field("num_repeats = 0"), "num_repeats");
+        assert_eq!(invalid_field("duty_cycle = 1.5"), "duty_cycle");
+        assert_eq!(invalid_field("duty_cycle = 0.0"), "duty_cycle");
+        assert_eq!(invalid_field("[ofdm]\npkt_spacing = 20"), "pkt_spacing");
+
+        // The builder checks the same things
+        let ofdm = ChannelEstConfig::default();
+        let config = MonitorConfig::builder(ofdm.clone())
+            .num_repeats(3)
+            .remove_dc(true)
+            .build()
+            .unwrap();
+        assert_eq!(config.num_repeats, 3);
+        assert!((config.duty_cycle - 0.5).abs() < 1e-9);
+        assert!(config.remove_dc);
+        assert!(MonitorConfig::builder(ofdm)
+            .duty_cycle(-0.1)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_record_dir() {
+        let mut config = monitor_config(2);
+        let dir = std::env::temp_dir().join("channel_est_test_record_dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        config.record_dir = Some(dir.clone());
+        let preamble = preamble(&config);
+        let mut recording = Vec::new();
+        for _ in 0..3 {
+            recording.extend(std::iter::repeat(Complex::zero()).take(100));
+            recording.extend(preamble.iter().cycle().take(2 * preamble.len()));
+        }
+
+        replay_rx(
+            &mut FileReplayRx::new(recording.clone()),
+            &config,
+            |_: &Estimate| {},
+        )
+        .unwrap();
+        for i in 0..3 {
+            let pkt = read_iq_fc32(dir.join(format!("pkt{}.cfile", i)).to_str().unwrap()).unwrap();
+            // The packet and the quiet samples around it
+            assert!(pkt.len() > 2 * preamble.len(), "{}", pkt.len());
+        }
+        assert!(!dir.join("pkt3.cfile").exists());
+
+        // A second run carries on numbering from the first, leaving its recordings alone
+        std::fs::write(dir.join("notes.txt"), "not a recording").unwrap();
+        replay_rx(
+            &mut FileReplayRx::new(recording),
+            &config,
+            |_: &Estimate| {},
+        )
+        .unwrap();
+        assert!(dir.join("pkt5.cfile").exists());
+        assert!(!dir.join("pkt6.cfile").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_burst() {
+        let config = monitor_config(10);
+        let preamble = preamble(&config);
+
+        // The transmitter stopped after 3 repeats
+        let burst: Vec<_> = preamble
+            .iter()
+            .cycle()
+            .take(3 * preamble.len())
+            .copied()
+            .collect();
+        assert_eq!(run_burst(&burst, &config), (3, 3));
+
+        // Another transmission collided with the burst after 4 repeats
+        let mut burst: Vec<_> = preamble
+            .iter()
+            .cycle()
+            .take(4 * preamble.len())
+            .copied()
+            .collect();
+        burst.extend((0..3 * preamble.len()).map(|i| {
+            let i = i as f32;
+            Complex::new((1.3 * i).sin() + (0.17 * i * i).cos(), (2.9 * i).cos()) * 0.5
+        }));
+        assert_eq!(run_burst(&burst, &config), (4, 4));
+    }
+
+    #[test]
+    fn test_packet_log() {
+        let mut config = monitor_config(2);
+        config.ofdm.samp_rate = Some(20e6);
+        config.ofdm.center_freq = Some(2.4e9);
+        let preamble = preamble(&config);
+        let cfo = 0.01;
+        let fields = [
+            PacketLogField::Timestamp,
+            PacketLogField::CfoHz,
+            PacketLogField::CfoPpm,
+            PacketLogField::Rssi,
+            PacketLogField::Evm,
+            PacketLogField::Channel,
+        ];
+
+        // Three bursts, each with a different gain
+        let gains = [1., 0.5, 0.1];
+        let mut samps = Vec::new();
+        for gain in &gains {
+            samps.extend(std::iter::repeat(Complex::zero()).take(200));
+            samps.extend(
+                preamble
+                    .iter()
+                    .cycle()
+                    .take(2 * preamble.len())
+                    .map(|x| x * *gain),
+            );
+        }
+        samps.extend(std::iter::repeat(Complex::zero()).take(200));
+        let samps: Vec<_> = samps
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x * Complex::new(0., cfo * i as f32).exp())
+            .collect();
+
+        let mut trigger = PktTrigger::new(&config.ofdm);
+        let mut sink = Vec::new();
+        for samp in &samps {
+            if let Some(pkt) = trigger.push_samp(*samp) {
+                log_packet(&mut sink, &pkt, &config, &fields).unwrap();
+            }
+        }
+
+        let lines: Vec<_> = std::str::from_utf8(&sink).unwrap().lines().collect();
+        assert_eq!(lines.len(), gains.len());
+        let expected_cfo_hz = f64::from(cfo) * 20e6 / (2. * std::f64::consts::PI);
+        for (line, gain) in lines.iter().zip(&gains) {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(record["timestamp"].as_f64().unwrap() > 0.);
+            let cfo_hz = record["cfo_hz"].as_f64().unwrap();
+            assert!((cfo_hz - expected_cfo_hz).abs() < 0.01 * expected_cfo_hz);
+            let cfo_ppm = record["cfo_ppm"].as_f64().unwrap();
+            assert!((cfo_ppm - expected_cfo_hz / 2.4e3).abs() < 0.01 * expected_cfo_hz / 2.4e3);
+            // The preamble is normalized to unit power, apart from the guard interval
+            let rssi_db = record["rssi_db"].as_f64().unwrap() as f32;
+            let expected_rssi_db = 20. * gain.log10() + 10. * (4f32 / 5.).log10();
+            assert!((rssi_db - expected_rssi_db).abs() < 0.1);
+            assert!(record["evm"].as_f64().unwrap() < 1e-3);
+            let channel = record["channel"].as_array().unwrap();
+            assert_eq!(channel.len(), 52);
+            for h in channel {
+                let h = Complex::new(h[0].as_f64().unwrap(), h[1].as_f64().unwrap());
+                assert!((h.norm() as f32 - gain).abs() < 1e-2);
+            }
+        }
+    }
+}
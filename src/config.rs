@@ -1,11 +1,32 @@
-use num::Complex;
+use crate::capture::open_capture;
+use crate::demod::QamOrder;
+use crate::equalization::{EqualizerKind, FftPlans};
+use crate::error::{ConfigError, Result};
+use crate::lts_align::CorrelationPlans;
+use crate::pkt_trigger::PowerTrigMode;
+use crate::sim::modulate_symbol;
+use num::{Complex, Zero};
 use rustfft::FFTplanner;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::default::Default;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
 use transform_struct::transform_struct;
 
+/// The normalized LTS, its FFT (`None` on unused subcarriers), the FFT plans for the LTS length
+/// and those for correlating against the LTS (see `lts_align::lts_align`)
+pub type Lts = (
+    Vec<Complex<f32>>,
+    Vec<Option<Complex<f32>>>,
+    FftPlans,
+    CorrelationPlans,
+);
+
 transform_struct!(
-    #[derive(Deserialize)]
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(default)]
     pub struct ChannelEstConfigDes
     #[derive(Clone, Debug)]
     pub struct ChannelEstConfig {
@@ -13,60 +34,515 @@ transform_struct!(
         pub stabilize_samps: u64,
         /// Power (i.e. |x|^2) level for packet detection
         pub power_trig: f32,
-        /// We may assume there are at-least these many samples between packets
+        /// Whether `PktTrigger` uses `power_trig` or a level relative to the noise floor
+        pub power_trig_mode: PowerTrigMode,
+        /// Once a packet has started, `PktTrigger` only counts samples as quiet when their power
+        /// is below this fraction of the level that started it. Values below 1 add hysteresis,
+        /// so a packet whose power hovers around the trigger level isn't split into many. Must be
+        /// in (0, 1]. 1 means a single threshold. The stop level is given relative to the start
+        /// level rather than as a threshold of its own so that it follows the start level in
+        /// `PowerTrigMode::Adaptive`. In `PowerTrigMode::Fixed` it is
+        /// `power_trig * power_stop_ratio`
+        pub power_stop_ratio: f32,
+        /// We may assume there are at-least these many samples between packets. Must be at-least
+        /// the LTS length, so `PktTrigger` doesn't mistake the guard interval before the LTS for
+        /// the end of the packet
         pub pkt_spacing: u64,
+        /// Longest buffer (in samples, including the quiet samples before the trigger) that
+        /// `PktTrigger` holds on to. If the power stays above the threshold that long (e.g. a
+        /// continuous transmitter), it emits what it has, truncating the packet, and starts over.
+        /// With an adaptive `power_trig_mode`, it also estimates the noise floor afresh in case
+        /// that is what rose. `None` means no limit. Must be more than `pkt_spacing`
+        pub max_packet_len: Option<usize>,
+        /// Sample rate (in samples per second) of the stream, if known
+        pub samp_rate: Option<f64>,
+        /// Center frequency (in Hz) the stream was captured at, if known
+        pub center_freq: Option<f64>,
+        /// Track the sampling phase of each data symbol using the pilots and correct it before
+        /// equalization. Needed when the sample clock drifts appreciably over a packet
+        pub track_sampling_phase: bool,
+        /// Track the common phase of each data symbol using the pilots and correct it. Needed
+        /// when the CFO estimated from the preamble is slightly off and the packet is long
+        pub track_common_phase: bool,
+        /// Track the channel over the data symbols by nudging the equalization towards each
+        /// symbol's hard decisions (see `equalization::ChannelTracker`). Helps long packets when
+        /// the channel changes slowly during the packet
+        pub track_channel: bool,
+        /// Fraction of the way the channel estimate moves towards each symbol's observation when
+        /// `track_channel` is set. Must be in (0, 1]. Larger values track faster but are noisier
+        pub track_channel_step: f32,
+        /// Constellation of the data subcarriers, which `track_channel` makes decisions in.
+        /// `None` means BPSK
+        pub track_channel_order: Option<QamOrder>,
+        /// Estimate the sample frequency offset (i.e. sample clock mismatch) from the long
+        /// preamble and correct the resulting drift in each data symbol
+        pub correct_sfo: bool,
+        /// Number of samples at the start of each LTS copy left out of the fine CFO estimate. With
+        /// multipath, the start of the first copy also carries the channel's response to the
+        /// guard interval rather than to the end of an LTS, so it doesn't match the second copy.
+        /// Set this to about the channel's delay spread (in samples). Must be less than the LTS
+        pub cfo_fine_skip: usize,
+        /// Where the FFT window of each data symbol starts, as a fraction of the cyclic prefix
+        /// relative to the end of the cyclic prefix. Must be in [-1, 0]. 0 starts right after
+        /// the cyclic prefix, whereas negative values start earlier, which helps with channels
+        /// that have pre-cursor taps. Non-zero values assume the guard interval before the LTS is
+        /// a cyclic prefix (as in 802.11)
+        pub symbol_start_offset: f32,
+        /// Length (in samples) of the raised-cosine ramps of the receive window applied to each
+        /// data symbol before the FFT, or 0 for none. The last `symbol_window` samples of the FFT
+        /// window are faded out and blended with their copies in the cyclic prefix just before
+        /// it, faded in. Since those copies are identical for a channel within the cyclic prefix,
+        /// this keeps the subcarriers orthogonal, unlike windowing the FFT window itself (e.g.
+        /// with Hann), which leaks every subcarrier into its neighbours. It suppresses what
+        /// leaks into the end of the window from the next symbol (e.g. due to a timing error or
+        /// pre-cursor taps), but uses up as much of the cyclic prefix's tolerance to delay
+        /// spread. Must be at most the part of the cyclic prefix before the FFT window (see
+        /// `symbol_start_offset`)
+        pub symbol_window: usize,
+        /// Length (in samples) of the cyclic prefix before each data symbol. 802.11 uses a quarter
+        /// of the symbol (16 samples); a longer prefix tolerates more delay spread
+        pub cp_len: usize,
+        /// FFT size of the data symbols (excluding the cyclic prefix), for OFDM numerologies
+        /// other than 802.11's. `None` means the LTS length. The equalization estimated on the
+        /// LTS's bins is carried over to the symbol's (see
+        /// `equalization::resample_equalization`), so the symbols should span the same bandwidth
+        pub symbol_len: Option<usize>,
+        /// How to compute the equalization from the long preamble
+        pub equalizer: EqualizerKind,
+        /// Decode the 802.11 SIGNAL field from the first symbol after the long preamble and, if
+        /// its parity checks, parse exactly the number of data symbols it announces. Otherwise
+        /// the packet ends when a symbol is much weaker than the long preamble
+        pub use_signal_length: bool,
+        /// Number of times the STS is repeated in the short preamble. 802.11 uses 10
+        pub sts_repeats: u64,
         > {
-            /// The short training sequence. This sequence is repeated 10 times. It is normalized
-            /// as so0n as it is read
+            /// The short training sequence. This sequence is repeated `sts_repeats` times. It is
+            /// normalized as so0n as it is read. `EMBEDDED_802_11` (the default) names the
+            /// 802.11 sequence built into the crate rather than a file
             pub sts: Option<String>
-            => (read_sts -> Option<Vec<Complex<f32>>>),
+            => (sts_from_file -> Option<Vec<Complex<f32>>>),
             /// Filename where the Long Training Sequence (LTS) is stored. This is read and
             /// converted to a vec of complex numbers by `filename_to_cplx_vec`. We store both the
             /// lts and its FFT. If the FFT element has a magnitude < 1% of the maximum, then we
             /// store None. This implies that the sub-carrier isn't used. LTS is normalized as
-            /// soon as it is read.
+            /// soon as it is read. We also plan the FFTs of the LTS length once here, and keep the
+            /// plans for correlating against it (see `Lts`). As for `sts`, `EMBEDDED_802_11` (the
+            /// default) names the built-in 802.11 sequence
             pub lts: Option<String>
-            => (read_lts -> Option<(Vec<Complex<f32>>, Vec<Option<Complex<f32>>>)>),
+            => (lts_from_file -> Option<Lts>),
         }
     }
 );
 
-impl Default for ChannelEstConfig {
+impl Default for ChannelEstConfigDes {
     fn default() -> Self {
-        ChannelEstConfigDes {
+        Self {
             stabilize_samps: 0,
             power_trig: 0.01,
-            pkt_spacing: 20,
-            sts: Some("data/short-802.11.txt".to_string()),
-            lts: Some("data/lts-802.11.txt".to_string())
-        }.into()
+            power_trig_mode: PowerTrigMode::Fixed,
+            power_stop_ratio: 1.,
+            pkt_spacing: 64,
+            max_packet_len: Some(1 << 20),
+            samp_rate: None,
+            center_freq: None,
+            track_sampling_phase: false,
+            track_common_phase: false,
+            track_channel: false,
+            track_channel_step: 0.1,
+            track_channel_order: None,
+            correct_sfo: false,
+            cfo_fine_skip: 0,
+            symbol_start_offset: 0.,
+            symbol_window: 0,
+            cp_len: 16,
+            symbol_len: None,
+            equalizer: EqualizerKind::ZeroForcing,
+            use_signal_length: false,
+            sts_repeats: 10,
+            sts: Some(EMBEDDED_802_11.to_string()),
+            lts: Some(EMBEDDED_802_11.to_string()),
+        }
+    }
+}
+
+impl ChannelEstConfigDes {
+    /// Like `into()`, but returns an error if a training sequence file can't be read instead of
+    /// panicking
+    pub fn try_into_config(mut self) -> std::result::Result<ChannelEstConfig, ConfigError> {
+        let sts = read_sts(self.sts.take())?;
+        let lts = read_lts(self.lts.take())?;
+        let mut config: ChannelEstConfig = self.into();
+        config.sts = sts;
+        config.lts = lts;
+        Ok(config)
     }
 }
 
+/// Same as `ChannelEstConfigDes::default().into()`, which uses the embedded 802.11 sequences, so
+/// it doesn't read any files
+impl Default for ChannelEstConfig {
+    fn default() -> Self {
+        ChannelEstConfigDes::default().into()
+    }
+}
+
+impl ChannelEstConfig {
+    /// Load the config from a TOML file. See `from_toml_str`
+    pub fn from_toml_path(path: &str) -> std::result::Result<Self, ConfigError> {
+        let toml = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        Self::from_toml_str(&toml)
+    }
+
+    /// Parse the fields of `ChannelEstConfigDes` from TOML and read the sequence files they name.
+    /// Fields that are absent take their default values
+    pub fn from_toml_str(toml: &str) -> std::result::Result<Self, ConfigError> {
+        let des: ChannelEstConfigDes = toml::from_str(toml)?;
+        des.try_into_config()
+    }
+
+    /// Build the config from in-memory training sequences instead of files. They are processed
+    /// exactly as `read_sts` and `read_lts` would. All other fields take their default values
+    pub fn from_sequences(
+        mut sts: Vec<Complex<f32>>,
+        lts: Vec<Complex<f32>>,
+        power_trig: f32,
+        pkt_spacing: u64,
+    ) -> Self {
+        let mut config: Self = ChannelEstConfigDes {
+            power_trig,
+            pkt_spacing,
+            sts: None,
+            lts: None,
+            ..Default::default()
+        }
+        .into();
+        normalize(&mut sts);
+        config.sts = Some(sts);
+        config.lts = Some(process_lts(lts));
+        config
+    }
+
+    /// Length (in samples) of the short preamble, i.e. `sts_repeats` copies of the STS
+    pub fn short_len(&self) -> usize {
+        self.sts_repeats as usize * self.sts.as_ref().unwrap().len()
+    }
+
+    /// Number of samples of the cyclic prefix before the FFT window of each data symbol, as set by
+    /// `symbol_start_offset`
+    pub fn symbol_window_start(&self) -> usize {
+        (self.cp_len as f32 * (1. + self.symbol_start_offset)).round() as usize
+    }
+
+    /// FFT size of the data symbols, i.e. `symbol_len` if set and the LTS length otherwise
+    pub fn symbol_fft_len(&self) -> usize {
+        self.symbol_len
+            .unwrap_or_else(|| self.lts.as_ref().unwrap().0.len())
+    }
+
+    /// Check the invariants the processing assumes, which otherwise only fail as assertions deep
+    /// inside it. Call this once after building the config. `pkt_spacing` must be at-least an
+    /// LTS long so that `PktTrigger` doesn't mistake the guard interval for the end of the packet
+    /// (the default of 64 is exactly one 802.11 LTS)
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(ConfigError::Invalid {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+        match &self.sts {
+            None => return invalid("sts", "no STS configured"),
+            Some(sts) if sts.is_empty() => return invalid("sts", "STS is empty"),
+            Some(_) => {}
+        }
+        if self.sts_repeats < 2 {
+            return invalid(
+                "sts_repeats",
+                &format!("{} is too few to estimate CFO from", self.sts_repeats),
+            );
+        }
+        let lts_len = match &self.lts {
+            None => return invalid("lts", "no LTS configured"),
+            Some((lts, _, _, _)) => lts.len(),
+        };
+        if lts_len == 0 || lts_len % 4 != 0 {
+            return invalid(
+                "lts",
+                &format!("LTS length {} is not a non-zero multiple of 4", lts_len),
+            );
+        }
+        if self.power_trig.is_nan() || self.power_trig <= 0. {
+            return invalid(
+                "power_trig",
+                &format!("{} is not positive", self.power_trig),
+            );
+        }
+        let ratio = self.power_stop_ratio;
+        if ratio.is_nan() || ratio <= 0. || ratio > 1. {
+            return invalid("power_stop_ratio", &format!("{} is not in (0, 1]", ratio));
+        }
+        if (self.pkt_spacing as usize) < lts_len {
+            return invalid(
+                "pkt_spacing",
+                &format!("{} is shorter than the LTS ({})", self.pkt_spacing, lts_len),
+            );
+        }
+        if let Some(max_len) = self.max_packet_len {
+            if max_len <= self.pkt_spacing as usize {
+                return invalid(
+                    "max_packet_len",
+                    &format!(
+                        "{} is not longer than pkt_spacing ({})",
+                        max_len, self.pkt_spacing
+                    ),
+                );
+            }
+        }
+        let step = self.track_channel_step;
+        if step.is_nan() || step <= 0. || step > 1. {
+            return invalid("track_channel_step", &format!("{} is not in (0, 1]", step));
+        }
+        if self.cfo_fine_skip >= lts_len {
+            return invalid(
+                "cfo_fine_skip",
+                &format!(
+                    "{} is not shorter than the LTS ({})",
+                    self.cfo_fine_skip, lts_len
+                ),
+            );
+        }
+        let fft_len = self.symbol_fft_len();
+        if fft_len == 0 {
+            return invalid("symbol_len", "symbols can't be empty");
+        }
+        if self.cp_len > fft_len {
+            return invalid(
+                "cp_len",
+                &format!("{} is longer than a symbol ({})", self.cp_len, fft_len),
+            );
+        }
+        if !(-1. ..=0.).contains(&self.symbol_start_offset) {
+            return invalid(
+                "symbol_start_offset",
+                &format!("{} is not in [-1, 0]", self.symbol_start_offset),
+            );
+        }
+        if self.symbol_window > self.symbol_window_start() {
+            return invalid(
+                "symbol_window",
+                &format!(
+                    "{} is longer than the {} samples of cyclic prefix before the FFT window",
+                    self.symbol_window,
+                    self.symbol_window_start()
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The short and long preamble: 10 repeats of the STS, a guard interval of half an LTS and two
+/// repeats of the LTS
+pub fn build_preamble(config: &ChannelEstConfig) -> Vec<Complex<f32>> {
+    let sts = config.sts.as_ref().unwrap();
+    let lts = &config.lts.as_ref().unwrap().0;
+    assert_eq!(lts.len() % 2, 0);
+    let mut preamble = Vec::with_capacity(config.short_len() + 5 * lts.len() / 2);
+    preamble.extend(sts.iter().cycle().take(config.short_len()));
+    preamble.extend(std::iter::repeat(Complex::zero()).take(lts.len() / 2));
+    preamble.extend(lts.iter().cycle().take(2 * lts.len()));
+    preamble
+}
+
+/// The preamble followed by `data_symbols`, each given as the frequency-domain value of every bin
+/// and modulated with a cyclic prefix of `config.cp_len` as by `sim::modulate_symbol`
+pub fn build_packet(
+    config: &ChannelEstConfig,
+    data_symbols: &[Vec<Complex<f32>>],
+) -> Vec<Complex<f32>> {
+    let mut pkt = build_preamble(config);
+    for bins in data_symbols {
+        pkt.extend(modulate_symbol(bins, config.cp_len));
+    }
+    pkt
+}
+
 /// The file format is a list of numbers, each on a separate line. Lines 2 * i and 2 * i + 1
-/// contain the real and imaginary components of the i^th complex number
-pub fn filename_to_cplx_vec(fname: String) -> Vec<Complex<f32>> {
-    let str_data = std::fs::read_to_string(fname).unwrap();
+/// contain the real and imaginary components of the i^th complex number. The file may be gzip
+/// compressed
+pub fn filename_to_cplx_vec(fname: String) -> std::result::Result<Vec<Complex<f32>>, ConfigError> {
+    let io_err = |source| ConfigError::Io {
+        path: fname.clone(),
+        source,
+    };
+    let mut str_data = String::new();
+    open_capture(&fname)
+        .map_err(io_err)?
+        .read_to_string(&mut str_data)
+        .map_err(io_err)?;
+    parse_cplx_text(&fname, &str_data)
+}
+
+/// Parse the contents of `path` in the format read by `filename_to_cplx_vec`
+fn parse_cplx_text(
+    path: &str,
+    str_data: &str,
+) -> std::result::Result<Vec<Complex<f32>>, ConfigError> {
     // Split string into lines and parse floats
-    let f32_data: Vec<f32> = str_data
-        .split('\n')
-        .filter_map(|s| {
-            if s.len() == 0 {
-                None
-            } else {
-                Some(s.parse().unwrap())
-            }
-        })
-        .collect();
+    let mut f32_data: Vec<f32> = Vec::new();
+    for (i, line) in str_data.split('\n').enumerate() {
+        if !line.is_empty() {
+            let x = line.parse().map_err(|_| ConfigError::Parse {
+                path: path.to_string(),
+                line: i + 1,
+                text: line.to_string(),
+            })?;
+            f32_data.push(x);
+        }
+    }
 
     // Convert into complex. Even numbers are the real part and odd ones are the imaginary
-    assert_eq!(f32_data.len() % 2, 0);
-    let mut res = Vec::with_capacity(f32_data.len() / 2);
-    for i in 0..f32_data.len() / 2 {
-        res.push(Complex::new(f32_data[2 * i], f32_data[2 * i + 1]));
+    if f32_data.len() % 2 != 0 {
+        return Err(ConfigError::OddCount {
+            path: path.to_string(),
+            count: f32_data.len(),
+        });
+    }
+    let res = f32_data
+        .chunks(2)
+        .map(|x| Complex::new(x[0], x[1]))
+        .collect();
+
+    Ok(res)
+}
+
+/// The name `ChannelEstConfigDes::sts` and `lts` take to use the 802.11 sequences embedded in the
+/// binary (see `sts_802_11` and `lts_802_11`) instead of reading a file
+pub const EMBEDDED_802_11: &str = "802.11";
+
+/// The 802.11 short training sequence (before normalization), embedded in the binary so that the
+/// default config works from any directory. It is parsed the first time it is needed
+pub fn sts_802_11() -> Vec<Complex<f32>> {
+    static STS: OnceLock<Vec<Complex<f32>>> = OnceLock::new();
+    STS.get_or_init(|| {
+        parse_cplx_text("short-802.11.txt", include_str!("../data/short-802.11.txt")).unwrap()
+    })
+    .clone()
+}
+
+/// The 802.11 long training sequence (before normalization), embedded like `sts_802_11`
+pub fn lts_802_11() -> Vec<Complex<f32>> {
+    static LTS: OnceLock<Vec<Complex<f32>>> = OnceLock::new();
+    LTS.get_or_init(|| {
+        parse_cplx_text("lts-802.11.txt", include_str!("../data/lts-802.11.txt")).unwrap()
+    })
+    .clone()
+}
+
+/// Write `samps` in the format read by `filename_to_cplx_vec`
+pub fn cplx_vec_to_file<P: AsRef<Path>>(fname: P, samps: &[Complex<f32>]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(fname)?);
+    for x in samps {
+        writeln!(out, "{}\n{}", x.re, x.im)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Rough signature of one of the standard sequences shipped in `data/`, used to catch accidental
+/// edits to those files
+pub struct SequenceSignature {
+    /// Name of the file the sequence is stored in
+    pub file_name: &'static str,
+    /// Number of complex samples
+    pub len: usize,
+    /// RMS of the samples, before normalization
+    pub rms: f32,
+    /// Magnitude-weighted mean index, i.e. `sum_i i |x_i| / sum_i |x_i|`
+    pub centroid: f32,
+    /// First sample
+    pub first: Complex<f32>,
+    /// Last sample
+    pub last: Complex<f32>,
+}
+
+/// Signatures of the 802.11 short and long training sequences in `data/`
+pub const STANDARD_SEQUENCES: [SequenceSignature; 2] = [
+    SequenceSignature {
+        file_name: "short-802.11.txt",
+        len: 16,
+        rms: 0.112_699,
+        centroid: 7.700_347,
+        first: Complex {
+            re: 0.046,
+            im: 0.046,
+        },
+        last: Complex {
+            re: 0.002,
+            im: -0.132,
+        },
+    },
+    SequenceSignature {
+        file_name: "lts-802.11.txt",
+        len: 64,
+        rms: 0.112_631,
+        centroid: 31.274_399,
+        first: Complex { re: 0.156, im: 0. },
+        last: Complex {
+            re: -0.005,
+            im: 0.12,
+        },
+    },
+];
+
+/// If `fname` is one of the standard sequences in `STANDARD_SEQUENCES`, check that `vals` (before
+/// normalization) matches its signature. Returns a description of the mismatch if it doesn't.
+/// Sequences stored under any other file name aren't checked
+pub fn check_sequence_signature(fname: &str, vals: &[Complex<f32>]) -> Option<String> {
+    let file_name = Path::new(fname).file_name()?.to_str()?;
+    let sig = STANDARD_SEQUENCES
+        .iter()
+        .find(|sig| sig.file_name == file_name)?;
+
+    if vals.len() != sig.len {
+        return Some(format!(
+            "'{}' has {} samples, expected {}",
+            fname,
+            vals.len(),
+            sig.len
+        ));
+    }
+    let rms = (vals.iter().map(|x| x.norm_sqr()).sum::<f32>() / vals.len() as f32).sqrt();
+    let centroid = vals
+        .iter()
+        .enumerate()
+        .map(|(i, x)| i as f32 * x.norm())
+        .sum::<f32>()
+        / vals.iter().map(|x| x.norm()).sum::<f32>();
+    let tol = 1e-3;
+    if (rms - sig.rms).abs() > tol * sig.rms
+        || (centroid - sig.centroid).abs() > tol * sig.centroid
+        || (vals[0] - sig.first).norm() > tol
+        || (vals[vals.len() - 1] - sig.last).norm() > tol
+    {
+        return Some(format!(
+            "'{}' doesn't look like the standard sequence (rms {} vs {}, centroid {} vs {})",
+            fname, rms, sig.rms, centroid, sig.centroid
+        ));
     }
+    None
+}
 
-    res
+/// Read a sequence file and warn if it is a standard sequence that seems to have been modified
+fn read_checked_sequence(fname: String) -> std::result::Result<Vec<Complex<f32>>, ConfigError> {
+    let vals = filename_to_cplx_vec(fname.clone())?;
+    if let Some(warning) = check_sequence_signature(&fname, &vals) {
+        log::warn!("{}", warning);
+    }
+    Ok(vals)
 }
 
 /// Normalize so that the RMS = 1
@@ -77,22 +553,46 @@ fn normalize(vals: &mut [Complex<f32>]) {
     }
 }
 
-fn read_sts(fname: Option<String>) -> Option<Vec<Complex<f32>>> {
+fn read_sts(fname: Option<String>) -> std::result::Result<Option<Vec<Complex<f32>>>, ConfigError> {
     let fname = match fname {
         Some(fname) => fname,
-        None => return None,
+        None => return Ok(None),
+    };
+    let mut vals = if fname == EMBEDDED_802_11 {
+        sts_802_11()
+    } else {
+        read_checked_sequence(fname)?
     };
-    let mut vals = filename_to_cplx_vec(fname);
     normalize(&mut vals);
-    Some(vals)
+    Ok(Some(vals))
 }
 
-pub fn read_lts(fname: Option<String>) -> Option<(Vec<Complex<f32>>, Vec<Option<Complex<f32>>>)> {
+pub fn read_lts(fname: Option<String>) -> std::result::Result<Option<Lts>, ConfigError> {
     let fname = match fname {
         Some(fname) => fname,
-        None => return None,
+        None => return Ok(None),
     };
-    let mut lts = filename_to_cplx_vec(fname);
+    let vals = if fname == EMBEDDED_802_11 {
+        lts_802_11()
+    } else {
+        read_checked_sequence(fname)?
+    };
+    Ok(Some(process_lts(vals)))
+}
+
+/// Conversion used by `ChannelEstConfigDes::into()`, which can't fail. Use
+/// `ChannelEstConfigDes::try_into_config` to handle bad files
+fn sts_from_file(fname: Option<String>) -> Option<Vec<Complex<f32>>> {
+    read_sts(fname).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Like `sts_from_file`, for the LTS
+fn lts_from_file(fname: Option<String>) -> Option<Lts> {
+    read_lts(fname).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Normalize the LTS, compute its FFT, find which subcarriers are used and plan the FFTs
+fn process_lts(mut lts: Vec<Complex<f32>>) -> Lts {
     normalize(&mut lts);
     let lts = lts;
 
@@ -131,28 +631,74 @@ pub fn read_lts(fname: Option<String>) -> Option<(Vec<Complex<f32>>, Vec<Option<
         })
         .collect::<Vec<_>>();
 
-    Some((lts, lts_fft))
+    let plans = FftPlans::new(lts.len());
+    (lts, lts_fft, plans, CorrelationPlans::new())
 }
 
 #[cfg(test)]
 mod test {
-    use super::{filename_to_cplx_vec, read_lts};
-    use num::Complex;
+    use super::{
+        build_packet, build_preamble, check_sequence_signature, cplx_vec_to_file,
+        filename_to_cplx_vec, lts_802_11, read_lts, read_sts, sts_802_11, ChannelEstConfig,
+        ChannelEstConfigDes, EMBEDDED_802_11,
+    };
+    use crate::error::ConfigError;
+    use crate::lts_align::lts_align;
+    use crate::parse_80211::parse_80211_pkt;
+    use num::{Complex, Zero};
 
     #[test]
     fn test_filename_to_cplx_vec() {
-        let v = filename_to_cplx_vec("data/lts-802.11.txt".to_string());
+        let v = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
         assert_eq!(v.len(), 64);
 
         assert!((v[0] - Complex::new(1.56e-1, 0.)).norm() < 1e-6);
         assert!((v[v.len() - 1] - Complex::new(-5e-3, 1.2e-1)).norm() < 1e-6);
     }
 
+    #[test]
+    fn test_sequence_file_errors() {
+        match filename_to_cplx_vec("/nonexistent/lts.txt".to_string()) {
+            Err(ConfigError::Io { path, source }) => {
+                assert_eq!(path, "/nonexistent/lts.txt");
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            x => panic!("Expected an IO error, got {:?}", x),
+        }
+
+        let dir = std::env::temp_dir().join("channel_est_test_sequence_errors");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("typo.txt");
+        std::fs::write(&path, "0.1\n0.2\n0.3\n0,4\n").unwrap();
+        let fname = path.to_str().unwrap().to_string();
+        match filename_to_cplx_vec(fname.clone()) {
+            Err(e @ ConfigError::Parse { .. }) => {
+                assert_eq!(
+                    e.to_string(),
+                    format!("'{}' line 4 ('0,4') is not a number", fname)
+                );
+            }
+            x => panic!("Expected a parse error, got {:?}", x),
+        }
+        match read_lts(Some(fname)) {
+            Err(ConfigError::Parse { line: 4, .. }) => {}
+            x => panic!("Expected a parse error, got {:?}", x),
+        }
+
+        std::fs::write(&path, "0.1\n0.2\n0.3\n").unwrap();
+        match filename_to_cplx_vec(path.to_str().unwrap().to_string()) {
+            Err(ConfigError::OddCount { count: 3, .. }) => {}
+            x => panic!("Expected an odd count error, got {:?}", x),
+        }
+    }
+
     #[test]
     fn test_read_lts() {
-        assert!(read_lts(None).is_none());
+        assert!(read_lts(None).unwrap().is_none());
 
-        let v = read_lts(Some("data/lts-802.11.txt".to_string())).unwrap();
+        let v = read_lts(Some("data/lts-802.11.txt".to_string()))
+            .unwrap()
+            .unwrap();
         assert_eq!(v.0.len(), 64);
         assert_eq!(v.0.len(), v.1.len());
         assert!((v.0[0] - 1.385).norm() < 1e-3);
@@ -160,7 +706,6 @@ mod test {
 
         let fft_norm = (v.0.len() as f32).sqrt();
         assert!(v.1[0].is_none());
-        println!("{} {}", v.1[1].unwrap(), fft_norm);
         assert!(v.1[1].unwrap().re - 1.11 * fft_norm < 0.1);
         for x in v.1 {
             if let Some(x) = x {
@@ -169,4 +714,249 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_sequence_signature() {
+        for fname in &["data/lts-802.11.txt", "data/short-802.11.txt"] {
+            let vals = filename_to_cplx_vec(fname.to_string()).unwrap();
+            assert_eq!(check_sequence_signature(fname, &vals), None);
+        }
+
+        // A perturbed copy of the LTS stored under the standard name
+        let mut vals = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
+        vals[10] += Complex::new(0.05, -0.02);
+        let dir = std::env::temp_dir().join("channel_est_test_signature");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lts-802.11.txt");
+        cplx_vec_to_file(&path, &vals).unwrap();
+        let fname = path.to_str().unwrap();
+        let read = filename_to_cplx_vec(fname.to_string()).unwrap();
+        assert!(check_sequence_signature(fname, &read).is_some());
+        // Truncated
+        assert!(check_sequence_signature(fname, &read[..60]).is_some());
+
+        // Custom sequences are not checked
+        assert_eq!(check_sequence_signature("my-lts.txt", &read), None);
+    }
+
+    #[test]
+    fn test_embedded_sequences() {
+        let lts = lts_802_11();
+        assert_eq!(lts.len(), 64);
+        let file_lts = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
+        assert_eq!(lts, file_lts);
+        assert_eq!(check_sequence_signature("lts-802.11.txt", &lts), None);
+        let sts = sts_802_11();
+        let file_sts = filename_to_cplx_vec("data/short-802.11.txt".to_string()).unwrap();
+        assert_eq!(sts, file_sts);
+        assert_eq!(check_sequence_signature("short-802.11.txt", &sts), None);
+
+        // The default config is the same as one read from the files
+        let config = ChannelEstConfig::default();
+        let mut des = ChannelEstConfigDes::default();
+        assert_eq!(des.sts.as_deref(), Some(EMBEDDED_802_11));
+        assert_eq!(des.lts.as_deref(), Some(EMBEDDED_802_11));
+        des.sts = Some("data/short-802.11.txt".to_string());
+        des.lts = Some("data/lts-802.11.txt".to_string());
+        let from_files: ChannelEstConfig = des.into();
+        assert_eq!(config.sts, from_files.sts);
+        let (lts, lts_fft, _, _) = config.lts.unwrap();
+        let (file_lts, file_lts_fft, _, _) = from_files.lts.unwrap();
+        assert_eq!(lts, file_lts);
+        assert_eq!(lts_fft, file_lts_fft);
+        assert_eq!(config.pkt_spacing, from_files.pkt_spacing);
+
+        // A TOML config that doesn't name the sequences uses the embedded ones too
+        let from_toml = ChannelEstConfig::from_toml_str("pkt_spacing = 80").unwrap();
+        assert_eq!(from_toml.sts, config.sts);
+        assert_eq!(from_toml.lts.unwrap().0, lts);
+    }
+
+    #[test]
+    fn test_build_packet() {
+        let config = ChannelEstConfig::default();
+        let sts_len = config.sts.as_ref().unwrap().len();
+        let (lts, lts_fft, _, corr_plans) = config.lts.as_ref().unwrap();
+
+        let preamble = build_preamble(&config);
+        assert_eq!(preamble.len(), 10 * sts_len + 5 * lts.len() / 2);
+        assert_eq!(&preamble[preamble.len() - lts.len()..], &lts[..]);
+        for offset in &[0, 7, 50] {
+            let mut pkt = vec![Complex::zero(); *offset];
+            pkt.extend(&preamble);
+            pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len()));
+            assert_eq!(lts_align(&pkt, lts, corr_plans), offset + 10 * sts_len);
+        }
+
+        // BPSK symbols
+        let symbols: Vec<Vec<_>> = (0..3)
+            .map(|s| {
+                lts_fft
+                    .iter()
+                    .enumerate()
+                    .map(|(i, l)| match l {
+                        Some(_) if (i + s) % 3 == 0 => Complex::new(-1., 0.),
+                        Some(_) => Complex::new(1., 0.),
+                        None => Complex::zero(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let pkt = build_packet(&config, &symbols);
+        assert_eq!(pkt.len(), preamble.len() + 3 * (config.cp_len + lts.len()));
+        let mut buf = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        buf.extend(&pkt);
+        buf.extend(std::iter::repeat(Complex::zero()).take(2 * lts.len()));
+        let parsed = parse_80211_pkt(&buf, &config).unwrap();
+        let sent: Vec<_> = symbols
+            .iter()
+            .flat_map(|bins| lts_fft.iter().zip(bins).filter_map(|(l, x)| l.map(|_| *x)))
+            .collect();
+        assert_eq!(parsed.len(), sent.len());
+        for (x, y) in parsed.iter().zip(&sent) {
+            assert!((x - y).norm() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_from_sequences() {
+        let sts = filename_to_cplx_vec("data/short-802.11.txt".to_string()).unwrap();
+        let lts = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
+        let config = ChannelEstConfig::from_sequences(sts, lts, 0.02, 32);
+        assert!((config.power_trig - 0.02).abs() < 1e-9);
+        assert_eq!(config.pkt_spacing, 32);
+
+        let sts = read_sts(Some("data/short-802.11.txt".to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.sts.unwrap(), sts);
+        let (lts, lts_fft, _, _) = read_lts(Some("data/lts-802.11.txt".to_string()))
+            .unwrap()
+            .unwrap();
+        let (mem_lts, mem_lts_fft, _, _) = config.lts.unwrap();
+        assert_eq!(mem_lts, lts);
+        assert_eq!(mem_lts_fft, lts_fft);
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = ChannelEstConfig::default();
+        valid.validate().unwrap();
+
+        let invalid_field = |config: ChannelEstConfig| match config.validate() {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            x => panic!("Expected the config to be invalid, got {:?}", x),
+        };
+        let mut config = valid.clone();
+        config.pkt_spacing = 20;
+        assert_eq!(invalid_field(config), "pkt_spacing");
+        let mut config = valid.clone();
+        config.sts = None;
+        assert_eq!(invalid_field(config), "sts");
+        let mut config = valid.clone();
+        config.sts = Some(Vec::new());
+        assert_eq!(invalid_field(config), "sts");
+        let mut config = valid.clone();
+        config.sts_repeats = 1;
+        assert_eq!(invalid_field(config), "sts_repeats");
+
+        let mut config = valid.clone();
+        config.lts = None;
+        assert_eq!(invalid_field(config), "lts");
+        let mut lts = lts_802_11();
+        lts.truncate(62);
+        let config = ChannelEstConfig::from_sequences(sts_802_11(), lts, 0.01, 64);
+        assert_eq!(invalid_field(config), "lts");
+
+        for power_trig in &[0., -0.1, std::f32::NAN] {
+            let mut config = valid.clone();
+            config.power_trig = *power_trig;
+            assert_eq!(invalid_field(config), "power_trig");
+        }
+
+        for ratio in &[0., 1.5, std::f32::NAN] {
+            let mut config = valid.clone();
+            config.power_stop_ratio = *ratio;
+            assert_eq!(invalid_field(config), "power_stop_ratio");
+        }
+
+        let mut config = valid.clone();
+        config.max_packet_len = Some(64);
+        assert_eq!(invalid_field(config), "max_packet_len");
+
+        let mut config = valid.clone();
+        config.track_channel_step = 0.;
+        assert_eq!(invalid_field(config), "track_channel_step");
+
+        let mut config = valid.clone();
+        config.cfo_fine_skip = 64;
+        assert_eq!(invalid_field(config), "cfo_fine_skip");
+
+        let mut config = valid.clone();
+        config.cp_len = 65;
+        assert_eq!(invalid_field(config), "cp_len");
+        let mut config = valid.clone();
+        config.symbol_len = Some(128);
+        config.cp_len = 65;
+        config.validate().unwrap();
+        config.symbol_len = Some(0);
+        assert_eq!(invalid_field(config), "symbol_len");
+
+        let mut config = valid.clone();
+        config.symbol_start_offset = 0.5;
+        assert_eq!(invalid_field(config), "symbol_start_offset");
+
+        let mut config = valid;
+        config.symbol_window = 16;
+        config.validate().unwrap();
+        config.symbol_start_offset = -0.5;
+        assert_eq!(invalid_field(config), "symbol_window");
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let config = ChannelEstConfig::from_toml_str(
+            r#"
+            stabilize_samps = 100
+            power_trig = 0.05
+            pkt_spacing = 96
+            sts = "data/short-802.11.txt"
+            lts = "data/lts-802.11.txt"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.stabilize_samps, 100);
+        assert!((config.power_trig - 0.05).abs() < 1e-9);
+        assert_eq!(config.pkt_spacing, 96);
+        assert_eq!(config.sts.unwrap().len(), 16);
+        assert_eq!(config.lts.unwrap().0.len(), 64);
+        // Fields that weren't given keep their defaults
+        assert_eq!(config.cp_len, 16);
+
+        let config = ChannelEstConfig::from_toml_str("lts = \"data/lts-802.11.txt\"").unwrap();
+        assert_eq!(config.pkt_spacing, ChannelEstConfig::default().pkt_spacing);
+
+        // Errors
+        match ChannelEstConfig::from_toml_str("pkt_spacing = \"many\"") {
+            Err(ConfigError::Toml(_)) => {}
+            x => panic!("Expected a TOML error, got {:?}", x),
+        }
+        match ChannelEstConfig::from_toml_str("lts = \"/nonexistent/lts.txt\"") {
+            Err(ConfigError::Io { path, .. }) => assert_eq!(path, "/nonexistent/lts.txt"),
+            x => panic!("Expected an IO error, got {:?}", x),
+        }
+        let dir = std::env::temp_dir().join("channel_est_test_toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bad-lts.txt");
+        std::fs::write(&path, "0.1\n0.2\nfoo\n0.3\n").unwrap();
+        let toml = format!("lts = {:?}", path.to_str().unwrap());
+        match ChannelEstConfig::from_toml_str(&toml) {
+            Err(ConfigError::Parse { line, .. }) => assert_eq!(line, 3),
+            x => panic!("Expected a parse error, got {:?}", x),
+        }
+        match ChannelEstConfig::from_toml_path("/nonexistent/config.toml") {
+            Err(ConfigError::Io { .. }) => {}
+            x => panic!("Expected an IO error, got {:?}", x),
+        }
+    }
 }
@@ -0,0 +1,112 @@
+//! DC offset removal for raw samples. Direct-conversion receivers leak some of their local
+//! oscillator into the baseband signal, leaving a spike at DC. The DC subcarrier is already nulled
+//! in 802.11, so this barely matters for decoding. It mainly helps detection: `PktTrigger`
+//! compares the sample power against a fixed threshold, and a DC offset adds to the power of every
+//! sample, packet or not
+
+use crate::config::ChannelEstConfig;
+use crate::pkt_trigger::PktTrigger;
+use num::{Complex, Zero};
+
+/// Number of samples `remove_dc` averages over to track the DC offset. Long compared to a
+/// preamble, so the packets themselves barely move the estimate
+pub const DC_WINDOW: usize = 4096;
+
+/// Tracks the DC offset as the running mean of the samples and subtracts it, one sample at a time
+/// (e.g. as they arrive from the radio). The mean is exponentially weighted with a time constant
+/// of `window` samples. While fewer than `window` samples have been seen, it is the plain mean of
+/// the samples so far, so the estimate settles quickly at the start. The estimate should be held
+/// during packets, whose own mean would otherwise pull it away from the DC offset
+#[derive(Clone, Debug)]
+pub struct DcBlocker {
+    window: usize,
+    /// Number of samples seen so far, saturating at `window`
+    num_samps: usize,
+    offset: Complex<f32>,
+}
+
+impl DcBlocker {
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0);
+        Self {
+            window,
+            num_samps: 0,
+            offset: Complex::zero(),
+        }
+    }
+
+    /// The current estimate of the DC offset
+    pub fn offset(&self) -> Complex<f32> {
+        self.offset
+    }
+
+    /// Return `samp` with the DC offset removed. Unless `hold` is set (e.g. because `samp` is part
+    /// of a packet), the estimate is first updated with `samp`
+    pub fn push_samp(&mut self, samp: Complex<f32>, hold: bool) -> Complex<f32> {
+        if !hold {
+            self.num_samps = (self.num_samps + 1).min(self.window);
+            self.offset += (samp - self.offset) / self.num_samps as f32;
+        }
+        samp - self.offset
+    }
+}
+
+/// Subtract the DC offset from `samps`, tracking it as a running mean over `DC_WINDOW` samples
+/// (see `DcBlocker`). The estimate is held while a `PktTrigger` with `config` is in a packet
+pub fn remove_dc(samps: &mut [Complex<f32>], config: &ChannelEstConfig) {
+    let mut blocker = DcBlocker::new(DC_WINDOW);
+    let mut trigger = PktTrigger::new(config);
+    for x in samps {
+        *x = blocker.push_samp(*x, trigger.in_packet());
+        trigger.push_samp(*x);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::build_packet;
+    use crate::parse_80211::parse_80211_pkt;
+    use crate::sim::{add_awgn, random_bpsk_symbols};
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_remove_dc() {
+        let config = ChannelEstConfig::default();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let symbols = random_bpsk_symbols(&config, 10, &mut rng);
+        // Plenty of noise before the packet for the estimate to settle
+        let mut pkt = vec![Complex::zero(); 2 * DC_WINDOW];
+        pkt.extend(build_packet(&config, &symbols));
+        pkt.extend(vec![Complex::zero(); 2 * config.pkt_spacing as usize]);
+        add_awgn(&mut pkt, 1e-6, &mut rng);
+
+        let dc = Complex::new(0.3, -0.2);
+        let mut with_dc: Vec<_> = pkt.iter().map(|x| x + dc).collect();
+        remove_dc(&mut with_dc, &config);
+
+        // The offset is gone, and what's left matches the original packet
+        let start = 2 * DC_WINDOW - config.pkt_spacing as usize;
+        for (x, y) in with_dc[start..].iter().zip(&pkt[start..]) {
+            assert!((x - y).norm() < 2e-3, "{} {}", x, y);
+        }
+        let clean = parse_80211_pkt(&pkt[start..], &config).unwrap();
+        let parsed = parse_80211_pkt(&with_dc[start..], &config).unwrap();
+        assert_eq!(parsed.len(), clean.len());
+        for (x, y) in parsed.iter().zip(&clean) {
+            assert!((x - y).norm() < 0.05, "{} {}", x, y);
+        }
+
+        // The DC offset alone is enough to keep the trigger firing, but not once it's removed
+        let mut dc_only = vec![dc; 1000];
+        let count = |samps: &[Complex<f32>]| {
+            let mut trigger = PktTrigger::new(&config);
+            samps.iter().filter_map(|x| trigger.push_samp(*x)).count()
+        };
+        let mut flushed = dc_only.clone();
+        flushed.extend(vec![Complex::zero(); config.pkt_spacing as usize + 1]);
+        assert_eq!(count(&flushed), 1);
+        remove_dc(&mut dc_only, &config);
+        assert_eq!(count(&dc_only), 0);
+    }
+}
@@ -0,0 +1,308 @@
+//! Streaming decoder that runs packet detection, alignment, CFO correction and equalization on
+//! samples as they arrive, yielding each data symbol as soon as it has been received
+
+use crate::config::ChannelEstConfig;
+use crate::lts_align::lts_align;
+use crate::parse_80211::SymbolDecoder;
+use crate::ring_buffer::SampleRing;
+use num::Complex;
+use std::collections::VecDeque;
+
+/// Something `Decoder` found in the sample stream
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeEvent {
+    /// Found the preamble of a new packet
+    PacketStart {
+        /// CFO (in radians per sample) estimated from the preamble
+        cfo: f32,
+    },
+    /// The next data symbol of the current packet
+    Symbol {
+        /// Equalized value of every used subcarrier, as in `parse_80211_pkt`
+        bins: Vec<Complex<f32>>,
+        /// Common phase error (in radians) relative to the long preamble
+        cpe: f32,
+    },
+    /// The current packet has ended
+    PacketEnd { num_symbols: usize },
+}
+
+enum DecoderState {
+    /// Skip the first few samples (counts the number of samples so far)
+    Skip(u64),
+    /// Waiting for a sample whose `norm_sqr` exceeds `config.power_trig`
+    Idle,
+    /// Collecting enough samples to find the long preamble. `buf` starts with the (up to)
+    /// `pkt_spacing` samples before the trigger, which is at index `trigger`
+    Preamble {
+        buf: Vec<Complex<f32>>,
+        trigger: usize,
+    },
+    /// Decoding data symbols. `buf` holds the samples of the partially received next symbol
+    Symbols {
+        decoder: SymbolDecoder,
+        buf: Vec<Complex<f32>>,
+        num_symbols: usize,
+    },
+}
+
+/// Decodes packets from a stream of samples. It triggers like `pkt_trigger::PktTrigger`, but
+/// instead of buffering the whole packet it only keeps the preamble until it is aligned and then
+/// one symbol at a time. Produces the same symbols as `parse_80211_pkt` on the packets
+/// `PktTrigger` returns
+pub struct Decoder {
+    config: ChannelEstConfig,
+    /// The last `pkt_spacing` samples while idle
+    hist: SampleRing,
+    state: DecoderState,
+}
+
+impl Decoder {
+    pub fn new(config: &ChannelEstConfig) -> Self {
+        Self {
+            config: config.clone(),
+            hist: SampleRing::with_capacity(config.pkt_spacing as usize + 1),
+            state: DecoderState::Skip(0),
+        }
+    }
+
+    /// Takes in the next chunk of samples and returns whatever was decoded from them
+    pub fn push_samps(&mut self, samps: &[Complex<f32>]) -> Vec<DecodeEvent> {
+        let mut events = Vec::new();
+        for samp in samps {
+            self.push_samp(*samp, &mut events);
+        }
+        events
+    }
+
+    /// Whether we are in the middle of a packet
+    pub fn in_packet(&self) -> bool {
+        matches!(self.state, DecoderState::Symbols { .. })
+    }
+
+    /// Number of samples needed after the start of `Preamble::buf` to find the long preamble. Same
+    /// bound as `parse_80211_pkt` uses
+    fn preamble_len(&self) -> usize {
+        let lts_len = self.config.lts.as_ref().unwrap().0.len();
+        let short_len = self.config.short_len();
+        self.config.pkt_spacing as usize + short_len + 5 * lts_len / 2
+    }
+
+    fn push_samp(&mut self, samp: Complex<f32>, events: &mut Vec<DecodeEvent>) {
+        let preamble_len = self.preamble_len();
+        match &mut self.state {
+            DecoderState::Skip(skip) => {
+                if *skip >= self.config.stabilize_samps {
+                    self.state = DecoderState::Idle;
+                } else {
+                    *skip += 1;
+                }
+            }
+            DecoderState::Idle => {
+                self.hist.push_back(samp);
+                if samp.norm_sqr() > self.config.power_trig {
+                    self.state = DecoderState::Preamble {
+                        buf: self.hist.to_vec(),
+                        trigger: self.hist.len() - 1,
+                    };
+                } else {
+                    self.hist.keep_last(self.config.pkt_spacing as usize);
+                }
+            }
+            DecoderState::Preamble { buf, trigger } => {
+                buf.push(samp);
+                if buf.len() >= preamble_len {
+                    let (buf, trigger) = (std::mem::take(buf), *trigger);
+                    self.start_packet(&buf, trigger, events);
+                }
+            }
+            DecoderState::Symbols {
+                decoder,
+                buf,
+                num_symbols,
+            } => {
+                buf.push(samp);
+                if buf.len() >= decoder.symbol_len() {
+                    match decoder.decode(buf) {
+                        Some((bins, cpe)) => {
+                            buf.clear();
+                            *num_symbols += 1;
+                            events.push(DecodeEvent::Symbol { bins, cpe });
+                        }
+                        None => {
+                            events.push(DecodeEvent::PacketEnd {
+                                num_symbols: *num_symbols,
+                            });
+                            let buf = std::mem::take(buf);
+                            self.restart(&[], &buf, events);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find the long preamble in `buf` and start decoding the packet's symbols
+    fn start_packet(
+        &mut self,
+        buf: &[Complex<f32>],
+        trigger: usize,
+        events: &mut Vec<DecodeEvent>,
+    ) {
+        let (lts, _, _, corr_plans) = self.config.lts.as_ref().unwrap();
+        let lts_len = lts.len();
+        let short_len = self.config.short_len();
+        let lts_start = lts_align(buf, lts, corr_plans);
+        if lts_start < short_len.max(self.config.cp_len) {
+            // There is no room for a short preamble, so this wasn't the start of a packet
+            self.restart(&buf[..=trigger], &buf[trigger + 1..], events);
+            return;
+        }
+
+        let decoder = SymbolDecoder::from_preamble(buf, lts_start, &self.config);
+        events.push(DecodeEvent::PacketStart { cfo: decoder.cfo() });
+        self.state = DecoderState::Symbols {
+            buf: Vec::with_capacity(decoder.symbol_len()),
+            decoder,
+            num_symbols: 0,
+        };
+        for samp in &buf[lts_start + 5 * lts_len / 2..] {
+            self.push_samp(*samp, events);
+        }
+    }
+
+    /// Go back to looking for packets. `history` are samples that have already been checked for a
+    /// trigger and `leftover` the ones after them that haven't
+    fn restart(
+        &mut self,
+        history: &[Complex<f32>],
+        leftover: &[Complex<f32>],
+        events: &mut Vec<DecodeEvent>,
+    ) {
+        let pkt_spacing = self.config.pkt_spacing as usize;
+        self.state = DecoderState::Idle;
+        self.hist.keep_last(0);
+        for samp in &history[history.len().saturating_sub(pkt_spacing)..] {
+            self.hist.push_back(*samp);
+        }
+        // There are fewer than `preamble_len` leftover samples, so they can't complete another
+        // preamble and this recurses at most once
+        for samp in leftover {
+            self.push_samp(*samp, events);
+        }
+    }
+}
+
+/// What `StreamingParser` yields
+pub type ParsedEvent = DecodeEvent;
+
+/// Sample-at-a-time interface to `Decoder`, for pipelines that process one sample at a time
+pub struct StreamingParser {
+    decoder: Decoder,
+    /// Events decoded but not yet returned, oldest first
+    pending: VecDeque<ParsedEvent>,
+}
+
+impl StreamingParser {
+    pub fn new(config: &ChannelEstConfig) -> Self {
+        Self {
+            decoder: Decoder::new(config),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Takes in the next sample and returns the oldest event not returned yet. Occasionally a
+    /// sample completes more than one event (e.g. aligning the preamble can also complete the
+    /// first symbol). The extra events are returned by the next calls, or by `next_event`
+    pub fn push_samp(&mut self, samp: Complex<f32>) -> Option<ParsedEvent> {
+        self.pending.extend(self.decoder.push_samps(&[samp]));
+        self.pending.pop_front()
+    }
+
+    /// Returns the oldest pending event without taking in a sample
+    pub fn next_event(&mut self) -> Option<ParsedEvent> {
+        self.pending.pop_front()
+    }
+
+    /// Whether we are in the middle of a packet
+    pub fn in_packet(&self) -> bool {
+        self.decoder.in_packet()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DecodeEvent, Decoder};
+    use crate::config::{build_preamble, ChannelEstConfig};
+    use crate::parse_80211::parse_80211_pkt;
+    use crate::pkt_trigger::segment_packets;
+    use crate::sim::{modulate_symbol, random_bpsk_symbols};
+    use num::{Complex, Zero};
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_streaming_decode() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = config.lts.clone().unwrap().1;
+
+        // Packets with different numbers of random BPSK symbols and channel gains, separated by
+        // silence
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let num_symbols: [usize; 3] = [4, 6, 2];
+        let mut stream = vec![Complex::zero(); 300];
+        let mut sent = Vec::new();
+        for (i, n) in num_symbols.iter().enumerate() {
+            let mut pkt = build_preamble(&config);
+            let mut data = Vec::new();
+            for bins in random_bpsk_symbols(&config, *n, &mut rng) {
+                data.extend(lts_fft.iter().zip(&bins).filter_map(|(l, x)| l.map(|_| *x)));
+                pkt.extend(modulate_symbol(&bins, config.cp_len));
+            }
+            let gain = Complex::from_polar(&(0.5 + 0.3 * i as f32), &(1.3 * i as f32));
+            stream.extend(pkt.iter().map(|x| x * gain));
+            stream.extend(std::iter::repeat(Complex::zero()).take(300));
+            sent.push(data);
+        }
+
+        // Feed the stream in small chunks
+        let mut decoder = Decoder::new(&config);
+        let events: Vec<_> = stream
+            .chunks(37)
+            .flat_map(|c| decoder.push_samps(c))
+            .collect();
+        assert!(!decoder.in_packet());
+
+        // Split the events into packets
+        let mut packets: Vec<Vec<Complex<f32>>> = Vec::new();
+        let mut symbol_counts = Vec::new();
+        for event in &events {
+            match event {
+                DecodeEvent::PacketStart { .. } => {
+                    assert_eq!(packets.len(), symbol_counts.len());
+                    packets.push(Vec::new());
+                }
+                DecodeEvent::Symbol { bins, .. } => {
+                    assert_eq!(bins.len(), 52);
+                    packets.last_mut().unwrap().extend(bins);
+                }
+                DecodeEvent::PacketEnd { num_symbols } => symbol_counts.push(*num_symbols),
+            }
+        }
+        assert_eq!(symbol_counts, num_symbols);
+
+        // Symbols match what was sent and what the non-streaming parser decodes
+        let segments = segment_packets(&stream, &config);
+        assert_eq!(segments.len(), num_symbols.len());
+        for ((decoded, data), segment) in packets.iter().zip(&sent).zip(&segments) {
+            assert_eq!(decoded.len(), data.len());
+            for (x, y) in decoded.iter().zip(data) {
+                assert!((x - y).norm() < 0.1);
+            }
+            let parsed = parse_80211_pkt(segment, &config).unwrap();
+            assert_eq!(parsed.len(), decoded.len());
+            for (x, y) in decoded.iter().zip(&parsed) {
+                assert!((x - y).norm() < 1e-4);
+            }
+        }
+    }
+}
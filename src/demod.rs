@@ -0,0 +1,272 @@
+//! Mapping between bits and constellation points. Bits are `u8`s that are either 0 or 1, as in
+//! `signal`
+
+use num::Complex;
+use serde::{Deserialize, Serialize};
+
+/// Hard-decision BPSK demodulation of equalized subcarrier values (e.g. from `equalize_symbol`).
+/// As in 802.11, a positive real part is a 1 and a negative one a 0
+pub fn demod_bpsk(symbols: &[Complex<f32>]) -> Vec<u8> {
+    symbols.iter().map(|x| u8::from(x.re > 0.)).collect()
+}
+
+/// Constellations of 802.11a besides BPSK. Each is Gray coded separately on the I and Q axes
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum QamOrder {
+    Qpsk,
+    Qam16,
+    Qam64,
+}
+
+impl QamOrder {
+    /// Number of bits carried by each subcarrier
+    pub fn bits_per_symbol(&self) -> usize {
+        match self {
+            QamOrder::Qpsk => 2,
+            QamOrder::Qam16 => 4,
+            QamOrder::Qam64 => 6,
+        }
+    }
+
+    /// Factor 802.11 scales the constellation by so that its average power is 1
+    pub fn normalization(&self) -> f32 {
+        match self {
+            QamOrder::Qpsk => 1. / 2f32.sqrt(),
+            QamOrder::Qam16 => 1. / 10f32.sqrt(),
+            QamOrder::Qam64 => 1. / 42f32.sqrt(),
+        }
+    }
+}
+
+/// Hard-decision demodulation of equalized subcarrier values to `order.bits_per_symbol()` bits
+/// each. Values beyond the edge of the constellation are taken to be the nearest (outermost)
+/// point. As in 802.11a, the first half of each subcarrier's bits come from I and the rest from
+/// Q, each most significant first, and neighbouring levels differ in one bit (e.g. -3, -1, 1, 3
+/// are 00, 01, 11, 10 for 16-QAM)
+pub fn demod_qam(symbols: &[Complex<f32>], order: QamOrder) -> Vec<u8> {
+    let axis_bits = order.bits_per_symbol() / 2;
+    let num_levels = 1 << axis_bits;
+    let demod_axis = |x: f32, bits: &mut Vec<u8>| {
+        // Levels are -(num_levels - 1), ..., -1, 1, ..., num_levels - 1 before normalization
+        let level = ((x / order.normalization() + (num_levels - 1) as f32) / 2.).round();
+        let level = level.clamp(0., (num_levels - 1) as f32) as usize;
+        let gray = level ^ (level >> 1);
+        bits.extend((0..axis_bits).rev().map(|i| ((gray >> i) & 1) as u8));
+    };
+
+    let mut bits = Vec::with_capacity(symbols.len() * order.bits_per_symbol());
+    for x in symbols {
+        demod_axis(x.re, &mut bits);
+        demod_axis(x.im, &mut bits);
+    }
+    bits
+}
+
+/// Soft-decision demodulation of equalized subcarrier values to a log-likelihood ratio
+/// `ln(P(1) / P(0))` for each of the bits `demod_qam` would return, in the same order, using the
+/// max-log approximation: each is the difference of the squared distances to the nearest point
+/// with that bit 0 and the nearest with it 1, divided by `noise_var`. So a positive LLR is a 1,
+/// agreeing with `demod_qam`. `noise_var` is the (complex) noise power on the equalized values.
+/// For a subcarrier with equalization `eq` in an FFT of `n` bins, that is `|eq|^2 / n` times its
+/// value from `equalization::estimate_subcarrier_noise`
+pub fn demod_llr(symbols: &[Complex<f32>], order: QamOrder, noise_var: f32) -> Vec<f32> {
+    let axis_bits = order.bits_per_symbol() / 2;
+    let num_levels = 1 << axis_bits;
+    // Position and Gray coded bits of each level on an axis
+    let levels: Vec<(f32, usize)> = (0..num_levels)
+        .map(|level| {
+            let x = (2 * level as i32 - (num_levels as i32 - 1)) as f32 * order.normalization();
+            (x, level ^ (level >> 1))
+        })
+        .collect();
+    let demod_axis = |x: f32, llrs: &mut Vec<f32>| {
+        for i in (0..axis_bits).rev() {
+            let mut dist = [f32::INFINITY; 2];
+            for (point, gray) in &levels {
+                let bit = (gray >> i) & 1;
+                dist[bit] = dist[bit].min((x - point) * (x - point));
+            }
+            llrs.push((dist[0] - dist[1]) / noise_var);
+        }
+    };
+
+    let mut llrs = Vec::with_capacity(symbols.len() * order.bits_per_symbol());
+    for x in symbols {
+        demod_axis(x.re, &mut llrs);
+        demod_axis(x.im, &mut llrs);
+    }
+    llrs
+}
+
+/// Modulation with the 802.11a constellation of the given order (the inverse of `demod_qam`).
+/// `bits.len()` must be a multiple of `order.bits_per_symbol()`
+pub fn mod_qam(bits: &[u8], order: QamOrder) -> Vec<Complex<f32>> {
+    assert_eq!(bits.len() % order.bits_per_symbol(), 0);
+    let axis_bits = order.bits_per_symbol() / 2;
+    let num_levels = 1 << axis_bits;
+    let mod_axis = |bits: &[u8]| {
+        // Undo the Gray code
+        let gray = bits.iter().fold(0, |acc, b| (acc << 1) | usize::from(*b));
+        let level = (0..axis_bits).fold(0, |acc, i| acc ^ (gray >> i));
+        (2 * level as i32 - (num_levels - 1)) as f32 * order.normalization()
+    };
+    bits.chunks(order.bits_per_symbol())
+        .map(|b| Complex::new(mod_axis(&b[..axis_bits]), mod_axis(&b[axis_bits..])))
+        .collect()
+}
+
+/// RMS error vector magnitude (in percent) of `symbols` relative to the ideal points `reference`
+/// they should have landed on, normalized by the RMS of `reference`
+pub fn evm(symbols: &[Complex<f32>], reference: &[Complex<f32>]) -> f32 {
+    assert_eq!(symbols.len(), reference.len());
+    let err = symbols
+        .iter()
+        .zip(reference)
+        .map(|(x, r)| (x - r).norm_sqr())
+        .sum::<f32>();
+    let power = reference.iter().map(|r| r.norm_sqr()).sum::<f32>();
+    100. * (err / power).sqrt()
+}
+
+/// Like `evm`, but for when the transmitted symbols aren't known: each symbol is compared against
+/// the nearest point of the constellation. Underestimates the EVM when errors are large enough
+/// to cross decision boundaries
+pub fn evm_against_constellation(symbols: &[Complex<f32>], order: QamOrder) -> f32 {
+    evm(symbols, &mod_qam(&demod_qam(symbols, order), order))
+}
+
+/// BPSK modulation, mapping 0 to -1 and 1 to +1 (the inverse of `demod_bpsk`)
+pub fn mod_bpsk(bits: &[u8]) -> Vec<Complex<f32>> {
+    bits.iter()
+        .map(|b| match b {
+            0 => Complex::new(-1., 0.),
+            1 => Complex::new(1., 0.),
+            _ => panic!("BPSK bits must be 0 or 1, got {}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        demod_bpsk, demod_llr, demod_qam, evm, evm_against_constellation, mod_bpsk, mod_qam,
+        QamOrder,
+    };
+    use num::Complex;
+    use rand::Rng;
+
+    #[test]
+    fn test_bpsk_round_trip() {
+        let mut rng = rand::thread_rng();
+        let bits: Vec<u8> = (0..1000).map(|_| rng.gen_range(0, 2)).collect();
+        assert_eq!(demod_bpsk(&mod_bpsk(&bits)), bits);
+
+        // Only the sign of the real part matters
+        let noisy = [Complex::new(0.3, -2.), Complex::new(-0.01, 5.)];
+        assert_eq!(demod_bpsk(&noisy), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_qam_constellation_points() {
+        let point = |re: f32, im: f32, k: f32| Complex::new(re, im) / k.sqrt();
+        // QPSK: one bit per axis
+        assert_eq!(demod_qam(&[point(-1., 1., 2.)], QamOrder::Qpsk), vec![0, 1]);
+        // 16-QAM: I = -3, -1, 1, 3 is 00, 01, 11, 10
+        assert_eq!(
+            demod_qam(&[point(-3., 3., 10.)], QamOrder::Qam16),
+            vec![0, 0, 1, 0]
+        );
+        assert_eq!(
+            demod_qam(&[point(1., -1., 10.)], QamOrder::Qam16),
+            vec![1, 1, 0, 1]
+        );
+        // 64-QAM: I = -7, ..., 7 is 000, 001, 011, 010, 110, 111, 101, 100
+        assert_eq!(
+            demod_qam(&[point(-5., 3., 42.)], QamOrder::Qam64),
+            vec![0, 0, 1, 1, 1, 1]
+        );
+        assert_eq!(
+            demod_qam(&[point(7., -1., 42.)], QamOrder::Qam64),
+            vec![1, 0, 0, 0, 1, 0]
+        );
+
+        // Values beyond the constellation clamp to the outermost point
+        assert_eq!(
+            demod_qam(&[point(20., -9., 10.)], QamOrder::Qam16),
+            vec![1, 0, 0, 0]
+        );
+        assert_eq!(
+            demod_qam(&[Complex::new(-5., 5.)], QamOrder::Qpsk),
+            vec![0, 1]
+        );
+
+        // Every point survives a round trip, and has unit average power
+        for order in &[QamOrder::Qpsk, QamOrder::Qam16, QamOrder::Qam64] {
+            let n = order.bits_per_symbol();
+            let bits: Vec<u8> = (0..1 << n)
+                .flat_map(|x: usize| (0..n).rev().map(move |i| ((x >> i) & 1) as u8))
+                .collect();
+            let symbols = mod_qam(&bits, *order);
+            assert_eq!(demod_qam(&symbols, *order), bits);
+            let power = symbols.iter().map(|x| x.norm_sqr()).sum::<f32>() / symbols.len() as f32;
+            assert!((power - 1.).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_evm() {
+        let reference = mod_qam(&[0, 1, 1, 1, 1, 0, 0, 0], QamOrder::Qpsk);
+        assert_eq!(evm(&reference, &reference), 0.);
+
+        // The reference has unit power, so an error of 0.1 on every symbol is 10%
+        let offset: Vec<_> = reference
+            .iter()
+            .map(|x| x + Complex::new(0., 0.1))
+            .collect();
+        assert!((evm(&offset, &reference) - 10.).abs() < 1e-3);
+        assert!((evm_against_constellation(&offset, QamOrder::Qpsk) - 10.).abs() < 1e-3);
+
+        // Errors that cross to a neighbouring point are measured against that point instead
+        let point = QamOrder::Qam16.normalization();
+        let crossed = [Complex::new(2.2 * point, point)];
+        let nearest = [Complex::new(3. * point, point)];
+        assert!(
+            (evm_against_constellation(&crossed, QamOrder::Qam16) - evm(&crossed, &nearest)).abs()
+                < 1e-3
+        );
+    }
+
+    #[test]
+    fn test_demod_llr() {
+        // QPSK points are +-1/sqrt(2) on each axis, so the distances differ by 2 on a point
+        let x = Complex::new(1., -1.) * QamOrder::Qpsk.normalization();
+        let llrs = demod_llr(&[x], QamOrder::Qpsk, 0.5);
+        assert!(
+            (llrs[0] - 4.).abs() < 1e-5 && (llrs[1] + 4.).abs() < 1e-5,
+            "{:?}",
+            llrs
+        );
+
+        let mut rng = rand::thread_rng();
+        for order in &[QamOrder::Qpsk, QamOrder::Qam16, QamOrder::Qam64] {
+            let bits: Vec<u8> = (0..600).map(|_| rng.gen_range(0, 2)).collect();
+            let noisy: Vec<_> = mod_qam(&bits, *order)
+                .iter()
+                .map(|x| x + Complex::new(rng.gen_range(-0.2, 0.2), rng.gen_range(-0.2, 0.2)))
+                .collect();
+            let hard = demod_qam(&noisy, *order);
+            let llrs = demod_llr(&noisy, *order, 0.1);
+            assert_eq!(llrs.len(), hard.len());
+            // The sign is the hard decision
+            for (llr, bit) in llrs.iter().zip(&hard) {
+                assert_eq!(*llr > 0., *bit == 1, "{} {}", llr, bit);
+            }
+            // Less noise makes every decision more confident
+            let confident = demod_llr(&noisy, *order, 0.01);
+            for (llr, more) in llrs.iter().zip(&confident) {
+                assert!(more.abs() >= llr.abs());
+                assert!((more - 10. * llr).abs() <= 1e-3 * more.abs().max(1.));
+            }
+        }
+    }
+}
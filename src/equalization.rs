@@ -1,38 +1,578 @@
 use crate::config::ChannelEstConfig;
+use crate::demod::{demod_bpsk, demod_qam, mod_bpsk, mod_qam, QamOrder};
+use crate::pilots::{signed_bin, subcarrier_bin, PILOT_SUBCARRIERS};
+use crate::sample::Sample;
 use num::{Complex, Zero};
-use rustfft::FFTplanner;
+use rustfft::{FFTnum, FFTplanner, FFT};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+/// Forward and inverse FFT plans of one length. The config holds them for the LTS length,
+/// planned once when the LTS is read (see `config::read_lts`) so that decoding a packet doesn't
+/// replan for every symbol
+#[derive(Clone)]
+pub struct FftPlans<T: FFTnum = f32> {
+    pub forward: Arc<dyn FFT<T>>,
+    pub inverse: Arc<dyn FFT<T>>,
+}
+
+impl<T: FFTnum> FftPlans<T> {
+    pub fn new(len: usize) -> Self {
+        Self {
+            forward: FFTplanner::new(false).plan_fft(len),
+            inverse: FFTplanner::new(true).plan_fft(len),
+        }
+    }
+}
+
+impl<T: FFTnum> fmt::Debug for FftPlans<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FftPlans({})", self.forward.len())
+    }
+}
+
+/// A way of computing the per-subcarrier equalization from the long preamble
+pub trait Equalizer {
+    /// Estimate the equalization from `long`, the (CFO corrected) long preamble including its
+    /// guard interval. Returns one value per FFT bin, with `None` where the subcarrier is unused,
+    /// in the format `equalize_symbol` expects
+    fn estimate(
+        &self,
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Option<Complex<f32>>>;
+}
+
+/// Invert the channel on each subcarrier. This is what `estimate_subcarrier_equalization` does
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ZeroForcing;
+
+/// Minimum mean square error equalization, `conj(h) / (|h|^2 + 1 / snr)`. Unlike zero-forcing, it
+/// doesn't amplify noise on subcarriers in a deep fade
+#[derive(Clone, Copy, Debug)]
+pub struct Mmse {
+    /// Expected SNR (in dB) of the data symbols
+    pub snr_db: f32,
+}
+
+/// Like `Mmse`, but with the SNR of each subcarrier measured from the difference between the two
+/// LTS copies (see `estimate_subcarrier_noise`) instead of configured. This assumes the data
+/// subcarriers are sent at the same power as the LTS's, as in 802.11
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeasuredMmse;
+
+/// Zero-forcing with the channel averaged over the bins within `half_width` of each subcarrier
+/// (skipping unused ones). Reduces the estimation noise when the channel varies slowly across
+/// frequency (i.e. when the delay spread is small). A window as wide as the band or wider
+/// averages every subcarrier once
+#[derive(Clone, Copy, Debug)]
+pub struct Smoothed {
+    pub half_width: usize,
+}
+
+/// Selects one of the `Equalizer`s from the config. In a config file, the variant goes in `kind`,
+/// e.g. `{ kind = "Mmse", snr_db = 20.0 }`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum EqualizerKind {
+    #[default]
+    ZeroForcing,
+    Mmse {
+        snr_db: f32,
+    },
+    MeasuredMmse,
+    Smoothed {
+        half_width: usize,
+    },
+}
+
+impl Equalizer for ZeroForcing {
+    fn estimate(
+        &self,
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Option<Complex<f32>>> {
+        estimate_subcarrier_equalization(long, config)
+    }
+}
+
+impl Equalizer for Mmse {
+    fn estimate(
+        &self,
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Option<Complex<f32>>> {
+        let noise = 10f32.powf(-self.snr_db / 10.);
+        estimate_subcarrier_equalization(long, config)
+            .iter()
+            .map(|e| {
+                e.map(|e| {
+                    let h = 1. / e;
+                    h.conj() / (h.norm_sqr() + noise)
+                })
+            })
+            .collect()
+    }
+}
+
+impl Equalizer for MeasuredMmse {
+    fn estimate(
+        &self,
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Option<Complex<f32>>> {
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        let lts_len = lts_fft.len() as f32;
+        let noise = estimate_subcarrier_noise(long, config);
+        estimate_subcarrier_equalization(long, config)
+            .iter()
+            .zip(&noise)
+            .zip(lts_fft)
+            .map(|((e, n), l)| match (e, n, l) {
+                (Some(e), Some(n), Some(l)) => {
+                    // Power of the received LTS on this subcarrier, and the noise's in the same
+                    // units (it is per-sample)
+                    let (p, n) = ((l / e).norm_sqr(), n * lts_len);
+                    // Same as `conj(h) / (|h|^2 + n / p)` with `h = 1 / e`. Written so that it is
+                    // zero-forcing rather than NaN when there is no noise at all
+                    Some(e * p / (p + n))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Equalizer for Smoothed {
+    fn estimate(
+        &self,
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Option<Complex<f32>>> {
+        let channel: Vec<_> = estimate_subcarrier_equalization(long, config)
+            .iter()
+            .map(|e| e.map(|e| 1. / e))
+            .collect();
+        let n = channel.len();
+        // Offsets (modulo `n`) of the bins in the window
+        let offsets = if 2 * self.half_width + 1 >= n {
+            0..n
+        } else {
+            n - self.half_width..n + self.half_width + 1
+        };
+        (0..n)
+            .map(|i| {
+                channel[i]?;
+                // Neighbouring bins are neighbouring subcarriers, wrapping around at the end
+                let (sum, count) = offsets
+                    .clone()
+                    .filter_map(|j| channel[(i + j) % n])
+                    .fold((Complex::zero(), 0), |(sum, count), h| (sum + h, count + 1));
+                Some(count as f32 / sum)
+            })
+            .collect()
+    }
+}
+
+impl Equalizer for EqualizerKind {
+    fn estimate(
+        &self,
+        long: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Option<Complex<f32>>> {
+        match *self {
+            EqualizerKind::ZeroForcing => ZeroForcing.estimate(long, config),
+            EqualizerKind::Mmse { snr_db } => Mmse { snr_db }.estimate(long, config),
+            EqualizerKind::MeasuredMmse => MeasuredMmse.estimate(long, config),
+            EqualizerKind::Smoothed { half_width } => {
+                Smoothed { half_width }.estimate(long, config)
+            }
+        }
+    }
+}
+
+/// Decision-directed tracking of the channel over the data symbols of a packet, for when the
+/// estimate from the preamble goes stale. After each symbol, every used subcarrier is sliced to
+/// the nearest constellation point (BPSK for the pilots) and its equalization is nudged towards
+/// the one that would have landed exactly on that point
+#[derive(Clone, Debug)]
+pub struct ChannelTracker {
+    /// Fraction of the way the channel estimate moves towards each symbol's observation
+    step: f32,
+    /// Constellation of the data subcarriers. `None` is BPSK
+    order: Option<QamOrder>,
+}
+
+impl ChannelTracker {
+    pub fn new(config: &ChannelEstConfig) -> Self {
+        Self {
+            step: config.track_channel_step,
+            order: config.track_channel_order,
+        }
+    }
+
+    /// Update `equalization` given a symbol's bins as equalized with it, `observed`, and the same
+    /// bins after any other corrections (e.g. by the pilot trackers), `corrected`, on which the
+    /// decisions are made
+    pub fn track(
+        &self,
+        equalization: &mut [Option<Complex<f32>>],
+        observed: &[Option<Complex<f32>>],
+        corrected: &[Option<Complex<f32>>],
+    ) {
+        assert_eq!(equalization.len(), observed.len());
+        // Taken from the equalization's length as the symbols needn't be the size of the LTS
+        let fft_len = equalization.len();
+        let pilot_bins: Vec<_> = PILOT_SUBCARRIERS
+            .iter()
+            .map(|k| subcarrier_bin(*k, fft_len))
+            .collect();
+        let decide = |x: Complex<f32>, bin: usize| match self.order {
+            Some(order) if !pilot_bins.contains(&bin) => mod_qam(&demod_qam(&[x], order), order)[0],
+            _ => mod_bpsk(&demod_bpsk(&[x]))[0],
+        };
+        let iter = equalization.iter_mut().zip(observed).zip(corrected);
+        for (bin, ((eq, observed), corrected)) in iter.enumerate() {
+            if let (Some(eq), Some(observed), Some(corrected)) = (eq, observed, corrected) {
+                // What is left of the channel after equalization, as seen by this symbol
+                let residual = observed / decide(*corrected, bin);
+                *eq /= residual * self.step + (1. - self.step);
+            }
+        }
+    }
+}
 
 /// Estimate equalization for each OFDM subcarrier that is in-use. If the subcarrier in the lts is
 /// < 0.1 times the max subcarrier, we'll assume that subcarrier is absent return `None` there.
-pub fn estimate_subcarrier_equalization(
-    long: &[Complex<f32>],
+pub fn estimate_subcarrier_equalization<T: Sample>(
+    long: &[Complex<T>],
     config: &ChannelEstConfig,
-) -> Vec<Option<Complex<f32>>> {
+) -> Vec<Option<Complex<T>>> {
     let lts_len = config.lts.as_ref().unwrap().0.len();
     assert_eq!(long.len(), 5 * lts_len / 2);
 
     // Compute the average LTS before taking fft
     let mut lts: Vec<_> = (0..lts_len)
-        .map(|i| (long[lts_len / 2 + i] + long[3 * lts_len / 2 + i]) / 2.)
+        .map(|i| (long[lts_len / 2 + i] + long[3 * lts_len / 2 + i]) / T::from_config_f32(2.))
         .collect();
     assert_eq!(lts.len(), lts_len);
 
     // FFT of the long preamble
     let mut long_fft = vec![Complex::zero(); lts_len];
-    let mut planner = FFTplanner::new(true);
-    let fft = planner.plan_fft(lts_len);
-    fft.process(&mut lts, &mut long_fft);
+    T::lts_ifft(config).process(&mut lts, &mut long_fft);
 
     long_fft
         .iter()
         .zip(&config.lts.as_ref().unwrap().1)
-        .map(|(x, l)| match l {
-            Some(l) => Some(l / x),
-            None => None,
+        .map(|(x, l)| l.map(|l| T::from_cplx(l) / x))
+        .collect()
+}
+
+/// Fill in the unused (`None`) subcarriers of an equalization in FFT bin order (e.g. from
+/// `estimate_subcarrier_equalization`) by linearly interpolating the magnitude and unwrapped phase
+/// between the nearest used subcarriers on either side. DC lies between used subcarriers, so it is
+/// interpolated. Beyond the outermost used subcarriers, the line through the two outermost ones is
+/// extrapolated. Used subcarriers are returned unchanged
+pub fn interpolate_nulled(eq: &[Option<Complex<f32>>]) -> Vec<Complex<f32>> {
+    let len = eq.len();
+    // Position `j` in frequency order is FFT bin `bin(j)`, starting from the most negative
+    let bin = |j: usize| (j + len / 2) % len;
+
+    // Position, magnitude and unwrapped phase of each used subcarrier, in frequency order
+    let mut used: Vec<(f32, f32, f32)> = Vec::new();
+    for j in 0..len {
+        if let Some(x) = eq[bin(j)] {
+            let phase = match used.last() {
+                Some(&(_, _, prev)) => prev + (x * Complex::from_polar(&1., &-prev)).arg(),
+                None => x.arg(),
+            };
+            used.push((j as f32, x.norm(), phase));
+        }
+    }
+    let interpolate = |a: (f32, f32, f32), b: (f32, f32, f32), pos: f32| {
+        let t = (pos - a.0) / (b.0 - a.0);
+        let mag = (a.1 + t * (b.1 - a.1)).max(0.);
+        Complex::from_polar(&mag, &(a.2 + t * (b.2 - a.2)))
+    };
+
+    let mut res = vec![Complex::zero(); len];
+    for j in 0..len {
+        res[bin(j)] = match (eq[bin(j)], used.len()) {
+            (Some(x), _) => x,
+            (None, 0) => Complex::zero(),
+            (None, 1) => Complex::from_polar(&used[0].1, &used[0].2),
+            (None, _) => {
+                // The first used subcarrier above `j`, or the outermost pair when extrapolating
+                let next = used
+                    .iter()
+                    .position(|u| u.0 > j as f32)
+                    .unwrap_or(used.len() - 1)
+                    .max(1);
+                interpolate(used[next - 1], used[next], j as f32)
+            }
+        };
+    }
+    res
+}
+
+/// Carry an equalization in FFT bin order over to symbols with an FFT of `fft_len` bins spanning
+/// the same bandwidth (e.g. when `symbol_len` differs from the LTS length). The channel is fit
+/// by least squares over the used subcarriers with `cp_len` taps, at every delay from
+/// `-cp_len / 2` up to (but excluding) `cp_len / 2` samples, and evaluated at the new bins. A
+/// channel longer than the cyclic prefix causes inter-symbol interference anyway, so `cp_len`
+/// bounds the delay spread worth modelling. The taps are centred on 0 because an FFT window
+/// placed early (see `symbol_start_offset`) moves part of the response to negative delays. A new
+/// bin is used if the LTS bins on either side of it are. Returned unchanged if `fft_len` is the
+/// length of `eq`
+pub fn resample_equalization(
+    eq: &[Option<Complex<f32>>],
+    fft_len: usize,
+    cp_len: usize,
+) -> Vec<Option<Complex<f32>>> {
+    let lts_len = eq.len();
+    if fft_len == lts_len {
+        return eq.to_vec();
+    }
+    // Delays (in samples) of the `cp_len` taps, of which the LTS can only resolve up to its length
+    let first = -((cp_len / 2).min(lts_len / 2) as i64);
+    let delays: Vec<i64> = (first..first + cp_len.max(1).min(lts_len) as i64).collect();
+    // Response of a unit tap at `delay` at signed frequency `freq` (in bins of the LTS)
+    let tap = |delay: i64, freq: f64| {
+        let phase = 2. * std::f64::consts::PI * freq * delay as f64 / lts_len as f64;
+        Complex::from_polar(&1., &phase)
+    };
+
+    // Normal equations `A^H A h = A^H y` where row `b` of `A` is the taps' response at used bin
+    // `b` and `y` is the channel there
+    let n = delays.len();
+    let mut gram = vec![vec![Complex::<f64>::zero(); n]; n];
+    let mut rhs = vec![Complex::<f64>::zero(); n];
+    for (b, x) in eq.iter().enumerate() {
+        if let Some(x) = x {
+            let freq = f64::from(signed_bin(b, lts_len));
+            let row: Vec<_> = delays.iter().map(|d| tap(*d, freq)).collect();
+            let channel = Complex::new(f64::from(x.re), f64::from(x.im)).inv();
+            for i in 0..n {
+                for j in 0..n {
+                    gram[i][j] += row[i].conj() * row[j];
+                }
+                rhs[i] += row[i].conj() * channel;
+            }
+        }
+    }
+    let taps = solve_linear(gram, rhs);
+
+    (0..fft_len)
+        .map(|b| {
+            // Position of this bin in units of the LTS's bins
+            let freq = f64::from(signed_bin(b, fft_len)) * lts_len as f64 / fft_len as f64;
+            let lower = (freq.floor() as i64).rem_euclid(lts_len as i64) as usize;
+            let upper = (freq.ceil() as i64).rem_euclid(lts_len as i64) as usize;
+            if eq[lower].is_none() || eq[upper].is_none() {
+                return None;
+            }
+            let channel: Complex<f64> = delays
+                .iter()
+                .zip(&taps)
+                .map(|(d, h)| h * tap(*d, freq))
+                .sum();
+            let channel = channel.inv();
+            Some(Complex::new(channel.re as f32, channel.im as f32))
         })
         .collect()
 }
 
+/// Solve `a x = b` by Gaussian elimination with partial pivoting. Singular directions (e.g. from
+/// too few used subcarriers) are set to zero
+pub(crate) fn solve_linear(
+    mut a: Vec<Vec<Complex<f64>>>,
+    mut b: Vec<Complex<f64>>,
+) -> Vec<Complex<f64>> {
+    let n = b.len();
+    let scale = a.iter().flatten().map(|x| x.norm()).fold(0., f64::max);
+    let mut singular = vec![false; n];
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|i, j| a[*i][col].norm().partial_cmp(&a[*j][col].norm()).unwrap())
+            .unwrap();
+        if a[pivot][col].norm() <= scale * 1e-12 {
+            singular[col] = true;
+            continue;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let factor = a[row][col] / a[col][col];
+            for k in col..n {
+                let sub = factor * a[col][k];
+                a[row][k] -= sub;
+            }
+            let sub = factor * b[col];
+            b[row] -= sub;
+        }
+    }
+    let mut x = vec![Complex::zero(); n];
+    for col in (0..n).rev() {
+        if singular[col] {
+            continue;
+        }
+        let rest: Complex<f64> = (col + 1..n).map(|k| a[col][k] * x[k]).sum();
+        x[col] = (b[col] - rest) / a[col][col];
+    }
+    x
+}
+
+/// Estimate the channel impulse response from the (CFO corrected) long preamble `long`, including
+/// its guard interval. Tap `k` is the path delayed by `k` samples relative to the LTS alignment
+/// (cyclically, so paths arriving earlier appear at the end). Unused subcarriers are zero-filled,
+/// so the limited bandwidth spreads each path over its neighbouring taps
+pub fn estimate_impulse_response(
+    long: &[Complex<f32>],
+    config: &ChannelEstConfig,
+) -> Vec<Complex<f32>> {
+    let (lts, _, plans, _) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+    // The channel on each subcarrier is the inverse of its equalization
+    let mut channel: Vec<_> = estimate_subcarrier_equalization(long, config)
+        .iter()
+        .map(|eq| eq.map_or_else(Complex::zero, |eq| eq.inv()))
+        .collect();
+
+    // Since the symbols are demodulated with the inverse FFT, the forward FFT takes us back
+    let mut impulse_response = vec![Complex::zero(); lts_len];
+    plans.forward.process(&mut channel, &mut impulse_response);
+    for x in &mut impulse_response {
+        *x /= lts_len as f32;
+    }
+    impulse_response
+}
+
+/// Write the channel frequency response given by the equalization `eq` (e.g. from
+/// `estimate_subcarrier_equalization`) as CSV for plotting. There is a header and then one row
+/// per subcarrier, from the most negative frequency to the most positive: the subcarrier index,
+/// its frequency offset (in Hz) at sample rate `samp_rate`, and the magnitude (in dB) and phase
+/// (in degrees) of the channel, i.e. of `1 / eq`. Both are `NaN` for nulled subcarriers
+pub fn write_channel_csv(
+    path: &str,
+    eq: &[Option<Complex<f32>>],
+    samp_rate: f32,
+) -> std::io::Result<()> {
+    let fft_len = eq.len();
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "subcarrier,freq_hz,magnitude_db,phase_deg")?;
+    let half = fft_len as i32 / 2;
+    for k in -half..fft_len as i32 - half {
+        let (mag_db, phase_deg) = match eq[subcarrier_bin(k, fft_len)] {
+            Some(eq) => {
+                let channel = eq.inv();
+                (20. * channel.norm().log10(), channel.arg().to_degrees())
+            }
+            None => (f32::NAN, f32::NAN),
+        };
+        let freq = k as f32 * samp_rate / fft_len as f32;
+        writeln!(out, "{},{},{},{}", k, freq, mag_db, phase_deg)?;
+    }
+    out.flush()
+}
+
+/// Taps weaker than the strongest by more than this (in dB) are treated as noise by
+/// `rms_delay_spread`
+const DELAY_SPREAD_THRESHOLD_DB: f32 = 20.;
+
+/// Power-weighted RMS delay (in seconds) of an impulse response (e.g. from
+/// `estimate_impulse_response`), counting only taps within `DELAY_SPREAD_THRESHOLD_DB` of the
+/// strongest. Taps in the second half are treated as arriving early, as the impulse response is
+/// cyclic. If this is a sizeable fraction of the cyclic prefix, the prefix is too short
+pub fn rms_delay_spread(impulse: &[Complex<f32>], samp_rate: f32) -> f32 {
+    let max_power = impulse.iter().map(|x| x.norm_sqr()).fold(0., f32::max);
+    let threshold = max_power * 10f32.powf(-DELAY_SPREAD_THRESHOLD_DB / 10.);
+    let len = impulse.len() as f32;
+    let (mut total, mut mean, mut mean_sq) = (0., 0., 0.);
+    for (i, x) in impulse.iter().enumerate() {
+        let power = x.norm_sqr();
+        if power >= threshold && power > 0. {
+            let delay = if 2 * i < impulse.len() {
+                i as f32
+            } else {
+                i as f32 - len
+            };
+            total += power;
+            mean += power * delay;
+            mean_sq += power * delay * delay;
+        }
+    }
+    if total == 0. {
+        return 0.;
+    }
+    let (mean, mean_sq) = (mean / total, mean_sq / total);
+    (mean_sq - mean * mean).max(0.).sqrt() / samp_rate
+}
+
+/// FFT (as used for demodulation) of each of the two LTS copies in the long preamble `long`,
+/// including its guard interval
+fn lts_copy_ffts(
+    long: &[Complex<f32>],
+    config: &ChannelEstConfig,
+) -> (Vec<Complex<f32>>, Vec<Complex<f32>>) {
+    let (lts, _, plans, _) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+    assert_eq!(long.len(), 5 * lts_len / 2);
+
+    let fft = |copy: &[Complex<f32>]| {
+        let mut res = vec![Complex::zero(); lts_len];
+        plans.inverse.process(&mut copy.to_vec(), &mut res);
+        res
+    };
+    (
+        fft(&long[lts_len / 2..3 * lts_len / 2]),
+        fft(&long[3 * lts_len / 2..]),
+    )
+}
+
+/// Estimate the noise variance on each subcarrier from the (CFO corrected) long preamble `long`,
+/// including its guard interval, as half the squared difference between the two LTS copies. It is
+/// scaled to per-sample units, so white noise of power `p` gives `p` on every subcarrier on
+/// average. Returns `None` on unused subcarriers, like `estimate_subcarrier_equalization`
+pub fn estimate_subcarrier_noise(
+    long: &[Complex<f32>],
+    config: &ChannelEstConfig,
+) -> Vec<Option<f32>> {
+    let lts_fft = &config.lts.as_ref().unwrap().1;
+    let (first, second) = lts_copy_ffts(long, config);
+    let lts_len = first.len() as f32;
+
+    first
+        .iter()
+        .zip(&second)
+        .zip(lts_fft)
+        .map(|((x, y), l)| l.map(|_| (x - y).norm_sqr() / (2. * lts_len)))
+        .collect()
+}
+
+/// Estimate the SNR (in dB) of the (CFO corrected) long preamble `long`, including its guard
+/// interval. The two copies of the LTS should be identical, so on each used subcarrier their
+/// average is the signal (plus half the noise) and half their squared difference is the noise.
+/// Only counts noise on the used subcarriers, so this is the in-band SNR
+pub fn estimate_snr(long: &[Complex<f32>], config: &ChannelEstConfig) -> f32 {
+    let lts_fft = &config.lts.as_ref().unwrap().1;
+    let (first, second) = lts_copy_ffts(long, config);
+
+    let (signal, noise) = first
+        .iter()
+        .zip(&second)
+        .zip(lts_fft)
+        .filter(|(_, l)| l.is_some())
+        .fold((0., 0.), |(signal, noise), ((x, y), _)| {
+            let n = (x - y).norm_sqr() / 2.;
+            // The average also has half the noise power of each copy
+            (signal + ((x + y) / 2.).norm_sqr() - n / 2., noise + n)
+        });
+    10. * (signal / noise).log10()
+}
+
 /// Take an IFFT to get the symbol and equalize the result using the given equalization (e.g. from
 /// `estimate_subcarrier_equalization`). Returns a Vec of symbols (as many as there are `Some`
 /// values in `equalization`)
@@ -40,26 +580,50 @@ pub fn equalize_symbol(
     samps: &[Complex<f32>],
     equalization: &[Option<Complex<f32>>],
 ) -> Vec<Complex<f32>> {
+    equalize_symbol_bins(samps, equalization)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Like `equalize_symbol`, but returns every FFT bin, with `None` wherever `equalization` is
+/// `None`. Useful when the position of each subcarrier matters (e.g. to find the pilots)
+pub fn equalize_symbol_bins(
+    samps: &[Complex<f32>],
+    equalization: &[Option<Complex<f32>>],
+) -> Vec<Option<Complex<f32>>> {
+    let mut planner = FFTplanner::new(true);
+    equalize_symbol_bins_with(samps, equalization, &*planner.plan_fft(samps.len()))
+}
+
+/// Like `equalize_symbol_bins`, but with an already planned inverse FFT of the symbol's length
+/// (e.g. `FftPlans::inverse` from the config), for when many symbols are equalized
+pub fn equalize_symbol_bins_with(
+    samps: &[Complex<f32>],
+    equalization: &[Option<Complex<f32>>],
+    ifft_plan: &dyn FFT<f32>,
+) -> Vec<Option<Complex<f32>>> {
     assert_eq!(samps.len(), equalization.len());
+    assert_eq!(samps.len(), ifft_plan.len());
 
     // Compute inverse FFT of samps
-    let mut planner = FFTplanner::new(true);
-    let fft = planner.plan_fft(samps.len());
     let mut ifft = vec![Complex::zero(); samps.len()];
-    fft.process(&mut samps.to_vec(), &mut ifft);
+    ifft_plan.process(&mut samps.to_vec(), &mut ifft);
 
     // Equalize and compute result
     ifft.iter()
         .zip(equalization)
-        .filter_map(|(s, e)| e.map(|e| s * e / samps.len() as f32))
+        .map(|(s, e)| e.map(|e| s * e / samps.len() as f32))
         .collect()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::build_preamble;
+    use crate::sim::{add_awgn, modulate_symbol, random_bpsk_symbols};
     use num::One;
-    use rand::Rng;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     /// Little test to understand the conventions in rustfft
@@ -74,8 +638,16 @@ mod test {
         let fft = planner.plan_fft(16);
         fft.process(&mut data, &mut out);
 
-        let _norm = out.iter().map(|x| x.norm()).collect::<Vec<_>>();
-        //println!("{:?}", _norm);
+        // The forward FFT is unnormalized, so a cosine (of amplitude e) with 2 cycles lands half in
+        // bin 2 and half in bin 14
+        for (i, x) in out.iter().enumerate() {
+            let expected = if i == 2 || i == 14 {
+                8. * std::f32::consts::E
+            } else {
+                0.
+            };
+            assert!((x.norm() - expected).abs() < 1e-4, "{} {}", i, x);
+        }
     }
 
     /// Test equalization estimation and correct
@@ -110,9 +682,8 @@ mod test {
         // Construct a 'packet' with a long preamble and one data symbol
         let mut pkt = Vec::<Complex<f32>>::new();
         // Long preamble
-        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() / 2));
-        pkt.extend(lts);
-        pkt.extend(lts);
+        let short_len = 10 * config.sts.as_ref().unwrap().len();
+        pkt.extend(&build_preamble(&config)[short_len..]);
         // Cyclic prefix
         pkt.extend(&symbol_fft[3 * lts.len() / 4..]);
         pkt.extend(&symbol_fft.clone());
@@ -134,4 +705,465 @@ mod test {
             assert_eq!(x.re > 0., y.re > 0.);
         }
     }
+
+    #[test]
+    fn test_equalization_f64() {
+        let config = ChannelEstConfig::default();
+        let lts = &config.lts.as_ref().unwrap().0;
+        let mut long = vec![Complex::zero(); lts.len() / 2];
+        long.extend(lts.iter().chain(lts));
+        // A two-tap channel
+        let long: Vec<_> = (0..long.len())
+            .map(|i| long[i] + Complex::new(0.2, -0.1) * long[i.saturating_sub(3)])
+            .collect();
+        let long_f64: Vec<_> = long.iter().map(|x| f64::from_cplx(*x)).collect();
+
+        let eq = estimate_subcarrier_equalization(&long, &config);
+        let eq_f64 = estimate_subcarrier_equalization(&long_f64, &config);
+        assert_eq!(eq.len(), eq_f64.len());
+        for (x, y) in eq.iter().zip(&eq_f64) {
+            match (x, y) {
+                (Some(x), Some(y)) => assert!((f64::from_cplx(*x) - y).norm() < 1e-4),
+                (None, None) => {}
+                _ => panic!("Subcarrier usage differs: {:?} {:?}", x, y),
+            }
+        }
+    }
+
+    #[test]
+    fn test_impulse_response() {
+        let config = ChannelEstConfig::default();
+        let lts = &config.lts.as_ref().unwrap().0;
+        let short_len = 10 * config.sts.as_ref().unwrap().len();
+        let long = build_preamble(&config)[short_len..].to_vec();
+
+        // Direct path and an echo `lts.len() / 8` samples later, as in the other tests
+        let delay = lts.len() / 8;
+        let mut received = long.clone();
+        for i in delay..long.len() {
+            received[i] += Complex::new(0.1, 0.2) * long[i - delay];
+        }
+
+        let impulse_response = estimate_impulse_response(&received, &config);
+        assert_eq!(impulse_response.len(), lts.len());
+        let strongest = |range: std::ops::Range<usize>| {
+            range
+                .max_by(|a, b| {
+                    let (a, b) = (impulse_response[*a].norm(), impulse_response[*b].norm());
+                    a.partial_cmp(&b).unwrap()
+                })
+                .unwrap()
+        };
+        assert_eq!(strongest(0..lts.len()), 0);
+        // Outside the main lobe of the direct path, the echo stands out
+        assert_eq!(strongest(4..lts.len() - 3), delay);
+        assert!((impulse_response[delay].norm() - 0.18).abs() < 0.05);
+
+        // Without the echo, only the main lobe remains
+        let impulse_response = estimate_impulse_response(&long, &config);
+        assert!((impulse_response[0].norm() - 52. / 64.).abs() < 1e-3);
+        for x in &impulse_response[4..lts.len() - 3] {
+            assert!(x.norm() < 0.1);
+        }
+    }
+
+    #[test]
+    fn test_rms_delay_spread() {
+        let samp_rate = 20e6;
+        // Two taps `delay` samples apart with powers 1 and 0.25, over a noise floor 30 dB down.
+        // The mean delay is 0.2 * delay and the mean squared delay 0.2 * delay^2, so the RMS
+        // delay spread is 0.4 * delay
+        let delay = 5;
+        let mut impulse: Vec<_> = (0..64)
+            .map(|i| Complex::from_polar(&0.03, &(i as f32)))
+            .collect();
+        impulse[0] = Complex::new(1., 0.);
+        impulse[delay] = Complex::new(0., -0.5);
+        let expected = 0.4 * delay as f32 / samp_rate;
+        let spread = rms_delay_spread(&impulse, samp_rate);
+        assert!(
+            (spread - expected).abs() < 1e-3 * expected,
+            "{} {}",
+            spread,
+            expected
+        );
+
+        // Shifting cyclically (e.g. an early direct path) doesn't change the spread
+        impulse.rotate_right(62);
+        assert!((rms_delay_spread(&impulse, samp_rate) - expected).abs() < 1e-3 * expected);
+
+        // A single tap has no spread
+        let mut impulse = vec![Complex::zero(); 64];
+        impulse[3] = Complex::new(0.7, 0.1);
+        assert!(rms_delay_spread(&impulse, samp_rate).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_write_channel_csv() {
+        // A flat channel with gain 0.5 and a quarter turn of phase: `1 / eq = 0.5 i`
+        let config = ChannelEstConfig::default();
+        let eq: Vec<_> = config
+            .lts
+            .as_ref()
+            .unwrap()
+            .1
+            .iter()
+            .map(|l| l.map(|_| Complex::new(0., -2.)))
+            .collect();
+        let path = std::env::temp_dir().join("channel_est_test_channel.csv");
+        let path = path.to_str().unwrap();
+        write_channel_csv(path, &eq, 20e6).unwrap();
+        let csv = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let rows: Vec<Vec<&str>> = csv
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').collect())
+            .collect();
+        assert_eq!(rows.len(), eq.len());
+        for row in &rows {
+            let k: i32 = row[0].parse().unwrap();
+            let freq: f32 = row[1].parse().unwrap();
+            assert!((freq - k as f32 * 312.5e3).abs() < 1.);
+            let mag_db: f32 = row[2].parse().unwrap();
+            let phase_deg: f32 = row[3].parse().unwrap();
+            if eq[subcarrier_bin(k, eq.len())].is_some() {
+                assert!((mag_db + 6.02).abs() < 0.01);
+                assert!((phase_deg - 90.).abs() < 0.01);
+            } else {
+                assert!(mag_db.is_nan() && phase_deg.is_nan());
+            }
+        }
+        // From the most negative subcarrier to the most positive, with DC nulled
+        assert_eq!(rows[0][0], "-32");
+        assert_eq!(rows[63][0], "31");
+        assert_eq!(rows[32][2], "NaN");
+    }
+
+    /// A flat, noiseless channel with gain 0.5 through each strategy
+    #[test]
+    fn test_equalizer_strategies() {
+        let config = ChannelEstConfig::default();
+        let lts = &config.lts.as_ref().unwrap().0;
+        let mut long = vec![Complex::zero(); lts.len() / 2];
+        long.extend(lts.iter().chain(lts).map(|x| x * 0.5));
+
+        let strategies: [(Box<dyn Equalizer>, f32); 4] = [
+            (Box::new(ZeroForcing), 2.),
+            // conj(0.5) / (0.25 + 0.1)
+            (Box::new(Mmse { snr_db: 10. }), 0.5 / 0.35),
+            // The measured noise is 0, so this is zero-forcing
+            (Box::new(MeasuredMmse), 2.),
+            (Box::new(EqualizerKind::Smoothed { half_width: 2 }), 2.),
+        ];
+        for (equalizer, expected) in strategies.iter() {
+            let eq = equalizer.estimate(&long, &config);
+            assert_eq!(eq.len(), lts.len());
+            for (e, l) in eq.iter().zip(&config.lts.as_ref().unwrap().1) {
+                assert_eq!(e.is_some(), l.is_some());
+                if let Some(e) = e {
+                    assert!((e - expected).norm() < 1e-3);
+                }
+            }
+        }
+    }
+
+    /// A window at least as wide as the band averages the channel over every used subcarrier, each
+    /// counted once
+    #[test]
+    fn test_smoothed_whole_band() {
+        let config = ChannelEstConfig::default();
+        let lts = &config.lts.as_ref().unwrap().0;
+        let lts_len = lts.len();
+        // Cyclic guard interval and an echo 3 samples late, so the channel isn't flat
+        let mut long: Vec<_> = lts[lts_len / 2..].to_vec();
+        long.extend(lts.iter().chain(lts));
+        let long: Vec<_> = (0..long.len())
+            .map(|i| {
+                long[i]
+                    + if i >= 3 {
+                        long[i - 3] * 0.5
+                    } else {
+                        Complex::zero()
+                    }
+            })
+            .collect();
+
+        let channel: Vec<_> = ZeroForcing
+            .estimate(&long, &config)
+            .iter()
+            .flatten()
+            .map(|e| 1. / e)
+            .collect();
+        let mean = channel.iter().sum::<Complex<f32>>() / channel.len() as f32;
+        assert!(channel.iter().any(|h| (h - mean).norm() > 0.1));
+        for half_width in &[lts_len / 2, lts_len, 100] {
+            let eq = Smoothed {
+                half_width: *half_width,
+            }
+            .estimate(&long, &config);
+            for (e, l) in eq.iter().zip(&config.lts.as_ref().unwrap().1) {
+                assert_eq!(e.is_some(), l.is_some());
+                if let Some(e) = e {
+                    assert!((e * mean - 1.).norm() < 1e-4, "{} {}", half_width, e * mean);
+                }
+            }
+        }
+    }
+
+    /// On a subcarrier in a deep fade, zero-forcing amplifies the noise whereas MMSE with the
+    /// measured SNR shrinks it
+    #[test]
+    fn test_measured_mmse() {
+        let config = ChannelEstConfig::default();
+        let (lts, lts_fft, _, _) = config.lts.as_ref().unwrap();
+        let lts_len = lts.len();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+        // An echo 2 samples late nearly cancels the direct path on subcarriers 16 and -16
+        let channel = |samps: &[Complex<f32>]| -> Vec<_> {
+            (0..samps.len())
+                .map(|i| {
+                    samps[i]
+                        + if i >= 2 {
+                            samps[i - 2] * 0.95
+                        } else {
+                            Complex::zero()
+                        }
+                })
+                .collect()
+        };
+        let faded = [16, lts_len - 16];
+        // Data at the same per-subcarrier power as the LTS
+        let data_gain = 1. / (lts_fft.iter().flatten().count() as f32).sqrt();
+
+        let mut sq_err = [0f32; 2];
+        for _ in 0..200 {
+            // Cyclic guard interval, so the echo doesn't see silence
+            let mut long: Vec<_> = lts[lts_len / 2..].to_vec();
+            long.extend(lts.iter().chain(lts));
+            let mut long = channel(&long);
+            add_awgn(&mut long, 0.01, &mut rng);
+
+            let bins = &random_bpsk_symbols(&config, 1, &mut rng)[0];
+            let symbol: Vec<_> = modulate_symbol(bins, config.cp_len)
+                .iter()
+                .map(|x| x * data_gain)
+                .collect();
+            let mut symbol = channel(&symbol);
+            add_awgn(&mut symbol, 0.01, &mut rng);
+
+            let equalizers: [&dyn Equalizer; 2] = [&ZeroForcing, &MeasuredMmse];
+            for (err, equalizer) in sq_err.iter_mut().zip(&equalizers) {
+                let eq = equalizer.estimate(&long, &config);
+                let out = equalize_symbol_bins(&symbol[config.cp_len..], &eq);
+                for bin in &faded {
+                    *err += (out[*bin].unwrap() / data_gain - bins[*bin]).norm_sqr();
+                }
+            }
+        }
+        assert!(sq_err[1] < 0.5 * sq_err[0], "{:?}", sq_err);
+        // MMSE's error stays comparable to the symbols' power (200 trials of 2 subcarriers)
+        assert!(sq_err[1] < 2. * 400., "{:?}", sq_err);
+    }
+
+    /// The plans cached in the config give the same output as planning afresh
+    #[test]
+    fn test_cached_fft_plan() {
+        let config = ChannelEstConfig::default();
+        let (lts, lts_fft, plans, _) = config.lts.as_ref().unwrap();
+        assert_eq!(plans.inverse.len(), lts.len());
+        assert_eq!(plans.forward.len(), lts.len());
+
+        let mut rng = rand::thread_rng();
+        let samps: Vec<_> = (0..lts.len())
+            .map(|_| Complex::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5))
+            .collect();
+        assert_eq!(
+            equalize_symbol_bins(&samps, lts_fft),
+            equalize_symbol_bins_with(&samps, lts_fft, &*plans.inverse)
+        );
+    }
+
+    #[test]
+    fn test_interpolate_nulled() {
+        // A single interior null gets the average magnitude and phase of its neighbours
+        let mut eq: Vec<_> = (0..8)
+            .map(|i| {
+                Some(Complex::from_polar(
+                    &(1. + 0.1 * i as f32),
+                    &(0.2 * i as f32),
+                ))
+            })
+            .collect();
+        eq[2] = None;
+        let filled = interpolate_nulled(&eq);
+        assert!((filled[2].norm() - 1.2).abs() < 1e-5);
+        assert!((filled[2].arg() - 0.4).abs() < 1e-5);
+        for (x, e) in filled.iter().zip(&eq) {
+            if let Some(e) = e {
+                assert_eq!(x, e);
+            }
+        }
+
+        // The phase is unwrapped, so this doesn't average to 0. In frequency order the bins are
+        // 2, 3, 0, 1, so the null in bin 3 lies between bins 2 and 0
+        let eq = [
+            Some(Complex::from_polar(&1., &-3.)),
+            Some(Complex::one()),
+            Some(Complex::from_polar(&1., &3.)),
+            None,
+        ];
+        let filled = interpolate_nulled(&eq);
+        assert!((filled[3] - Complex::from_polar(&1., &std::f32::consts::PI)).norm() < 1e-5);
+
+        // With the 802.11 LTS, DC is interpolated and the band edges extrapolated. A channel
+        // that is linear in magnitude and phase across the band is filled in exactly
+        let config = ChannelEstConfig::default();
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        let channel = |bin: usize| {
+            let k = crate::pilots::signed_bin(bin, lts_fft.len());
+            Complex::from_polar(&(1. + 0.01 * k), &(0.05 * k))
+        };
+        let eq: Vec<_> = lts_fft
+            .iter()
+            .enumerate()
+            .map(|(bin, l)| l.map(|_| channel(bin)))
+            .collect();
+        assert!(eq[0].is_none() && eq[32].is_none());
+        for (bin, x) in interpolate_nulled(&eq).iter().enumerate() {
+            assert!(
+                (x - channel(bin)).norm() < 1e-4,
+                "{} {} {}",
+                bin,
+                x,
+                channel(bin)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resample_equalization() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        // Taps (delay, gain) within the cyclic prefix, including one arriving early
+        let taps = [
+            (0, Complex::new(1., 0.)),
+            (3, Complex::new(0.4, -0.2)),
+            (-2, Complex::i() * 0.3),
+        ];
+        let equalization = |fft_len: usize, used: &dyn Fn(usize) -> bool| -> Vec<_> {
+            (0..fft_len)
+                .map(|b| {
+                    let freq = signed_bin(b, fft_len);
+                    let channel: Complex<f32> = taps
+                        .iter()
+                        .map(|(d, h)| {
+                            let phase = 2. * std::f32::consts::PI * freq * *d as f32;
+                            h * Complex::from_polar(&1., &(phase / fft_len as f32))
+                        })
+                        .sum();
+                    Some(channel.inv()).filter(|_| used(b))
+                })
+                .collect()
+        };
+        let eq = equalization(64, &|b| lts_fft[b].is_some());
+        assert_eq!(resample_equalization(&eq, 64, 16), eq);
+
+        let resampled = resample_equalization(&eq, 128, 16);
+        // The same band, so about twice as many subcarriers are in use
+        let num_used = resampled.iter().flatten().count();
+        let lts_used = lts_fft.iter().flatten().count();
+        assert!(
+            num_used > 2 * lts_used - 6 && num_used <= 2 * lts_used,
+            "{}",
+            num_used
+        );
+        let expected = equalization(128, &|_| true);
+        for (x, y) in resampled.iter().zip(&expected) {
+            if let (Some(x), Some(y)) = (x, y) {
+                assert!((x - y).norm() < 1e-3, "{} {}", x, y);
+            }
+        }
+        // DC and the band edges stay unused
+        assert_eq!(resampled[0], None);
+        assert_eq!(resampled[64], None);
+    }
+
+    #[test]
+    fn test_subcarrier_noise() {
+        let config = ChannelEstConfig::default();
+        let (lts, lts_fft, _, _) = config.lts.as_ref().unwrap();
+        let lts_len = lts.len();
+        let mut long = lts[lts_len / 2..].to_vec();
+        long.extend(lts);
+        long.extend(lts);
+
+        // A tone on one subcarrier's bin, added to the second copy only, is seen as noise on
+        // that subcarrier alone. All of its power, `amp^2` per sample, is in that bin
+        let (bin, amp) = (5, 0.1f32);
+        let mut toned = long.clone();
+        for (i, x) in toned[3 * lts_len / 2..].iter_mut().enumerate() {
+            let phase = -2. * std::f32::consts::PI * (bin * i) as f32 / lts_len as f32;
+            *x += Complex::from_polar(&amp, &phase);
+        }
+        let noise = estimate_subcarrier_noise(&toned, &config);
+        assert_eq!(noise.len(), lts_len);
+        for (i, (n, l)) in noise.iter().zip(lts_fft).enumerate() {
+            assert_eq!(n.is_some(), l.is_some());
+            let expected = if i == bin {
+                lts_len as f32 * amp * amp / 2.
+            } else {
+                0.
+            };
+            if let Some(n) = n {
+                assert!((n - expected).abs() < 1e-4, "{} {} {}", i, n, expected);
+            }
+        }
+
+        // White noise of power `p` on one copy is like `p / 2` on each
+        let noise_power = 0.1;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let num_trials = 50;
+        let estimates: Vec<f32> = (0..num_trials)
+            .flat_map(|_| {
+                let mut noisy = long.clone();
+                add_awgn(&mut noisy[3 * lts_len / 2..], noise_power, &mut rng);
+                estimate_subcarrier_noise(&noisy, &config)
+            })
+            .flatten()
+            .collect();
+        let mean = estimates.iter().sum::<f32>() / estimates.len() as f32;
+        assert!(
+            (mean - noise_power / 2.).abs() < 0.1 * noise_power / 2.,
+            "{}",
+            mean
+        );
+    }
+
+    #[test]
+    fn test_estimate_snr() {
+        let config = ChannelEstConfig::default();
+        let lts = &config.lts.as_ref().unwrap().0;
+        let num_used = config.lts.as_ref().unwrap().1.iter().flatten().count();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+
+        for noise_power in &[0.01f32, 0.1, 0.5] {
+            // The LTS has unit power, all in the used subcarriers, whereas the noise is spread
+            // over all of them
+            let expected = 10. * (lts.len() as f32 / num_used as f32 / noise_power).log10();
+            let num_trials = 20;
+            let mean = (0..num_trials)
+                .map(|_| {
+                    let mut long = lts[lts.len() / 2..].to_vec();
+                    long.extend(lts);
+                    long.extend(lts);
+                    add_awgn(&mut long, *noise_power, &mut rng);
+                    estimate_snr(&long, &config)
+                })
+                .sum::<f32>()
+                / num_trials as f32;
+            assert!((mean - expected).abs() < 1., "{} {}", mean, expected);
+        }
+    }
 }
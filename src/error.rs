@@ -0,0 +1,122 @@
+//! The crate-wide error type. Fallible public functions return `error::Result`
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Reading or writing a file failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// Input data (e.g. a capture or its metadata) is malformed
+    #[error("malformed input: {0}")]
+    Parse(String),
+    /// Could not find the LTS in a packet
+    #[error("could not align to the LTS: {0}")]
+    Alignment(String),
+    /// Could not decode a packet
+    #[error("could not decode packet: {0}")]
+    Decode(String),
+    /// The configuration is inconsistent or incomplete
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// A packet buffer could not be parsed
+    #[error("could not parse packet: {0}")]
+    Packet(#[from] ParseError),
+    /// Sending to or receiving from the radio failed
+    #[error("radio error: {0}")]
+    Radio(String),
+}
+
+/// Why `parse_80211::parse_80211_pkt` rejected a buffer (or `signal::parse_signal` a symbol, or
+/// `mac::parse_mac_header` a frame)
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+pub enum ParseError {
+    /// The buffer can't contain the preamble
+    #[error("buffer has {len} samples, need at-least {needed}")]
+    BufferTooShort { len: usize, needed: usize },
+    /// `ChannelEstConfig::lts` is not set
+    #[error("no LTS configured")]
+    MissingLts,
+    /// `ChannelEstConfig::sts` is not set
+    #[error("no STS configured")]
+    MissingSts,
+    /// The LTS peak is too early for a short preamble to precede it
+    #[error("LTS found at sample {lts_start}, before the end of the short preamble")]
+    LtsAlignmentFailed { lts_start: usize },
+    /// The RATE bits of the SIGNAL field aren't one of the valid rates
+    #[error("SIGNAL field has invalid RATE bits {rate_bits:?}")]
+    InvalidSignalRate { rate_bits: [u8; 4] },
+    /// The frame is shorter than a MAC header
+    #[error("frame has {len} bytes, need at-least {needed} for the MAC header")]
+    FrameTooShort { len: usize, needed: usize },
+}
+
+/// Why a `ChannelEstConfig` could not be loaded
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The configuration file or a training sequence file could not be read
+    #[error("could not read '{path}': {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// The configuration is not valid TOML or a field has the wrong type
+    #[error("invalid config: {0}")]
+    Toml(#[from] toml::de::Error),
+    /// A line of a training sequence file is not a number. Lines are numbered from 1
+    #[error("'{path}' line {line} ('{text}') is not a number")]
+    Parse {
+        path: String,
+        line: usize,
+        text: String,
+    },
+    /// A training sequence file has an odd number of values, so they can't be paired into
+    /// complex numbers
+    #[error("'{path}' has an odd number of values ({count})")]
+    OddCount { path: String, count: usize },
+    /// A field violates an invariant the processing relies on (see `ChannelEstConfig::validate`)
+    #[error("invalid `{field}`: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use super::{Error, ParseError};
+    use crate::capture::{parse_json_meta, read_raw_header, write_raw_header, CaptureMeta};
+
+    #[test]
+    fn test_error_paths() {
+        // IO errors convert transparently
+        match read_raw_header("/nonexistent/channel_est/capture.iq") {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+            x => panic!("Expected an IO error, got {:?}", x),
+        }
+
+        match parse_json_meta("not json") {
+            Err(Error::Parse(_)) => {}
+            x => panic!("Expected a parse error, got {:?}", x),
+        }
+
+        match write_raw_header(&mut Vec::new(), &CaptureMeta::default()) {
+            Err(Error::Config(msg)) => assert!(msg.contains("sample rate")),
+            x => panic!("Expected a config error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_error_display() {
+        let e: Error = std::io::Error::new(std::io::ErrorKind::Other, "oops").into();
+        assert_eq!(e.to_string(), "I/O error: oops");
+        assert_eq!(
+            Error::Alignment("no peak".to_string()).to_string(),
+            "could not align to the LTS: no peak"
+        );
+        assert_eq!(
+            Error::from(ParseError::MissingLts).to_string(),
+            "could not parse packet: no LTS configured"
+        );
+    }
+}
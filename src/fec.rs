@@ -0,0 +1,191 @@
+//! The 802.11a forward error correction: the K = 7 rate 1/2 convolutional code with generators
+//! 133 and 171 (octal), and the puncturing that derives rates 2/3 and 3/4 from it. Bits are `u8`s
+//! that are either 0 or 1, as in `demod`, and soft values are LLRs as from `demod::demod_llr`:
+//! positive for a 1 and negative for a 0, with a larger magnitude for more confidence
+
+/// Generators of the first and second coded bit of each pair, as masks of the encoder's register
+/// in `conv_encode`
+const GENERATORS: [u8; 2] = [0b101_1011, 0b111_1001];
+
+/// Coding rates of 802.11a
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeRate {
+    Half,
+    TwoThirds,
+    ThreeQuarters,
+}
+
+impl CodeRate {
+    /// Which coded bits of each period of the rate 1/2 output are sent. Rate 2/3 drops the
+    /// second bit of every other pair; rate 3/4 the second bit of the second pair and the first
+    /// of the third
+    fn puncture_pattern(&self) -> &'static [bool] {
+        match self {
+            CodeRate::Half => &[true, true],
+            CodeRate::TwoThirds => &[true, true, true, false],
+            CodeRate::ThreeQuarters => &[true, true, true, false, false, true],
+        }
+    }
+}
+
+/// Encode `bits` with the rate 1/2 convolutional code, starting from the all-zeros state
+pub fn conv_encode(bits: &[u8]) -> Vec<u8> {
+    let mut state = 0u8;
+    let mut res = Vec::with_capacity(2 * bits.len());
+    for b in bits {
+        // Bit 6 of `state` is the newest input, bit 0 the one six inputs ago
+        state = (state >> 1) | (b << 6);
+        for g in &GENERATORS {
+            res.push(((state & g).count_ones() % 2) as u8);
+        }
+    }
+    res
+}
+
+/// Drop the coded bits (from `conv_encode`) that `rate` doesn't send. `coded` must be a whole
+/// number of puncturing periods (e.g. 6 bits, from 3 input bits, for rate 3/4)
+pub fn puncture(coded: &[u8], rate: CodeRate) -> Vec<u8> {
+    let pattern = rate.puncture_pattern();
+    assert_eq!(coded.len() % pattern.len(), 0);
+    coded
+        .iter()
+        .zip(pattern.iter().cycle())
+        .filter(|(_, keep)| **keep)
+        .map(|(c, _)| *c)
+        .collect()
+}
+
+/// Undo `puncture` on soft values by inserting a 0 (no information) for every coded bit that
+/// wasn't sent, giving input for `viterbi_decode`. `llrs` must be a whole number of puncturing
+/// periods, as the coded bits of an 802.11a symbol always are
+pub fn depuncture(llrs: &[f32], rate: CodeRate) -> Vec<f32> {
+    let pattern = rate.puncture_pattern();
+    let sent = pattern.iter().filter(|keep| **keep).count();
+    assert_eq!(llrs.len() % sent, 0);
+    let mut res = Vec::with_capacity(llrs.len() / sent * pattern.len());
+    for period in llrs.chunks(sent) {
+        let mut period = period.iter();
+        res.extend(pattern.iter().map(|keep| match keep {
+            true => *period.next().unwrap(),
+            false => 0.,
+        }));
+    }
+    res
+}
+
+/// Maximum-likelihood (Viterbi) decoding of the output of `conv_encode`, given a soft value for
+/// each coded bit (use `depuncture` first for rates above 1/2). Assumes the encoder ended in the
+/// all-zeros state (e.g. after a tail of six zeros)
+pub fn viterbi_decode(llrs: &[f32]) -> Vec<u8> {
+    assert_eq!(llrs.len() % 2, 0);
+    // The state is the last six inputs, with bit 5 the newest. Appending input `b` to state `s`
+    // gives the encoder's register `s | b << 6` in `conv_encode`
+    let mut metric = [f32::NEG_INFINITY; 64];
+    metric[0] = 0.;
+    // For each step and state, the previous state and the input that led there
+    let mut history = Vec::with_capacity(llrs.len() / 2);
+    for pair in llrs.chunks(2) {
+        let mut next = [f32::NEG_INFINITY; 64];
+        let mut from = [(0, 0); 64];
+        for prev in (0..64u8).filter(|s| metric[*s as usize].is_finite()) {
+            for b in 0..2u8 {
+                let reg = prev | (b << 6);
+                let score = |g: u8, s: f32| {
+                    if (reg & g).count_ones() % 2 == 1 {
+                        s
+                    } else {
+                        -s
+                    }
+                };
+                let m = metric[prev as usize]
+                    + score(GENERATORS[0], pair[0])
+                    + score(GENERATORS[1], pair[1]);
+                let state = (reg >> 1) as usize;
+                if m > next[state] {
+                    next[state] = m;
+                    from[state] = (prev, b);
+                }
+            }
+        }
+        metric = next;
+        history.push(from);
+    }
+
+    // Trace back from the all-zeros state
+    let mut bits = vec![0; history.len()];
+    let mut state = 0;
+    for (i, from) in history.iter().enumerate().rev() {
+        let (prev, b) = from[state];
+        bits[i] = b;
+        state = prev as usize;
+    }
+    bits
+}
+
+#[cfg(test)]
+mod test {
+    use super::{conv_encode, depuncture, puncture, viterbi_decode, CodeRate};
+    use rand::Rng;
+
+    /// BPSK-like soft values for coded bits
+    fn to_soft(coded: &[u8]) -> Vec<f32> {
+        coded.iter().map(|c| 2. * f32::from(*c) - 1.).collect()
+    }
+
+    #[test]
+    fn test_conv_encode() {
+        // The impulse response of the code is its two generators interleaved
+        let mut impulse = vec![0; 7];
+        impulse[0] = 1;
+        assert_eq!(
+            conv_encode(&impulse),
+            vec![1, 1, 0, 1, 1, 1, 1, 1, 0, 0, 1, 0, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_viterbi_decode() {
+        let mut rng = rand::thread_rng();
+        let mut bits: Vec<u8> = (0..100).map(|_| rng.gen_range(0, 2)).collect();
+        bits.extend(&[0; 6]);
+        let mut soft = to_soft(&conv_encode(&bits));
+        assert_eq!(viterbi_decode(&soft), bits);
+
+        // Isolated errors are corrected
+        for i in &[3, 50, 120, 190] {
+            soft[*i] = -soft[*i];
+        }
+        assert_eq!(viterbi_decode(&soft), bits);
+    }
+
+    #[test]
+    fn test_punctured() {
+        let coded = conv_encode(&[1, 0, 1, 1, 0, 0]);
+        assert_eq!(puncture(&coded, CodeRate::Half), coded);
+        let punctured = puncture(&coded, CodeRate::ThreeQuarters);
+        assert_eq!(punctured, vec![1, 1, 0, 0, 0, 1, 1, 0]);
+        let depunctured = depuncture(&to_soft(&punctured), CodeRate::ThreeQuarters);
+        assert_eq!(depunctured.len(), coded.len());
+        assert_eq!(depunctured.iter().filter(|x| x.abs() < 1e-6).count(), 4);
+
+        let mut rng = rand::thread_rng();
+        for rate in &[CodeRate::TwoThirds, CodeRate::ThreeQuarters] {
+            // A multiple of 6 input bits is a whole number of periods for both rates
+            let mut bits: Vec<u8> = (0..210).map(|_| rng.gen_range(0, 2)).collect();
+            bits.extend(&[0; 6]);
+            let mut soft = to_soft(&puncture(&conv_encode(&bits), *rate));
+            assert_eq!(viterbi_decode(&depuncture(&soft, *rate)), bits);
+
+            // The punctured codes correct fewer errors, but still isolated ones
+            for i in &[10, 150, 280] {
+                soft[*i] = -soft[*i];
+            }
+            assert_eq!(
+                viterbi_decode(&depuncture(&soft, *rate)),
+                bits,
+                "{:?}",
+                rate
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Conversion of samples to fixed point, for feeding fixed-point DSPs.
+//!
+//! Q15 convention: each of the real and imaginary parts is an `i16` `q` representing
+//! `q / 32768 * full_scale`. So `full_scale` maps to just beyond the largest representable value
+//! (32767) and `-full_scale` maps to -32768. Values are rounded to the nearest step and values
+//! outside `[-full_scale, full_scale)` saturate instead of wrapping around
+
+use num::Complex;
+
+/// One part (real or imaginary) in Q15 with the given full scale
+fn part_to_q15(x: f32, full_scale: f32) -> i16 {
+    let q = (x / full_scale * 32768.).round();
+    if q >= f32::from(i16::MAX) {
+        i16::MAX
+    } else if q <= f32::from(i16::MIN) {
+        i16::MIN
+    } else {
+        q as i16
+    }
+}
+
+/// Quantize `samps` to Q15 with a full scale of 1, i.e. covering `[-1, 1)`
+pub fn to_q15(samps: &[Complex<f32>]) -> Vec<(i16, i16)> {
+    to_q15_scaled(samps, 1.)
+}
+
+/// Quantize `samps` to Q15 with the given full scale (see the module documentation). For
+/// equalized BPSK/QPSK symbols, a full scale of 2 leaves headroom for noise
+pub fn to_q15_scaled(samps: &[Complex<f32>], full_scale: f32) -> Vec<(i16, i16)> {
+    assert!(full_scale > 0.);
+    samps
+        .iter()
+        .map(|x| (part_to_q15(x.re, full_scale), part_to_q15(x.im, full_scale)))
+        .collect()
+}
+
+/// Convert Q15 values back to floating point. Inverse of `to_q15_scaled` up to quantization and
+/// saturation
+pub fn from_q15_scaled(samps: &[(i16, i16)], full_scale: f32) -> Vec<Complex<f32>> {
+    samps
+        .iter()
+        .map(|(re, im)| Complex::new(f32::from(*re), f32::from(*im)) * full_scale / 32768.)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_q15() {
+        let samps = [
+            Complex::new(0., 0.),
+            Complex::new(0.5, -0.5),
+            Complex::new(-1., 0.25),
+            // Half a step rounds away from zero
+            Complex::new(1.5 / 32768., -1.5 / 32768.),
+            // Saturation
+            Complex::new(1., -1.5),
+            Complex::new(7., 0.999_99),
+        ];
+        assert_eq!(
+            to_q15(&samps),
+            vec![
+                (0, 0),
+                (16384, -16384),
+                (-32768, 8192),
+                (2, -2),
+                (32767, -32768),
+                (32767, 32767),
+            ]
+        );
+
+        // Round trip within half a step, except where saturated
+        let scaled = to_q15_scaled(&samps, 8.);
+        for (x, y) in samps.iter().zip(from_q15_scaled(&scaled, 8.)) {
+            assert!((x - y).norm() <= 8. / 32768.);
+        }
+        assert_eq!(
+            to_q15_scaled(&[Complex::new(2., -4.)], 4.),
+            vec![(16384, -32768)]
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! The 802.11a block interleaver, which permutes the coded bits of each OFDM symbol so adjacent
+//! coded bits land on non-adjacent subcarriers and alternate between more and less significant
+//! bits of the constellation. Bits are `u8`s that are either 0 or 1, as in `demod`
+
+/// Position after interleaving of coded bit `k` of a symbol with `n_bpsc` coded bits per
+/// subcarrier and `n_cbps` coded bits per symbol. This is the standard's two permutations: the
+/// first spreads adjacent bits 16 subcarriers' worth apart and the second rotates them among the
+/// bits of a subcarrier
+pub fn interleaved_index(k: usize, n_bpsc: usize, n_cbps: usize) -> usize {
+    let s = (n_bpsc / 2).max(1);
+    let i = (n_cbps / 16) * (k % 16) + k / 16;
+    s * (i / s) + (i + n_cbps - 16 * i / n_cbps) % s
+}
+
+/// Interleave `bits`, which must be a whole number of symbols of `n_cbps` coded bits each, with
+/// `n_bpsc` coded bits per subcarrier (1 for BPSK, 2 for QPSK, 4 for 16-QAM and 6 for 64-QAM)
+pub fn interleave(bits: &[u8], n_bpsc: usize, n_cbps: usize) -> Vec<u8> {
+    check_sizes(bits, n_bpsc, n_cbps);
+    let mut res = vec![0; bits.len()];
+    for (symbol, out) in bits.chunks(n_cbps).zip(res.chunks_mut(n_cbps)) {
+        for (k, bit) in symbol.iter().enumerate() {
+            out[interleaved_index(k, n_bpsc, n_cbps)] = *bit;
+        }
+    }
+    res
+}
+
+/// Undo `interleave`, e.g. on the hard decisions from `demod::demod_qam` before FEC decoding
+pub fn deinterleave(bits: &[u8], n_bpsc: usize, n_cbps: usize) -> Vec<u8> {
+    check_sizes(bits, n_bpsc, n_cbps);
+    bits.chunks(n_cbps)
+        .flat_map(|symbol| (0..n_cbps).map(move |k| symbol[interleaved_index(k, n_bpsc, n_cbps)]))
+        .collect()
+}
+
+fn check_sizes(bits: &[u8], n_bpsc: usize, n_cbps: usize) {
+    assert!(n_bpsc > 0 && n_cbps % 16 == 0 && n_cbps % n_bpsc == 0);
+    assert_eq!(
+        bits.len() % n_cbps,
+        0,
+        "bits must be a whole number of symbols"
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::{deinterleave, interleave, interleaved_index};
+    use rand::Rng;
+
+    /// The 802.11a modulations as (n_bpsc, n_cbps)
+    const SIZES: [(usize, usize); 4] = [(1, 48), (2, 96), (4, 192), (6, 288)];
+
+    #[test]
+    fn test_interleaved_index() {
+        // BPSK only needs the first permutation
+        assert_eq!(interleaved_index(1, 1, 48), 3);
+        assert_eq!(interleaved_index(16, 1, 48), 1);
+        // 16-QAM: bit 1 goes to 12 and then swaps with its neighbour on the same subcarrier
+        assert_eq!(interleaved_index(1, 4, 192), 13);
+
+        for (n_bpsc, n_cbps) in &SIZES {
+            let mut used: Vec<_> = (0..*n_cbps)
+                .map(|k| interleaved_index(k, *n_bpsc, *n_cbps))
+                .collect();
+            used.sort_unstable();
+            assert_eq!(used, (0..*n_cbps).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_interleave_round_trip() {
+        let mut rng = rand::thread_rng();
+        for (n_bpsc, n_cbps) in &SIZES {
+            let bits: Vec<u8> = (0..3 * n_cbps).map(|_| rng.gen_range(0, 2)).collect();
+            let interleaved = interleave(&bits, *n_bpsc, *n_cbps);
+            assert_ne!(interleaved, bits);
+            assert_eq!(deinterleave(&interleaved, *n_bpsc, *n_cbps), bits);
+            assert_eq!(
+                interleave(&deinterleave(&bits, *n_bpsc, *n_cbps), *n_bpsc, *n_cbps),
+                bits
+            );
+        }
+    }
+}
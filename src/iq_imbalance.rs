@@ -0,0 +1,174 @@
+//! IQ imbalance: cheap direct-conversion receivers mix the I and Q branches down with slightly
+//! different gains and not quite 90 degrees apart. This leaks each subcarrier into its mirror image
+//! on the other side of DC. It happens in the receiver, after any CFO, so it should be corrected
+//! before the CFO is
+
+use crate::config::ChannelEstConfig;
+use crate::equalization::solve_linear;
+use crate::pilots::signed_bin;
+use num::{Complex, Zero};
+
+/// Gain and phase mismatch of the Q branch relative to the I branch. A sample `I + jQ` is received
+/// as `I + j * gain * (Q * cos(phase) - I * sin(phase))`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IqImbalance {
+    /// Amplitude of the Q branch relative to the I branch. 1 when balanced
+    pub gain: f32,
+    /// Phase error (in radians) of the Q branch. 0 when balanced
+    pub phase: f32,
+}
+
+impl Default for IqImbalance {
+    fn default() -> Self {
+        Self::new(1., 0.)
+    }
+}
+
+impl IqImbalance {
+    pub fn new(gain: f32, phase: f32) -> Self {
+        Self { gain, phase }
+    }
+
+    /// `(mu, nu)` such that a sample `s` is received as `mu * s + nu * conj(s)`
+    pub fn coefficients(&self) -> (Complex<f32>, Complex<f32>) {
+        let z = Complex::from_polar(&self.gain, &self.phase);
+        ((1. + z.conj()) / 2., (1. - z) / 2.)
+    }
+
+    /// The imbalance whose `nu / conj(mu)` (see `coefficients`) is `ratio`
+    fn from_image_ratio(ratio: Complex<f32>) -> Self {
+        let z = (1. - ratio) / (1. + ratio);
+        Self::new(z.norm(), z.arg())
+    }
+}
+
+/// Estimate the IQ imbalance from the long preamble `long` including its guard interval, before
+/// CFO correction (a large CFO biases the estimate slightly). On subcarrier `k`, the channel
+/// measured on the LTS is the true channel plus the mirror of subcarrier `-k`, scaled by the
+/// ratio of the LTS's values there. Unlike the channel, that ratio flips sign erratically from one
+/// subcarrier to the next, so fitting the measurement with a channel of `config.cp_len` taps plus
+/// the mirror term by least squares separates the two
+pub fn estimate_iq_imbalance(long: &[Complex<f32>], config: &ChannelEstConfig) -> IqImbalance {
+    let (lts, lts_fft, plans, _) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+    assert_eq!(long.len(), 5 * lts_len / 2);
+    let cp_len = config.cp_len;
+
+    // FFT of the second LTS copy. The guard interval needn't be a cyclic prefix (`build_preamble`
+    // leaves it silent), in which case the start of the first copy lacks the channel's response to
+    // the end of an LTS. The second copy follows the first, so it sees a circular convolution
+    let mut second = long[3 * lts_len / 2..].to_vec();
+    let mut long_fft = vec![Complex::zero(); lts_len];
+    plans.inverse.process(&mut second, &mut long_fft);
+
+    let mirror = |b: usize| (lts_len - b) % lts_len;
+    let to_f64 = |x: Complex<f32>| Complex::new(f64::from(x.re), f64::from(x.im));
+    // Measured channel on each used subcarrier whose mirror is also used
+    let channel: Vec<_> = (0..lts_len)
+        .map(|b| match (lts_fft[b], lts_fft[mirror(b)]) {
+            (Some(l), Some(_)) => Some(to_f64(long_fft[b] / l)),
+            _ => None,
+        })
+        .collect();
+
+    // Taps at the same delays as `resample_equalization`, followed by the mirror term
+    let first = -((cp_len / 2).min(lts_len / 2) as i64);
+    let delays: Vec<i64> = (first..first + cp_len.max(1).min(lts_len) as i64).collect();
+    let n = delays.len() + 1;
+    let mut gram = vec![vec![Complex::<f64>::zero(); n]; n];
+    let mut rhs = vec![Complex::<f64>::zero(); n];
+    for (b, x) in channel.iter().enumerate() {
+        if let (Some(x), Some(x_mirror)) = (x, channel[mirror(b)]) {
+            let (l, l_mirror) = (lts_fft[b].unwrap(), lts_fft[mirror(b)].unwrap());
+            let freq = f64::from(signed_bin(b, lts_len));
+            let mut row: Vec<_> = delays
+                .iter()
+                .map(|d| {
+                    let phase = 2. * std::f64::consts::PI * freq * *d as f64 / lts_len as f64;
+                    Complex::from_polar(&1., &phase)
+                })
+                .collect();
+            row.push(to_f64(l_mirror.conj() / l) * x_mirror.conj());
+            for i in 0..n {
+                for j in 0..n {
+                    gram[i][j] += row[i].conj() * row[j];
+                }
+                rhs[i] += row[i].conj() * x;
+            }
+        }
+    }
+    let ratio = solve_linear(gram, rhs)[n - 1];
+    IqImbalance::from_image_ratio(Complex::new(ratio.re as f32, ratio.im as f32))
+}
+
+/// Undo the IQ imbalance `imb` (e.g. from `estimate_iq_imbalance`) on `samps`
+pub fn correct_iq_imbalance(samps: &[Complex<f32>], imb: &IqImbalance) -> Vec<Complex<f32>> {
+    let (mu, nu) = imb.coefficients();
+    let det = mu.norm_sqr() - nu.norm_sqr();
+    samps
+        .iter()
+        .map(|r| (mu.conj() * r - nu * r.conj()) / det)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::build_packet;
+    use crate::lts_align::lts_align;
+    use crate::parse_80211::parse_80211_pkt;
+    use crate::sim::{add_awgn, apply_iq_imbalance, random_bpsk_symbols};
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_iq_imbalance() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        let lts_len = lts_fft.len();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let symbols = random_bpsk_symbols(&config, 10, &mut rng);
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize];
+        pkt.extend(build_packet(&config, &symbols));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * lts_len));
+        // An echo 3 samples late, so the channel isn't flat
+        let echo = Complex::new(0.4, 0.2);
+        for i in (3..pkt.len()).rev() {
+            pkt[i] = pkt[i] + echo * pkt[i - 3];
+        }
+        add_awgn(&mut pkt, 1e-8, &mut rng);
+
+        let imb = IqImbalance::new(1.03, 2f32.to_radians());
+        let received = apply_iq_imbalance(&pkt, &imb);
+
+        // Search only the preamble, as `parse_80211_pkt` does, so a data symbol can't win
+        let short_len = config.sts.as_ref().unwrap().len() * config.sts_repeats as usize;
+        let lts_bound = config.pkt_spacing as usize + short_len + 5 * lts_len / 2;
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+        let lts_start = lts_align(&received[..lts_bound], lts, corr_plans);
+        assert_eq!(lts_start, config.pkt_spacing as usize + short_len);
+        let long = &received[lts_start..lts_start + 5 * lts_len / 2];
+        let est = estimate_iq_imbalance(long, &config);
+        assert!((est.gain - imb.gain).abs() < 2e-3, "{:?}", est);
+        assert!((est.phase - imb.phase).abs() < 2e-3, "{:?}", est);
+
+        // No imbalance estimated on a balanced packet
+        let long = &pkt[lts_start..lts_start + 5 * lts_len / 2];
+        let est_none = estimate_iq_imbalance(long, &config);
+        assert!((est_none.gain - 1.).abs() < 2e-3, "{:?}", est_none);
+        assert!(est_none.phase.abs() < 2e-3, "{:?}", est_none);
+
+        // Correction restores the symbols the balanced packet parses to
+        let max_err = |samps: &[Complex<f32>]| {
+            let clean = parse_80211_pkt(&pkt, &config).unwrap();
+            let parsed = parse_80211_pkt(samps, &config).unwrap();
+            assert_eq!(parsed.len(), clean.len());
+            parsed
+                .iter()
+                .zip(&clean)
+                .map(|(x, y)| (x - y).norm())
+                .fold(0., f32::max)
+        };
+        assert!(max_err(&received) > 0.02);
+        assert!(max_err(&correct_iq_imbalance(&received, &est)) < 5e-3);
+    }
+}
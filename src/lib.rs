@@ -12,17 +12,52 @@
 //!
 //! Data Symbol
 //!  - <Cyclic Prefix> <Symbol>
-//!    The cyclic prefix is 1/4 the size of the symbol. In 802.11, the symbol is 64 samples long
+//!    The cyclic prefix is `ChannelEstConfig::cp_len` samples long, 1/4 the size of the symbol by
+//!    default. In 802.11, the symbol is 64 samples long
 
+pub mod agc;
+pub mod capture;
 pub mod cfo;
 pub mod config;
+pub mod dc_offset;
+pub mod decoder;
+pub mod demod;
 pub mod equalization;
+pub mod error;
+pub mod fec;
+pub mod fixed_point;
+pub mod interleaver;
+pub mod iq_imbalance;
 pub mod lts_align;
+pub mod mac;
+pub mod multichannel;
 pub mod parse_80211;
+pub mod pilots;
 pub mod pkt_trigger;
+pub mod resample;
+mod ring_buffer;
+pub mod sample;
+pub mod scrambler;
+pub mod signal;
+pub mod sim;
 
-pub use cfo::{correct_cfo, estimate_cfo};
-pub use equalization::{equalize_symbol, estimate_subcarrier_equalization};
-pub use lts_align::lts_align;
-pub use parse_80211::parse_80211_pkt;
-pub use pkt_trigger::PktTrigger;
+pub use cfo::{
+    cfo_hz_to_rad_per_samp, cfo_rad_per_samp_to_hz, correct_cfo, correct_cfo_in_place,
+    estimate_cfo, estimate_cfo_from_pilots, estimate_cfo_wide, CfoEstimate,
+};
+pub use decoder::{DecodeEvent, Decoder, ParsedEvent, StreamingParser};
+pub use equalization::{
+    equalize_symbol, estimate_impulse_response, estimate_snr, estimate_subcarrier_equalization,
+    estimate_subcarrier_noise, interpolate_nulled, resample_equalization, rms_delay_spread,
+    write_channel_csv, Equalizer, EqualizerKind,
+};
+pub use error::{ConfigError, Error, ParseError, Result};
+pub use iq_imbalance::{correct_iq_imbalance, estimate_iq_imbalance, IqImbalance};
+pub use lts_align::{
+    coarse_timing, fractional_timing, lts_align, lts_align_coarse, lts_align_repeats,
+    lts_align_with_confidence,
+};
+pub use parse_80211::{
+    parse_80211_pkt, parse_80211_pkt_detailed, parse_all, parse_detected, ParsedPacket,
+};
+pub use pkt_trigger::{DetectedPacket, PktTrigger, PreambleTrigger};
@@ -1,53 +1,468 @@
-use num::Complex;
+use crate::config::ChannelEstConfig;
+use crate::equalization::FftPlans;
+use crate::sample::Sample;
+use num::{Complex, Zero};
+use rustfft::FFTnum;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
-/// The the long-train sequence (LTS) to align the start of the packet. Returns the symbol index at
-/// which the packet starts. Takes the sequences in the packet and the known LTS. Note: Should only
-/// be called on a small portion of data that is known to contain the LTS. Providing more data may
-/// case spurious peaks
-pub fn lts_align(pkt: &[Complex<f32>], lts: &[Complex<f32>]) -> usize {
-    // Compute cross correlation with the known LTS
-    let mut corr = Vec::<f32>::with_capacity(pkt.len());
-    for i in 0..pkt.len() - lts.len() {
+/// `cross_correlation` switches to `cross_correlation_fft` for buffers at least this long. The
+/// direct method costs `pkt.len() * lts.len()` multiply-adds and the FFT method about three FFTs
+/// of the next power of two, so the FFT wins from around here for a 64-sample LTS
+pub const FFT_CORRELATION_MIN_LEN: usize = 2048;
+
+/// FFT plans for `cross_correlation_fft`, by length. Each length is planned the first time it is
+/// needed and then reused, including by clones. The config holds one next to the LTS, so every
+/// packet aligned with that config shares the plans
+#[derive(Clone)]
+pub struct CorrelationPlans<T: FFTnum = f32> {
+    plans: Arc<Mutex<HashMap<usize, FftPlans<T>>>>,
+}
+
+impl<T: FFTnum> CorrelationPlans<T> {
+    pub fn new() -> Self {
+        Self {
+            plans: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The plans for `len` samples, planning them if they haven't been yet
+    pub fn get(&self, len: usize) -> FftPlans<T> {
+        let mut plans = self.plans.lock().unwrap();
+        plans
+            .entry(len)
+            .or_insert_with(|| FftPlans::new(len))
+            .clone()
+    }
+}
+
+impl<T: FFTnum> Default for CorrelationPlans<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: FFTnum> fmt::Debug for CorrelationPlans<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lens: Vec<_> = self.plans.lock().unwrap().keys().cloned().collect();
+        lens.sort_unstable();
+        write!(f, "CorrelationPlans({:?})", lens)
+    }
+}
+
+/// Cross-correlation power `|sum_k conj(lts[k]) * pkt[i + k]|^2` for each valid `i`, by whichever
+/// of `cross_correlation_direct` and `cross_correlation_fft` is faster for `pkt`'s length. The
+/// FFT's plans come from `plans`
+pub fn cross_correlation<T: Sample>(
+    pkt: &[Complex<T>],
+    lts: &[Complex<T>],
+    plans: &CorrelationPlans<T>,
+) -> Vec<T> {
+    if pkt.len() >= FFT_CORRELATION_MIN_LEN {
+        cross_correlation_fft(pkt, lts, &plans.get(pkt.len().next_power_of_two()))
+    } else {
+        cross_correlation_direct(pkt, lts)
+    }
+}
+
+/// `cross_correlation` computed term by term
+pub fn cross_correlation_direct<T: Sample>(pkt: &[Complex<T>], lts: &[Complex<T>]) -> Vec<T> {
+    let mut corr = Vec::<T>::with_capacity(pkt.len());
+    for i in 0..=pkt.len() - lts.len() {
         corr.push(
             lts.iter()
                 .enumerate()
                 .map(|(k, l)| l.conj() * pkt[i + k])
-                .sum::<Complex<f32>>()
+                .sum::<Complex<T>>()
                 .norm_sqr(),
         );
     }
+    corr
+}
 
-    // To detect first of the two peaks, find argmax_i corr[i] * corr[i + lts.len()]
-    let (mut max, mut max_idx) = (0., 0);
-    for i in 0..pkt.len() - 2 * lts.len() {
-        let val = corr[i] * corr[i + lts.len()];
+/// `cross_correlation` computed as the inverse FFT of `FFT(pkt) * conj(FFT(lts))`, with both
+/// zero-padded to the next power of two. The correlation this gives is circular, but none of the
+/// outputs we keep wrap around as `lts` is shorter than `pkt`. `plans` are FFTs of that padded
+/// length
+pub fn cross_correlation_fft<T: Sample>(
+    pkt: &[Complex<T>],
+    lts: &[Complex<T>],
+    plans: &FftPlans<T>,
+) -> Vec<T> {
+    assert!(lts.len() <= pkt.len());
+    let n = pkt.len().next_power_of_two();
+    assert_eq!(plans.forward.len(), n);
+    assert_eq!(plans.inverse.len(), n);
+    let forward = &plans.forward;
+    let mut pkt_fft = vec![Complex::zero(); n];
+    let mut padded = pkt.to_vec();
+    padded.resize(n, Complex::zero());
+    forward.process(&mut padded, &mut pkt_fft);
+    let mut lts_fft = vec![Complex::zero(); n];
+    let mut padded = lts.to_vec();
+    padded.resize(n, Complex::zero());
+    forward.process(&mut padded, &mut lts_fft);
+
+    let mut product: Vec<_> = pkt_fft
+        .iter()
+        .zip(&lts_fft)
+        .map(|(p, l)| *p * l.conj())
+        .collect();
+    let mut corr = vec![Complex::zero(); n];
+    plans.inverse.process(&mut product, &mut corr);
+    let scale = T::from_config_f32(n as f32);
+    corr[..=pkt.len() - lts.len()]
+        .iter()
+        .map(|x| (*x / scale).norm_sqr())
+        .collect()
+}
+
+/// The the long-train sequence (LTS) to align the start of the packet. Returns the symbol index at
+/// which the packet starts. Takes the sequences in the packet and the known LTS. Note: Should only
+/// be called on a small portion of data that is known to contain the LTS. Providing more data may
+/// case spurious peaks.
+///
+/// The LTS may excite only part of the band (e.g. for sounding a sub-channel). Its correlation
+/// peak is then broader, but since the correlation integrates over the whole LTS, the peak stays
+/// at the right offset down to about 0 dB SNR per sample
+///
+/// If the peak is less than half an LTS from the start of `pkt`, there is no room for the guard
+/// interval before it and the returned start saturates to 0 rather than underflowing. Callers
+/// that need the short preamble before the LTS should check there is room for it (as
+/// `parse_80211_pkt` does)
+///
+/// Long buffers are correlated by FFT, with the plans in `plans` (e.g. the config's, see
+/// `CorrelationPlans`)
+pub fn lts_align<T: Sample>(
+    pkt: &[Complex<T>],
+    lts: &[Complex<T>],
+    plans: &CorrelationPlans<T>,
+) -> usize {
+    lts_align_repeats(pkt, lts, 2, plans)
+}
+
+/// Like `lts_align`, but for a long preamble with `num_repeats` back-to-back copies of the LTS
+/// (after the guard interval) instead of two. Requiring a correlation peak at every repeat
+/// sharpens the peak and makes alignment more robust to noise
+pub fn lts_align_repeats<T: Sample>(
+    pkt: &[Complex<T>],
+    lts: &[Complex<T>],
+    num_repeats: usize,
+    plans: &CorrelationPlans<T>,
+) -> usize {
+    align_repeats(pkt, lts, num_repeats, plans).0
+}
+
+/// Like `lts_align`, but also returns how confident we are that `pkt` contains an LTS: the
+/// winning `corr[i] * corr[i + lts.len()]` divided by its mean over all candidate `i`. A clean
+/// long preamble gives a confidence of 100 or more, whereas noise rarely exceeds 30. The exact
+/// values depend on how much longer than the long preamble `pkt` is
+pub fn lts_align_with_confidence<T: Sample>(
+    pkt: &[Complex<T>],
+    lts: &[Complex<T>],
+    plans: &CorrelationPlans<T>,
+) -> (usize, f32) {
+    align_repeats(pkt, lts, 2, plans)
+}
+
+/// Implements `lts_align_repeats`. Also returns the ratio of the peak to the mean of the product
+/// of correlations
+fn align_repeats<T: Sample>(
+    pkt: &[Complex<T>],
+    lts: &[Complex<T>],
+    num_repeats: usize,
+    plans: &CorrelationPlans<T>,
+) -> (usize, f32) {
+    assert!(num_repeats > 0);
+    // Compute cross correlation with the known LTS
+    let corr = cross_correlation(pkt, lts, plans);
+
+    // To detect first of the peaks, find argmax_i prod_r corr[i + r * lts.len()]. Multiply in
+    // f64 since the product of many correlations can overflow an f32
+    let (mut max, mut max_idx, mut sum) = (0., 0, 0.);
+    let num_candidates = pkt.len() - num_repeats * lts.len() + 1;
+    for i in 0..num_candidates {
+        let val = (0..num_repeats)
+            .map(|r| corr[i + r * lts.len()].to_f64().unwrap())
+            .product::<f64>();
+        sum += val;
         if val > max {
             max = val;
             max_idx = i;
         }
     }
+    let confidence = if sum > 0. {
+        (max * num_candidates as f64 / sum) as f32
+    } else {
+        0.
+    };
 
-    // Subtract config.lts.len() to account for the fact that a guard interval is present
+    // Subtract config.lts.len() to account for the fact that a guard interval is present. Saturate
+    // at 0 if there is no room for it (see `lts_align`)
     if lts.len() / 2 > max_idx {
-        dbg!("Warning: maximum came earlier than expected");
-        0
+        log::warn!(
+            "LTS peak at sample {}, too early for its guard interval",
+            max_idx
+        );
+        (0, confidence)
     } else {
-        max_idx - lts.len() / 2
+        (max_idx - lts.len() / 2, confidence)
     }
 }
 
+/// Index and value of the largest element of `vals`, the first if there are ties. `(0, 0.)` if
+/// none are positive
+fn argmax(vals: &[f32]) -> (usize, f32) {
+    vals.iter().enumerate().fold(
+        (0, 0.),
+        |(max_idx, max), (i, x)| if *x > max { (i, *x) } else { (max_idx, max) },
+    )
+}
+
+/// Coarse timing from the short preamble: the index in `samps` of the first sample after it,
+/// i.e. where the long preamble starts. It slides a window of `2 * sts_len` samples over the
+/// autocorrelation at a lag of one STS, `sum_k conj(x[n - k - sts_len]) x[n - k]`, as
+/// `PreambleTrigger` does. Its magnitude plateaus over the short preamble and, once the window
+/// passes the end of the preamble, falls linearly to the noise level whatever follows. So the end
+/// is half a window before the point where it falls to half the plateau.
+///
+/// Accurate to a few samples at moderate SNR, which is enough to narrow the search for the LTS
+/// (see `lts_align_coarse`). Returns `samps.len()` if the plateau doesn't end within `samps`
+pub fn coarse_timing(samps: &[Complex<f32>], config: &ChannelEstConfig) -> usize {
+    let lag = config.sts.as_ref().unwrap().len();
+    let window = 2 * lag;
+    let prods: Vec<_> = (0..samps.len())
+        .map(|n| match n.checked_sub(lag) {
+            Some(m) => samps[m].conj() * samps[n],
+            None => Complex::zero(),
+        })
+        .collect();
+    let mut corr = Complex::<f32>::zero();
+    let mags: Vec<f32> = (0..samps.len())
+        .map(|n| {
+            corr += prods[n];
+            if n >= window {
+                corr -= prods[n - window];
+            }
+            corr.norm()
+        })
+        .collect();
+
+    let (peak_idx, peak) = argmax(&mags);
+    let half = peak / 2.;
+    let below = match (peak_idx..mags.len()).find(|n| mags[*n] < half) {
+        Some(n) => n,
+        None => return samps.len(),
+    };
+    // Interpolate where it crosses half the plateau. The magnitude at `n` counts the products
+    // with `n - k < end` for `k < window`, so it is half the plateau at `end - 1 + window / 2`
+    let frac = (mags[below - 1] - half) / (mags[below - 1] - mags[below]);
+    let crossing = (below - 1) as f32 + frac;
+    (crossing + 1. - (window / 2) as f32).round().max(0.) as usize
+}
+
+/// Like `lts_align` on the first `samps.len()` samples, but only searches for the LTS within
+/// `lts.len() / 2` samples either side of where `coarse_timing` expects the long preamble. The
+/// narrower search is cheaper and less likely to lock on to a spurious peak. Falls back to
+/// searching all of `samps` if the coarse timing leaves too little room
+pub fn lts_align_coarse(samps: &[Complex<f32>], config: &ChannelEstConfig) -> usize {
+    let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+    let (long_len, margin) = (5 * lts.len() / 2, lts.len() / 2);
+    let coarse = coarse_timing(samps, config);
+    let start = coarse.saturating_sub(margin);
+    let end = (coarse + long_len + margin).min(samps.len());
+    if end < start + long_len {
+        return lts_align(samps, lts, corr_plans);
+    }
+    start + lts_align(&samps[start..end], lts, corr_plans)
+}
+
+/// Refine `lts_align` to a fraction of a sample: the delay (in samples) of the long preamble
+/// `long` (including its guard interval, starting where `lts_align` says) relative to where it
+/// was assumed to start. A delay of `d` rotates each subcarrier of the LTS copies by `2 pi b d /
+/// lts_len` for signed bin `b`, so it is measured from the average phase step between adjacent
+/// used subcarriers, over an LTS-length window in the middle of the preamble. A multipath channel
+/// adds its own (mean) delay. Pass the negative of the result to `resample::shift_fractional` to
+/// undo it
+pub fn fractional_timing(long: &[Complex<f32>], config: &ChannelEstConfig) -> f32 {
+    let (lts, lts_fft, plans, _) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+    assert_eq!(long.len(), 5 * lts_len / 2);
+
+    // One LTS-length window in the middle of the preamble, a quarter LTS later than the first
+    // copy. Being far from both ends, it doesn't pick up what precedes and follows the preamble,
+    // which a fractional delay smears into its ends
+    let offset = lts_len / 4;
+    let mut window = long[lts_len / 2 + offset..3 * lts_len / 2 + offset].to_vec();
+    let mut bins = vec![Complex::zero(); lts_len];
+    plans.inverse.process(&mut window, &mut bins);
+    let channel = |bin: usize| Some(bins[bin] / lts_fft[bin]?);
+    let mut sum = Complex::<f32>::zero();
+    for bin in 0..lts_len {
+        // The bin after the most positive frequency is the most negative one
+        let next = (bin + 1) % lts_len;
+        if next == lts_len / 2 {
+            continue;
+        }
+        if let (Some(h), Some(h_next)) = (channel(bin), channel(next)) {
+            sum += h_next * h.conj();
+        }
+    }
+    sum.arg() * lts_len as f32 / (2. * PI) + offset as f32
+}
+
+/// Find where a data symbol starts by matching its cyclic prefix against the end of the symbol.
+/// `symbol_with_cp` should contain the whole symbol (`cp_len + symbol_len` samples) starting
+/// somewhere in its first `symbol_with_cp.len() - cp_len - symbol_len + 1` samples. Returns the
+/// offset of the start of the cyclic prefix, so the FFT window starts `cp_len` samples later.
+///
+/// Every candidate offset is scored by the normalized correlation between the `cp_len` samples
+/// there and the `cp_len` samples one `symbol_len` later. This peaks where the cyclic prefix
+/// repeats exactly, which in a channel with a large delay spread may differ from where the
+/// preamble predicts
+pub fn optimal_symbol_offset(
+    symbol_with_cp: &[Complex<f32>],
+    cp_len: usize,
+    symbol_len: usize,
+) -> usize {
+    assert!(cp_len > 0);
+    assert!(symbol_with_cp.len() >= cp_len + symbol_len);
+    let (mut max, mut max_idx) = (0., 0);
+    for i in 0..=symbol_with_cp.len() - cp_len - symbol_len {
+        let cp = &symbol_with_cp[i..i + cp_len];
+        let tail = &symbol_with_cp[i + symbol_len..i + symbol_len + cp_len];
+        let corr = cp
+            .iter()
+            .zip(tail)
+            .map(|(c, t)| c * t.conj())
+            .sum::<Complex<f32>>()
+            .norm();
+        let energy = cp.iter().map(|x| x.norm_sqr()).sum::<f32>()
+            * tail.iter().map(|x| x.norm_sqr()).sum::<f32>();
+        if energy > 0. && corr / energy.sqrt() > max {
+            max = corr / energy.sqrt();
+            max_idx = i;
+        }
+    }
+    max_idx
+}
+
+/// Peak-to-sidelobe ratio (in dB) of the cross-correlation between `pkt` and the known `lts`. The
+/// main lobe is the highest correlation peak along with the `lts.len() / 16` samples on either
+/// side of it. Since the long preamble contains two back-to-back copies of the LTS, the same
+/// region one LTS length before and after the peak also counts as main lobe. A clean LTS gives a
+/// high ratio whereas noise gives a ratio close to 0 dB. Returns infinity if there are no
+/// sidelobes at all, including when `pkt` is silent
+pub fn correlation_pslr(
+    pkt: &[Complex<f32>],
+    lts: &[Complex<f32>],
+    plans: &CorrelationPlans,
+) -> f32 {
+    let corr = cross_correlation(pkt, lts, plans);
+    let guard = lts.len() / 16;
+
+    let (peak_idx, peak) = argmax(&corr);
+
+    let sidelobe = corr
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            let dist = (*i as i64 - peak_idx as i64).abs() as usize;
+            let dist_from_repeat = dist % lts.len();
+            let near_repeat = dist_from_repeat.min(lts.len() - dist_from_repeat) <= guard;
+            !(dist <= 2 * lts.len() && near_repeat)
+        })
+        .fold(0., |max: f32, (_, x)| max.max(*x));
+
+    if sidelobe == 0. {
+        return f32::INFINITY;
+    }
+    10. * (peak / sidelobe).log10()
+}
+
 #[cfg(test)]
 mod test {
-    use super::lts_align;
-    use crate::config::{filename_to_cplx_vec, ChannelEstConfig};
+    use super::{
+        coarse_timing, correlation_pslr, cross_correlation_direct, cross_correlation_fft,
+        fractional_timing, lts_align, lts_align_coarse, lts_align_repeats,
+        lts_align_with_confidence, optimal_symbol_offset, CorrelationPlans,
+    };
+    use crate::config::{build_packet, build_preamble, filename_to_cplx_vec, ChannelEstConfig};
+    use crate::equalization::{equalize_symbol, FftPlans};
+    use crate::parse_80211::parse_80211_pkt;
+    use crate::resample::shift_fractional;
+    use crate::sample::Sample;
+    use crate::sim::{add_awgn, modulate_symbol, random_bpsk_symbols};
     use num::{Complex, One, Zero};
+    use rand::{Rng, SeedableRng};
+    use std::f32::consts::PI;
 
     #[test]
     fn lts_align_example_pkt() {
-        let lts = filename_to_cplx_vec("data/lts-802.11.txt".to_string());
-        let pkt = filename_to_cplx_vec("data/example_pkt.txt".to_string());
+        let lts = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
+        let pkt = filename_to_cplx_vec("data/example_pkt.txt".to_string()).unwrap();
+
+        assert_eq!(
+            lts_align(&pkt[0..1400], &lts, &CorrelationPlans::new()),
+            171
+        );
+    }
+
+    #[test]
+    fn lts_align_f64() {
+        let lts = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
+        let pkt = filename_to_cplx_vec("data/example_pkt.txt".to_string()).unwrap();
+        let to_f64 = |v: &[Complex<f32>]| -> Vec<Complex<f64>> {
+            v.iter().map(|x| f64::from_cplx(*x)).collect()
+        };
+        let (lts_f64, pkt_f64) = (to_f64(&lts), to_f64(&pkt[0..1400]));
+        let (plans_f64, plans) = (CorrelationPlans::new(), CorrelationPlans::new());
+
+        assert_eq!(lts_align(&pkt_f64, &lts_f64, &plans_f64), 171);
+        for num_repeats in 1..3 {
+            assert_eq!(
+                lts_align_repeats(&pkt_f64, &lts_f64, num_repeats, &plans_f64),
+                lts_align_repeats(&pkt[0..1400], &lts, num_repeats, &plans)
+            );
+        }
+        let (start, confidence) = lts_align_with_confidence(&pkt_f64, &lts_f64, &plans_f64);
+        let (start_f32, confidence_f32) = lts_align_with_confidence(&pkt[0..1400], &lts, &plans);
+        assert_eq!(start, start_f32);
+        assert!((confidence - confidence_f32).abs() < 1e-3 * confidence);
+    }
+
+    #[test]
+    fn test_cross_correlation_fft() {
+        let lts = filename_to_cplx_vec("data/lts-802.11.txt".to_string()).unwrap();
+        let pkt = filename_to_cplx_vec("data/example_pkt.txt".to_string()).unwrap();
+        // Around the FFT threshold, at a power of two and just past one
+        for len in &[1400, 2048, 4096, 4097] {
+            let direct = cross_correlation_direct(&pkt[..*len], &lts);
+            let plans = FftPlans::new(len.next_power_of_two());
+            let fft = cross_correlation_fft(&pkt[..*len], &lts, &plans);
+            assert_eq!(fft.len(), direct.len());
+            let peak = direct.iter().fold(0., |max: f32, x| max.max(*x));
+            for (x, y) in fft.iter().zip(&direct) {
+                assert!((x - y).abs() < 1e-4 * peak, "{} {}", x, y);
+            }
+        }
 
-        assert_eq!(lts_align(&pkt[0..1400], &lts), 171);
+        // The FFT path (taken for long buffers) finds the same LTS. It plans the FFTs the first
+        // time and reuses them after
+        let plans = CorrelationPlans::new();
+        assert_eq!(lts_align(&pkt[..4096], &lts, &plans), 171);
+        assert_eq!(lts_align(&pkt[1..4097], &lts, &plans), 170);
+        assert_eq!(format!("{:?}", plans), "CorrelationPlans([4096])");
+        let to_f64 = |v: &[Complex<f32>]| -> Vec<Complex<f64>> {
+            v.iter().map(|x| f64::from_cplx(*x)).collect()
+        };
+        let plans_f64 = CorrelationPlans::new();
+        assert_eq!(
+            lts_align(&to_f64(&pkt[..4096]), &to_f64(&lts), &plans_f64),
+            171
+        );
     }
 
     #[test]
@@ -68,7 +483,7 @@ mod test {
 
         let real_start = pkt.len();
         // Add the long preamble
-        let lts = &config.lts.as_ref().unwrap().0;
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
         assert_eq!(lts.len() % 2, 0);
         pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() / 2));
         pkt.extend(lts);
@@ -87,6 +502,297 @@ mod test {
             .take(100),
         );
 
-        assert_eq!(lts_align(&pkt, &lts), real_start);
+        assert_eq!(lts_align(&pkt, &lts, corr_plans), real_start);
+    }
+
+    #[test]
+    fn test_coarse_timing() {
+        let config = ChannelEstConfig::default();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+        for lead in &[0, 7, 20, 45] {
+            // The preamble followed by random data, with a CFO and at 10 dB SNR
+            let mut pkt = vec![Complex::zero(); *lead];
+            pkt.extend(build_preamble(&config));
+            pkt.extend((0..200).map(|_| Complex::from_polar(&1., &rng.gen_range(0., 2. * PI))));
+            let mut pkt: Vec<_> = pkt
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x * Complex::new(0., 0.02 * i as f32).exp())
+                .collect();
+            add_awgn(&mut pkt, 0.1, &mut rng);
+
+            let truth = (lead + config.short_len()) as i64;
+            let coarse = coarse_timing(&pkt, &config) as i64;
+            assert!((coarse - truth).abs() <= 8, "{} {}", coarse, truth);
+            let fine = lts_align_coarse(&pkt, &config) as i64;
+            assert!((fine - truth).abs() <= 2, "{} {}", fine, truth);
+        }
+
+        // Without a short preamble there is no plateau to time from
+        assert_eq!(coarse_timing(&[Complex::zero(); 100], &config), 100);
+    }
+
+    #[test]
+    fn test_fractional_timing() {
+        let config = ChannelEstConfig::default();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+        let symbols = random_bpsk_symbols(&config, 6, &mut rng);
+        let sent: Vec<_> = symbols
+            .iter()
+            .flat_map(|bins| lts_fft.iter().zip(bins).filter_map(|(l, x)| l.map(|_| *x)))
+            .collect();
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_packet(&config, &symbols));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * lts.len()));
+
+        let timing = |buf: &[Complex<f32>]| {
+            let start = lts_align(&buf[..400], &lts, &config.lts.as_ref().unwrap().3);
+            fractional_timing(&buf[start..start + 5 * lts.len() / 2], &config)
+        };
+        assert!(timing(&pkt).abs() < 0.01);
+
+        // A bit under a third of a sample late, or early. `lts_align` doesn't move, so the
+        // delay is recovered with its sign
+        let advanced = shift_fractional(&pkt, -0.3);
+        let offset = timing(&advanced);
+        assert!((offset + 0.3).abs() < 0.01, "{}", offset);
+        let delayed = shift_fractional(&pkt, 0.3);
+        let offset = timing(&delayed);
+        assert!((offset - 0.3).abs() < 0.01, "{}", offset);
+
+        // Between samples, each symbol's FFT window picks up ringing from the neighbouring
+        // symbols. Back on the sample grid it doesn't
+        let error = |buf: &[Complex<f32>]| {
+            let parsed = parse_80211_pkt(buf, &config).unwrap();
+            assert_eq!(parsed.len(), sent.len());
+            parsed
+                .iter()
+                .zip(&sent)
+                .map(|(x, y)| (x - y).norm_sqr())
+                .sum::<f32>()
+                / sent.len() as f32
+        };
+        let before = error(&delayed);
+        let after = error(&shift_fractional(&delayed, -offset));
+        assert!(before > 1e-5, "{}", before);
+        assert!(after < 0.1 * before, "{} {}", after, before);
+    }
+
+    #[test]
+    fn test_correlation_pslr() {
+        let config = ChannelEstConfig::default();
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+
+        // A clean long preamble surrounded by silence
+        let mut pkt = vec![Complex::zero(); 100 + lts.len() / 2];
+        pkt.extend(lts);
+        pkt.extend(lts);
+        pkt.extend(std::iter::repeat(Complex::zero()).take(100));
+        assert!(correlation_pslr(&pkt, lts, corr_plans) > 10.);
+
+        // Pure noise has no distinguishable peak
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut noise = vec![Complex::zero(); 400];
+        add_awgn(&mut noise, 1., &mut rng);
+        assert!(correlation_pslr(&noise, lts, corr_plans) < 3.);
+
+        // Silence has no sidelobes, nor a peak
+        let silence = vec![Complex::zero(); 400];
+        assert_eq!(correlation_pslr(&silence, lts, corr_plans), f32::INFINITY);
+    }
+
+    #[test]
+    fn test_optimal_symbol_offset() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        let (cp_len, symbol_len) = (config.cp_len, lts_fft.len());
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let symbols = random_bpsk_symbols(&config, 3, &mut rng);
+
+        // The symbol we want sits between two others and starts `shift` samples into the buffer
+        let bins = &symbols[0];
+        let data: Vec<_> = bins
+            .iter()
+            .zip(lts_fft)
+            .filter_map(|(x, l)| l.map(|_| *x))
+            .collect();
+        let shift = 11;
+        let prev = modulate_symbol(&symbols[1], cp_len);
+        let mut samps = prev[prev.len() - shift..].to_vec();
+        samps.extend(modulate_symbol(bins, cp_len));
+        samps.extend(modulate_symbol(&symbols[2], cp_len));
+        add_awgn(&mut samps, 0.01, &mut rng);
+        let samps = &samps[..cp_len + symbol_len + 2 * shift];
+
+        let offset = optimal_symbol_offset(samps, cp_len, symbol_len);
+        assert_eq!(offset, shift);
+
+        // The symbol decodes best with the FFT window placed there
+        let no_equalization = vec![Some(Complex::one()); symbol_len];
+        let error = |start: usize| {
+            equalize_symbol(&samps[start..start + symbol_len], &no_equalization)
+                .iter()
+                .zip(lts_fft)
+                .filter(|(_, l)| l.is_some())
+                .zip(&data)
+                .map(|((x, _), y)| (x - y).norm_sqr())
+                .sum::<f32>()
+        };
+        let best = error(offset + cp_len);
+        assert!(best < 0.1 * data.len() as f32);
+        for start in 0..=samps.len() - symbol_len {
+            if start != offset + cp_len {
+                assert!(error(start) > best);
+            }
+        }
+    }
+
+    /// An LTS that excites only 12 contiguous subcarriers, as used for sounding a sub-channel
+    #[test]
+    fn test_narrowband_lts() {
+        use crate::cfo::{correct_cfo, estimate_cfo};
+        use crate::config::{cplx_vec_to_file, read_lts};
+        use crate::equalization::estimate_subcarrier_equalization;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let excited = 4..16;
+        let bins: Vec<_> = (0..64)
+            .map(|i| match i {
+                i if excited.contains(&i) && rng.gen() => Complex::one(),
+                i if excited.contains(&i) => -Complex::one(),
+                _ => Complex::zero(),
+            })
+            .collect();
+        let path = std::env::temp_dir().join("channel_est_test_narrowband_lts.txt");
+        cplx_vec_to_file(&path, &modulate_symbol(&bins, 0)).unwrap();
+        let mut config = ChannelEstConfig::default();
+        config.lts = read_lts(Some(path.to_str().unwrap().to_string())).unwrap();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        for (i, x) in config.lts.as_ref().unwrap().1.iter().enumerate() {
+            assert_eq!(x.is_some(), excited.contains(&i));
+        }
+
+        // Preamble through a two-tap channel, with CFO and noise
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_preamble(&config));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(100));
+        let cfo = 0.02;
+        let mut pkt: Vec<_> = (0..pkt.len())
+            .map(|i| {
+                let echo = if i >= 2 {
+                    pkt[i - 2] * 0.3
+                } else {
+                    Complex::zero()
+                };
+                (pkt[i] + echo) * Complex::new(0., cfo * i as f32).exp()
+            })
+            .collect();
+        add_awgn(&mut pkt, 0.01, &mut rng);
+
+        let short_len = config.short_len();
+        let lts_bound = config.pkt_spacing as usize + short_len + 5 * lts.len() / 2;
+        let corr_plans = &config.lts.as_ref().unwrap().3;
+        let lts_start = lts_align(&pkt[..lts_bound], &lts, corr_plans);
+        assert_eq!(lts_start, config.pkt_spacing as usize - 1 + short_len);
+
+        let short = &pkt[lts_start - short_len..lts_start];
+        let long = &pkt[lts_start..lts_start + 5 * lts.len() / 2];
+        let est_cfo = estimate_cfo(short, long, &config);
+        assert!((est_cfo - cfo).abs() < 1e-3);
+
+        // The equalization inverts the channel on the excited subcarriers
+        let long = correct_cfo(long, est_cfo);
+        let equalization = estimate_subcarrier_equalization(&long, &config);
+        for (i, e) in equalization.iter().enumerate() {
+            assert_eq!(e.is_some(), excited.contains(&i));
+            if let Some(e) = e {
+                let echo_phase = 2. * PI * 2. * i as f32 / 64.;
+                let h = Complex::<f32>::one() + Complex::new(0., echo_phase).exp() * 0.3;
+                assert!(((e * h).norm() - 1.).abs() < 0.1);
+            }
+        }
+    }
+
+    /// At low SNR, four repeats of the LTS align more reliably than two
+    #[test]
+    fn test_lts_align_repeats() {
+        let config = ChannelEstConfig::default();
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        let num_trials = 100;
+        let num_correct = |num_repeats: usize, rng: &mut rand::rngs::StdRng| {
+            (0..num_trials)
+                .filter(|_| {
+                    let mut pkt = vec![Complex::zero(); 40 + lts.len() / 2];
+                    pkt.extend(lts.iter().cycle().take(num_repeats * lts.len()));
+                    pkt.extend(std::iter::repeat(Complex::zero()).take(40));
+                    // -12 dB SNR
+                    add_awgn(&mut pkt, 10f32.powf(1.2), rng);
+                    lts_align_repeats(&pkt, lts, num_repeats, corr_plans) == 40
+                })
+                .count()
+        };
+        let correct_2 = num_correct(2, &mut rng);
+        let correct_4 = num_correct(4, &mut rng);
+        assert!(correct_4 > correct_2);
+        assert!(correct_4 >= 3 * num_trials / 4);
+
+        // With two repeats it is the same as `lts_align`
+        let mut pkt = vec![Complex::zero(); 40 + lts.len() / 2];
+        pkt.extend(lts.iter().cycle().take(2 * lts.len()));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(40));
+        add_awgn(&mut pkt, 0.1, &mut rng);
+        assert_eq!(
+            lts_align_repeats(&pkt, lts, 2, corr_plans),
+            lts_align(&pkt, lts, corr_plans)
+        );
+    }
+
+    /// A buffer that starts right at the LTS, with no guard interval before it
+    #[test]
+    fn test_early_peak_saturates() {
+        let config = ChannelEstConfig::default();
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+
+        let mut pkt = vec![Complex::zero(); 3];
+        pkt.extend(lts);
+        pkt.extend(lts);
+        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len()));
+        assert_eq!(lts_align(&pkt, lts, corr_plans), 0);
+        assert_eq!(lts_align_repeats(&pkt, lts, 2, corr_plans), 0);
+
+        // Just enough room for the guard interval
+        let mut pkt = vec![Complex::zero(); lts.len() / 2 + 1];
+        pkt.extend(lts);
+        pkt.extend(lts);
+        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len()));
+        assert_eq!(lts_align(&pkt, lts, corr_plans), 1);
+    }
+
+    #[test]
+    fn test_alignment_confidence() {
+        let config = ChannelEstConfig::default();
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+
+        let mut pkt = build_preamble(&config);
+        let lts_start = config.short_len();
+        pkt.extend(std::iter::repeat(Complex::zero()).take(50));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        add_awgn(&mut pkt, 1e-3, &mut rng);
+        let (start, confidence) = lts_align_with_confidence(&pkt, lts, corr_plans);
+        assert_eq!(start, lts_start);
+        assert!(confidence > 100., "{}", confidence);
+
+        // Pure noise
+        for _ in 0..10 {
+            let noise: Vec<_> = (0..pkt.len())
+                .map(|_| Complex::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)))
+                .collect();
+            let (_, confidence) = lts_align_with_confidence(&noise, lts, corr_plans);
+            assert!(confidence < 50., "{}", confidence);
+        }
     }
 }
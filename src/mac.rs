@@ -0,0 +1,151 @@
+//! Checks on and parsing of decoded 802.11 MAC frames (i.e. the PSDU, after descrambling and FEC
+//! decoding)
+
+use crate::error::ParseError;
+use flate2::Crc;
+
+/// Length (in bytes) of the frame check sequence at the end of every MAC frame
+pub const FCS_LEN: usize = 4;
+/// Length (in bytes) of the MAC header fields common to data and management frames
+pub const MAC_HEADER_LEN: usize = 24;
+
+/// The fields at the start of a MAC frame. Which device each address is depends on the frame
+/// type, but for management frames they are the receiver, transmitter and BSSID
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacHeader {
+    pub frame_control: u16,
+    /// Time (in microseconds) the medium is reserved for
+    pub duration: u16,
+    pub addr1: [u8; 6],
+    pub addr2: [u8; 6],
+    pub addr3: [u8; 6],
+    /// Sequence number (upper 12 bits) and fragment number (lower 4 bits)
+    pub seq_ctrl: u16,
+}
+
+/// Parse the first `MAC_HEADER_LEN` bytes of `frame`. The 16-bit fields are little-endian, as
+/// transmitted. Returns an error if the frame is too short
+pub fn parse_mac_header(frame: &[u8]) -> Result<MacHeader, ParseError> {
+    if frame.len() < MAC_HEADER_LEN {
+        return Err(ParseError::FrameTooShort {
+            len: frame.len(),
+            needed: MAC_HEADER_LEN,
+        });
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([frame[i], frame[i + 1]]);
+    let addr_at = |i: usize| {
+        let mut addr = [0; 6];
+        addr.copy_from_slice(&frame[i..i + 6]);
+        addr
+    };
+    Ok(MacHeader {
+        frame_control: u16_at(0),
+        duration: u16_at(2),
+        addr1: addr_at(4),
+        addr2: addr_at(10),
+        addr3: addr_at(16),
+        seq_ctrl: u16_at(22),
+    })
+}
+
+/// The 802.11 frame check sequence of `data`: the CRC-32 of Ethernet (and gzip), i.e. polynomial
+/// 0x04c11db7, bit-reversed, starting from all ones and inverted at the end
+pub fn compute_fcs(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+/// Whether the last `FCS_LEN` bytes of `frame` (least significant byte first, as transmitted)
+/// are the FCS of the rest. Frames too short to have an FCS fail
+pub fn check_fcs(frame: &[u8]) -> bool {
+    if frame.len() < FCS_LEN {
+        return false;
+    }
+    let (data, fcs) = frame.split_at(frame.len() - FCS_LEN);
+    fcs == compute_fcs(data).to_le_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_fcs, compute_fcs, parse_mac_header, MacHeader, MAC_HEADER_LEN};
+    use crate::error::ParseError;
+
+    #[test]
+    fn test_compute_fcs() {
+        // The standard check value of CRC-32
+        assert_eq!(compute_fcs(b"123456789"), 0xcbf4_3926);
+        assert_eq!(compute_fcs(&[]), 0);
+    }
+
+    #[test]
+    fn test_check_fcs() {
+        // Start of a beacon: frame control, duration, addresses, sequence control, timestamp,
+        // interval, capabilities and an SSID element ("test")
+        let mut frame = vec![
+            0x80, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x11, 0x22, 0x33,
+            0x44, 0x55, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x10, 0x00, 0x01, 0x02, 0x03, 0x04,
+            0x05, 0x06, 0x07, 0x08, 0x64, 0x00, 0x01, 0x04, 0x00, 0x04, b't', b'e', b's', b't',
+        ];
+        let fcs = compute_fcs(&frame);
+        frame.extend(&fcs.to_le_bytes());
+        assert!(check_fcs(&frame));
+
+        // Any flipped bit, in the data or the FCS, is caught
+        for i in &[0, 17, frame.len() - 1] {
+            let mut corrupted = frame.clone();
+            corrupted[*i] ^= 0x10;
+            assert!(!check_fcs(&corrupted));
+        }
+        assert!(!check_fcs(&frame[..3]));
+    }
+
+    /// The 100 byte data frame of the 802.11 OFDM PHY's worked example (Annex L of IEEE
+    /// 802.11-2012), with the FCS bytes as the standard lists them
+    #[test]
+    fn test_standard_example_frame() {
+        let mut frame = vec![
+            0x04, 0x02, 0x00, 0x2e, 0x00, 0x60, 0x08, 0xcd, 0x37, 0xa6, 0x00, 0x20, 0xd6, 0x01,
+            0x3c, 0xf1, 0x00, 0x60, 0x08, 0xad, 0x3b, 0xaf, 0x00, 0x00,
+        ];
+        frame.extend(b"Joy, bright spark of divinity,\nDaughter of Elysium,\nFire-insired we trea");
+        frame.extend(&[0x67, 0x33, 0x21, 0xb6]);
+        assert_eq!(frame.len(), 100);
+        assert!(check_fcs(&frame));
+
+        let header = parse_mac_header(&frame).unwrap();
+        assert_eq!(header.addr1, [0x00, 0x60, 0x08, 0xcd, 0x37, 0xa6]);
+        assert_eq!(header.addr2, [0x00, 0x20, 0xd6, 0x01, 0x3c, 0xf1]);
+        assert_eq!(header.addr3, [0x00, 0x60, 0x08, 0xad, 0x3b, 0xaf]);
+    }
+
+    #[test]
+    fn test_parse_mac_header() {
+        // A probe response with a 314 microsecond duration, sequence number 291, fragment 4
+        let mut frame = vec![0x50, 0x00, 0x3a, 0x01];
+        frame.extend(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        frame.extend(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        frame.extend(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x66]);
+        frame.extend(&[0x34, 0x12]);
+        frame.extend(&[0xaa; 10]);
+        assert_eq!(
+            parse_mac_header(&frame).unwrap(),
+            MacHeader {
+                frame_control: 0x0050,
+                duration: 314,
+                addr1: [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+                addr2: [0x00, 0x11, 0x22, 0x33, 0x44, 0x55],
+                addr3: [0x00, 0x11, 0x22, 0x33, 0x44, 0x66],
+                seq_ctrl: 0x1234,
+            }
+        );
+
+        assert_eq!(
+            parse_mac_header(&frame[..MAC_HEADER_LEN - 1]),
+            Err(ParseError::FrameTooShort {
+                len: MAC_HEADER_LEN - 1,
+                needed: MAC_HEADER_LEN
+            })
+        );
+    }
+}
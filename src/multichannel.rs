@@ -0,0 +1,428 @@
+//! Processing for captures from multiple synchronized receivers (e.g. one per antenna). Packet
+//! detection and timing is done on a reference channel (channel 0) and the same timing is applied
+//! to the other channels. Since the receivers share a clock, they also share the CFO estimated on
+//! the reference channel. Each channel gets its own equalization.
+
+use crate::cfo::{correct_cfo, estimate_cfo};
+use crate::config::ChannelEstConfig;
+use crate::equalization::Equalizer;
+use crate::lts_align::lts_align;
+use crate::pkt_trigger::PktTrigger;
+use num::{Complex, Zero};
+use rustfft::FFTplanner;
+use std::collections::VecDeque;
+
+/// Runs `PktTrigger` on the reference channel and returns the same span of samples from every
+/// channel when a packet is detected
+pub struct MultiChannelTrigger {
+    trigger: PktTrigger,
+    /// History of every channel other than the reference. Always at-least as long as the
+    /// trigger's internal history
+    hist: Vec<VecDeque<Complex<f32>>>,
+    pkt_spacing: usize,
+}
+
+impl MultiChannelTrigger {
+    pub fn new(config: &ChannelEstConfig, num_channels: usize) -> Self {
+        assert!(num_channels >= 1);
+        Self {
+            trigger: PktTrigger::new(config),
+            hist: vec![VecDeque::new(); num_channels - 1],
+            pkt_spacing: config.pkt_spacing as usize,
+        }
+    }
+
+    /// Takes in one sample per channel (`samps[0]` is the reference) and returns one buffer per
+    /// channel if a packet was detected. The buffers are all of the same length and cover the
+    /// same time span
+    pub fn push_samps(&mut self, samps: &[Complex<f32>]) -> Option<Vec<Vec<Complex<f32>>>> {
+        assert_eq!(samps.len(), self.hist.len() + 1);
+        for (hist, samp) in self.hist.iter_mut().zip(&samps[1..]) {
+            hist.push_back(*samp);
+        }
+
+        match self.trigger.push_samp(samps[0]) {
+            Some(pkt) => {
+                let reference = pkt.samples;
+                let len = reference.len();
+                let mut res = Vec::with_capacity(samps.len());
+                res.push(reference);
+                for hist in &mut self.hist {
+                    assert!(hist.len() >= len);
+                    res.push(hist.iter().skip(hist.len() - len).copied().collect());
+                    while hist.len() > self.pkt_spacing {
+                        hist.pop_front();
+                    }
+                }
+                Some(res)
+            }
+            None => {
+                // Outside a packet, the trigger never keeps more than this many samples
+                if !self.trigger.in_packet() {
+                    for hist in &mut self.hist {
+                        while hist.len() > self.pkt_spacing + 1 {
+                            hist.pop_front();
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Channel estimates for one packet received on multiple channels
+#[derive(Clone, Debug)]
+pub struct MultiChannelEstimate {
+    /// Index (same for all channels) at which the long preamble starts
+    pub lts_start: usize,
+    /// CFO (in radians per sample) estimated on the reference channel
+    pub cfo: f32,
+    /// Equalization for each channel, as returned by `config.equalizer`
+    pub equalization: Vec<Vec<Option<Complex<f32>>>>,
+}
+
+/// Estimate the channel on every channel of a packet (e.g. as returned by `MultiChannelTrigger`).
+/// `pkts[0]` is the reference used for timing and CFO. Assumes the packet starts within the first
+/// ChannelEstConfig::pkt_spacing samples
+pub fn estimate_multichannel(
+    pkts: &[Vec<Complex<f32>>],
+    config: &ChannelEstConfig,
+) -> MultiChannelEstimate {
+    assert!(!pkts.is_empty());
+    let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+    let short_len = config.short_len();
+    for pkt in pkts {
+        assert_eq!(pkt.len(), pkts[0].len());
+    }
+
+    // Align using the reference channel. Leave lts_len / 2 samples of margin
+    let lts_bound = (config.pkt_spacing as usize + short_len + 3 * lts_len).min(pkts[0].len());
+    let lts_start = lts_align(&pkts[0][..lts_bound], lts, corr_plans);
+
+    let short = &pkts[0][lts_start - short_len..lts_start];
+    let long = &pkts[0][lts_start..lts_start + 5 * lts_len / 2];
+    let cfo = estimate_cfo(short, long, config);
+
+    let equalization = pkts
+        .iter()
+        .map(|pkt| {
+            let long = correct_cfo(&pkt[lts_start..lts_start + 5 * lts_len / 2], cfo);
+            config.equalizer.estimate(&long, config)
+        })
+        .collect();
+
+    MultiChannelEstimate {
+        lts_start,
+        cfo,
+        equalization,
+    }
+}
+
+/// Maximal-ratio combine equalized symbols received on several antennas. `symbols_per_antenna[a]`
+/// are the equalized symbols from antenna `a` (e.g. from `equalize_symbol`) and
+/// `snr_per_antenna[a]` the corresponding linear (not dB) SNR of each symbol's subcarrier. Since
+/// the symbols are already equalized, weighting each one by its SNR maximizes the SNR of the
+/// combination. Subcarriers whose SNR is `None` are ignored. If no antenna has an SNR for a
+/// subcarrier, the antennas are averaged
+pub fn mrc_combine(
+    symbols_per_antenna: &[Vec<Complex<f32>>],
+    snr_per_antenna: &[Vec<Option<f32>>],
+) -> Vec<Complex<f32>> {
+    assert!(!symbols_per_antenna.is_empty());
+    assert_eq!(symbols_per_antenna.len(), snr_per_antenna.len());
+    let num_symbols = symbols_per_antenna[0].len();
+    for (symbols, snr) in symbols_per_antenna.iter().zip(snr_per_antenna) {
+        assert_eq!(symbols.len(), num_symbols);
+        assert_eq!(snr.len(), num_symbols);
+    }
+
+    (0..num_symbols)
+        .map(|i| {
+            let (sum, weight) = symbols_per_antenna.iter().zip(snr_per_antenna).fold(
+                (Complex::zero(), 0.),
+                |(sum, weight), (symbols, snr)| match snr[i] {
+                    Some(snr) => (sum + symbols[i] * snr, weight + snr),
+                    None => (sum, weight),
+                },
+            );
+            if weight > 0. {
+                sum / weight
+            } else {
+                symbols_per_antenna
+                    .iter()
+                    .map(|s| s[i])
+                    .sum::<Complex<f32>>()
+                    / symbols_per_antenna.len() as f32
+            }
+        })
+        .collect()
+}
+
+/// A 2x2 complex matrix, indexed `[row][column]`
+pub type Matrix2 = [[Complex<f32>; 2]; 2];
+
+/// Inverse of a 2x2 matrix, or `None` if it is (numerically) singular
+fn invert_2x2(m: &Matrix2) -> Option<Matrix2> {
+    let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+    let scale: f32 = m.iter().flatten().map(|x| x.norm_sqr()).sum();
+    if det.norm_sqr() <= 1e-12 * scale * scale {
+        return None;
+    }
+    let inv_det = det.inv();
+    Some([
+        [m[1][1] * inv_det, -m[0][1] * inv_det],
+        [-m[1][0] * inv_det, m[0][0] * inv_det],
+    ])
+}
+
+/// Estimate the 2x2 MIMO equalization from the (CFO corrected) long preambles received on two
+/// antennas, `long[rx]`, each including its guard interval. Transmit antenna 0 sends the LTS
+/// twice and antenna 1 sends it with the second copy negated (as 802.11n does with its HT-LTFs),
+/// so the sum of the two copies sees only antenna 0 and their difference only antenna 1. Since
+/// antenna 1's second copy isn't preceded by its own cyclic extension, the delay spread should be
+/// small compared to a sample. Returns the inverse of the channel matrix `H[rx][tx]` for each FFT
+/// bin, with `None` where the subcarrier is unused or the channel can't be inverted
+pub fn estimate_mimo_equalization(
+    long: [&[Complex<f32>]; 2],
+    config: &ChannelEstConfig,
+) -> Vec<Option<Matrix2>> {
+    let (lts, lts_fft, plans, _) = config.lts.as_ref().unwrap();
+    let lts_len = lts.len();
+
+    // FFT of the sum and difference of the two LTS copies on each receive antenna
+    let fft = |mut samps: Vec<Complex<f32>>| {
+        let mut res = vec![Complex::zero(); lts_len];
+        plans.inverse.process(&mut samps, &mut res);
+        res
+    };
+    let (sums, diffs): (Vec<_>, Vec<_>) = long
+        .iter()
+        .map(|long| {
+            assert_eq!(long.len(), 5 * lts_len / 2);
+            let (first, second) = long[lts_len / 2..].split_at(lts_len);
+            let sum = first
+                .iter()
+                .zip(second)
+                .map(|(a, b)| (a + b) / 2.)
+                .collect();
+            let diff = first
+                .iter()
+                .zip(second)
+                .map(|(a, b)| (a - b) / 2.)
+                .collect();
+            (fft(sum), fft(diff))
+        })
+        .unzip();
+
+    lts_fft
+        .iter()
+        .enumerate()
+        .map(|(bin, l)| {
+            l.and_then(|l| {
+                let channel = [
+                    [sums[0][bin] / l, diffs[0][bin] / l],
+                    [sums[1][bin] / l, diffs[1][bin] / l],
+                ];
+                invert_2x2(&channel)
+            })
+        })
+        .collect()
+}
+
+/// Take the IFFT of a symbol received on each of two antennas and separate the two transmitted
+/// streams using the given equalization (from `estimate_mimo_equalization`). Returns the symbols
+/// of each stream, with as many symbols as there are `Some` values in `equalization`
+pub fn equalize_symbol_mimo(
+    samps: [&[Complex<f32>]; 2],
+    equalization: &[Option<Matrix2>],
+) -> [Vec<Complex<f32>>; 2] {
+    let len = equalization.len();
+    let ifft_plan = FFTplanner::new(true).plan_fft(len);
+    let mut bins = [vec![Complex::zero(); len], vec![Complex::zero(); len]];
+    for (samps, bins) in samps.iter().zip(bins.iter_mut()) {
+        assert_eq!(samps.len(), len);
+        ifft_plan.process(&mut samps.to_vec(), bins);
+    }
+
+    let mut res = [Vec::new(), Vec::new()];
+    for (i, eq) in equalization.iter().enumerate() {
+        if let Some(eq) = eq {
+            for (stream, row) in res.iter_mut().zip(eq) {
+                stream.push((row[0] * bins[0][i] + row[1] * bins[1][i]) / len as f32);
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::build_preamble;
+    use crate::sim::add_awgn;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_mimo_equalization() {
+        let config = ChannelEstConfig::default();
+        let (lts, lts_fft, _, _) = config.lts.as_ref().unwrap();
+        let lts_len = lts.len();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+
+        // Each transmit antenna sends the long preamble (with the second LTS copy negated on
+        // antenna 1) followed by a data symbol of random QPSK values on the used subcarriers
+        let mut data = Vec::new();
+        let tx: Vec<Vec<_>> = [1f32, -1.]
+            .iter()
+            .map(|sign| {
+                let mut samps = lts[lts_len / 2..].to_vec();
+                samps.extend(lts);
+                samps.extend(lts.iter().map(|x| x * sign));
+                let bins: Vec<_> = lts_fft
+                    .iter()
+                    .map(|l| match l {
+                        Some(_) => Complex::new(
+                            if rng.gen() { 1. } else { -1. },
+                            if rng.gen() { 1. } else { -1. },
+                        ),
+                        None => Complex::zero(),
+                    })
+                    .collect();
+                samps.extend(crate::sim::modulate_symbol(&bins, config.cp_len));
+                data.push(bins);
+                samps
+            })
+            .collect();
+
+        // Mix the two through a 2x2 channel
+        let channel = [
+            [Complex::new(0.9, 0.2), Complex::new(0.3, -0.5)],
+            [Complex::new(-0.4, 0.3), Complex::new(0.1, 0.8)],
+        ];
+        let rx: Vec<Vec<_>> = channel
+            .iter()
+            .map(|row| {
+                let mut samps: Vec<_> = tx[0]
+                    .iter()
+                    .zip(&tx[1])
+                    .map(|(a, b)| row[0] * a + row[1] * b)
+                    .collect();
+                add_awgn(&mut samps, 1e-6, &mut rng);
+                samps
+            })
+            .collect();
+
+        let long_len = 5 * lts_len / 2;
+        let eq = estimate_mimo_equalization([&rx[0][..long_len], &rx[1][..long_len]], &config);
+        assert_eq!(eq.len(), lts_len);
+        assert_eq!(eq.iter().filter(|e| e.is_some()).count(), 52);
+
+        let symbol_start = long_len + config.cp_len;
+        let symbol = symbol_start..symbol_start + lts_len;
+        let streams = equalize_symbol_mimo([&rx[0][symbol.clone()], &rx[1][symbol]], &eq);
+        for (stream, bins) in streams.iter().zip(&data) {
+            let expected: Vec<_> = bins
+                .iter()
+                .zip(lts_fft)
+                .filter_map(|(x, l)| l.map(|_| *x))
+                .collect();
+            assert_eq!(stream.len(), expected.len());
+            for (x, y) in stream.iter().zip(&expected) {
+                assert!((x - y).norm() < 1e-2, "{} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_two_channels() {
+        let config = ChannelEstConfig::default();
+        let lts = &config.lts.as_ref().unwrap().0;
+
+        // The reference channel. Silence, preamble with some CFO and silence
+        let cfo = 0.01;
+        let mut pkt = vec![Complex::zero(); 200];
+        pkt.extend(build_preamble(&config));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * config.pkt_spacing as usize));
+        let pkt: Vec<_> = pkt
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * Complex::new(0., cfo * i as f32).exp())
+            .collect();
+        // The second channel sees a weaker, phase-shifted version of the same packet
+        let gain = Complex::from_polar(&0.5f32, &0.3);
+        let pkt2: Vec<_> = pkt.iter().map(|s| s * gain).collect();
+
+        let mut trigger = MultiChannelTrigger::new(&config, 2);
+        let detected: Vec<_> = pkt
+            .iter()
+            .zip(&pkt2)
+            .filter_map(|(a, b)| trigger.push_samps(&[*a, *b]))
+            .collect();
+        assert_eq!(detected.len(), 1);
+        let detected = &detected[0];
+        assert_eq!(detected.len(), 2);
+        assert_eq!(detected[0].len(), detected[1].len());
+
+        let est = estimate_multichannel(detected, &config);
+        assert!((est.cfo - cfo).abs() < 1e-3);
+        assert_eq!(est.equalization.len(), 2);
+        // Same timing in both channels
+        let long = &detected[1][est.lts_start..est.lts_start + 5 * lts.len() / 2];
+        let offset = 200 + config.short_len() - est.lts_start;
+        for (i, x) in long.iter().enumerate() {
+            assert!((x - pkt2[offset + est.lts_start + i]).norm() < 1e-6);
+        }
+
+        // The second channel's equalization should undo its gain relative to the first
+        for (e1, e2) in est.equalization[0].iter().zip(&est.equalization[1]) {
+            assert_eq!(e1.is_some(), e2.is_some());
+            if let (Some(e1), Some(e2)) = (e1, e2) {
+                assert!((e2 * gain - e1).norm() < 1e-2 * e1.norm());
+            }
+        }
+    }
+
+    #[test]
+    fn test_mrc_combine() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let symbols: Vec<_> = (0..1000)
+            .map(|_| match rng.gen() {
+                true => Complex::new(1., 0.),
+                false => Complex::new(-1., 0.),
+            })
+            .collect();
+
+        // Two antennas with different noise levels
+        let noise_vars = [0.1, 0.2];
+        let mut symbols_per_antenna = Vec::new();
+        let mut snr_per_antenna = Vec::new();
+        for noise_var in &noise_vars {
+            let mut noisy = symbols.clone();
+            add_awgn(&mut noisy, *noise_var, &mut rng);
+            symbols_per_antenna.push(noisy);
+            snr_per_antenna.push(vec![Some(1. / noise_var); symbols.len()]);
+        }
+
+        let mse = |x: &[Complex<f32>]| {
+            x.iter()
+                .zip(&symbols)
+                .map(|(x, y)| (x - y).norm_sqr())
+                .sum::<f32>()
+                / symbols.len() as f32
+        };
+        let combined = mrc_combine(&symbols_per_antenna, &snr_per_antenna);
+        assert!(mse(&combined) < mse(&symbols_per_antenna[0]));
+        assert!(mse(&combined) < mse(&symbols_per_antenna[1]));
+        // Theoretical value is 1 / (1 / 0.1 + 1 / 0.2) = 0.067
+        assert!((mse(&combined) - 0.067).abs() < 0.01);
+
+        // Ignore antennas without an SNR
+        snr_per_antenna[1] = vec![None; symbols.len()];
+        let combined = mrc_combine(&symbols_per_antenna, &snr_per_antenna);
+        for (x, y) in combined.iter().zip(&symbols_per_antenna[0]) {
+            assert!((x - y).norm() < 1e-6);
+        }
+    }
+}
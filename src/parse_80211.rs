@@ -1,62 +1,387 @@
-use crate::cfo::{correct_cfo, estimate_cfo};
+use crate::cfo::{correct_cfo, correct_cfo_in_place, correct_sfo, estimate_cfo, estimate_sfo};
 use crate::config::ChannelEstConfig;
-use crate::equalization::{equalize_symbol, estimate_subcarrier_equalization};
-use crate::lts_align::lts_align;
+use crate::equalization::{
+    equalize_symbol_bins_with, estimate_snr, resample_equalization, ChannelTracker, Equalizer,
+};
+use crate::error::ParseError;
+use crate::fixed_point::to_q15_scaled;
+use crate::lts_align::lts_align_coarse;
+use crate::pilots::{common_phase_error, extract_pilots, PilotTracker, SamplingPhaseTracker};
+use crate::pkt_trigger::{DetectedPacket, PktTrigger};
+use crate::signal::parse_signal;
+use log::debug;
 use num::Complex;
+use rustfft::{FFTplanner, FFT};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// A packet parsed by `parse_80211_pkt_detailed`
+#[derive(Clone, Debug)]
+pub struct ParsedPacket {
+    /// The equalized value of every used subcarrier (in FFT bin order, pilots included) of each
+    /// data symbol
+    pub symbols: Vec<Vec<Complex<f32>>>,
+    /// Common phase error (in radians) of each data symbol relative to the long preamble, as
+    /// measured on the pilots. Only meaningful if the transmitter sends 802.11 pilots
+    pub cpe: Vec<f32>,
+    /// CFO (in radians per sample) estimated from the preamble
+    pub cfo: f32,
+    /// SNR (in dB) of the long preamble, as by `equalization::estimate_snr`
+    pub snr_db: f32,
+    /// Mean power of the long preamble (including its guard interval) in dB relative to full
+    /// scale, i.e. to samples of magnitude 1
+    pub rssi_dbfs: f32,
+}
+
+impl ParsedPacket {
+    /// Number of data symbols decoded
+    pub fn num_symbols(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// All of `symbols` one after the other, as returned by `parse_80211_pkt`
+    pub fn flat_symbols(&self) -> Vec<Complex<f32>> {
+        self.symbols.iter().flatten().copied().collect()
+    }
+
+    /// `flat_symbols` quantized to Q15 with the given full scale, for fixed-point consumers (see
+    /// `fixed_point` for the convention)
+    pub fn symbols_q15(&self, full_scale: f32) -> Vec<(i16, i16)> {
+        to_q15_scaled(&self.flat_symbols(), full_scale)
+    }
+}
 
 /// Given a buffer possibly containing a packet (e.g. as detected by `pkt_trigger::PktTrigger`),
 /// returns a parsed version of that packet if it is indeed a packet. Assumes the packet starts
-/// within the first ChannelEstConfig::pkt_spacing samples
-pub fn parse_80211_pkt(samps: &[Complex<f32>], config: &ChannelEstConfig) -> Vec<Complex<f32>> {
+/// within the first ChannelEstConfig::pkt_spacing samples. Returns an error instead if the buffer
+/// is too short or has no plausible preamble, so callers can skip it. The symbols are returned one
+/// after the other; `parse_80211_pkt_detailed` keeps them apart
+pub fn parse_80211_pkt(
+    samps: &[Complex<f32>],
+    config: &ChannelEstConfig,
+) -> Result<Vec<Complex<f32>>, ParseError> {
+    Ok(parse_80211_pkt_detailed(samps, config)?.flat_symbols())
+}
+
+/// Like `parse_80211_pkt`, but returns each data symbol separately along with diagnostics
+pub fn parse_80211_pkt_detailed(
+    samps: &[Complex<f32>],
+    config: &ChannelEstConfig,
+) -> Result<ParsedPacket, ParseError> {
+    parse_from(samps, 0, config.pkt_spacing as usize, config)
+}
+
+/// Like `parse_80211_pkt_detailed`, but for a packet from `PktTrigger`. The LTS is searched for
+/// after the trigger offset rather than after the first `pkt_spacing` samples, so the search
+/// doesn't reach into the data when the trigger kept fewer leading samples
+pub fn parse_detected(
+    pkt: &DetectedPacket,
+    config: &ChannelEstConfig,
+) -> Result<ParsedPacket, ParseError> {
+    parse_from(&pkt.samples, pkt.trigger_offset, 0, config)
+}
+
+/// Parse a packet whose long preamble starts after `origin` and ends by `origin + slack` plus the
+/// preamble length
+fn parse_from(
+    samps: &[Complex<f32>],
+    origin: usize,
+    slack: usize,
+    config: &ChannelEstConfig,
+) -> Result<ParsedPacket, ParseError> {
     // Lengths of the various piecs
     // Two repeats of the LTS + guard interval
-    let lts_len = config.lts.as_ref().unwrap().0.len();
-    let short_len = 10 * config.sts.as_ref().unwrap().len() as usize;
-    assert!(samps.len() > 3 * lts_len / 2 + short_len);
+    let lts = &config.lts.as_ref().ok_or(ParseError::MissingLts)?.0;
+    let lts_len = lts.len();
+    config.sts.as_ref().ok_or(ParseError::MissingSts)?;
+    let short_len = config.short_len();
 
     // The LTS symbol should be contained within this range
-    let lts_bound = config.pkt_spacing as usize + short_len + 5 * lts_len / 2;
-    // Sync the packet using LTS so we know where everything is
-    let lts_start = lts_align(&samps[..lts_bound], &config.lts.as_ref().unwrap().0);
+    let lts_bound = origin + slack + short_len + 5 * lts_len / 2;
+    if samps.len() < lts_bound {
+        return Err(ParseError::BufferTooShort {
+            len: samps.len(),
+            needed: lts_bound,
+        });
+    }
+    // Sync the packet using the LTS, searching near where the short preamble ends
+    let lts_start = origin + lts_align_coarse(&samps[origin..lts_bound], config);
+    if lts_start < short_len.max(config.cp_len) {
+        return Err(ParseError::LtsAlignmentFailed { lts_start });
+    }
 
-    let short = &samps[lts_start - short_len..lts_start];
-    let long = &samps[lts_start..lts_start + 5 * lts_len / 2];
+    let mut decoder = SymbolDecoder::from_preamble(samps, lts_start, config);
 
-    let cfo = estimate_cfo(short, long, config);
+    // Go through the symbols one by one
+    let mut i = lts_start + 5 * lts_len / 2;
+    let mut res = Vec::new();
+    let mut cpe = Vec::new();
+    // Number of symbols (including SIGNAL) announced by the SIGNAL field, if we decoded it
+    let mut num_symbols = None;
+    while i < samps.len() - decoder.symbol_len() {
+        let window = &samps[i..i + decoder.symbol_len()];
+        match num_symbols {
+            Some(n) if cpe.len() >= n => break,
+            None if decoder.is_weak(window) => break,
+            _ => {}
+        }
+        let (bins, symbol_cpe) = decoder.demodulate(window);
+        if config.use_signal_length && cpe.is_empty() {
+            let symbol: Vec<_> = bins.iter().map(|x| x.unwrap_or_default()).collect();
+            num_symbols = parse_signal(&symbol)
+                .ok()
+                .filter(|signal| signal.parity_ok)
+                .map(|signal| 1 + signal.num_data_symbols());
+        }
+        res.push(bins.iter().flatten().copied().collect());
+        cpe.push(symbol_cpe);
+        i += decoder.symbol_len();
+    }
+    Ok(ParsedPacket {
+        symbols: res,
+        cpe,
+        cfo: decoder.cfo(),
+        snr_db: decoder.snr_db(),
+        rssi_dbfs: decoder.rssi_dbfs(),
+    })
+}
 
-    let long_corr = correct_cfo(long, cfo);
-    let equalization = estimate_subcarrier_equalization(&long_corr, config);
+/// Find every packet in `samps` with a `PktTrigger` and parse each, in order. Packets must be
+/// separated by more than `config.pkt_spacing` quiet samples, or the trigger merges them and only
+/// the first is parsed. The end of the buffer counts as quiet, so a packet running up to it is
+/// still returned. Segments that fail to parse (e.g. bursts of interference) are skipped.
+/// `config.pkt_spacing` must also be longer than the quiet guard interval before the LTS (i.e.
+/// at-least the LTS length, as `ChannelEstConfig::validate` checks), or the trigger splits each
+/// packet there and none of them parse
+pub fn parse_all(samps: &[Complex<f32>], config: &ChannelEstConfig) -> Vec<ParsedPacket> {
+    let mut trigger = PktTrigger::new(config);
+    let flush = std::iter::repeat(Complex::default()).take(config.pkt_spacing as usize + 1);
+    samps
+        .iter()
+        .copied()
+        .chain(flush)
+        .filter_map(|x| trigger.push_samp(x))
+        .filter_map(|pkt| match parse_detected(&pkt, config) {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                debug!("Skipping segment of {} samples: {}", pkt.samples.len(), e);
+                None
+            }
+        })
+        .collect()
+}
 
-    // Calculate the rms for the long preamble. If any symbol has <10% of this strength, we assume
-    // the packet has ended there. Packet length is also available in the SIGNAL symbol right after
-    // the long preamble, but we haven't implemented decoding yet
-    let pkt_rms = long.iter().map(|x| x.norm_sqr()).sum::<f32>().sqrt();
+/// Decodes the data symbols of a packet one at a time, given the state estimated from its
+/// preamble. Used by `parse_80211_pkt` and the streaming `decoder::Decoder`
+pub(crate) struct SymbolDecoder {
+    /// CFO in radians per sample
+    cfo: f32,
+    /// SNR (in dB) of the long preamble
+    snr_db: f32,
+    equalization: Vec<Option<Complex<f32>>>,
+    /// Inverse FFT of the symbol length. If it is the LTS length, the one planned in the config
+    ifft: Arc<dyn FFT<f32>>,
+    /// Strength of the long preamble. If any symbol has <10% of this strength, we assume the
+    /// packet has ended there. With `ChannelEstConfig::use_signal_length`, the length in the
+    /// SIGNAL symbol right after the long preamble ends the packet instead, if its parity checks
+    pkt_rms: f32,
+    cp_len: usize,
+    lts_len: usize,
+    /// FFT size of the data symbols (`ChannelEstConfig::symbol_fft_len`)
+    fft_len: usize,
+    /// The FFT window of each symbol starts this many samples after the symbol boundary
+    window_start: usize,
+    /// Weights of the cyclic prefix samples blended into the end of the FFT window, rising from 0
+    /// to 1 (see `ChannelEstConfig::symbol_window`). Empty if there is no window
+    window_ramp: Vec<f32>,
+    /// Sample frequency offset to correct (0 if `config.correct_sfo` is off)
+    sfo: f32,
+    track_sampling_phase: bool,
+    tracker: SamplingPhaseTracker,
+    track_common_phase: bool,
+    phase_tracker: PilotTracker,
+    /// Number of symbols decoded so far
+    symbol_idx: usize,
+    /// Reused across symbols to hold the CFO corrected FFT window
+    symbol_buf: Vec<Complex<f32>>,
+    /// Updates `equalization` after each symbol if `config.track_channel` is set
+    channel_tracker: Option<ChannelTracker>,
+}
 
-    // Go through the symbols one by one and correct CFO and qualize
-    assert_eq!(lts_len % 4, 0);
-    let mut i = lts_start + 5 * lts_len / 2;
-    let mut res = Vec::new();
-    while i < samps.len() - 5 * lts_len / 4 {
-        let symbol = &samps[i + lts_len / 4..i + 5 * lts_len / 4];
+impl SymbolDecoder {
+    /// Estimate the CFO and equalization from the preamble of `samps`, whose long preamble
+    /// (including the guard interval) starts at `lts_start`. `samps` must contain the short
+    /// preamble before it and the cyclic prefix's worth of samples before that
+    pub fn from_preamble(
+        samps: &[Complex<f32>],
+        lts_start: usize,
+        config: &ChannelEstConfig,
+    ) -> Self {
+        let lts_len = config.lts.as_ref().unwrap().0.len();
+        let short_len = config.short_len();
+
+        let short = &samps[lts_start - short_len..lts_start];
+        let long = &samps[lts_start..lts_start + 5 * lts_len / 2];
+
+        let cfo = estimate_cfo(short, long, config);
+
+        // The FFT window of each symbol starts this many samples after the symbol boundary
+        assert!(-1. <= config.symbol_start_offset && config.symbol_start_offset <= 0.);
+        let cp_len = config.cp_len;
+        let fft_len = config.symbol_fft_len();
+        assert!(cp_len <= fft_len);
+        let window_start = config.symbol_window_start();
+        assert!(config.symbol_window <= window_start);
+        let window_ramp = (0..config.symbol_window)
+            .map(|j| {
+                let x = PI * (j as f32 + 0.5) / (2 * config.symbol_window) as f32;
+                x.sin().powi(2)
+            })
+            .collect();
+        // Starting the window early rotates each subcarrier. Estimate the equalization from a
+        // long preamble window advanced by the same amount so it undoes the rotation
+        let advance = cp_len - window_start;
+        let long_corr = correct_cfo(
+            &samps[lts_start - advance..lts_start - advance + 5 * lts_len / 2],
+            cfo,
+        );
+        let equalization = resample_equalization(
+            &config.equalizer.estimate(&long_corr, config),
+            fft_len,
+            cp_len,
+        );
+        let sfo = if config.correct_sfo {
+            estimate_sfo(&long_corr, config)
+        } else {
+            0.
+        };
+
+        Self {
+            cfo,
+            snr_db: estimate_snr(&long_corr, config),
+            equalization,
+            ifft: if fft_len == lts_len {
+                config.lts.as_ref().unwrap().2.inverse.clone()
+            } else {
+                FFTplanner::new(true).plan_fft(fft_len)
+            },
+            pkt_rms: long.iter().map(|x| x.norm_sqr()).sum::<f32>().sqrt(),
+            cp_len,
+            lts_len,
+            fft_len,
+            window_start,
+            window_ramp,
+            sfo,
+            track_sampling_phase: config.track_sampling_phase,
+            tracker: SamplingPhaseTracker::new(),
+            track_common_phase: config.track_common_phase,
+            phase_tracker: PilotTracker::new(),
+            symbol_idx: 0,
+            symbol_buf: Vec::with_capacity(fft_len),
+            channel_tracker: config.track_channel.then(|| ChannelTracker::new(config)),
+        }
+    }
+
+    /// CFO (in radians per sample) estimated from the preamble
+    pub fn cfo(&self) -> f32 {
+        self.cfo
+    }
+
+    /// SNR (in dB) of the long preamble
+    pub fn snr_db(&self) -> f32 {
+        self.snr_db
+    }
+
+    /// Mean power of the long preamble in dBFS, from `pkt_rms`
+    pub fn rssi_dbfs(&self) -> f32 {
+        let long_len = 5 * self.lts_len / 2;
+        10. * (self.pkt_rms * self.pkt_rms / long_len as f32).log10()
+    }
+
+    /// Length of a data symbol including its cyclic prefix
+    pub fn symbol_len(&self) -> usize {
+        self.cp_len + self.fft_len
+    }
+
+    /// Whether the symbol in `samps` is too weak to be part of the packet, i.e. the packet has
+    /// ended
+    pub fn is_weak(&self, samps: &[Complex<f32>]) -> bool {
+        let symbol = &samps[self.window_start..self.window_start + self.fft_len];
         let rms = symbol.iter().map(|x| x.norm_sqr()).sum::<f32>().sqrt();
-        if rms < 0.1 * pkt_rms {
-            break;
+        rms < 0.1 * self.pkt_rms
+    }
+
+    /// Decode the next data symbol from `samps` (`symbol_len()` samples starting at the symbol
+    /// boundary). Returns the equalized used subcarriers and the symbol's common phase error, or
+    /// `None` if the symbol is too weak, i.e. the packet has ended
+    pub fn decode(&mut self, samps: &[Complex<f32>]) -> Option<(Vec<Complex<f32>>, f32)> {
+        if self.is_weak(samps) {
+            return None;
         }
+        let (bins, cpe) = self.demodulate(samps);
+        Some((bins.iter().filter_map(|x| *x).collect(), cpe))
+    }
+
+    /// Like `decode`, but decodes the symbol however weak it is and returns every FFT bin, with
+    /// `None` for unused subcarriers
+    pub fn demodulate(&mut self, samps: &[Complex<f32>]) -> (Vec<Option<Complex<f32>>>, f32) {
+        assert_eq!(samps.len(), self.symbol_len());
+        let symbol = &samps[self.window_start..self.window_start + self.fft_len];
 
-        let symbol = correct_cfo(symbol, cfo);
-        let mut symbol = equalize_symbol(&symbol, &equalization);
-        res.append(&mut symbol);
-        i += 5 * lts_len / 4;
+        // Correct CFO and equalize
+        self.symbol_buf.clear();
+        self.symbol_buf.extend_from_slice(symbol);
+        if !self.window_ramp.is_empty() {
+            // Fade the end of the window into its copy just before the window, rotated to undo
+            // the CFO over the `fft_len` samples between them
+            let ramp_len = self.window_ramp.len();
+            let copy = &samps[self.window_start - ramp_len..self.window_start];
+            let rot = Complex::from_polar(&1., &(self.cfo * self.fft_len as f32));
+            let end = &mut self.symbol_buf[self.fft_len - ramp_len..];
+            for ((x, c), r) in end.iter_mut().zip(copy).zip(&self.window_ramp) {
+                *x = *x * (1. - r) + c * rot * r;
+            }
+        }
+        correct_cfo_in_place(&mut self.symbol_buf, self.cfo);
+        let mut bins = equalize_symbol_bins_with(&self.symbol_buf, &self.equalization, &*self.ifft);
+        if self.sfo != 0. {
+            // The equalization is from the average of the two LTS copies, whose windows start
+            // `lts_len` samples after the guard interval on average. Relative to that, this
+            // symbol's window starts `cp_len` samples later than the symbol boundary, since the
+            // equalization window was advanced by as much as this one
+            let elapsed = 3 * self.lts_len / 2 + self.symbol_idx * self.symbol_len() + self.cp_len;
+            correct_sfo(&mut bins, self.sfo, elapsed as f32);
+        }
+        let observed = self.channel_tracker.as_ref().map(|_| bins.clone());
+        // The equalization already removes the preamble's phase, so the pilots are compared
+        // against their known values, which `extract_pilots` divides out
+        let pilots = extract_pilots(&bins, self.symbol_idx);
+        let cpe = common_phase_error(&pilots, &vec![Complex::new(1., 0.); pilots.len()]);
+        if self.track_common_phase {
+            self.phase_tracker.track(&mut bins, self.symbol_idx);
+        }
+        if self.track_sampling_phase {
+            self.tracker.track(&mut bins, self.symbol_idx);
+        }
+        if let (Some(tracker), Some(observed)) = (&self.channel_tracker, &observed) {
+            tracker.track(&mut self.equalization, observed, &bins);
+        }
+        self.symbol_idx += 1;
+        (bins, cpe)
     }
-    res
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::{build_packet, build_preamble};
+    use crate::decoder::{ParsedEvent, StreamingParser};
+    use crate::demod::evm;
+    use crate::pilots::insert_pilots;
+    use crate::signal::{encode_signal, DataRate, SignalField};
+    use crate::sim::{add_awgn, modulate_symbol, random_bpsk_symbols};
     use num::Zero;
-    use rand::Rng;
-    use rustfft::FFTplanner;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_parse_80211_pkt() {
@@ -101,14 +426,8 @@ mod test {
         // Add some silence period
         pkt.extend(std::iter::repeat(Complex::zero()).take(config.pkt_spacing as usize - 1));
 
-        // Short preamble
-        let sts = config.sts.as_ref().unwrap();
-        pkt.extend(sts.iter().cycle().take(10 * sts.len()));
-
-        // Long preamble
-        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() / 2));
-        pkt.extend(lts);
-        pkt.extend(lts);
+        // Short and long preambles
+        pkt.extend(build_preamble(&config));
 
         // The symbols
         pkt.extend(&symbols.clone());
@@ -121,13 +440,496 @@ mod test {
             pkt[i] = pkt[i] + Complex::new(0.1, 0.2) * pkt[i - lts.len() / 8];
         }
 
-        let parsed_symbols = parse_80211_pkt(&pkt, &config);
+        let parsed_symbols = parse_80211_pkt(&pkt, &config).unwrap();
+        let parsed = parse_80211_pkt_detailed(&pkt, &config).unwrap();
+        assert_eq!(parsed.num_symbols(), 2);
+        assert_eq!(parsed.flat_symbols(), parsed_symbols);
 
         // See that the symbol has been decoded correctly
         assert_eq!(parsed_symbols.len(), symbols_data.len());
-        for (x, y) in parsed_symbols.iter().zip(symbols_data) {
+        assert!(evm(&parsed_symbols, &symbols_data) < 1.);
+        for (x, y) in parsed_symbols.iter().zip(&symbols_data) {
             assert!((x - y).norm() < 0.5);
             assert_eq!(x.re > 0., y.re > 0.);
         }
+
+        // The streaming parser yields the same symbols when fed one sample at a time
+        let mut parser = StreamingParser::new(&config);
+        let mut events: Vec<_> = pkt.iter().filter_map(|x| parser.push_samp(*x)).collect();
+        events.extend(std::iter::from_fn(|| parser.next_event()));
+        assert!(matches!(events[0], ParsedEvent::PacketStart { .. }));
+        assert_eq!(
+            events.last(),
+            Some(&ParsedEvent::PacketEnd { num_symbols: 2 })
+        );
+        let streamed: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ParsedEvent::Symbol { bins, .. } => Some(bins.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(streamed.len(), parsed_symbols.len());
+        for (x, y) in streamed.iter().zip(&parsed_symbols) {
+            assert!((x - y).norm() < 1e-4);
+        }
+    }
+
+    /// A packet of 10 BPSK symbols (returned with it) with an 802.11-style cyclic guard interval
+    /// before the LTS, through a channel with a pre-cursor tap arriving 3 samples before the main
+    /// path. So the end of each FFT window picks up the start of the next symbol. The symbols are
+    /// scaled to the power of the LTS, so the start of the first one doesn't swamp the end of the
+    /// LTS it leaks into
+    fn precursor_packet(config: &ChannelEstConfig) -> (Vec<Complex<f32>>, Vec<Complex<f32>>) {
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let sts = config.sts.as_ref().unwrap().clone();
+
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(sts.iter().cycle().take(10 * sts.len()));
+        pkt.extend(&lts[lts.len() / 2..]);
+        pkt.extend(&lts);
+        pkt.extend(&lts);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut symbols_data = Vec::new();
+        for bins in random_bpsk_symbols(config, 10, &mut rng) {
+            let symbol = modulate_symbol(&bins, config.cp_len);
+            let scale = rms(&lts) / rms(&symbol);
+            symbols_data.extend(
+                lts_fft
+                    .iter()
+                    .zip(&bins)
+                    .filter_map(|(l, x)| l.map(|_| x * scale)),
+            );
+            pkt.extend(symbol.iter().map(|x| x * scale));
+        }
+        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() * 2));
+
+        let pkt = (0..pkt.len())
+            .map(|i| pkt[i] + pkt.get(i + 3).map_or(Complex::zero(), |x| x * 0.3))
+            .collect();
+        (pkt, symbols_data)
+    }
+
+    fn rms(samps: &[Complex<f32>]) -> f32 {
+        (samps.iter().map(|x| x.norm_sqr()).sum::<f32>() / samps.len() as f32).sqrt()
+    }
+
+    /// RMS error of the symbols parsed from `pkt` relative to `symbols_data`, as a fraction of the
+    /// RMS of `symbols_data`
+    fn parse_rms_error(
+        pkt: &[Complex<f32>],
+        symbols_data: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> f32 {
+        let parsed = parse_80211_pkt(pkt, config).unwrap();
+        assert_eq!(parsed.len(), symbols_data.len());
+        let err: Vec<_> = parsed
+            .iter()
+            .zip(symbols_data)
+            .map(|(x, y)| x - y)
+            .collect();
+        rms(&err) / rms(symbols_data)
+    }
+
+    /// Starting the window within the cyclic prefix avoids the next symbol in `precursor_packet`
+    #[test]
+    fn test_symbol_start_offset() {
+        let mut config = ChannelEstConfig::default();
+        let (pkt, symbols_data) = precursor_packet(&config);
+        let evm = |config: &ChannelEstConfig| parse_rms_error(&pkt, &symbols_data, config);
+
+        let evm_default = evm(&config);
+        config.symbol_start_offset = -0.25;
+        let evm_early = evm(&config);
+        assert!(evm_early < evm_default);
+        assert!(evm_early < 0.05);
+    }
+
+    /// Windowing over the cyclic prefix fades out the part of the next symbol the FFT window of
+    /// `precursor_packet` picks up, without hurting a clean channel
+    #[test]
+    fn test_symbol_window() {
+        let mut config = ChannelEstConfig::default();
+        let (pkt, symbols_data) = precursor_packet(&config);
+        // Starting 2 samples early, the last sample of each FFT window (and of the LTS the
+        // equalization is estimated on) still has the next symbol in it. The window removes it
+        // from the symbols
+        config.symbol_start_offset = -0.125;
+        let evm_default = parse_rms_error(&pkt, &symbols_data, &config);
+        config.symbol_window = 8;
+        let evm_windowed = parse_rms_error(&pkt, &symbols_data, &config);
+        assert!(
+            evm_windowed < 0.5 * evm_default,
+            "{} {}",
+            evm_windowed,
+            evm_default
+        );
+
+        // Without the pre-cursor, and with a CFO the window has to account for, the window
+        // changes nothing
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let symbols: Vec<Vec<_>> = (0..4)
+            .map(|s| {
+                let mut bins: Vec<_> = lts_fft.iter().map(|x| x.unwrap_or_default()).collect();
+                insert_pilots(&mut bins, s);
+                bins
+            })
+            .collect();
+        let mut clean = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        clean.extend(build_packet(&config, &symbols));
+        clean.extend(std::iter::repeat(Complex::zero()).take(128));
+        let clean: Vec<_> = clean
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x * Complex::from_polar(&1., &(0.01 * i as f32)))
+            .collect();
+        let windowed = parse_80211_pkt(&clean, &config).unwrap();
+        config.symbol_window = 0;
+        let unwindowed = parse_80211_pkt(&clean, &config).unwrap();
+        assert_eq!(windowed.len(), unwindowed.len());
+        for (x, y) in windowed.iter().zip(&unwindowed) {
+            assert!((x - y).norm() < 1e-3, "{} {}", x, y);
+        }
+    }
+
+    /// Rotate each data symbol by a different phase (as phase noise would) and check that the CPE
+    /// recovers it
+    #[test]
+    fn test_common_phase_error() {
+        let config = ChannelEstConfig::default();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_preamble(&config));
+
+        let phases = [0., 0.1, -0.3, 0.7, -1.5, 2.5];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let symbols = random_bpsk_symbols(&config, phases.len(), &mut rng);
+        for (bins, phase) in symbols.iter().zip(&phases) {
+            let rot = Complex::from_polar(&1., phase);
+            pkt.extend(modulate_symbol(bins, config.cp_len).iter().map(|x| x * rot));
+        }
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * lts.len()));
+
+        let parsed = parse_80211_pkt_detailed(&pkt, &config).unwrap();
+        assert_eq!(parsed.num_symbols(), phases.len());
+        assert_eq!(parsed.cpe.len(), phases.len());
+        let num_used = lts_fft.iter().flatten().count();
+        assert!(parsed.symbols.iter().all(|symbol| symbol.len() == num_used));
+        assert_eq!(
+            parsed.flat_symbols(),
+            parse_80211_pkt(&pkt, &config).unwrap()
+        );
+        assert!(parsed.cfo.abs() < 1e-4);
+        // Noiseless, so the two LTS copies match exactly
+        assert!(parsed.snr_db > 60.);
+        // Unit magnitude symbols at half of full scale
+        let q15 = parsed.symbols_q15(2.);
+        assert_eq!(q15.len(), phases.len() * num_used);
+        for (re, im) in q15 {
+            let mag = Complex::new(f32::from(re), f32::from(im)).norm();
+            assert!((mag - 16384.).abs() < 200.);
+        }
+        for (cpe, phase) in parsed.cpe.iter().zip(&phases) {
+            assert!((cpe - phase).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_rssi() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        let mut bins: Vec<_> = lts_fft.iter().map(|x| x.unwrap_or_default()).collect();
+        insert_pilots(&mut bins, 0);
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_packet(&config, &[bins]));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(128));
+
+        let rssi = |gain: f32| {
+            let scaled: Vec<_> = pkt.iter().map(|x| x * gain).collect();
+            parse_80211_pkt_detailed(&scaled, &config)
+                .unwrap()
+                .rssi_dbfs
+        };
+        // The unit power LTS copies fill 128 of the 160 samples; the guard interval is silent
+        let full = rssi(1.);
+        assert!((full - 10. * 0.8f32.log10()).abs() < 0.01, "{}", full);
+        let half = rssi(0.5);
+        assert!((full - half - 6.02).abs() < 0.01, "{} {}", full, half);
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+
+        // Three packets of 1, 2 and 3 BPSK symbols at different strengths, separated by more
+        // silence than `pkt_spacing` (the LTS length) so the trigger tells them apart. The last
+        // one runs to the end
+        assert_eq!(
+            config.pkt_spacing as usize,
+            config.lts.as_ref().unwrap().0.len()
+        );
+        let gap = 2 * config.pkt_spacing as usize;
+        let mut buf = Vec::new();
+        let mut sent = Vec::new();
+        for (num_symbols, gain) in &[(1, 1.), (2, 0.5), (3, 0.8)] {
+            let symbols = random_bpsk_symbols(&config, *num_symbols, &mut rng);
+            sent.push(
+                symbols
+                    .iter()
+                    .flat_map(|bins| lts_fft.iter().zip(bins).filter_map(|(l, x)| l.map(|_| *x)))
+                    .collect::<Vec<_>>(),
+            );
+            buf.extend(std::iter::repeat(Complex::zero()).take(gap));
+            buf.extend(build_packet(&config, &symbols).iter().map(|x| x * *gain));
+        }
+        add_awgn(&mut buf, 1e-4, &mut rng);
+
+        let parsed = parse_all(&buf, &config);
+        assert_eq!(parsed.len(), 3);
+        for (i, (pkt, sent)) in parsed.iter().zip(&sent).enumerate() {
+            assert_eq!(pkt.num_symbols(), i + 1);
+            let symbols = pkt.flat_symbols();
+            assert_eq!(symbols.len(), sent.len());
+            for (x, y) in symbols.iter().zip(sent) {
+                assert!((x - y).norm() < 0.5, "packet {}: {} vs {}", i, x, y);
+            }
+        }
+    }
+
+    /// A long cyclic prefix absorbs an echo longer than the default prefix
+    #[test]
+    fn test_long_cyclic_prefix() {
+        let mut config = ChannelEstConfig::default();
+        config.cp_len = 32;
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let sts = config.sts.as_ref().unwrap().clone();
+
+        // Preamble with an 802.11-style cyclic guard interval
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(sts.iter().cycle().take(10 * sts.len()));
+        pkt.extend(&lts[lts.len() / 2..]);
+        pkt.extend(&lts);
+        pkt.extend(&lts);
+
+        let num_symbols = 6;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut symbols_data = Vec::new();
+        for bins in random_bpsk_symbols(&config, num_symbols, &mut rng) {
+            symbols_data.extend(lts_fft.iter().zip(&bins).filter_map(|(l, x)| l.map(|_| *x)));
+            let symbol = modulate_symbol(&bins, config.cp_len);
+            assert_eq!(symbol.len(), config.cp_len + lts.len());
+            pkt.extend(symbol);
+        }
+        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() * 2));
+
+        // An echo 24 samples late: longer than the default prefix, but within this one
+        let pkt: Vec<_> = (0..pkt.len())
+            .map(|i| {
+                pkt[i]
+                    + if i >= 24 {
+                        pkt[i - 24] * 0.3
+                    } else {
+                        Complex::zero()
+                    }
+            })
+            .collect();
+
+        let parsed = parse_80211_pkt(&pkt, &config).unwrap();
+        assert_eq!(parsed.len(), symbols_data.len());
+        for (x, y) in parsed.iter().zip(&symbols_data) {
+            assert!((x - y).norm() < 1e-3);
+        }
+    }
+
+    /// Decision-directed tracking follows an echo that grows over the packet
+    #[test]
+    fn test_channel_tracking() {
+        let mut config = ChannelEstConfig::default();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let lts_len = lts_fft.len();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+
+        let num_symbols = 50;
+        let symbols = random_bpsk_symbols(&config, num_symbols, &mut rng);
+        let sent: Vec<_> = symbols
+            .iter()
+            .flat_map(|bins| lts_fft.iter().zip(bins).filter_map(|(l, x)| l.map(|_| *x)))
+            .collect();
+        let pkt = build_packet(&config, &symbols);
+
+        // An echo 3 samples late, absent during the preamble, whose gain grows linearly to 1.5 by
+        // the end of the packet. Towards the end it cancels the direct path on some subcarriers
+        // and flips others
+        let data_start = pkt.len() - num_symbols * (config.cp_len + lts_len);
+        let gain =
+            |i: usize| 1.5 * i.saturating_sub(data_start) as f32 / (pkt.len() - data_start) as f32;
+        let mut buf = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        buf.extend((0..pkt.len()).map(|i| {
+            pkt[i]
+                + if i >= 3 {
+                    pkt[i - 3] * gain(i)
+                } else {
+                    Complex::zero()
+                }
+        }));
+        buf.extend(std::iter::repeat(Complex::zero()).take(2 * lts_len));
+        add_awgn(&mut buf, 1e-3, &mut rng);
+
+        // Number of wrong decisions in the last 10 symbols
+        let tail_errors = |config: &ChannelEstConfig| {
+            let parsed = parse_80211_pkt(&buf, config).unwrap();
+            assert_eq!(parsed.len(), sent.len());
+            let tail = 10 * sent.len() / num_symbols;
+            parsed[parsed.len() - tail..]
+                .iter()
+                .zip(&sent[sent.len() - tail..])
+                .filter(|(x, y)| (x.re > 0.) != (y.re > 0.))
+                .count()
+        };
+        let static_errors = tail_errors(&config);
+        config.track_channel = true;
+        config.track_channel_step = 0.3;
+        let tracking_errors = tail_errors(&config);
+        assert!(static_errors > 50, "{}", static_errors);
+        assert_eq!(tracking_errors, 0);
+    }
+
+    #[test]
+    fn test_symbol_len() {
+        // 128-point data symbols with a 32 sample cyclic prefix after the usual preamble
+        let mut config = ChannelEstConfig::default();
+        config.symbol_len = Some(128);
+        config.cp_len = 32;
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let used = resample_equalization(&lts_fft, 128, config.cp_len);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+
+        let symbols: Vec<Vec<_>> = (0..4)
+            .map(|_| {
+                used.iter()
+                    .map(|x| match x {
+                        Some(_) if rng.gen() => Complex::new(1., 0.),
+                        Some(_) => Complex::new(-1., 0.),
+                        None => Complex::zero(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let sent: Vec<_> = symbols
+            .iter()
+            .flat_map(|bins| used.iter().zip(bins).filter_map(|(u, x)| u.map(|_| *x)))
+            .collect();
+        let mut pkt = build_packet(&config, &symbols);
+        // Make the guard interval before the LTS cyclic, as in 802.11, rather than silent. Else
+        // the start of the first LTS copy misses the echo of the guard interval and the
+        // equalization is off
+        let lts = &config.lts.as_ref().unwrap().0;
+        let short_len = config.short_len();
+        pkt[short_len..short_len + lts.len() / 2].copy_from_slice(&lts[lts.len() / 2..]);
+
+        // An echo 5 samples late, which is estimated on the LTS's bins and has to be carried
+        // over to the symbols'
+        let mut buf = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        buf.extend((0..pkt.len()).map(|i| {
+            pkt[i]
+                + if i >= 5 {
+                    pkt[i - 5] * 0.5
+                } else {
+                    Complex::zero()
+                }
+        }));
+        buf.extend(std::iter::repeat(Complex::zero()).take(256));
+        add_awgn(&mut buf, 1e-4, &mut rng);
+
+        let parsed = parse_80211_pkt(&buf, &config).unwrap();
+        assert_eq!(parsed.len(), sent.len());
+        for (x, y) in parsed.iter().zip(&sent) {
+            assert!((x - y).norm() < 0.1, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let config = ChannelEstConfig::default();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_preamble(&config));
+        let lts_fft = &config.lts.as_ref().unwrap().1;
+        let bins: Vec<_> = lts_fft.iter().map(|x| x.unwrap_or_default()).collect();
+        pkt.extend(modulate_symbol(&bins, config.cp_len));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() * 2));
+        assert_eq!(parse_80211_pkt(&pkt, &config).unwrap().len(), 52);
+
+        // Truncated in the middle of the long preamble
+        let needed = config.pkt_spacing as usize + config.short_len() + 5 * lts.len() / 2;
+        assert_eq!(
+            parse_80211_pkt(&pkt[..300], &config),
+            Err(ParseError::BufferTooShort { len: 300, needed })
+        );
+        assert!(parse_80211_pkt(&pkt[..needed], &config).is_ok());
+
+        // The long preamble without a short preamble before it
+        let mut no_sts = vec![Complex::zero(); lts.len() / 2];
+        no_sts.extend(&lts);
+        no_sts.extend(&lts);
+        no_sts.extend(std::iter::repeat(Complex::zero()).take(needed));
+        assert!(matches!(
+            parse_80211_pkt(&no_sts, &config),
+            Err(ParseError::LtsAlignmentFailed { .. })
+        ));
+
+        let mut missing = config.clone();
+        missing.lts = None;
+        assert_eq!(parse_80211_pkt(&pkt, &missing), Err(ParseError::MissingLts));
+        let mut missing = config.clone();
+        missing.sts = None;
+        assert_eq!(parse_80211_pkt(&pkt, &missing), Err(ParseError::MissingSts));
+    }
+
+    #[test]
+    fn test_signal_length() {
+        let mut config = ChannelEstConfig::default();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        // 9 bytes at 6 Mbit/s take 4 data symbols. Follow them with 3 more that aren't part of
+        // the packet (e.g. another transmitter) before the silence
+        let build = |parity_ok: bool, rng: &mut rand::rngs::StdRng| {
+            let field = SignalField {
+                rate: DataRate::Mbps6,
+                length: 9,
+                parity_ok,
+            };
+            assert_eq!(field.num_data_symbols(), 4);
+            let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+            pkt.extend(build_preamble(&config));
+            pkt.extend(modulate_symbol(
+                &encode_signal(&field, lts.len()),
+                config.cp_len,
+            ));
+            for bins in random_bpsk_symbols(&config, 4 + 3, rng) {
+                pkt.extend(modulate_symbol(&bins, config.cp_len));
+            }
+            pkt.extend(std::iter::repeat(Complex::zero()).take(lts.len() * 2));
+            pkt
+        };
+        let pkt = build(true, &mut rng);
+        let corrupt = build(false, &mut rng);
+
+        // Without the SIGNAL field we can only tell where the transmissions stop
+        assert_eq!(parse_80211_pkt(&pkt, &config).unwrap().len(), 8 * 52);
+        config.use_signal_length = true;
+        assert_eq!(parse_80211_pkt(&pkt, &config).unwrap().len(), 5 * 52);
+        // A SIGNAL field that fails its parity check is ignored
+        assert_eq!(parse_80211_pkt(&corrupt, &config).unwrap().len(), 8 * 52);
     }
 }
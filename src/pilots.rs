@@ -0,0 +1,508 @@
+//! The 802.11a pilot subcarriers and the tracking that relies on them. Every OFDM symbol after the
+//! long preamble carries known BPSK values on subcarriers -21, -7, 7 and 21, multiplied by a
+//! per-symbol polarity.
+//!
+//! Note: since we demodulate with the inverse FFT (see `equalize_symbol`), subcarrier `k` lands in
+//! FFT bin `-k` (mod the FFT length). Use `subcarrier_bin` rather than indexing directly.
+
+use crate::config::ChannelEstConfig;
+use num::Complex;
+use std::f32::consts::PI;
+
+/// Subcarriers (relative to DC) carrying pilots
+pub const PILOT_SUBCARRIERS: [i32; 4] = [-21, -7, 7, 21];
+/// Pilot values before the polarity is applied, in the same order as `PILOT_SUBCARRIERS`
+pub const PILOT_VALUES: [f32; 4] = [1., 1., 1., -1.];
+/// Polarity of the pilots in the n^th OFDM symbol after the long preamble (n = 0 is the SIGNAL
+/// symbol). The sequence repeats every 127 symbols
+pub const PILOT_POLARITY: [i8; 127] = [
+    1, 1, 1, 1, -1, -1, -1, 1, -1, -1, -1, -1, 1, 1, -1, 1, -1, -1, 1, 1, -1, 1, 1, -1, 1, 1, 1, 1,
+    1, 1, -1, 1, 1, 1, -1, 1, 1, -1, -1, 1, 1, 1, -1, 1, -1, -1, -1, 1, -1, 1, -1, -1, 1, -1, -1,
+    1, 1, 1, 1, 1, -1, -1, 1, 1, -1, -1, 1, -1, 1, -1, 1, 1, -1, -1, -1, 1, 1, -1, -1, -1, -1, 1,
+    -1, -1, 1, -1, 1, 1, 1, 1, -1, 1, -1, 1, -1, 1, -1, -1, -1, -1, -1, 1, -1, 1, 1, -1, 1, -1, 1,
+    1, 1, -1, -1, 1, -1, -1, -1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+
+/// What an FFT bin (i.e. an index into the equalization vector) is used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubcarrierRole {
+    /// Carries data
+    Data,
+    /// Carries a known pilot value
+    Pilot,
+    /// The unused subcarrier at DC
+    DcNull,
+    /// Unused subcarrier at the band edges
+    GuardNull,
+}
+
+/// The role of each FFT bin, in the same order as the equalization returned by
+/// `estimate_subcarrier_equalization`. Bins the LTS doesn't use are nulls and the 802.11 pilot
+/// positions are pilots if the LTS uses them
+pub fn subcarrier_roles(config: &ChannelEstConfig) -> Vec<SubcarrierRole> {
+    let lts_fft = &config.lts.as_ref().unwrap().1;
+    let fft_len = lts_fft.len();
+    let pilot_bins: Vec<_> = PILOT_SUBCARRIERS
+        .iter()
+        .map(|k| subcarrier_bin(*k, fft_len))
+        .collect();
+    lts_fft
+        .iter()
+        .enumerate()
+        .map(|(i, x)| match x {
+            None if i == 0 => SubcarrierRole::DcNull,
+            None => SubcarrierRole::GuardNull,
+            Some(_) if pilot_bins.contains(&i) => SubcarrierRole::Pilot,
+            Some(_) => SubcarrierRole::Data,
+        })
+        .collect()
+}
+
+/// Loop gain with which `SamplingPhaseTracker` updates its drift estimate
+const DRIFT_GAIN: f32 = 0.5;
+/// Loop gain with which `PilotTracker` updates its estimate of the phase change per symbol
+const PHASE_STEP_GAIN: f32 = 0.5;
+
+/// FFT bin in which subcarrier `k` appears when demodulating with an FFT of `fft_len` bins
+pub fn subcarrier_bin(k: i32, fft_len: usize) -> usize {
+    (-k).rem_euclid(fft_len as i32) as usize
+}
+
+/// Signed frequency of FFT bin `bin` (i.e. bins above `fft_len / 2` are negative)
+pub(crate) fn signed_bin(bin: usize, fft_len: usize) -> f32 {
+    if bin < fft_len / 2 {
+        bin as f32
+    } else {
+        bin as f32 - fft_len as f32
+    }
+}
+
+/// The known values of the four pilots (in the order of `PILOT_SUBCARRIERS`) in the
+/// `symbol_idx`^th OFDM symbol after the long preamble
+pub fn expected_pilots(symbol_idx: usize) -> [f32; 4] {
+    let polarity = f32::from(PILOT_POLARITY[symbol_idx % PILOT_POLARITY.len()]);
+    let mut res = PILOT_VALUES;
+    for x in &mut res {
+        *x *= polarity;
+    }
+    res
+}
+
+/// Set the pilot bins of a frequency-domain symbol to their known values
+pub fn insert_pilots(bins: &mut [Complex<f32>], symbol_idx: usize) {
+    let fft_len = bins.len();
+    for (k, p) in PILOT_SUBCARRIERS.iter().zip(&expected_pilots(symbol_idx)) {
+        bins[subcarrier_bin(*k, fft_len)] = Complex::new(*p, 0.);
+    }
+}
+
+/// The pilots of an equalized symbol (as returned by `equalize_symbol_bins`) with their known
+/// values removed, in the order of `PILOT_SUBCARRIERS`. Ideally these are all 1. Pilots on bins
+/// that aren't in use are skipped
+pub fn extract_pilots(bins: &[Option<Complex<f32>>], symbol_idx: usize) -> Vec<Complex<f32>> {
+    let fft_len = bins.len();
+    PILOT_SUBCARRIERS
+        .iter()
+        .zip(&expected_pilots(symbol_idx))
+        .filter_map(|(k, p)| bins[subcarrier_bin(*k, fft_len)].map(|x| x * *p))
+        .collect()
+}
+
+/// Common phase error (in radians) of a symbol: the average phase of `symbol_pilots` relative to
+/// `reference_pilots` (e.g. the same pilots in the preamble, or their known values). Residual CFO
+/// and phase noise show up as a CPE that changes from symbol to symbol. Pilots are weighted by
+/// their magnitude, so weak (e.g. faded) pilots count for less
+pub fn common_phase_error(
+    symbol_pilots: &[Complex<f32>],
+    reference_pilots: &[Complex<f32>],
+) -> f32 {
+    assert_eq!(symbol_pilots.len(), reference_pilots.len());
+    symbol_pilots
+        .iter()
+        .zip(reference_pilots)
+        .map(|(x, r)| x * r.conj())
+        .sum::<Complex<f32>>()
+        .arg()
+}
+
+/// Rotate each bin of an equalized symbol (as returned by `equalize_symbol_bins`) to undo a
+/// sampling offset of `offset` samples. A delay of `d` samples rotates bin `b` by
+/// `2 * pi * b * d / fft_len`, so this is a fractional delay applied in the frequency domain
+pub fn correct_sampling_offset(bins: &mut [Option<Complex<f32>>], offset: f32) {
+    let fft_len = bins.len();
+    for (i, x) in bins.iter_mut().enumerate() {
+        if let Some(x) = x {
+            let phase = -2. * PI * signed_bin(i, fft_len) * offset / fft_len as f32;
+            *x *= Complex::new(0., phase).exp();
+        }
+    }
+}
+
+/// Estimate the sampling offset (in samples) of an equalized symbol from the slope of the pilots'
+/// phase across frequency. Only offsets smaller than about 1.5 samples are unambiguous
+pub fn estimate_sampling_offset(bins: &[Option<Complex<f32>>], symbol_idx: usize) -> f32 {
+    let fft_len = bins.len();
+    // Least squares fit of phase = common + slope * bin. The pilots are symmetric about DC, so
+    // the common phase doesn't affect the slope
+    let (mut num, mut den) = (0., 0.);
+    for (k, p) in PILOT_SUBCARRIERS.iter().zip(&expected_pilots(symbol_idx)) {
+        let bin = subcarrier_bin(*k, fft_len);
+        if let Some(x) = bins[bin] {
+            let b = signed_bin(bin, fft_len);
+            num += b * (x * *p).arg();
+            den += b * b;
+        }
+    }
+    if den == 0. {
+        return 0.;
+    }
+    num / den * fft_len as f32 / (2. * PI)
+}
+
+/// Tracks the sampling phase as it drifts across the symbols of a packet because of a sample
+/// clock offset. Before measuring each symbol, it applies the offset predicted from the previous
+/// symbols, so the measured residual stays within the unambiguous range even when the total drift
+/// exceeds a sample
+#[derive(Clone, Debug, Default)]
+pub struct SamplingPhaseTracker {
+    /// Sampling offset (in samples) of the most recent symbol relative to the long preamble
+    offset: f32,
+    /// Estimated change in `offset` from one symbol to the next
+    drift: f32,
+}
+
+impl SamplingPhaseTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sampling offset (in samples) of the most recently tracked symbol
+    pub fn offset(&self) -> f32 {
+        self.offset
+    }
+
+    /// Estimated drift in sampling offset per symbol
+    pub fn drift(&self) -> f32 {
+        self.drift
+    }
+
+    /// Take the equalized bins of the `symbol_idx`^th symbol after the long preamble (as returned
+    /// by `equalize_symbol_bins`), update the estimate and correct the bins in place. Returns the
+    /// sampling offset of this symbol
+    pub fn track(&mut self, bins: &mut [Option<Complex<f32>>], symbol_idx: usize) -> f32 {
+        let predicted = self.offset + self.drift;
+        correct_sampling_offset(bins, predicted);
+        let residual = estimate_sampling_offset(bins, symbol_idx);
+        correct_sampling_offset(bins, residual);
+
+        self.offset = predicted + residual;
+        self.drift += DRIFT_GAIN * residual;
+        self.offset
+    }
+}
+
+/// Rotate each bin of an equalized symbol (as returned by `equalize_symbol_bins`) by `-phase`
+/// radians, undoing a common phase error of `phase`
+pub fn correct_common_phase(bins: &mut [Option<Complex<f32>>], phase: f32) {
+    let rot = Complex::new(0., -phase).exp();
+    for x in bins.iter_mut().flatten() {
+        *x *= rot;
+    }
+}
+
+/// Tracks the common phase of the symbols of a packet, which keeps rotating when the CFO
+/// estimated from the preamble is slightly off (and wanders with phase noise). Like
+/// `SamplingPhaseTracker`, it first applies the phase predicted from the previous symbols and then
+/// measures the residual on the pilots, so the measurement never wraps around
+#[derive(Clone, Debug, Default)]
+pub struct PilotTracker {
+    /// Common phase (in radians) of the most recent symbol relative to the long preamble
+    phase: f32,
+    /// Estimated change in `phase` from one symbol to the next, i.e. the residual CFO in radians
+    /// per symbol
+    phase_step: f32,
+}
+
+impl PilotTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Common phase (in radians) of the most recently tracked symbol. Not wrapped to [-pi, pi]
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    /// Estimated residual CFO in radians per symbol
+    pub fn phase_step(&self) -> f32 {
+        self.phase_step
+    }
+
+    /// Take the equalized bins of the `symbol_idx`^th symbol after the long preamble (as returned
+    /// by `equalize_symbol_bins`), update the estimate and correct the bins in place. Returns the
+    /// common phase of this symbol
+    pub fn track(&mut self, bins: &mut [Option<Complex<f32>>], symbol_idx: usize) -> f32 {
+        let predicted = self.phase + self.phase_step;
+        correct_common_phase(bins, predicted);
+        let pilots = extract_pilots(bins, symbol_idx);
+        let residual = common_phase_error(&pilots, &vec![Complex::new(1., 0.); pilots.len()]);
+        correct_common_phase(bins, residual);
+
+        self.phase = predicted + residual;
+        self.phase_step += PHASE_STEP_GAIN * residual;
+        self.phase
+    }
+}
+
+/// Estimate phase noise from how the pilots' common phase error jitters across the symbols of a
+/// packet. `symbols_fft` holds the equalized bins of consecutive symbols after the long preamble
+/// (the first being symbol 0), in FFT bin order. Bins that are null in `config.lts` are ignored.
+/// A steady phase rotation (residual CFO) is fitted and removed, so only the random part counts.
+/// Returns the RMS of what remains, in radians, or 0 with fewer than three symbols
+pub fn estimate_phase_noise(symbols_fft: &[Vec<Complex<f32>>], config: &ChannelEstConfig) -> f32 {
+    if symbols_fft.len() < 3 {
+        return 0.;
+    }
+    let lts_fft = config.lts.as_ref().map(|lts| &lts.1);
+
+    // Common phase of each symbol, unwrapped so a rotation doesn't jump by 2 pi
+    let mut phases: Vec<f32> = Vec::with_capacity(symbols_fft.len());
+    for (symbol_idx, symbol) in symbols_fft.iter().enumerate() {
+        let bins: Vec<_> = match lts_fft {
+            Some(lts_fft) if lts_fft.len() == symbol.len() => symbol
+                .iter()
+                .zip(lts_fft)
+                .map(|(x, l)| l.map(|_| *x))
+                .collect(),
+            _ => symbol.iter().map(|x| Some(*x)).collect(),
+        };
+        let pilots = extract_pilots(&bins, symbol_idx);
+        let mut phase = common_phase_error(&pilots, &vec![Complex::new(1., 0.); pilots.len()]);
+        if let Some(prev) = phases.last() {
+            phase -= 2. * PI * ((phase - prev) / (2. * PI)).round();
+        }
+        phases.push(phase);
+    }
+
+    // Least squares fit of phase = a + b * symbol_idx
+    let n = phases.len() as f32;
+    let mean_idx = (n - 1.) / 2.;
+    let mean_phase = phases.iter().sum::<f32>() / n;
+    let (mut num, mut den) = (0., 0.);
+    for (i, phase) in phases.iter().enumerate() {
+        num += (i as f32 - mean_idx) * (phase - mean_phase);
+        den += (i as f32 - mean_idx).powi(2);
+    }
+    let slope = num / den;
+
+    let sq_err: f32 = phases
+        .iter()
+        .enumerate()
+        .map(|(i, phase)| (phase - mean_phase - slope * (i as f32 - mean_idx)).powi(2))
+        .sum();
+    (sq_err / n).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{build_preamble, ChannelEstConfig};
+    use crate::equalization::equalize_symbol_bins;
+    use crate::parse_80211::parse_80211_pkt;
+    use crate::sim::{modulate_symbol, random_bpsk_symbols};
+    use num::Zero;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_pilot_polarity() {
+        // The polarity is the output of the 802.11 scrambler seeded with all ones
+        let mut state = 0x7fu8;
+        for p in PILOT_POLARITY.iter() {
+            let bit = ((state >> 3) ^ (state >> 6)) & 1;
+            state = ((state << 1) | bit) & 0x7f;
+            assert_eq!(*p, 1 - 2 * bit as i8);
+        }
+        assert_eq!(expected_pilots(0), [1., 1., 1., -1.]);
+        assert_eq!(expected_pilots(4), [-1., -1., -1., 1.]);
+        assert_eq!(subcarrier_bin(-21, 64), 21);
+        assert_eq!(subcarrier_bin(21, 64), 43);
+    }
+
+    #[test]
+    fn test_subcarrier_roles() {
+        let roles = subcarrier_roles(&ChannelEstConfig::default());
+        assert_eq!(roles.len(), 64);
+        assert_eq!(roles[0], SubcarrierRole::DcNull);
+        for bin in &[7, 21, 43, 57] {
+            assert_eq!(roles[*bin], SubcarrierRole::Pilot);
+        }
+        let count = |role| roles.iter().filter(|r| **r == role).count();
+        assert_eq!(count(SubcarrierRole::Pilot), 4);
+        assert_eq!(count(SubcarrierRole::Data), 48);
+        assert_eq!(count(SubcarrierRole::DcNull), 1);
+        assert_eq!(count(SubcarrierRole::GuardNull), 11);
+        // The guard band is at the edges, i.e. around bin 32
+        assert!(roles[27..38]
+            .iter()
+            .all(|r| *r == SubcarrierRole::GuardNull));
+    }
+
+    #[test]
+    fn test_common_phase_error() {
+        let reference: Vec<_> = expected_pilots(3)
+            .iter()
+            .map(|p| Complex::new(*p, 0.))
+            .collect();
+        for &cpe in &[0., 0.3, -1.2, 3.] {
+            let rot = Complex::from_polar(&1., &cpe);
+            let pilots: Vec<_> = reference.iter().map(|r| r * rot * 0.7).collect();
+            assert!((common_phase_error(&pilots, &reference) - cpe).abs() < 1e-5);
+        }
+    }
+
+    /// A sample clock offset makes each symbol's sampling phase drift a little further. By the
+    /// end of this packet it has drifted by 2 samples
+    #[test]
+    fn test_sampling_phase_tracking() {
+        let mut config = ChannelEstConfig::default();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let fft_len = lts.len();
+
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_preamble(&config));
+
+        // Random BPSK symbols with pilots. Symbol `s` is delayed by 0.1 * (s + 1) samples
+        let num_symbols = 20;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut symbols_data = Vec::new();
+        for (s, mut bins) in random_bpsk_symbols(&config, num_symbols, &mut rng)
+            .into_iter()
+            .enumerate()
+        {
+            symbols_data.extend(lts_fft.iter().zip(&bins).filter_map(|(l, x)| l.map(|_| *x)));
+
+            let delay = 0.1 * (s + 1) as f32;
+            for (i, x) in bins.iter_mut().enumerate() {
+                let phase = 2. * PI * signed_bin(i, fft_len) * delay;
+                *x *= Complex::new(0., phase / fft_len as f32).exp();
+            }
+            pkt.extend(modulate_symbol(&bins, config.cp_len));
+        }
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * lts.len()));
+
+        let count_errors = |parsed: &[Complex<f32>]| {
+            assert_eq!(parsed.len(), symbols_data.len());
+            parsed
+                .iter()
+                .zip(&symbols_data)
+                .filter(|(x, y)| (x.re > 0.) != (y.re > 0.))
+                .count()
+        };
+
+        // Without tracking, the later symbols are rotated beyond recognition
+        assert!(count_errors(&parse_80211_pkt(&pkt, &config).unwrap()) > 0);
+
+        config.track_sampling_phase = true;
+        let parsed = parse_80211_pkt(&pkt, &config).unwrap();
+        assert_eq!(count_errors(&parsed), 0);
+        for (x, y) in parsed.iter().zip(&symbols_data) {
+            assert!((x - y).norm() < 0.1);
+        }
+    }
+
+    /// The CFO estimated from the preamble is off by 1e-3 radians per sample, so the data symbols
+    /// keep rotating, by over 2 radians by the end of this packet
+    #[test]
+    fn test_pilot_tracking() {
+        let mut config = ChannelEstConfig::default();
+        let lts = config.lts.as_ref().unwrap().0.clone();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+
+        let mut pkt = vec![Complex::zero(); config.pkt_spacing as usize - 1];
+        pkt.extend(build_preamble(&config));
+        let data_start = pkt.len();
+
+        let num_symbols = 30;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut symbols_data = Vec::new();
+        for bins in random_bpsk_symbols(&config, num_symbols, &mut rng) {
+            symbols_data.extend(lts_fft.iter().zip(&bins).filter_map(|(l, x)| l.map(|_| *x)));
+            pkt.extend(modulate_symbol(&bins, config.cp_len));
+        }
+        for (i, x) in pkt[data_start..].iter_mut().enumerate() {
+            *x *= Complex::new(0., 1e-3 * i as f32).exp();
+        }
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * lts.len()));
+
+        let count_errors = |parsed: &[Complex<f32>]| {
+            assert_eq!(parsed.len(), symbols_data.len());
+            parsed
+                .iter()
+                .zip(&symbols_data)
+                .filter(|(x, y)| (x.re > 0.) != (y.re > 0.))
+                .count()
+        };
+        assert!(count_errors(&parse_80211_pkt(&pkt, &config).unwrap()) > 0);
+
+        config.track_common_phase = true;
+        let parsed = parse_80211_pkt(&pkt, &config).unwrap();
+        assert_eq!(count_errors(&parsed), 0);
+        for (x, y) in parsed.iter().zip(&symbols_data) {
+            assert!((x - y).norm() < 0.1);
+        }
+
+        // The tracker on its own, on symbols rotated by a fixed amount each
+        let mut tracker = PilotTracker::new();
+        for s in 0..10 {
+            let mut bins: Vec<_> = (0..64).map(|_| Some(Complex::new(1., 0.))).collect();
+            for (k, p) in PILOT_SUBCARRIERS.iter().zip(&expected_pilots(s)) {
+                bins[subcarrier_bin(*k, 64)] = Some(Complex::new(*p, 0.));
+            }
+            let sent = bins.clone();
+            correct_common_phase(&mut bins, -0.4 * (s + 1) as f32);
+            tracker.track(&mut bins, s);
+            assert!((tracker.phase() - 0.4 * (s + 1) as f32).abs() < 1e-4);
+            for (x, y) in bins.iter().flatten().zip(sent.iter().flatten()) {
+                assert!((x - y).norm() < 1e-4);
+            }
+        }
+        assert!((tracker.phase_step() - 0.4).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_estimate_phase_noise() {
+        let config = ChannelEstConfig::default();
+        let lts_fft = config.lts.as_ref().unwrap().1.clone();
+        let fft_len = lts_fft.len();
+
+        // Transmit 40 symbols with a small CFO, adding a random walk to the phase of each sample
+        // with the given standard deviation per step, and demodulate them again
+        let received = |phase_noise: f32| -> Vec<Vec<Complex<f32>>> {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+            let mut phase = 0.;
+            let identity = vec![Some(Complex::new(1., 0.)); fft_len];
+            random_bpsk_symbols(&config, 40, &mut rng)
+                .iter()
+                .map(|bins| {
+                    let mut samps = modulate_symbol(bins, config.cp_len);
+                    for x in samps.iter_mut() {
+                        // Uniform steps with the given standard deviation
+                        phase += 1e-3 + phase_noise * 3f32.sqrt() * rng.gen_range(-1., 1.);
+                        *x *= Complex::new(0., phase).exp();
+                    }
+                    equalize_symbol_bins(&samps[config.cp_len..], &identity)
+                        .into_iter()
+                        .map(|x| x.unwrap())
+                        .collect()
+                })
+                .collect()
+        };
+
+        let clean = estimate_phase_noise(&received(0.), &config);
+        let noisy = estimate_phase_noise(&received(0.02), &config);
+        assert!(clean < 0.01, "{}", clean);
+        assert!(noisy > 0.05, "{}", noisy);
+        assert_eq!(estimate_phase_noise(&received(0.02)[..2], &config), 0.);
+    }
+}
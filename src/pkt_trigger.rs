@@ -1,42 +1,135 @@
-use crate::config::ChannelEstConfig;
-use num::Complex;
-use std::collections::VecDeque;
+use crate::config::{cplx_vec_to_file, ChannelEstConfig};
+use crate::error::Result;
+use crate::ring_buffer::SampleRing;
+use num::{Complex, Zero};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Weight of each new idle sample in `PktTrigger`'s running estimate of the noise floor
+const NOISE_FLOOR_ALPHA: f32 = 0.01;
+
+/// How `PktTrigger` decides that a sample is loud enough to start a packet. In a config file, the
+/// variant goes in `kind`, e.g. `{ kind = "Adaptive", factor = 4.0 }`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind")]
+pub enum PowerTrigMode {
+    /// Trigger when the power exceeds `ChannelEstConfig::power_trig`
+    Fixed,
+    /// Trigger when the power exceeds `factor` times the noise floor, which is tracked as an
+    /// exponential moving average of the power while idle, starting from the mean power of the
+    /// samples skipped at the start (see `ChannelEstConfig::stabilize_samps`). Works regardless
+    /// of the receiver gain
+    Adaptive { factor: f32 },
+}
 
 enum PktTriggerState {
     /// Skip the first few samples (counts the number of samples so far)
     Skip(u64),
     /// No packets so far
     Idle,
-    /// Yup, we are sampling a packet now. Number indicates the number of samples whose `norm_sqr`
-    /// has been less than `config.power_trig`
+    /// Yup, we are sampling a packet now. Number indicates the number of consecutive samples whose
+    /// `norm_sqr` has been less than `PktTrigger::stop_trig`
     Packet(u64),
 }
 
-/// Looks for a sudden increase in received signal strength and returns a `Vec<Complex<f32>>` that
+/// A buffer returned by `PktTrigger` that should contain a packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectedPacket {
+    /// The (suspected) packet along with up to `pkt_spacing` samples on either side
+    pub samples: Vec<Complex<f32>>,
+    /// Index in `samples` of the sample whose power first exceeded the threshold
+    pub trigger_offset: usize,
+}
+
+/// Looks for a sudden increase in received signal strength and returns a `DetectedPacket` that
 /// should contain the packet. It is conservative and may return some extra samples on either side.
 /// Other techniques should be used to detect the start of the packet.
 pub struct PktTrigger {
     config: ChannelEstConfig,
     /// Short history of samples. If state is `Packet`, then the entire (suspected) packet is
     /// contained in `hist`
-    hist: VecDeque<Complex<f32>>,
+    hist: SampleRing,
     state: PktTriggerState,
+    /// Running estimate of the noise power, if `config.power_trig_mode` is adaptive. `None` until
+    /// the skipped samples it starts from have been seen
+    noise_floor: Option<f32>,
+    /// Total power of the samples skipped so far, from which `noise_floor` starts
+    skip_power: f32,
+    /// Index in `hist` of the sample that triggered the current packet
+    trigger_offset: usize,
 }
 
 impl PktTrigger {
     pub fn new(config: &ChannelEstConfig) -> Self {
         Self {
             config: config.clone(),
-            hist: VecDeque::new(),
+            hist: SampleRing::with_capacity(config.pkt_spacing as usize + 1),
             state: PktTriggerState::Skip(0),
+            noise_floor: None,
+            skip_power: 0.,
+            trigger_offset: 0,
+        }
+    }
+
+    /// The current power level for packet detection. Only changes while idle
+    pub fn power_trig(&self) -> f32 {
+        match (self.config.power_trig_mode, self.noise_floor) {
+            (PowerTrigMode::Adaptive { factor }, Some(floor)) => factor * floor,
+            // Until there is a noise floor estimate, any sample is above the noise
+            (PowerTrigMode::Adaptive { .. }, None) => std::f32::INFINITY,
+            (PowerTrigMode::Fixed, _) => self.config.power_trig,
         }
     }
 
+    /// The power level below which a sample counts as quiet once a packet has started:
+    /// `power_trig` scaled by `config.power_stop_ratio`
+    pub fn stop_trig(&self) -> f32 {
+        self.power_trig() * self.config.power_stop_ratio
+    }
+
+    /// Return to the state `new` leaves it in, e.g. when switching to another capture: any partial
+    /// packet and the noise floor estimate are dropped, and the first `stabilize_samps` samples
+    /// are skipped again
+    pub fn reset(&mut self) {
+        self.hist.keep_last(0);
+        self.state = PktTriggerState::Skip(0);
+        self.noise_floor = None;
+        self.skip_power = 0.;
+        self.trigger_offset = 0;
+    }
+
+    /// Whether we are in the middle of a (suspected) packet
+    pub fn in_packet(&self) -> bool {
+        matches!(self.state, PktTriggerState::Packet(_))
+    }
+
     /// Takes in samples and returns a packets if detected
-    pub fn push_samp(&mut self, samp: Complex<f32>) -> Option<Vec<Complex<f32>>> {
+    pub fn push_samp(&mut self, samp: Complex<f32>) -> Option<DetectedPacket> {
+        self.push(samp, samp.norm_sqr())
+    }
+
+    /// Like `push_samp`, but for a stream of real samples (e.g. from an ADC without an IQ
+    /// demodulator). Returned packets contain the samples with a zero imaginary part.
+    ///
+    /// Note: a real bandpass signal `|a| cos(wt + phi)` has half the average power of its analytic
+    /// signal `a e^{jwt}`, and its instantaneous power dips to zero twice per cycle. Hence
+    /// `power_trig` should be set about 3 dB lower than for the equivalent complex stream, and
+    /// `pkt_spacing` must be long enough that zero crossings aren't mistaken for the end of a
+    /// packet. The returned packets must be converted to complex baseband (e.g. with a Hilbert
+    /// transform and downconversion) before they can be parsed
+    pub fn push_samp_real(&mut self, samp: f32) -> Option<DetectedPacket> {
+        self.push(Complex::new(samp, 0.), samp * samp)
+    }
+
+    /// Implements `push_samp` given the sample's power (`|samp|^2`)
+    fn push(&mut self, samp: Complex<f32>, power: f32) -> Option<DetectedPacket> {
         match self.state {
             PktTriggerState::Skip(skip) => {
+                self.skip_power += power;
                 if skip >= self.config.stabilize_samps {
+                    if let PowerTrigMode::Adaptive { .. } = self.config.power_trig_mode {
+                        self.noise_floor = Some(self.skip_power / (skip + 1) as f32);
+                    }
                     self.state = PktTriggerState::Idle;
                 } else {
                     self.state = PktTriggerState::Skip(skip + 1);
@@ -45,31 +138,42 @@ impl PktTrigger {
             }
             PktTriggerState::Idle => {
                 self.hist.push_back(samp);
-                if samp.norm_sqr() > self.config.power_trig {
+                if power > self.power_trig() {
+                    self.trigger_offset = self.hist.len() - 1;
                     self.state = PktTriggerState::Packet(0);
                 } else {
-                    if self.hist.len() as u64 > self.config.pkt_spacing {
-                        self.hist.pop_front();
+                    self.hist.keep_last(self.config.pkt_spacing as usize);
+                    if let Some(floor) = &mut self.noise_floor {
+                        *floor += NOISE_FLOOR_ALPHA * (power - *floor);
                     }
                 }
                 None
             }
             PktTriggerState::Packet(n) => {
                 self.hist.push_back(samp);
-                // Signal strength should be < power_trig for at-least pkt_spacing samples
-                if samp.norm() >= self.config.power_trig {
+                if let Some(max_len) = self.config.max_packet_len {
+                    if self.hist.len() >= max_len {
+                        // Too long to keep buffering. Emit it as though it had ended
+                        let pkt = self.emit();
+                        if self.noise_floor.is_some() {
+                            // Most likely the noise floor rose (e.g. the gain changed) and the
+                            // estimate, which doesn't adapt during packets, is stuck below it.
+                            // Estimate it afresh from the next samples
+                            self.noise_floor = None;
+                            self.skip_power = 0.;
+                            self.state = PktTriggerState::Skip(0);
+                        }
+                        return Some(pkt);
+                    }
+                }
+                // Power should be < stop_trig for at-least pkt_spacing samples
+                if power >= self.stop_trig() {
                     self.state = PktTriggerState::Packet(0);
                     None
                 } else {
                     if n >= self.config.pkt_spacing {
                         // This is our packet
-                        let res = self.hist.iter().map(|x| *x).collect();
-                        // Clear hist and while keeping last self.config.pkt_spacing elements in it
-                        while self.hist.len() as u64 > self.config.pkt_spacing {
-                            self.hist.pop_front();
-                        }
-                        self.state = PktTriggerState::Idle;
-                        Some(res)
+                        Some(self.emit())
                     } else {
                         self.state = PktTriggerState::Packet(n + 1);
                         None
@@ -78,13 +182,302 @@ impl PktTrigger {
             }
         }
     }
+
+    /// Return the packet in `hist` and go back to `Idle`
+    fn emit(&mut self) -> DetectedPacket {
+        let res = DetectedPacket {
+            samples: self.hist.to_vec(),
+            trigger_offset: self.trigger_offset,
+        };
+        // Clear hist and while keeping last self.config.pkt_spacing elements in it
+        self.hist.keep_last(self.config.pkt_spacing as usize);
+        self.state = PktTriggerState::Idle;
+        res
+    }
+}
+
+/// Detects packets by the repetition in the short preamble rather than by energy (as in Schmidl and
+/// Cox). It slides a window of `2 * sts_len` samples and computes the normalized autocorrelation
+/// at a lag of `L = sts_len`,
+///
+/// `|sum_k conj(x[n - k - L]) x[n - k]|^2 / (sum_k |x[n - k - L]|^2 * sum_k |x[n - k]|^2)`
+///
+/// which is in [0, 1]. During the short preamble it plateaus near `S / (S + N)`, whereas for noise
+/// (or any non-repeating signal) it is around `1 / (2 * sts_len)`. Unlike `PktTrigger`, it
+/// doesn't fire on bursts of noise or interference however strong they are
+pub struct PreambleTrigger {
+    /// The metric must exceed this to count towards a plateau
+    threshold: f32,
+    /// Lag of the autocorrelation (length of the STS)
+    lag: usize,
+    /// Length of the correlation window
+    window: usize,
+    /// The last `window + lag + 1` samples, as a circular buffer indexed by sample number
+    hist: Vec<Complex<f32>>,
+    /// Number of samples pushed so far
+    num_samps: u64,
+    /// Running sum of `conj(x[n - lag]) x[n]` over the window
+    corr: Complex<f64>,
+    /// Running sum of `|x[n]|^2` over the window
+    energy: f64,
+    /// Running sum of `|x[n - lag]|^2` over the window
+    lag_energy: f64,
+    /// Number of consecutive samples the metric has been above `threshold`
+    plateau_len: usize,
+    /// Whether we already fired for the current plateau
+    fired: bool,
+}
+
+impl PreambleTrigger {
+    /// `threshold` is the value `metric` must stay above for an STS length for the trigger to
+    /// fire. 0.7 is a reasonable start; lower it to detect weaker packets
+    pub fn new(config: &ChannelEstConfig, threshold: f32) -> Self {
+        let lag = config.sts.as_ref().unwrap().len();
+        let window = 2 * lag;
+        Self {
+            threshold,
+            lag,
+            window,
+            hist: vec![Complex::zero(); window + lag + 1],
+            num_samps: 0,
+            corr: Complex::zero(),
+            energy: 0.,
+            lag_energy: 0.,
+            plateau_len: 0,
+            fired: false,
+        }
+    }
+
+    /// The normalized autocorrelation (see `PreambleTrigger`) at the latest sample
+    pub fn metric(&self) -> f32 {
+        let norm = self.energy * self.lag_energy;
+        if norm > 0. {
+            (self.corr.norm_sqr() / norm).min(1.) as f32
+        } else {
+            0.
+        }
+    }
+
+    /// Takes in the next sample. Returns the index (counting from 0 for the first sample pushed)
+    /// of the sample at which the metric first crossed the threshold, once it has stayed above
+    /// it for an STS length. Fires at-most once per plateau
+    pub fn push_samp(&mut self, samp: Complex<f32>) -> Option<u64> {
+        let len = self.hist.len() as u64;
+        let at = |n: u64| (n % len) as usize;
+        let n = self.num_samps;
+        self.hist[at(n)] = samp;
+        let cplx = |x: Complex<f32>| Complex::new(f64::from(x.re), f64::from(x.im));
+
+        // Add the newest sample and remove the one that left the window
+        let (lag, window) = (self.lag as u64, self.window as u64);
+        if n >= lag {
+            let prev = cplx(self.hist[at(n - lag)]);
+            self.corr += prev.conj() * cplx(samp);
+            self.lag_energy += prev.norm_sqr();
+        }
+        self.energy += cplx(samp).norm_sqr();
+        if n >= window {
+            let old = cplx(self.hist[at(n - window)]);
+            self.energy -= old.norm_sqr();
+            if n >= window + lag {
+                let old_prev = cplx(self.hist[at(n - window - lag)]);
+                self.corr -= old_prev.conj() * old;
+                self.lag_energy -= old_prev.norm_sqr();
+            }
+        }
+        self.num_samps += 1;
+
+        if self.metric() > self.threshold {
+            self.plateau_len += 1;
+            if self.plateau_len >= self.lag && !self.fired {
+                self.fired = true;
+                return Some(n + 1 - self.plateau_len as u64);
+            }
+        } else {
+            self.plateau_len = 0;
+            self.fired = false;
+        }
+        None
+    }
+}
+
+/// How the detector's test statistic behaves on noise alone, for picking `power_trig`. The
+/// statistic is an average of `|x|^2` over `integrated_samples` independent noise samples, so with
+/// complex Gaussian noise it is Gamma distributed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetectorNoiseModel {
+    /// Effective number of independent samples the detector integrates before comparing against
+    /// the threshold
+    pub integrated_samples: usize,
+    /// Sample rate (in samples per second), if known
+    pub samp_rate: Option<f64>,
+}
+
+/// Effective number of independent noise samples integrated by a detector that sums `window`
+/// terms per test and fires once `plateau` consecutive tests pass. The tests in a plateau share
+/// all but one term with their neighbour, so between them they span `window + plateau - 1` terms.
+/// This treats the plateau as a single test over all of them, which is a good approximation when
+/// the threshold is well above the noise
+pub fn effective_integrated_samples(window: usize, plateau: usize) -> usize {
+    assert!(window > 0 && plateau > 0);
+    window + plateau - 1
+}
+
+impl DetectorNoiseModel {
+    /// The model for `PktTrigger` with the given config. It compares every sample's power against
+    /// `power_trig` individually and fires on the first one above it, so it integrates a single
+    /// sample
+    pub fn new(config: &ChannelEstConfig) -> Self {
+        Self {
+            integrated_samples: effective_integrated_samples(1, 1),
+            samp_rate: config.samp_rate,
+        }
+    }
+
+    /// The model for `trigger`. Its autocorrelation sums `window` products of samples `lag`
+    /// apart, which are uncorrelated for white noise, and it fires once the metric has stayed
+    /// above the threshold for `lag` samples. The metric is normalized by the power, so on noise
+    /// alone it averages `1 / window` rather than the noise power
+    pub fn for_preamble_trigger(trigger: &PreambleTrigger, samp_rate: Option<f64>) -> Self {
+        Self {
+            integrated_samples: effective_integrated_samples(trigger.window, trigger.lag),
+            samp_rate,
+        }
+    }
+
+    /// Noise-equivalent bandwidth (in Hz) of the detector, if the sample rate is known.
+    /// Integrating `n` samples narrows the bandwidth of the full-band samples by `n`
+    pub fn noise_equivalent_bandwidth(&self) -> Option<f64> {
+        self.samp_rate.map(|r| r / self.integrated_samples as f64)
+    }
+
+    /// Probability that noise of power `noise_floor` alone exceeds `threshold` in one test
+    pub fn false_alarm_prob(&self, threshold: f32, noise_floor: f32) -> f64 {
+        // The sum of n exponentials exceeds x with probability Q(n, x) = e^-x sum_{k<n} x^k / k!
+        let n = self.integrated_samples;
+        let x = f64::from(threshold / noise_floor) * n as f64;
+        let (mut term, mut sum) = (1f64, 1f64);
+        for k in 1..n {
+            term *= x / k as f64;
+            sum += term;
+        }
+        (-x).exp() * sum
+    }
+
+    /// The `power_trig` at which noise of power `noise_floor` alone triggers the detector with
+    /// probability `pfa` per test
+    pub fn threshold_for_pfa(&self, pfa: f64, noise_floor: f32) -> f32 {
+        assert!(0. < pfa && pfa < 1.);
+        // `false_alarm_prob` decreases with the threshold. Find an upper bound and bisect
+        let mut hi = noise_floor;
+        while self.false_alarm_prob(hi, noise_floor) > pfa {
+            hi *= 2.;
+        }
+        let mut lo = 0.;
+        for _ in 0..50 {
+            let mid = (lo + hi) / 2.;
+            if self.false_alarm_prob(mid, noise_floor) > pfa {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        hi
+    }
+}
+
+/// Run `PktTrigger` over a recording and return every packet buffer it detects, without decoding
+/// them. Useful to split a long recording into individual packets
+pub fn segment_packets(
+    samps: &[Complex<f32>],
+    config: &ChannelEstConfig,
+) -> Vec<Vec<Complex<f32>>> {
+    let mut trigger = PktTrigger::new(config);
+    samps
+        .iter()
+        .filter_map(|s| trigger.push_samp(*s))
+        .map(|pkt| pkt.samples)
+        .collect()
+}
+
+/// Write each segment (e.g. from `segment_packets`) to its own file in `dir`, named
+/// `<prefix><index>.txt`, in the format read by `config::filename_to_cplx_vec`. Returns the paths
+/// written
+pub fn write_segments<P: AsRef<Path>>(
+    dir: P,
+    prefix: &str,
+    segments: &[Vec<Complex<f32>>],
+) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(&dir)?;
+    let mut paths = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let path = dir.as_ref().join(format!("{}{}.txt", prefix, i));
+        cplx_vec_to_file(&path, segment)?;
+        paths.push(path);
+    }
+    Ok(paths)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::PktTrigger;
-    use crate::config::ChannelEstConfig;
-    use num::Complex;
+    use super::{
+        effective_integrated_samples, segment_packets, write_segments, DetectorNoiseModel,
+        PktTrigger, PowerTrigMode, PreambleTrigger,
+    };
+    use crate::config::{build_preamble, filename_to_cplx_vec, ChannelEstConfig};
+    use crate::sim::add_awgn;
+    use num::{Complex, Zero};
+    use rand::SeedableRng;
+    use std::collections::VecDeque;
+
+    /// The trigger as it was implemented with a `VecDeque`, to check `PktTrigger` against
+    fn vec_deque_trigger(
+        samps: &[Complex<f32>],
+        config: &ChannelEstConfig,
+    ) -> Vec<Vec<Complex<f32>>> {
+        let spacing = config.pkt_spacing as usize;
+        let mut hist = VecDeque::new();
+        let mut pkts = Vec::new();
+        // `None` when idle, else the number of quiet samples in the packet so far
+        let mut quiet = None;
+        for samp in samps.iter().skip(config.stabilize_samps as usize + 1) {
+            hist.push_back(*samp);
+            match quiet {
+                None => {
+                    if samp.norm_sqr() > config.power_trig {
+                        quiet = Some(0);
+                    } else if hist.len() > spacing {
+                        hist.pop_front();
+                    }
+                }
+                Some(_) if samp.norm_sqr() >= config.power_trig => quiet = Some(0),
+                Some(n) if n >= spacing => {
+                    pkts.push(hist.iter().copied().collect());
+                    while hist.len() > spacing {
+                        hist.pop_front();
+                    }
+                    quiet = None;
+                }
+                Some(n) => quiet = Some(n + 1),
+            }
+        }
+        pkts
+    }
+
+    /// A long stretch of silence followed by a few bursts with noisy edges
+    fn idle_then_packets(idle_len: usize) -> Vec<Complex<f32>> {
+        let mut samps: Vec<_> = (0..idle_len)
+            .map(|i| Complex::new(0.001 * ((i % 13) as f32), 0.))
+            .collect();
+        for len in &[400, 90, 1500] {
+            samps.extend((0..*len).map(|i| Complex::new(0., 0.37 * i as f32).exp()));
+            // Dips below the threshold shorter than `pkt_spacing` shouldn't split the packet
+            samps.extend(std::iter::repeat(Complex::zero()).take(10));
+            samps.extend((0..*len).map(|i| Complex::new(0.2, 0.05 * i as f32)));
+            samps.extend(std::iter::repeat(Complex::new(0.001, 0.)).take(200));
+        }
+        samps
+    }
 
     #[test]
     fn test_basic_pkt_trigger() {
@@ -117,7 +510,386 @@ mod tests {
             }
             let pkt = trigger.push_samp(Complex::new(0., 0.));
             assert!(pkt.is_some());
-            assert!(pkt.unwrap()[config.pkt_spacing as usize] == Complex::new(1.1, 0.9));
+            let pkt = pkt.unwrap();
+            assert_eq!(pkt.trigger_offset, config.pkt_spacing as usize);
+            assert!(pkt.samples[pkt.trigger_offset] == Complex::new(1.1, 0.9));
+        }
+    }
+
+    #[test]
+    fn test_max_packet_len() {
+        let mut config = ChannelEstConfig::default();
+        config.max_packet_len = Some(1000);
+        let mut trigger = PktTrigger::new(&config);
+        // The first sample is skipped (`stabilize_samps` is 0)
+        for _ in 0..=config.pkt_spacing {
+            assert!(trigger.push_samp(Complex::zero()).is_none());
+        }
+
+        // A transmitter that never stops
+        let mut pkts = Vec::new();
+        for i in 0..5000 {
+            if let Some(pkt) = trigger.push_samp(Complex::new(0., 0.1 * i as f32).exp()) {
+                assert!(!trigger.in_packet());
+                pkts.push(pkt);
+            }
+        }
+        assert_eq!(pkts.len(), 5);
+        for pkt in &pkts {
+            assert_eq!(pkt.samples.len(), 1000);
+            assert_eq!(pkt.trigger_offset, config.pkt_spacing as usize);
+        }
+        let pkt_spacing = config.pkt_spacing as usize;
+        assert!(pkts[0].samples[..pkt_spacing].iter().all(|x| x.is_zero()));
+
+        // Without a limit, it keeps buffering
+        config.max_packet_len = None;
+        let mut trigger = PktTrigger::new(&config);
+        for i in 0..5000 {
+            assert!(trigger
+                .push_samp(Complex::new(0., 0.1 * i as f32).exp())
+                .is_none());
+        }
+        assert!(trigger.in_packet());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut config = ChannelEstConfig::default();
+        config.stabilize_samps = 10;
+        let mut trigger = PktTrigger::new(&config);
+        for _ in 0..100 {
+            assert!(trigger.push_samp(Complex::zero()).is_none());
+        }
+        for _ in 0..50 {
+            assert!(trigger.push_samp(Complex::new(1., 0.)).is_none());
+        }
+        assert!(trigger.in_packet());
+
+        // The partial packet is dropped and the stabilization period skipped again
+        trigger.reset();
+        assert!(!trigger.in_packet());
+        for _ in 0..=config.stabilize_samps {
+            assert!(trigger.push_samp(Complex::new(1., 0.)).is_none());
+            assert!(!trigger.in_packet());
+        }
+        for _ in 0..2 * config.pkt_spacing {
+            assert!(trigger.push_samp(Complex::zero()).is_none());
+        }
+        for _ in 0..5 {
+            assert!(trigger.push_samp(Complex::new(0., 1.)).is_none());
+        }
+        let pkt = (0..=config.pkt_spacing)
+            .find_map(|_| trigger.push_samp(Complex::zero()))
+            .unwrap();
+        let pkt_spacing = config.pkt_spacing as usize;
+        assert_eq!(pkt.samples.len(), 2 * pkt_spacing + 6);
+        assert_eq!(pkt.trigger_offset, pkt_spacing);
+        assert!(pkt.samples[..pkt_spacing].iter().all(|x| x.is_zero()));
+        assert!(pkt.samples[pkt_spacing..pkt_spacing + 5]
+            .iter()
+            .all(|x| *x == Complex::new(0., 1.)));
+    }
+
+    #[test]
+    fn test_power_stop_ratio() {
+        // A packet whose power dips just under the trigger level for a while in the middle
+        let level = |power: f32, len: usize| vec![Complex::new(power.sqrt(), 0.); len];
+        let mut config = ChannelEstConfig::default();
+        let pkt_spacing = config.pkt_spacing as usize;
+        let mut samps = level(1e-4, 100);
+        samps.extend(level(0.02, 300));
+        samps.extend(level(0.006, 3 * pkt_spacing));
+        samps.extend(level(0.02, 300));
+        samps.extend(level(1e-4, 100));
+
+        let segment = |config: &ChannelEstConfig| {
+            let mut trigger = PktTrigger::new(config);
+            samps
+                .iter()
+                .filter_map(|x| trigger.push_samp(*x))
+                .map(|pkt| pkt.samples.len())
+                .collect::<Vec<_>>()
+        };
+        // With a single threshold, the dip splits it in two
+        assert_eq!(segment(&config).len(), 2);
+        config.power_stop_ratio = 0.5;
+        assert_eq!(segment(&config), vec![600 + 5 * pkt_spacing + 1]);
+    }
+
+    #[test]
+    fn test_segment_packets() {
+        let config = ChannelEstConfig::default();
+        let spacing = config.pkt_spacing as usize;
+
+        // Three bursts of different lengths separated by silence
+        let pkt_lens = [300, 500, 200];
+        let mut samps = vec![Complex::zero(); 100];
+        for len in &pkt_lens {
+            samps.extend((0..*len).map(|i| Complex::new(0., 0.1 * i as f32).exp()));
+            samps.extend(std::iter::repeat(Complex::zero()).take(3 * spacing));
+        }
+
+        let segments = segment_packets(&samps, &config);
+        assert_eq!(segments.len(), pkt_lens.len());
+        for (segment, len) in segments.iter().zip(&pkt_lens) {
+            // `pkt_spacing` samples on either side, and the sample that ended the packet
+            assert_eq!(segment.len(), len + 2 * spacing + 1);
+            assert_eq!(segment[spacing], Complex::new(1., 0.));
+        }
+
+        let dir = std::env::temp_dir().join("channel_est_test_segments");
+        let paths = write_segments(&dir, "pkt", &segments).unwrap();
+        assert_eq!(paths.len(), segments.len());
+        for (path, segment) in paths.iter().zip(&segments) {
+            let read = filename_to_cplx_vec(path.to_str().unwrap().to_string()).unwrap();
+            assert_eq!(&read, segment);
+        }
+    }
+
+    #[test]
+    fn test_push_samp_real() {
+        let mut config = ChannelEstConfig::default();
+        config.stabilize_samps = 10;
+        let mut real_trigger = PktTrigger::new(&config);
+        let mut cplx_trigger = PktTrigger::new(&config);
+
+        // A real-valued tone burst between stretches of silence
+        let mut samps = vec![0f32; 100];
+        for _ in 0..3 {
+            samps.extend((0..300).map(|i| (0.3 * i as f32).cos()));
+            samps.extend(std::iter::repeat(0.).take(100));
+        }
+
+        let mut num_pkts = 0;
+        for x in samps {
+            let real_pkt = real_trigger.push_samp_real(x);
+            let cplx_pkt = cplx_trigger.push_samp(Complex::new(x, 0.));
+            assert_eq!(real_pkt, cplx_pkt);
+            if real_pkt.is_some() {
+                num_pkts += 1;
+            }
+        }
+        assert_eq!(num_pkts, 3);
+    }
+
+    #[test]
+    fn test_matches_vec_deque() {
+        let mut config = ChannelEstConfig::default();
+        for &(stabilize_samps, pkt_spacing) in &[(0, 20), (7, 1), (100, 64)] {
+            config.stabilize_samps = stabilize_samps;
+            config.pkt_spacing = pkt_spacing;
+            let samps = idle_then_packets(1000);
+            let expected = vec_deque_trigger(&samps, &config);
+            assert!(expected.len() >= 3);
+            assert_eq!(segment_packets(&samps, &config), expected);
+        }
+    }
+
+    #[test]
+    fn test_threshold_for_pfa() {
+        let mut config = ChannelEstConfig::default();
+        config.samp_rate = Some(20e6);
+        let model = DetectorNoiseModel::new(&config);
+        assert_eq!(model.noise_equivalent_bandwidth(), Some(20e6));
+
+        // With a single sample, the threshold is -noise_floor * ln(pfa)
+        let noise_floor = 0.01;
+        let threshold = model.threshold_for_pfa(1e-3, noise_floor);
+        assert!((threshold - noise_floor * 1e3f32.ln()).abs() < 1e-5);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut noise = vec![Complex::zero(); 500_000];
+        add_awgn(&mut noise, noise_floor, &mut rng);
+        for &pfa in &[1e-2, 1e-3] {
+            let threshold = model.threshold_for_pfa(pfa, noise_floor);
+            let num_alarms = noise.iter().filter(|x| x.norm_sqr() > threshold).count();
+            let measured = num_alarms as f64 / noise.len() as f64;
+            assert!((measured - pfa).abs() < 0.2 * pfa);
+        }
+
+        // Integrating several samples
+        let model = DetectorNoiseModel {
+            integrated_samples: 4,
+            samp_rate: None,
+        };
+        assert_eq!(model.noise_equivalent_bandwidth(), None);
+        let pfa = 1e-2;
+        let threshold = model.threshold_for_pfa(pfa, noise_floor);
+        let chunks = noise.chunks(4);
+        let num_tests = chunks.len();
+        let num_alarms = chunks
+            .filter(|c| c.iter().map(|x| x.norm_sqr()).sum::<f32>() / 4. > threshold)
+            .count();
+        let measured = num_alarms as f64 / num_tests as f64;
+        assert!((measured - pfa).abs() < 0.2 * pfa);
+    }
+
+    #[test]
+    fn test_effective_integration() {
+        let config = ChannelEstConfig::default();
+        assert_eq!(DetectorNoiseModel::new(&config).integrated_samples, 1);
+        // A window of two STSs and a plateau of one STS
+        let trigger = PreambleTrigger::new(&config, 0.7);
+        let model = DetectorNoiseModel::for_preamble_trigger(&trigger, Some(20e6));
+        assert_eq!(model.integrated_samples, 16 * 2 + 16 - 1);
+
+        // A longer window, or a longer plateau, makes false alarms less likely at a given
+        // threshold
+        let (threshold, noise_floor) = (2., 1.);
+        let pfa = |window: usize, plateau: usize| {
+            let model = DetectorNoiseModel {
+                integrated_samples: effective_integrated_samples(window, plateau),
+                samp_rate: None,
+            };
+            model.false_alarm_prob(threshold, noise_floor)
+        };
+        for &window in &[1, 4, 16] {
+            assert!(pfa(2 * window, 1) < pfa(window, 1));
+            assert!(pfa(window, 8) < pfa(window, 1));
+        }
+    }
+
+    #[test]
+    fn test_preamble_trigger() {
+        let config = ChannelEstConfig::default();
+        let sts = config.sts.clone().unwrap();
+
+        // A packet starting at `pkt_start` in noise that is much stronger than `power_trig`
+        let noise_power = 0.05;
+        assert!(noise_power > 2. * config.power_trig);
+        let pkt_start = 1000;
+        let mut samps = vec![Complex::zero(); pkt_start];
+        samps.extend(build_preamble(&config));
+        samps.extend(std::iter::repeat(Complex::zero()).take(1000));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        add_awgn(&mut samps, noise_power, &mut rng);
+
+        // The energy detector triggers on the noise long before the packet
+        let mut energy_trigger = PktTrigger::new(&config);
+        for x in &samps[..pkt_start / 2] {
+            assert!(energy_trigger.push_samp(*x).is_none());
+        }
+        assert!(energy_trigger.in_packet());
+
+        let mut trigger = PreambleTrigger::new(&config, 0.7);
+        let mut max_noise_metric = 0f32;
+        let mut fired = Vec::new();
+        for (i, x) in samps.iter().enumerate() {
+            if let Some(start) = trigger.push_samp(*x) {
+                fired.push(start);
+            }
+            if i < pkt_start {
+                max_noise_metric = max_noise_metric.max(trigger.metric());
+            }
+        }
+        assert!(max_noise_metric < 0.5, "{}", max_noise_metric);
+        assert_eq!(fired.len(), 1);
+        let start = fired[0] as usize;
+        assert!(
+            pkt_start < start && start < pkt_start + 5 * sts.len(),
+            "{}",
+            start
+        );
+    }
+
+    #[test]
+    fn test_adaptive_power_trig() {
+        let mut config = ChannelEstConfig::default();
+        config.power_trig_mode = PowerTrigMode::Adaptive { factor: 20. };
+        // Far above both the noise and the packets, so only the adaptive level can trigger
+        config.power_trig = 100.;
+        let noise_power = 1e-4;
+
+        // Two packets 10 dB apart, both well above the noise
+        let mut samps = vec![Complex::zero(); 500];
+        let amplitudes = [0.3, 0.1];
+        for amplitude in &amplitudes {
+            samps.extend((0..300).map(|i| Complex::from_polar(amplitude, &(0.7 * i as f32))));
+            samps.extend(std::iter::repeat(Complex::zero()).take(500));
         }
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        add_awgn(&mut samps, noise_power, &mut rng);
+
+        let mut trigger = PktTrigger::new(&config);
+        let mut pkts = Vec::new();
+        for (i, x) in samps.iter().enumerate() {
+            if i == 450 {
+                // The floor has converged to the noise power
+                let floor = trigger.power_trig() / 20.;
+                assert!((floor / noise_power - 1.).abs() < 0.5, "{}", floor);
+            }
+            if let Some(pkt) = trigger.push_samp(*x) {
+                pkts.push(pkt.samples);
+            }
+        }
+        assert_eq!(pkts.len(), amplitudes.len());
+        for (pkt, amplitude) in pkts.iter().zip(&amplitudes) {
+            let max = pkt.iter().map(|x| x.norm()).fold(0., f32::max);
+            assert!((max - amplitude).abs() < 0.5 * amplitude);
+            assert!(pkt.len() >= 300 && pkt.len() < 300 + 4 * config.pkt_spacing as usize);
+        }
+
+        // The floor doesn't adapt to the packet, so it remains near the noise power
+        assert!(trigger.power_trig() < 100. * noise_power);
+
+        // With the fixed level, neither packet triggers
+        config.power_trig_mode = PowerTrigMode::Fixed;
+        assert!(segment_packets(&samps, &config).is_empty());
+    }
+
+    #[test]
+    fn test_adaptive_floor_recovers() {
+        let mut config = ChannelEstConfig::default();
+        config.power_trig_mode = PowerTrigMode::Adaptive { factor: 20. };
+        config.stabilize_samps = 100;
+        config.max_packet_len = Some(1000);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut noise = |power: f32, len: usize| {
+            let mut samps = vec![Complex::zero(); len];
+            add_awgn(&mut samps, power, &mut rng);
+            samps
+        };
+
+        // The floor starts from the mean of the skipped samples, not from a single sample
+        let mut trigger = PktTrigger::new(&config);
+        for x in noise(1e-4, config.stabilize_samps as usize + 1) {
+            assert!(trigger.push_samp(x).is_none());
+        }
+        let floor = trigger.power_trig() / 20.;
+        assert!((floor / 1e-4 - 1.).abs() < 0.3, "{}", floor);
+
+        // The noise floor rises 20 dB for good. The trigger fires, but once the packet hits
+        // `max_packet_len` the floor is estimated afresh and it settles down
+        let mut pkts = 0;
+        for x in noise(1e-4, 500).into_iter().chain(noise(1e-2, 5000)) {
+            if trigger.push_samp(x).is_some() {
+                pkts += 1;
+            }
+        }
+        assert_eq!(pkts, 1);
+        assert!(!trigger.in_packet());
+        let floor = trigger.power_trig() / 20.;
+        assert!((floor / 1e-2 - 1.).abs() < 0.5, "{}", floor);
+    }
+
+    /// A packet whose tail has an amplitude between `power_trig` and `sqrt(power_trig)`, i.e. a
+    /// power below `power_trig`. The packet must end at the start of the tail
+    #[test]
+    fn test_end_of_packet_power() {
+        let config = ChannelEstConfig::default();
+        let spacing = config.pkt_spacing as usize;
+        let tail_amplitude = 0.05;
+        assert!(config.power_trig < tail_amplitude);
+        assert!(tail_amplitude * tail_amplitude < config.power_trig);
+
+        let mut samps = vec![Complex::zero(); 100];
+        samps.extend((0..200).map(|i| Complex::new(0., 0.2 * i as f32).exp()));
+        samps.extend(std::iter::repeat(Complex::new(tail_amplitude, 0.)).take(500));
+
+        let segments = segment_packets(&samps, &config);
+        assert_eq!(segments.len(), 1);
+        // `pkt_spacing` samples before the packet, the packet, then `pkt_spacing + 1` tail samples
+        assert_eq!(segments[0].len(), 200 + 2 * spacing + 1);
     }
 }
@@ -0,0 +1,310 @@
+//! Filters for shaping, shifting and resampling sample streams
+
+use num::{Complex, Zero};
+use rustfft::FFTplanner;
+use std::f32::consts::PI;
+
+/// Shape parameter of the Kaiser window used by `fractional_delay`. Larger values trade a wider
+/// transition band for lower ripple; 8 keeps the error below 1e-4 up to about 0.6 * Nyquist with
+/// 31 taps
+pub const KAISER_BETA: f32 = 8.;
+
+/// Zeroth-order modified Bessel function of the first kind, needed for the Kaiser window
+fn bessel_i0(x: f32) -> f32 {
+    // Power series: sum_k ((x / 2)^k / k!)^2
+    let (mut sum, mut term, mut k) = (1., 1., 1.);
+    loop {
+        term *= (x / (2. * k)) * (x / (2. * k));
+        sum += term;
+        k += 1.;
+        if term < 1e-9 * sum {
+            return sum;
+        }
+    }
+}
+
+/// Taps of a Kaiser-windowed sinc filter that delays a signal by `delay` samples. Tap `k` applies
+/// to the sample `k - (num_taps - 1) / 2` samples in the past, so the filter's own group delay is
+/// `(num_taps - 1) / 2` samples (with integer division). `fractional_delay` compensates for it
+pub fn kaiser_sinc_taps(delay: f32, num_taps: usize, beta: f32) -> Vec<f32> {
+    assert!(num_taps > 0);
+    let center = ((num_taps - 1) / 2) as f32;
+    let half_width = (num_taps - 1) as f32 / 2.;
+    (0..num_taps)
+        .map(|k| {
+            let t = k as f32 - center - delay;
+            sinc(t) * kaiser_window(t, half_width, beta)
+        })
+        .collect()
+}
+
+/// `sin(pi t) / (pi t)`
+fn sinc(t: f32) -> f32 {
+    if t == 0. {
+        1.
+    } else {
+        (PI * t).sin() / (PI * t)
+    }
+}
+
+/// Kaiser window of the given half width (and shape `beta`) at `t` samples from its center
+fn kaiser_window(t: f32, half_width: f32, beta: f32) -> f32 {
+    if half_width == 0. {
+        1.
+    } else if t.abs() <= half_width {
+        bessel_i0(beta * (1. - (t / half_width).powi(2)).sqrt()) / bessel_i0(beta)
+    } else {
+        0.
+    }
+}
+
+/// Convolve `samps` with the FIR filter `taps`, i.e. `y[n] = sum_k taps[k] * x[n - k]`. The
+/// output contains the full response and is `taps.len() - 1` samples longer than the input
+pub fn fir_filter(samps: &[Complex<f32>], taps: &[Complex<f32>]) -> Vec<Complex<f32>> {
+    if samps.is_empty() || taps.is_empty() {
+        return Vec::new();
+    }
+    (0..samps.len() + taps.len() - 1)
+        .map(|n| {
+            taps.iter()
+                .enumerate()
+                .filter_map(|(k, h)| Some(h * samps.get(n.checked_sub(k)?)?))
+                .sum()
+        })
+        .collect()
+}
+
+/// Delay `samps` by `delay` samples (which need not be an integer) using a Kaiser-windowed sinc
+/// interpolator with `num_taps` taps. The output is as long as the input and is aligned with it
+/// (i.e. the filter's group delay is removed). Samples outside the input are taken to be zero, so
+/// the first and last `num_taps / 2` outputs are less accurate. Delays should be small compared to
+/// `num_taps / 2`
+pub fn fractional_delay(samps: &[Complex<f32>], delay: f32, num_taps: usize) -> Vec<Complex<f32>> {
+    let taps = kaiser_sinc_taps(delay, num_taps, KAISER_BETA);
+    let center = (num_taps - 1) / 2;
+    (0..samps.len())
+        .map(|n| {
+            // y[n] = sum_k taps[k] * x[n + center - k]
+            taps.iter()
+                .enumerate()
+                .filter_map(|(k, h)| {
+                    let i = (n + center).checked_sub(k)?;
+                    samps.get(i).map(|x| x * *h)
+                })
+                .sum::<Complex<f32>>()
+        })
+        .collect()
+}
+
+/// Delay `samps` by `delay` samples (which need not be an integer, and may be negative to advance
+/// them) by applying a linear phase ramp in the frequency domain. Unlike `fractional_delay`, it
+/// is exact for any delay, but the shift is circular: samples delayed past the end wrap around to
+/// the start, so `samps` should begin and end with some silence. Used with
+/// `lts_align::fractional_timing` to align a packet to the sample grid
+pub fn shift_fractional(samps: &[Complex<f32>], delay: f32) -> Vec<Complex<f32>> {
+    let n = samps.len();
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut spectrum = vec![Complex::zero(); n];
+    FFTplanner::new(false)
+        .plan_fft(n)
+        .process(&mut samps.to_vec(), &mut spectrum);
+    for (m, x) in spectrum.iter_mut().enumerate() {
+        let freq = if 2 * m < n {
+            m as f32
+        } else {
+            m as f32 - n as f32
+        };
+        *x *= Complex::new(0., -2. * PI * freq * delay / n as f32).exp() / n as f32;
+    }
+    let mut res = vec![Complex::zero(); n];
+    FFTplanner::new(true)
+        .plan_fft(n)
+        .process(&mut spectrum, &mut res);
+    res
+}
+
+/// Number of input samples on either side of each output sample that `resample` interpolates
+/// from. More taps give a sharper anti-aliasing filter
+pub const RESAMPLE_TAPS_PER_SIDE: usize = 32;
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Change the sample rate of `samps` from `from_rate` to `to_rate` (in any unit, e.g. Hz), for
+/// instance to bring a 25 MHz capture to the 20 MHz of 802.11a before packet detection. The ratio
+/// is reduced to `up / down` and the signal conceptually upsampled by `up`, low-pass filtered
+/// (with a Kaiser-windowed sinc cutting off at the lower of the two Nyquist frequencies) and
+/// downsampled by `down`. A polyphase implementation only evaluates the taps that land on input
+/// samples at the output instants.
+///
+/// The filter's group delay is removed, so output sample `m` is at the same time as input sample
+/// `m * from_rate / to_rate` (in particular the first samples coincide) and the output has
+/// `ceil(samps.len() * to_rate / from_rate)` samples. Samples outside the input are taken to be
+/// zero, so about `RESAMPLE_TAPS_PER_SIDE` outputs at either end are less accurate
+pub fn resample(samps: &[Complex<f32>], from_rate: u32, to_rate: u32) -> Vec<Complex<f32>> {
+    assert!(
+        from_rate > 0 && to_rate > 0,
+        "sample rates must be positive"
+    );
+    let g = gcd(from_rate, to_rate);
+    let (up, down) = ((to_rate / g) as usize, (from_rate / g) as usize);
+    if up == 1 && down == 1 {
+        return samps.to_vec();
+    }
+
+    // Prototype filter at the upsampled rate. It has an odd length, so its group delay `center`
+    // is a whole number of upsampled samples. The gain of `up` makes up for the zeros that
+    // upsampling inserts
+    let center = RESAMPLE_TAPS_PER_SIDE * up;
+    let cutoff = 1. / up.max(down) as f32;
+    let taps: Vec<f32> = (0..2 * center + 1)
+        .map(|k| {
+            let t = k as f32 - center as f32;
+            up as f32 * cutoff * sinc(cutoff * t) * kaiser_window(t, center as f32, KAISER_BETA)
+        })
+        .collect();
+
+    let num_out = (samps.len() * up + down - 1) / down;
+    (0..num_out)
+        .map(|m| {
+            // y[m] = sum_k taps[k] * x_up[m * down + center - k], where x_up[i] is x[i / up] if
+            // `up` divides i and zero otherwise. So only every `up`th tap (one polyphase branch)
+            // contributes
+            let t = m * down + center;
+            (t % up..taps.len())
+                .step_by(up)
+                .filter_map(|k| {
+                    let i = t.checked_sub(k)? / up;
+                    samps.get(i).map(|x| x * taps[k])
+                })
+                .sum()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bessel_i0() {
+        assert!((bessel_i0(0.) - 1.).abs() < 1e-6);
+        assert!((bessel_i0(1.) - 1.266_066).abs() < 1e-5);
+        assert!((bessel_i0(8.) - 427.564_1).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_fractional_delay_tone() {
+        let delay = 0.3;
+        for &freq in &[0.2f32, 1., 2.] {
+            let tone: Vec<_> = (0..200)
+                .map(|n| Complex::new(0., freq * n as f32).exp())
+                .collect();
+            let delayed = fractional_delay(&tone, delay, 31);
+            assert_eq!(delayed.len(), tone.len());
+
+            // Away from the edges, delaying a tone is the same as rotating its phase
+            let shift = Complex::new(0., -freq * delay).exp();
+            for (y, x) in delayed.iter().zip(&tone).skip(40).take(120) {
+                assert!((y - x * shift).norm() < 1e-3);
+            }
+        }
+
+        // An integer delay just shifts the samples
+        let samps: Vec<_> = (0..50)
+            .map(|n| Complex::new(n as f32, -(n as f32)))
+            .collect();
+        let delayed = fractional_delay(&samps, 2., 15);
+        for (y, x) in delayed[10..40].iter().zip(&samps[8..38]) {
+            assert!((y - x).norm() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_shift_fractional() {
+        let mut samps = vec![Complex::zero(); 20];
+        samps.extend((0..50).map(|n| Complex::new((n as f32 * 0.3).sin(), n as f32 / 50.)));
+        samps.extend(vec![Complex::zero(); 20]);
+
+        // Integer delays just move the samples
+        let shifted = shift_fractional(&samps, 3.);
+        for (y, x) in shifted[3..].iter().zip(&samps) {
+            assert!((y - x).norm() < 1e-4);
+        }
+        // Fractional shifts undo each other
+        let back = shift_fractional(&shift_fractional(&samps, 0.37), -0.37);
+        for (y, x) in back.iter().zip(&samps) {
+            assert!((y - x).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_resample_tone() {
+        let tone = |freq: f32, rate: u32, len: usize| -> Vec<Complex<f32>> {
+            (0..len)
+                .map(|n| Complex::from_polar(&0.7, &(2. * PI * freq * n as f32 / rate as f32)))
+                .collect()
+        };
+        // Frequencies in MHz, including ones near the edge of the 802.11a band
+        for &(from, to, freq) in &[(25, 20, 3.), (25, 20, -8.), (20, 25, 5.), (3, 2, 0.6)] {
+            let out = resample(&tone(freq, from, 400), from, to);
+            assert_eq!(
+                out.len(),
+                (400 * to as usize + from as usize - 1) / from as usize
+            );
+
+            // Away from the edges, it is the same tone sampled at the new rate
+            let expected = tone(freq, to, out.len());
+            for (y, x) in out.iter().zip(&expected).skip(50).take(out.len() - 100) {
+                assert!(
+                    (y - x).norm() < 1e-3,
+                    "{} -> {} at {}: {}",
+                    from,
+                    to,
+                    freq,
+                    y
+                );
+            }
+        }
+
+        // Tones beyond the new Nyquist frequency are filtered out rather than aliased
+        let out = resample(&tone(12., 25, 400), 25, 20);
+        assert!(out[50..out.len() - 50].iter().all(|y| y.norm() < 1e-3));
+
+        // Equal rates leave the samples untouched
+        let samps = tone(1., 20, 30);
+        assert_eq!(resample(&samps, 20_000_000, 20_000_000), samps);
+    }
+
+    #[test]
+    fn test_fir_filter() {
+        let samps = [
+            Complex::new(1., 0.),
+            Complex::new(0., 1.),
+            Complex::new(2., -1.),
+        ];
+        let taps = [Complex::new(0.5, 0.), Complex::new(0., -0.25)];
+        // Worked out by hand
+        let expected = [
+            Complex::new(0.5, 0.),
+            Complex::new(0., 0.25),
+            Complex::new(1.25, -0.5),
+            Complex::new(-0.25, -0.5),
+        ];
+        let out = fir_filter(&samps, &taps);
+        assert_eq!(out.len(), expected.len());
+        for (y, e) in out.iter().zip(&expected) {
+            assert!((y - e).norm() < 1e-6);
+        }
+
+        // A single unit tap leaves the input unchanged
+        assert_eq!(fir_filter(&samps, &[Complex::new(1., 0.)]), samps.to_vec());
+    }
+}
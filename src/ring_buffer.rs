@@ -0,0 +1,102 @@
+//! A ring buffer of samples, for keeping a short history in the hot sample-by-sample loops
+
+use num::{Complex, Zero};
+
+/// FIFO of samples backed by a single contiguous allocation. Pushing to a full buffer doubles its
+/// capacity, so a buffer that is regularly trimmed back to the same length (e.g. `PktTrigger`'s
+/// history while idle) never reallocates
+pub(crate) struct SampleRing {
+    buf: Vec<Complex<f32>>,
+    /// Index of the oldest sample in `buf`
+    head: usize,
+    /// Number of valid samples
+    len: usize,
+}
+
+impl SampleRing {
+    /// Create an empty buffer that can hold `capacity` samples before it reallocates
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buf: vec![Complex::zero(); capacity.max(1)],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Append a sample at the end (newest side)
+    pub fn push_back(&mut self, samp: Complex<f32>) {
+        if self.len == self.buf.len() {
+            self.grow();
+        }
+        let idx = (self.head + self.len) % self.buf.len();
+        self.buf[idx] = samp;
+        self.len += 1;
+    }
+
+    /// Drop the oldest samples so that at-most `len` remain
+    pub fn keep_last(&mut self, len: usize) {
+        if self.len > len {
+            self.head = (self.head + self.len - len) % self.buf.len();
+            self.len = len;
+        }
+    }
+
+    /// Copy the contents, oldest first
+    pub fn to_vec(&self) -> Vec<Complex<f32>> {
+        let (first, second) = self.as_slices();
+        let mut res = Vec::with_capacity(self.len);
+        res.extend_from_slice(first);
+        res.extend_from_slice(second);
+        res
+    }
+
+    /// The contents as two slices which, concatenated, are in order oldest first
+    fn as_slices(&self) -> (&[Complex<f32>], &[Complex<f32>]) {
+        let end = self.head + self.len;
+        if end <= self.buf.len() {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - self.buf.len()])
+        }
+    }
+
+    /// Double the capacity, moving the contents to the start of the new allocation
+    fn grow(&mut self) {
+        let mut buf = self.to_vec();
+        buf.resize(2 * self.buf.len(), Complex::zero());
+        self.buf = buf;
+        self.head = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SampleRing;
+    use num::Complex;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_matches_vec_deque() {
+        let mut ring = SampleRing::with_capacity(4);
+        let mut deque = VecDeque::new();
+        // Mix of pushes and trims that wraps around and grows the buffer several times
+        for i in 0..1000 {
+            let samp = Complex::new(i as f32, -(i as f32));
+            ring.push_back(samp);
+            deque.push_back(samp);
+            if i % 7 == 0 {
+                let keep = (i * 13) % 20;
+                ring.keep_last(keep);
+                while deque.len() > keep {
+                    deque.pop_front();
+                }
+            }
+            assert_eq!(ring.len(), deque.len());
+            assert_eq!(ring.to_vec(), deque.iter().copied().collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! The real number type samples are made of. Most of the pipeline uses `f32`, but the core
+//! estimation functions also accept `f64` samples, e.g. to study how much precision matters
+
+use crate::config::ChannelEstConfig;
+use num::{Complex, Float};
+use rustfft::{FFTnum, FFTplanner, FFT};
+use std::sync::Arc;
+
+/// A real number type that samples can be made of (`f32` or `f64`)
+pub trait Sample: Float + FFTnum {
+    /// The FFT used to demodulate an LTS-length symbol. `f32` reuses the plan cached in `config`
+    fn lts_ifft(config: &ChannelEstConfig) -> Arc<dyn FFT<Self>>;
+
+    /// Convert a value from the `f32` the config is stored in
+    fn from_config_f32(x: f32) -> Self;
+
+    /// Convert a value computed in `f64`, rounding if need be
+    fn from_calc_f64(x: f64) -> Self;
+
+    /// Convert a complex value from the `f32` the config is stored in
+    fn from_cplx(x: Complex<f32>) -> Complex<Self> {
+        Complex::new(Self::from_config_f32(x.re), Self::from_config_f32(x.im))
+    }
+}
+
+impl Sample for f32 {
+    fn lts_ifft(config: &ChannelEstConfig) -> Arc<dyn FFT<f32>> {
+        config.lts.as_ref().unwrap().2.inverse.clone()
+    }
+
+    fn from_config_f32(x: f32) -> Self {
+        x
+    }
+
+    fn from_calc_f64(x: f64) -> Self {
+        x as f32
+    }
+}
+
+impl Sample for f64 {
+    fn lts_ifft(config: &ChannelEstConfig) -> Arc<dyn FFT<f64>> {
+        let lts_len = config.lts.as_ref().unwrap().0.len();
+        FFTplanner::new(true).plan_fft(lts_len)
+    }
+
+    fn from_config_f32(x: f32) -> Self {
+        f64::from(x)
+    }
+
+    fn from_calc_f64(x: f64) -> Self {
+        x
+    }
+}
@@ -0,0 +1,91 @@
+//! The 802.11a data scrambler, which XORs the DATA field (SERVICE, PSDU, tail and pad bits) with
+//! the 127-bit sequence of the LFSR x^7 + x^4 + 1. Bits are `u8`s that are either 0 or 1, as in
+//! `demod`. The 7-bit LFSR state is held in a `u8` whose bit `i` (least significant first) is
+//! register `x^(i + 1)`
+
+/// Number of bits of LFSR state, which is also the number of leading zeros of the SERVICE field
+/// that reveal it
+pub const SEED_BITS: usize = 7;
+
+/// Generator of the scrambling sequence from a given state
+struct Lfsr(u8);
+
+impl Lfsr {
+    fn new(seed: u8) -> Self {
+        assert!(
+            seed < 1 << SEED_BITS,
+            "scrambler seed {} is wider than 7 bits",
+            seed
+        );
+        assert_ne!(seed, 0, "the all zeros scrambler seed never changes");
+        Self(seed)
+    }
+
+    /// Next bit of the sequence, which is also shifted into the register
+    fn next_bit(&mut self) -> u8 {
+        let bit = ((self.0 >> 6) ^ (self.0 >> 3)) & 1;
+        self.0 = ((self.0 << 1) | bit) & 0x7f;
+        bit
+    }
+}
+
+/// Scramble `bits` starting with the LFSR state `seed`, which must be non-zero and fit in 7 bits
+pub fn scramble(bits: &[u8], seed: u8) -> Vec<u8> {
+    let mut lfsr = Lfsr::new(seed);
+    bits.iter().map(|b| b ^ lfsr.next_bit()).collect()
+}
+
+/// Undo `scramble` with the same `seed`. Scrambling is an XOR, so this is the same operation
+pub fn descramble(bits: &[u8], seed: u8) -> Vec<u8> {
+    scramble(bits, seed)
+}
+
+/// Recover the seed the transmitter scrambled with from the first `SEED_BITS` scrambled bits of
+/// the DATA field, which are zeros before scrambling (the start of SERVICE). Returns `None` if
+/// there are too few bits or they are all zero, which no seed produces
+pub fn recover_seed(bits: &[u8]) -> Option<u8> {
+    let bits = bits.get(..SEED_BITS)?;
+    (1..1 << SEED_BITS).find(|seed| scramble(&[0; SEED_BITS], *seed) == bits)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{descramble, recover_seed, scramble, SEED_BITS};
+    use rand::Rng;
+
+    #[test]
+    fn test_scrambler_sequence() {
+        // The sequence for the all ones state, from the standard
+        let expected = "00001110 11110010 11001001 00000010 00100110 00101110 10110110 \
+                        00001100 11010100 11100111 10110100 00101010 11111010 01010001 \
+                        10111000 1111111";
+        let expected: Vec<u8> = expected
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| u8::from(c == '1'))
+            .collect();
+        assert_eq!(expected.len(), 127);
+        // It repeats every 127 bits
+        let zeros = vec![0; 2 * 127];
+        let sequence = scramble(&zeros, 0x7f);
+        assert_eq!(&sequence[..127], &expected[..]);
+        assert_eq!(&sequence[127..], &expected[..]);
+    }
+
+    #[test]
+    fn test_scramble_round_trip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let seed = rng.gen_range(1, 128);
+            // SERVICE starts with seven zeros
+            let mut bits = vec![0; SEED_BITS];
+            bits.extend((0..500).map(|_| rng.gen_range(0, 2)));
+            let scrambled = scramble(&bits, seed);
+            assert_ne!(scrambled, bits);
+            assert_eq!(descramble(&scrambled, seed), bits);
+            assert_eq!(recover_seed(&scrambled), Some(seed));
+        }
+        assert_eq!(recover_seed(&[0; SEED_BITS]), None);
+        assert_eq!(recover_seed(&[1; SEED_BITS - 1]), None);
+    }
+}
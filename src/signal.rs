@@ -0,0 +1,332 @@
+//! Partial decoding of the 802.11a SIGNAL symbol, the first OFDM symbol after the long preamble.
+//! It carries 24 bits (RATE, a reserved bit, LENGTH, parity and tail) encoded with the rate 1/2
+//! convolutional code and sent with BPSK on the 48 data subcarriers
+
+use crate::equalization::equalize_symbol_bins;
+use crate::error::ParseError;
+use crate::fec::{conv_encode, viterbi_decode};
+use crate::interleaver::interleaved_index;
+use crate::pilots::{insert_pilots, subcarrier_bin, PILOT_SUBCARRIERS};
+use num::{Complex, Zero};
+use std::fmt;
+
+/// Data rates of 802.11a with 20 MHz channels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Mbps6,
+    Mbps9,
+    Mbps12,
+    Mbps18,
+    Mbps24,
+    Mbps36,
+    Mbps48,
+    Mbps54,
+}
+
+/// Every data rate along with its RATE bits (R1 to R4, in the order they are transmitted)
+const RATE_BITS: [(DataRate, [u8; 4]); 8] = [
+    (DataRate::Mbps6, [1, 1, 0, 1]),
+    (DataRate::Mbps9, [1, 1, 1, 1]),
+    (DataRate::Mbps12, [0, 1, 0, 1]),
+    (DataRate::Mbps18, [0, 1, 1, 1]),
+    (DataRate::Mbps24, [1, 0, 0, 1]),
+    (DataRate::Mbps36, [1, 0, 1, 1]),
+    (DataRate::Mbps48, [0, 0, 0, 1]),
+    (DataRate::Mbps54, [0, 0, 1, 1]),
+];
+
+impl DataRate {
+    /// Data rate in Mbit/s
+    pub fn mbps(&self) -> f32 {
+        match self {
+            DataRate::Mbps6 => 6.,
+            DataRate::Mbps9 => 9.,
+            DataRate::Mbps12 => 12.,
+            DataRate::Mbps18 => 18.,
+            DataRate::Mbps24 => 24.,
+            DataRate::Mbps36 => 36.,
+            DataRate::Mbps48 => 48.,
+            DataRate::Mbps54 => 54.,
+        }
+    }
+
+    /// Modulation used on the data subcarriers
+    pub fn modulation(&self) -> &'static str {
+        match self {
+            DataRate::Mbps6 | DataRate::Mbps9 => "BPSK",
+            DataRate::Mbps12 | DataRate::Mbps18 => "QPSK",
+            DataRate::Mbps24 | DataRate::Mbps36 => "16-QAM",
+            DataRate::Mbps48 | DataRate::Mbps54 => "64-QAM",
+        }
+    }
+
+    /// Rate of the convolutional code
+    pub fn coding_rate(&self) -> &'static str {
+        match self {
+            DataRate::Mbps6 | DataRate::Mbps12 | DataRate::Mbps24 => "1/2",
+            DataRate::Mbps48 => "2/3",
+            DataRate::Mbps9 | DataRate::Mbps18 | DataRate::Mbps36 | DataRate::Mbps54 => "3/4",
+        }
+    }
+
+    /// The RATE bits (R1 to R4) that signal this rate
+    pub fn rate_bits(&self) -> [u8; 4] {
+        RATE_BITS.iter().find(|(r, _)| r == self).unwrap().1
+    }
+
+    /// Number of data bits carried by each OFDM symbol
+    pub fn data_bits_per_symbol(&self) -> usize {
+        match self {
+            DataRate::Mbps6 => 24,
+            DataRate::Mbps9 => 36,
+            DataRate::Mbps12 => 48,
+            DataRate::Mbps18 => 72,
+            DataRate::Mbps24 => 96,
+            DataRate::Mbps36 => 144,
+            DataRate::Mbps48 => 192,
+            DataRate::Mbps54 => 216,
+        }
+    }
+}
+
+impl fmt::Display for DataRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} Mbit/s ({}, rate {})",
+            self.mbps(),
+            self.modulation(),
+            self.coding_rate()
+        )
+    }
+}
+
+/// Contents of the SIGNAL field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignalField {
+    pub rate: DataRate,
+    /// Length of the PSDU in bytes (12 bits)
+    pub length: u16,
+    /// Whether the even parity over the RATE, reserved and LENGTH bits checked out
+    pub parity_ok: bool,
+}
+
+impl SignalField {
+    /// Number of data symbols following the SIGNAL symbol. They carry the 16-bit SERVICE field,
+    /// the PSDU and a 6-bit tail, padded to a whole number of symbols
+    pub fn num_data_symbols(&self) -> usize {
+        let bits = 16 + 8 * self.length as usize + 6;
+        let per_symbol = self.rate.data_bits_per_symbol();
+        (bits + per_symbol - 1) / per_symbol
+    }
+}
+
+/// FFT bin carrying each of the 48 data subcarriers, in increasing order of frequency
+fn data_subcarrier_bins(fft_len: usize) -> Vec<usize> {
+    (-26..=26)
+        .filter(|k| *k != 0 && !PILOT_SUBCARRIERS.contains(k))
+        .map(|k| subcarrier_bin(k, fft_len))
+        .collect()
+}
+
+/// Data subcarrier (as an index into `data_subcarrier_bins`) that carries coded bit `k` of a
+/// BPSK symbol after interleaving
+fn interleave(k: usize) -> usize {
+    interleaved_index(k, 1, 48)
+}
+
+/// Decode the SIGNAL field from `symbol`, the equalized FFT bins of the SIGNAL symbol (e.g. from
+/// `equalize_symbol_bins`, with unused subcarriers set to zero). Returns an error if the RATE
+/// isn't valid. A failed parity check is reported in `SignalField::parity_ok` instead, since the
+/// rate and length may still be of interest
+pub fn parse_signal(symbol: &[Complex<f32>]) -> Result<SignalField, ParseError> {
+    let data_bins = data_subcarrier_bins(symbol.len());
+    // BPSK maps 0 to -1 and 1 to +1
+    let soft: Vec<_> = (0..48)
+        .map(|k| symbol[data_bins[interleave(k)]].re)
+        .collect();
+    let bits = viterbi_decode(&soft);
+
+    let rate_bits = [bits[0], bits[1], bits[2], bits[3]];
+    let rate = RATE_BITS
+        .iter()
+        .find(|(_, b)| *b == rate_bits)
+        .map(|(rate, _)| *rate)
+        .ok_or(ParseError::InvalidSignalRate { rate_bits })?;
+    // LENGTH is sent least significant bit first
+    let length = bits[5..17]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| u16::from(*b) << i)
+        .sum();
+    Ok(SignalField {
+        rate,
+        length,
+        parity_ok: bits[..18].iter().sum::<u8>() % 2 == 0,
+    })
+}
+
+/// The SIGNAL symbol carrying `field` in the frequency domain, i.e. one value per FFT bin with the
+/// pilots of the first symbol after the long preamble (e.g. for `sim::modulate_symbol`). The
+/// parity bit is wrong if `field.parity_ok` is false, for testing receivers
+pub fn encode_signal(field: &SignalField, fft_len: usize) -> Vec<Complex<f32>> {
+    assert!(field.length < 1 << 12);
+    let mut bits = field.rate.rate_bits().to_vec();
+    bits.push(0);
+    bits.extend((0..12).map(|i| ((field.length >> i) & 1) as u8));
+    let parity = bits.iter().sum::<u8>() % 2;
+    bits.push(if field.parity_ok { parity } else { 1 - parity });
+    bits.extend(&[0; 6]);
+    assert_eq!(bits.len(), 24);
+
+    let coded = conv_encode(&bits);
+    let data_bins = data_subcarrier_bins(fft_len);
+    let mut freq = vec![Complex::zero(); fft_len];
+    for (k, c) in coded.iter().enumerate() {
+        freq[data_bins[interleave(k)]] = Complex::new(2. * f32::from(*c) - 1., 0.);
+    }
+    insert_pilots(&mut freq, 0);
+    freq
+}
+
+/// Demap the RATE field of the SIGNAL symbol. `signal_symbol` is the FFT window of the SIGNAL
+/// symbol (i.e. without its cyclic prefix) and `equalization` is from the long preamble (e.g.
+/// `estimate_subcarrier_equalization`). Since the first eight coded bits only depend on the four
+/// RATE bits, we pick the RATE that best explains them. Returns `None` if the best match isn't a
+/// valid rate, or if a data subcarrier the RATE is sent on isn't in use
+pub fn decode_signal_rate(
+    signal_symbol: &[Complex<f32>],
+    equalization: &[Option<Complex<f32>>],
+) -> Option<DataRate> {
+    let bins = equalize_symbol_bins(signal_symbol, equalization);
+    let data_bins = data_subcarrier_bins(bins.len());
+    // Soft value of each of the first eight coded bits. BPSK maps 0 to -1 and 1 to +1
+    let soft = (0..8)
+        .map(|k| bins[data_bins[interleave(k)]].map(|x| x.re))
+        .collect::<Option<Vec<_>>>()?;
+
+    // Try every combination of four bits, not just the valid ones, so a corrupted symbol is
+    // reported as `None` rather than a wrong rate
+    let score = |bits: &[u8]| -> f32 {
+        conv_encode(bits)
+            .iter()
+            .zip(&soft)
+            .map(|(c, s)| if *c == 1 { *s } else { -*s })
+            .sum()
+    };
+    let best = (0..16u8)
+        .map(|n| [(n >> 3) & 1, (n >> 2) & 1, (n >> 1) & 1, n & 1])
+        .fold(([0; 4], f32::MIN), |(best, max), bits| {
+            let s = score(&bits);
+            if s > max {
+                (bits, s)
+            } else {
+                (best, max)
+            }
+        })
+        .0;
+    RATE_BITS
+        .iter()
+        .find(|(_, bits)| *bits == best)
+        .map(|(rate, _)| *rate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ChannelEstConfig;
+    use crate::sim::modulate_symbol;
+
+    #[test]
+    fn test_interleave() {
+        // Every data subcarrier is used exactly once
+        let mut used: Vec<_> = (0..48).map(interleave).collect();
+        used.sort();
+        assert_eq!(used, (0..48).collect::<Vec<_>>());
+    }
+
+    /// Build the SIGNAL symbol for `rate` and a packet length of 100 bytes, through a channel
+    fn signal_symbol(rate: DataRate, config: &ChannelEstConfig) -> Vec<Complex<f32>> {
+        let fft_len = config.lts.as_ref().unwrap().0.len();
+        let field = SignalField {
+            rate,
+            length: 100,
+            parity_ok: true,
+        };
+        let mut freq = encode_signal(&field, fft_len);
+        // A channel that scales and rotates each subcarrier differently
+        for (i, x) in freq.iter_mut().enumerate() {
+            *x *= Complex::from_polar(&(0.5 + 0.01 * i as f32), &(0.1 * i as f32));
+        }
+        modulate_symbol(&freq, 0)
+    }
+
+    #[test]
+    fn test_decode_signal_rate() {
+        let config = ChannelEstConfig::default();
+        let fft_len = config.lts.as_ref().unwrap().0.len();
+        let equalization: Vec<_> = config
+            .lts
+            .as_ref()
+            .unwrap()
+            .1
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                l.map(|_| 1. / Complex::from_polar(&(0.5 + 0.01 * i as f32), &(0.1 * i as f32)))
+            })
+            .collect();
+
+        for (rate, _) in RATE_BITS.iter() {
+            let symbol = signal_symbol(*rate, &config);
+            assert_eq!(symbol.len(), fft_len);
+            assert_eq!(decode_signal_rate(&symbol, &equalization), Some(*rate));
+        }
+        assert_eq!(
+            format!("{}", DataRate::Mbps36),
+            "36 Mbit/s (16-QAM, rate 3/4)"
+        );
+
+        // Nothing was sent
+        let silence = vec![Complex::zero(); fft_len];
+        assert!(decode_signal_rate(&silence, &equalization).is_none());
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        let fft_len = 64;
+        for (rate, _) in RATE_BITS.iter() {
+            for length in &[1, 100, 1500, 4095] {
+                for parity_ok in &[true, false] {
+                    let field = SignalField {
+                        rate: *rate,
+                        length: *length,
+                        parity_ok: *parity_ok,
+                    };
+                    assert_eq!(parse_signal(&encode_signal(&field, fft_len)), Ok(field));
+                }
+            }
+        }
+
+        // 100 bytes at 6 Mbit/s is 16 + 800 + 6 bits in 24-bit symbols
+        let field = SignalField {
+            rate: DataRate::Mbps6,
+            length: 100,
+            parity_ok: true,
+        };
+        assert_eq!(field.num_data_symbols(), 35);
+
+        // RATE bits 0000 aren't a valid rate
+        let mut invalid = vec![Complex::zero(); fft_len];
+        let data_bins = data_subcarrier_bins(fft_len);
+        for (k, c) in conv_encode(&[0; 24]).iter().enumerate() {
+            invalid[data_bins[interleave(k)]] = Complex::new(2. * f32::from(*c) - 1., 0.);
+        }
+        assert_eq!(
+            parse_signal(&invalid),
+            Err(ParseError::InvalidSignalRate {
+                rate_bits: [0, 0, 0, 0]
+            })
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Helpers that apply receiver impairments to a buffer of samples, so tests can produce packets
+//! that look like what a real frontend delivers. These complement the impairments that
+//! `usrp::RadioSimulatorConfig` injects on the channel.
+
+use crate::config::ChannelEstConfig;
+use crate::iq_imbalance::IqImbalance;
+use crate::pilots::insert_pilots;
+use num::{Complex, Zero};
+use rand::Rng;
+use rustfft::FFTplanner;
+
+/// Emulates a receiver's automatic gain control (AGC). Real receivers change their gain shortly
+/// after a packet starts, typically partway through the short preamble. The gain is piecewise
+/// constant, with each change ramped linearly over `settle_samps` samples.
+#[derive(Clone, Debug)]
+pub struct AgcModel {
+    /// Amplitude gain applied before the first step
+    pub initial_gain: f32,
+    /// `(sample index, amplitude gain)` pairs in increasing order of sample index. At each index
+    /// the gain starts moving toward the new value
+    pub steps: Vec<(usize, f32)>,
+    /// Number of samples it takes for the gain to settle after a step. 0 means the step is
+    /// instantaneous
+    pub settle_samps: usize,
+}
+
+impl AgcModel {
+    /// A single gain step from `from` to `to` at sample `at`
+    pub fn step(from: f32, to: f32, at: usize, settle_samps: usize) -> Self {
+        Self {
+            initial_gain: from,
+            steps: vec![(at, to)],
+            settle_samps,
+        }
+    }
+
+    /// The amplitude gain applied to the `i`^th sample
+    pub fn gain_at(&self, i: usize) -> f32 {
+        let mut gain = self.initial_gain;
+        for &(at, target) in &self.steps {
+            if i < at {
+                break;
+            }
+            let progress = if self.settle_samps == 0 {
+                1.
+            } else {
+                ((i - at) as f32 / self.settle_samps as f32).min(1.)
+            };
+            gain += (target - gain) * progress;
+        }
+        gain
+    }
+
+    /// Apply the gain envelope to `samps`, where `samps[0]` is sample index 0
+    pub fn apply(&self, samps: &[Complex<f32>]) -> Vec<Complex<f32>> {
+        samps
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * self.gain_at(i))
+            .collect()
+    }
+}
+
+/// Multiply each sample by the corresponding amplitude gain. `gains` must be as long as `samps`
+pub fn apply_gain_envelope(samps: &[Complex<f32>], gains: &[f32]) -> Vec<Complex<f32>> {
+    assert_eq!(samps.len(), gains.len());
+    samps.iter().zip(gains).map(|(s, g)| s * g).collect()
+}
+
+/// Pass `samps` through a receiver with the IQ imbalance `imb`
+pub fn apply_iq_imbalance(samps: &[Complex<f32>], imb: &IqImbalance) -> Vec<Complex<f32>> {
+    let (mu, nu) = imb.coefficients();
+    samps.iter().map(|s| mu * s + nu * s.conj()).collect()
+}
+
+/// Add circularly-symmetric complex Gaussian noise with the given average power (i.e. E[|n|^2])
+/// to `samps`
+pub fn add_awgn<R: Rng>(samps: &mut [Complex<f32>], noise_power: f32, rng: &mut R) {
+    for s in samps {
+        // Box-Muller. |n|^2 is exponentially distributed and the phase is uniform
+        let u: f32 = 1. - rng.gen::<f32>();
+        let theta: f32 = 2. * std::f32::consts::PI * rng.gen::<f32>();
+        *s += Complex::from_polar(&(noise_power * -u.ln()).sqrt(), &theta);
+    }
+}
+
+/// Convert a frequency-domain symbol into time-domain samples and prepend a cyclic prefix of
+/// `cp_len` samples (usually `ChannelEstConfig::cp_len`). This is the inverse of `equalize_symbol`
+/// (with an identity equalization, up to the subcarriers that are in use)
+pub fn modulate_symbol(bins: &[Complex<f32>], cp_len: usize) -> Vec<Complex<f32>> {
+    assert!(cp_len <= bins.len());
+    let mut planner = FFTplanner::new(false);
+    let fft = planner.plan_fft(bins.len());
+    let mut symbol = vec![Complex::zero(); bins.len()];
+    fft.process(&mut bins.to_vec(), &mut symbol);
+
+    let mut res = Vec::with_capacity(cp_len + symbol.len());
+    res.extend(&symbol[symbol.len() - cp_len..]);
+    res.extend(&symbol);
+    res
+}
+
+/// `num_symbols` data symbols, each given as the value of every FFT bin like `config.lts`, with
+/// random BPSK on the subcarriers the LTS uses and 802.11 pilots (see `pilots::insert_pilots`)
+/// in place. Symbol `s` gets the pilot polarity of data symbol `s`
+pub fn random_bpsk_symbols<R: Rng>(
+    config: &ChannelEstConfig,
+    num_symbols: usize,
+    rng: &mut R,
+) -> Vec<Vec<Complex<f32>>> {
+    let lts_fft = &config.lts.as_ref().unwrap().1;
+    (0..num_symbols)
+        .map(|s| {
+            let mut bins: Vec<_> = lts_fft
+                .iter()
+                .map(|x| match x {
+                    Some(_) if rng.gen() => Complex::new(1., 0.),
+                    Some(_) => Complex::new(-1., 0.),
+                    None => Complex::zero(),
+                })
+                .collect();
+            insert_pilots(&mut bins, s);
+            bins
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cfo::estimate_cfo;
+    use crate::config::{build_preamble, ChannelEstConfig};
+    use crate::lts_align::lts_align;
+    use crate::pkt_trigger::PktTrigger;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_agc_model_gain() {
+        let agc = AgcModel::step(1., 0.5, 10, 4);
+        assert_eq!(agc.gain_at(0), 1.);
+        assert_eq!(agc.gain_at(9), 1.);
+        assert_eq!(agc.gain_at(10), 1.);
+        assert!((agc.gain_at(12) - 0.75).abs() < 1e-6);
+        assert_eq!(agc.gain_at(14), 0.5);
+        assert_eq!(agc.gain_at(1000), 0.5);
+
+        let samps = vec![Complex::new(1., -1.); 20];
+        let gains: Vec<_> = (0..20).map(|i| agc.gain_at(i)).collect();
+        assert_eq!(agc.apply(&samps), apply_gain_envelope(&samps, &gains));
+    }
+
+    /// A gain step in the middle of the short preamble should not prevent detection, alignment
+    /// or CFO estimation
+    #[test]
+    fn test_gain_step_mid_preamble() {
+        let cfo = 0.02;
+        let config = ChannelEstConfig::default();
+        let sts = config.sts.as_ref().unwrap();
+        let (lts, _, _, corr_plans) = config.lts.as_ref().unwrap();
+
+        // Silence, preamble and more silence. Then add CFO
+        let mut pkt = vec![Complex::zero(); 100];
+        let pkt_start = pkt.len();
+        pkt.extend(build_preamble(&config));
+        pkt.extend(std::iter::repeat(Complex::zero()).take(2 * config.pkt_spacing as usize));
+        let pkt: Vec<_> = pkt
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * Complex::new(0., cfo * i as f32).exp())
+            .collect();
+
+        // The AGC kicks in halfway through the short preamble, reducing gain by 12 dB
+        let agc = AgcModel::step(1., 0.25, pkt_start + 5 * sts.len(), 8);
+        let pkt = agc.apply(&pkt);
+
+        // Detect the packet
+        let mut trigger = PktTrigger::new(&config);
+        let detected: Vec<_> = pkt.iter().filter_map(|s| trigger.push_samp(*s)).collect();
+        assert_eq!(detected.len(), 1);
+        let detected = &detected[0].samples;
+
+        // Align and check we landed on the long preamble. Leave lts.len() / 2 samples of margin
+        let short_len = config.short_len();
+        let lts_bound = config.pkt_spacing as usize + short_len + 3 * lts.len();
+        let lts_start = lts_align(&detected[..lts_bound], lts, corr_plans);
+        let offset = pkt_start + short_len - lts_start;
+        assert_eq!(
+            detected[lts_start..],
+            pkt[offset + lts_start..offset + detected.len()]
+        );
+
+        // CFO estimation only depends on phase, so the gain step shouldn't matter
+        let short = &detected[lts_start - short_len..lts_start];
+        let long = &detected[lts_start..lts_start + 5 * lts.len() / 2];
+        let cfo_est = estimate_cfo(short, long, &config);
+        assert!((cfo_est - cfo).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_add_awgn_power() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut samps = vec![Complex::zero(); 100_000];
+        add_awgn(&mut samps, 0.5, &mut rng);
+        let power = samps.iter().map(|x| x.norm_sqr()).sum::<f32>() / samps.len() as f32;
+        assert!((power - 0.5).abs() < 0.01);
+        let mean = samps.iter().sum::<Complex<f32>>() / samps.len() as f32;
+        assert!(mean.norm() < 0.01);
+    }
+}